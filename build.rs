@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The target triple names the binary that self-update downloads.
+    println!("cargo:rustc-env=TARGET={}", std::env::var("TARGET")?);
+    // Use the vendored protoc, so that building does not require a system installation.
+    // SAFETY: The build script is single-threaded.
+    unsafe {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/logs.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,386 @@
+//! Alerts for log lines matching configured patterns, sent to a webhook, Slack, or Discord.
+//!
+//! The webhook receives every alert as a JSON payload; Slack and Discord receive a message
+//! rendered from a template. To avoid alert storms, every pattern fires at most once per
+//! rate-limit interval, and with a dedup window the same line fires at most once per window;
+//! lines matched in between are counted and reported as `suppressed` with the next alert of
+//! that pattern. Every destination sends its alerts in order and keeps to the rate limits of
+//! the chat services, waiting when they ask it to slow down.
+
+use crate::event::LogEvent;
+use crate::template::Template;
+use log::{error, info, warn};
+use regex::Regex;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use url::Url;
+
+/// Timeout of a single webhook request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Template of the Slack and Discord messages when --alert-template is not given.
+pub const DEFAULT_ALERT_TEMPLATE: &str = "{canister} on {node}: {msg}";
+
+/// Alerts queued per destination; further alerts are dropped while the destination is slow.
+const QUEUE_CAPACITY: usize = 100;
+
+/// Shortest time between two messages to Slack or Discord, whose webhooks accept about one
+/// message per second.
+const CHAT_MIN_SPACING: Duration = Duration::from_secs(1);
+
+/// Attempts to send an alert that a chat service rate-limited.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Longest wait requested by a chat service that is honored.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Longest message text; Discord rejects longer messages, and Slack truncates them.
+const DISCORD_MAX_CHARS: usize = 2000;
+const SLACK_MAX_CHARS: usize = 3000;
+
+/// How often flushing checks whether the queued alerts are sent.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Entries of the dedup window above which expired lines are pruned.
+const DEDUP_PRUNE_THRESHOLD: usize = 10_000;
+
+/// The kind of service that receives alerts.
+#[derive(Clone, Copy)]
+enum Service {
+    Webhook,
+    Slack,
+    Discord,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Webhook => "webhook",
+            Self::Slack => "Slack",
+            Self::Discord => "Discord",
+        }
+    }
+}
+
+/// A webhook that receives alerts.
+pub struct Destination {
+    service: Service,
+    url: Url,
+}
+
+impl Destination {
+    /// A webhook receiving every alert as a JSON payload.
+    pub fn webhook(url: Url) -> Self {
+        Self {
+            service: Service::Webhook,
+            url,
+        }
+    }
+
+    /// A Slack incoming webhook.
+    pub fn slack(url: Url) -> Self {
+        Self {
+            service: Service::Slack,
+            url,
+        }
+    }
+
+    /// A Discord channel webhook.
+    pub fn discord(url: Url) -> Self {
+        Self {
+            service: Service::Discord,
+            url,
+        }
+    }
+}
+
+/// Rate-limit state of a single pattern.
+struct PatternState {
+    last_fired: Option<Instant>,
+    suppressed: u64,
+}
+
+struct AlertRule {
+    pattern: Regex,
+    state: Mutex<PatternState>,
+}
+
+/// The queue of a destination, drained by its sending task.
+struct Outbox {
+    service: Service,
+    queue: mpsc::Sender<(String, Value)>,
+    /// Alerts queued or being sent.
+    pending: Arc<AtomicUsize>,
+}
+
+/// Sends alerts for matching log lines to the destinations.
+pub struct Alerter {
+    rules: Vec<AlertRule>,
+    outboxes: Vec<Outbox>,
+    template: Template,
+    min_interval: Duration,
+    dedup_window: Option<Duration>,
+    /// When each pattern last fired for a line, by pattern index and line.
+    fired_lines: Mutex<HashMap<(usize, String), Instant>>,
+}
+
+impl Alerter {
+    pub fn new(
+        patterns: Vec<Regex>,
+        destinations: Vec<Destination>,
+        template: Template,
+        min_interval: Duration,
+        dedup_window: Option<Duration>,
+        client: reqwest::Client,
+    ) -> Self {
+        let hosts: Vec<String> = destinations
+            .iter()
+            .map(|destination| {
+                format!(
+                    "{} {}",
+                    destination.service.name(),
+                    destination.url.host_str().unwrap_or_default()
+                )
+            })
+            .collect();
+        info!(
+            "Alerting on {} pattern(s) via {}.",
+            patterns.len(),
+            hosts.join(", ")
+        );
+        let rules = patterns
+            .into_iter()
+            .map(|pattern| AlertRule {
+                pattern,
+                state: Mutex::new(PatternState {
+                    last_fired: None,
+                    suppressed: 0,
+                }),
+            })
+            .collect();
+        let outboxes = destinations
+            .into_iter()
+            .map(|destination| {
+                let (queue, alerts) = mpsc::channel(QUEUE_CAPACITY);
+                let service = destination.service;
+                let pending = Arc::new(AtomicUsize::new(0));
+                tokio::spawn(deliver(
+                    destination,
+                    alerts,
+                    pending.clone(),
+                    client.clone(),
+                ));
+                Outbox {
+                    service,
+                    queue,
+                    pending,
+                }
+            })
+            .collect();
+        Self {
+            rules,
+            outboxes,
+            template,
+            min_interval,
+            dedup_window,
+            fired_lines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if the line matches any of the patterns.
+    pub fn matches(&self, line: &str) -> bool {
+        self.rules.iter().any(|rule| rule.pattern.is_match(line))
+    }
+
+    /// Checks a log event against all patterns and sends alerts for matches.
+    pub fn check(&self, event: &LogEvent) {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.pattern.is_match(&event.message) {
+                continue;
+            }
+
+            let suppressed = {
+                let mut state = rule.state.lock().unwrap();
+                let now = Instant::now();
+                if state
+                    .last_fired
+                    .is_some_and(|last| now.duration_since(last) < self.min_interval)
+                    || self.fired_recently(index, &event.message, now)
+                {
+                    state.suppressed += 1;
+                    continue;
+                }
+                state.last_fired = Some(now);
+                std::mem::take(&mut state.suppressed)
+            };
+
+            let pattern = rule.pattern.as_str();
+            for outbox in &self.outboxes {
+                let payload = match outbox.service {
+                    Service::Webhook => json!({
+                        "pattern": pattern,
+                        "canister_id": event.canister_id,
+                        "node": event.node,
+                        "line": event.message,
+                        "timestamp": event.timestamp_rfc3339(),
+                        "suppressed": suppressed,
+                    }),
+                    Service::Slack => json!({
+                        "text": self.chat_text(event, pattern, suppressed, SLACK_MAX_CHARS, true),
+                    }),
+                    Service::Discord => json!({
+                        "content":
+                            self.chat_text(event, pattern, suppressed, DISCORD_MAX_CHARS, false),
+                        // Lines must not ping anyone through @everyone or user mentions.
+                        "allowed_mentions": {"parse": []},
+                    }),
+                };
+                outbox.pending.fetch_add(1, Ordering::Relaxed);
+                if outbox
+                    .queue
+                    .try_send((pattern.to_string(), payload))
+                    .is_err()
+                {
+                    outbox.pending.fetch_sub(1, Ordering::Relaxed);
+                    warn!(
+                        "Dropped alert for pattern {pattern}: too many alerts waiting for {}.",
+                        outbox.service.name()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Waits until the queued alerts are sent, e.g. on exit.
+    pub async fn flush(&self) {
+        while self
+            .outboxes
+            .iter()
+            .any(|outbox| outbox.pending.load(Ordering::Relaxed) > 0)
+        {
+            sleep(FLUSH_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Returns whether the pattern fired for the same line within the dedup window, and
+    /// otherwise records that it fires now.
+    fn fired_recently(&self, index: usize, line: &str, now: Instant) -> bool {
+        let Some(window) = self.dedup_window else {
+            return false;
+        };
+        let mut fired_lines = self.fired_lines.lock().unwrap();
+        if fired_lines.len() > DEDUP_PRUNE_THRESHOLD {
+            fired_lines.retain(|_, fired| now.duration_since(*fired) < window);
+        }
+        let key = (index, line.to_string());
+        if fired_lines
+            .get(&key)
+            .is_some_and(|fired| now.duration_since(*fired) < window)
+        {
+            return true;
+        }
+        fired_lines.insert(key, now);
+        false
+    }
+
+    /// Renders the message of a chat alert, escaping the line for Slack, which would otherwise
+    /// interpret `<!channel>` and links in it.
+    fn chat_text(
+        &self,
+        event: &LogEvent,
+        pattern: &str,
+        suppressed: u64,
+        max_chars: usize,
+        slack: bool,
+    ) -> String {
+        let mut text = self.template.render(event);
+        if slack {
+            text = text
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+        }
+        let mut footer = format!("\n(pattern `{pattern}`");
+        if suppressed > 0 {
+            footer.push_str(&format!(", {suppressed} more matches since the last alert"));
+        }
+        footer.push(')');
+        let room = max_chars.saturating_sub(footer.chars().count());
+        if text.chars().count() > room {
+            text = text.chars().take(room.saturating_sub(1)).collect();
+            text.push('…');
+        }
+        text.push_str(&footer);
+        text
+    }
+}
+
+/// Sends the alerts queued for a destination one after the other.
+async fn deliver(
+    destination: Destination,
+    mut alerts: mpsc::Receiver<(String, Value)>,
+    pending: Arc<AtomicUsize>,
+    client: reqwest::Client,
+) {
+    let service = destination.service.name();
+    let mut next_send = Instant::now();
+    while let Some((pattern, payload)) = alerts.recv().await {
+        for attempt in 1..=MAX_ATTEMPTS {
+            sleep_until(next_send).await;
+            if !matches!(destination.service, Service::Webhook) {
+                next_send = Instant::now() + CHAT_MIN_SPACING;
+            }
+            let response = client
+                .post(destination.url.clone())
+                .timeout(REQUEST_TIMEOUT)
+                .json(&payload)
+                .send()
+                .await;
+            match response {
+                Ok(response)
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS
+                        && attempt < MAX_ATTEMPTS =>
+                {
+                    let wait = retry_after(&response).min(MAX_RETRY_AFTER);
+                    warn!(
+                        "{service} rate-limited the alert for pattern {pattern}, retrying in {}.",
+                        humantime::format_duration(wait)
+                    );
+                    sleep(wait).await;
+                }
+                Ok(response) => {
+                    match response.error_for_status() {
+                        Ok(_) => info!("Sent alert for pattern {pattern} to {service}."),
+                        Err(e) => {
+                            error!("Failed to send alert for pattern {pattern} to {service}: {e}")
+                        }
+                    }
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to send alert for pattern {pattern} to {service}: {e}");
+                    break;
+                }
+            }
+        }
+        pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the wait requested by the Retry-After header of a response, in seconds as sent by
+/// Slack and Discord.
+fn retry_after(response: &reqwest::Response) -> Duration {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map_or(CHAT_MIN_SPACING, Duration::from_secs_f64)
+}
@@ -0,0 +1,76 @@
+//! Hooks that attach metadata to log events, such as the deployment version or tenant, before
+//! the scripts, filters, and outputs see them.
+//!
+//! A hook is an async function that may change the event in place. Hooks run in the order
+//! they are registered, in the annotation stage of the event bus, which annotates at most a
+//! limited number of events at once, so that hooks which look up metadata elsewhere cannot
+//! pile up work when nodes deliver faster than the lookups complete. Applications that embed the client register
+//! their hooks with `Client::annotator`, and `--annotate` registers a hook that sets fixed
+//! fields after them.
+
+use crate::event::LogEvent;
+use futures_util::future::BoxFuture;
+use serde_json::{Map, Value};
+
+/// Default maximum number of events annotated at once.
+pub const DEFAULT_MAX_CONCURRENT: usize = 64;
+
+/// An async function that annotates an event.
+type Hook = Box<dyn for<'a> Fn(&'a mut LogEvent) -> BoxFuture<'a, ()> + Send + Sync>;
+
+/// The hooks applied to every event, in order.
+pub struct Annotator {
+    hooks: Vec<Hook>,
+    max_concurrent: usize,
+}
+
+impl Annotator {
+    /// Creates an annotator without hooks that annotates at most `max_concurrent` events at
+    /// once.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            hooks: Vec::new(),
+            max_concurrent: max_concurrent.max(1),
+        }
+    }
+
+    /// Registers a hook, which runs after the hooks registered before it.
+    pub fn register<F>(&mut self, hook: F)
+    where
+        F: for<'a> Fn(&'a mut LogEvent) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Returns the most events annotated at once.
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Runs the hooks on the event.
+    pub async fn annotate(&self, event: &mut LogEvent) {
+        for hook in &self.hooks {
+            hook(event).await;
+        }
+    }
+}
+
+/// Returns a hook that sets fixed fields, overwriting fields of the record with the same name.
+pub fn fixed_fields(fields: Vec<(String, String)>) -> impl Fn(&mut LogEvent) -> BoxFuture<'_, ()> {
+    move |event| {
+        let target = event.fields.get_or_insert_with(Map::new);
+        for (key, value) in &fields {
+            target.insert(key.clone(), Value::String(value.clone()));
+        }
+        Box::pin(async {})
+    }
+}
+
+/// Parses a fixed field given as KEY=VALUE.
+pub fn parse_field(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value.split_once('=').ok_or("expected KEY=VALUE")?;
+    if key.is_empty() {
+        return Err("the key must not be empty".to_string());
+    }
+    Ok((key.to_string(), value.to_string()))
+}
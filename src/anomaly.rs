@@ -0,0 +1,344 @@
+//! Detection of unusual log rates per canister, enabled with `--anomaly-factor`.
+//!
+//! Every `--anomaly-window`, the lines and the error lines that each canister logged in the
+//! window are compared with their baselines, moving averages over `--anomaly-baseline` of the
+//! previous windows. A count at least the factor times its baseline is a spike, and a line
+//! count at most the baseline divided by the factor is a drop, e.g. a canister that stopped
+//! logging. Counts below `--anomaly-min-lines` never make a spike, and baselines below it
+//! never make a drop, so that a quiet canister logging 2 lines instead of 0 raises nothing.
+//!
+//! Only changes are reported: when a rate becomes a spike or a drop, and when it is back to
+//! normal. Reports are written to stderr whatever the level filter, counted in the statistics,
+//! listed by the control interface, and sent to the `--anomaly-webhook` if given. Spikes and
+//! drops also start a recording with `--anomaly-record-dir`. The baselines keep learning during
+//! an anomaly, so a lasting change of the rate becomes the new normal after a while.
+
+use crate::connection::ConnectionConfig;
+use crate::event::LogEvent;
+use crate::selflog;
+use crate::severity::Severity;
+use chrono::{SecondsFormat, Utc};
+use log::{error, Level};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval_at, Duration, Instant};
+use url::Url;
+
+/// Windows counted before a baseline is trusted.
+const WARM_UP_WINDOWS: u32 = 5;
+
+/// Time within which the webhook must accept a report.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parses the factor by which a rate must deviate from its baseline.
+pub fn parse_factor(value: &str) -> Result<f64, String> {
+    let factor: f64 = value
+        .parse()
+        .map_err(|_| format!("expected a number like 3 or 2.5, got {value}"))?;
+    if !factor.is_finite() || factor <= 1.0 {
+        return Err(format!("the factor {value} must be greater than 1"));
+    }
+    Ok(factor)
+}
+
+/// The rates that are watched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Metric {
+    /// All lines.
+    Rate,
+    /// Lines with the error severity.
+    ErrorRate,
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Rate => "rate",
+            Self::ErrorRate => "error_rate",
+        }
+    }
+
+    fn lines(self) -> &'static str {
+        match self {
+            Self::Rate => "lines",
+            Self::ErrorRate => "error lines",
+        }
+    }
+}
+
+/// How a rate compares with its baseline.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Normal,
+    Spike,
+    Drop,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Normal => "normal",
+            Self::Spike => "spike",
+            Self::Drop => "drop",
+        })
+    }
+}
+
+/// The baseline of one rate of a canister.
+#[derive(Default)]
+struct Baseline {
+    average: f64,
+    state: State,
+}
+
+/// The counts and baselines of a canister.
+#[derive(Default)]
+struct Canister {
+    lines: u64,
+    errors: u64,
+    /// Windows evaluated so far.
+    windows: u32,
+    rate: Baseline,
+    error_rate: Baseline,
+}
+
+/// A rate that changed its state.
+struct Anomaly {
+    canister_id: String,
+    metric: Metric,
+    state: State,
+    count: u64,
+    baseline: f64,
+}
+
+/// Settings of the anomaly detection.
+pub struct AnomalySettings {
+    pub factor: f64,
+    pub window: Duration,
+    pub baseline: Duration,
+    pub min_lines: u64,
+    pub webhook: Option<Url>,
+}
+
+/// Learns the baselines of the canisters and reports their anomalies.
+pub struct AnomalyDetector {
+    settings: AnomalySettings,
+    client: reqwest::Client,
+    canisters: Mutex<HashMap<String, Canister>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(settings: AnomalySettings, client: reqwest::Client) -> Self {
+        Self {
+            settings,
+            client,
+            canisters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.settings.window
+    }
+
+    /// Counts a line received from a canister.
+    pub fn record(&self, event: &LogEvent) {
+        let mut canisters = self.canisters.lock().unwrap();
+        let canister = canisters.entry(event.canister_id.clone()).or_default();
+        canister.lines += 1;
+        if Severity::of(event) == Severity::Error {
+            canister.errors += 1;
+        }
+    }
+
+    /// Forgets the counts of the current window, e.g. while the connections are parked.
+    fn discard(&self) {
+        for canister in self.canisters.lock().unwrap().values_mut() {
+            canister.lines = 0;
+            canister.errors = 0;
+        }
+    }
+
+    /// Compares the counts of the ended window of the monitored canisters with their
+    /// baselines, updates the baselines, and returns the rates that changed their state.
+    fn evaluate(&self, canister_ids: &[String]) -> Vec<Anomaly> {
+        let settings = &self.settings;
+        let mut canisters = self.canisters.lock().unwrap();
+        canisters.retain(|canister_id, _| canister_ids.contains(canister_id));
+        let mut anomalies = Vec::new();
+        for canister_id in canister_ids {
+            let canister = canisters.entry(canister_id.clone()).or_default();
+            let counts = [
+                (Metric::Rate, canister.lines, &mut canister.rate),
+                (Metric::ErrorRate, canister.errors, &mut canister.error_rate),
+            ];
+            for (metric, count, baseline) in counts {
+                if canister.windows >= WARM_UP_WINDOWS {
+                    let state = settings.state(metric, count, baseline.average);
+                    if state != baseline.state {
+                        baseline.state = state;
+                        anomalies.push(Anomaly {
+                            canister_id: canister_id.clone(),
+                            metric,
+                            state,
+                            count,
+                            baseline: baseline.average,
+                        });
+                    }
+                }
+                // Until the baseline spans enough windows, it is the plain average of them.
+                let weight = (settings.window.as_secs_f64() / settings.baseline.as_secs_f64())
+                    .max(1.0 / f64::from(canister.windows + 1))
+                    .min(1.0);
+                baseline.average += weight * (count as f64 - baseline.average);
+            }
+            canister.windows = canister.windows.saturating_add(1);
+            canister.lines = 0;
+            canister.errors = 0;
+        }
+        anomalies
+    }
+
+    /// Writes an anomaly to stderr and sends it to the webhook.
+    fn report(&self, anomaly: &Anomaly) {
+        let Anomaly {
+            canister_id,
+            metric,
+            state,
+            count,
+            baseline,
+        } = anomaly;
+        let window = humantime::format_duration(self.settings.window);
+        let lines = metric.lines();
+        let (level, message) = match state {
+            State::Spike => (
+                Level::Warn,
+                format!(
+                    "Anomaly: canister {canister_id} logged {count} {lines} in {window}, a spike \
+                     over its baseline of {baseline:.1}."
+                ),
+            ),
+            State::Drop => (
+                Level::Warn,
+                format!(
+                    "Anomaly: canister {canister_id} logged {count} {lines} in {window}, a drop \
+                     below its baseline of {baseline:.1}."
+                ),
+            ),
+            State::Normal => (
+                Level::Info,
+                format!(
+                    "The {} of canister {canister_id} is back to normal with {count} {lines} in \
+                     {window}.",
+                    metric.name().replace('_', " ")
+                ),
+            ),
+        };
+        selflog::report(level, module_path!(), &message);
+        let Some(url) = self.settings.webhook.clone() else {
+            return;
+        };
+        let payload = json!({
+            "canister_id": canister_id,
+            "metric": metric.name(),
+            "state": state.to_string(),
+            "count": count,
+            "baseline": baseline,
+            "factor": self.settings.factor,
+            "window_secs": self.settings.window.as_secs_f64(),
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        });
+        let request = self
+            .client
+            .post(url)
+            .timeout(REQUEST_TIMEOUT)
+            .json(&payload);
+        let canister_id = canister_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                error!("Failed to send the anomaly of canister {canister_id} to the webhook: {e}");
+            }
+        });
+    }
+
+    /// Returns the baselines and states of the canisters, for the control interface.
+    pub fn to_json(&self) -> Value {
+        let canisters = self.canisters.lock().unwrap();
+        let canisters: serde_json::Map<String, Value> = canisters
+            .iter()
+            .map(|(canister_id, canister)| {
+                let rate = |baseline: &Baseline| {
+                    json!({"baseline": baseline.average, "state": baseline.state.to_string()})
+                };
+                (
+                    canister_id.clone(),
+                    json!({
+                        "warming_up": canister.windows < WARM_UP_WINDOWS,
+                        "rate": rate(&canister.rate),
+                        "error_rate": rate(&canister.error_rate),
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "factor": self.settings.factor,
+            "window_secs": self.settings.window.as_secs_f64(),
+            "canisters": canisters,
+        })
+    }
+}
+
+impl AnomalySettings {
+    /// Returns the state of a rate with the count of a window and the baseline.
+    fn state(&self, metric: Metric, count: u64, baseline: f64) -> State {
+        let min_lines = self.min_lines as f64;
+        let count = count as f64;
+        if count >= min_lines && count >= baseline * self.factor {
+            State::Spike
+        } else if metric == Metric::Rate && baseline >= min_lines && count <= baseline / self.factor
+        {
+            State::Drop
+        } else {
+            State::Normal
+        }
+    }
+}
+
+/// Evaluates the rates every window until the client ends.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    let Some(detector) = &config.anomalies else {
+        return;
+    };
+    let every = detector.window();
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + every, every);
+        loop {
+            ticker.tick().await;
+            let Some(detector) = &config.anomalies else {
+                return;
+            };
+            // Parked connections receive nothing, which is not a drop of the rates.
+            if config.parked.load(Ordering::Relaxed) {
+                detector.discard();
+                continue;
+            }
+            let canister_ids = config.canister_ids.borrow().clone();
+            for anomaly in detector.evaluate(&canister_ids) {
+                if anomaly.state != State::Normal {
+                    config.stats.record_anomaly(&anomaly.canister_id);
+                    if let Some(recorder) = &config.flight_recorder {
+                        recorder.trigger(&anomaly.canister_id);
+                    }
+                }
+                detector.report(&anomaly);
+            }
+        }
+    });
+}
@@ -0,0 +1,35 @@
+//! Removal of ANSI escape sequences and other control characters from the messages, so that a
+//! canister cannot move the cursor, retitle the terminal, or hide text in the terminal of
+//! whoever tails its logs.
+//!
+//! With `--keep-ansi`, the sequences that only set colors and text styles (SGR, `ESC [ ... m`)
+//! are kept, so that the lines look the way the canister colored them; all other sequences
+//! are removed either way.
+
+use regex::Regex;
+use std::sync::LazyLock;
+use strip_ansi_escapes::strip_str;
+
+/// A sequence that sets colors or text styles, and nothing else.
+static STYLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;:]*m").unwrap());
+
+/// Removes the escape sequences and control characters other than line feeds from a message,
+/// keeping the sequences that set colors and text styles if `keep_styles` is set.
+pub fn clean(message: String, keep_styles: bool) -> String {
+    // Most lines hold no control characters at all, and are passed on without copying.
+    if !message.chars().any(|c| c.is_control() && c != '\n') {
+        return message;
+    }
+    if !keep_styles {
+        return strip_str(&message);
+    }
+    let mut cleaned = String::with_capacity(message.len());
+    let mut rest = message.as_str();
+    while let Some(style) = STYLE.find(rest) {
+        cleaned.push_str(&strip_str(&rest[..style.start()]));
+        cleaned.push_str(style.as_str());
+        rest = &rest[style.end()..];
+    }
+    cleaned.push_str(&strip_str(rest));
+    cleaned
+}
@@ -0,0 +1,319 @@
+//! Load generation against the log stream endpoint of the API boundary nodes.
+//!
+//! Boundary node operators can check how a node copes with many subscribers: the benchmark
+//! opens a number of parallel connections per node for a canister, keeps them open for a
+//! while, and reports how long the handshakes took, how many messages and bytes the
+//! connections received, and how many of them the node closed or lost before the end.
+
+use crate::connection::{self, ConnectTimeouts, Upgrade, WsStream};
+use crate::deflate::Compression;
+use crate::endpoint::PathTemplate;
+use crate::error::Error;
+use crate::nodes;
+use crate::proxy;
+use crate::resolve::Resolver;
+use crate::tls::TlsSettings;
+use crate::BenchArgs;
+use futures_util::StreamExt;
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout_at, Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+/// Most distinct errors listed per node in the report.
+const MAX_LISTED_ERRORS: usize = 3;
+
+/// How a benchmark connection ended.
+enum Ending {
+    /// The connection stayed open until the end of the benchmark.
+    Open,
+    /// The node closed the connection, with its Close code if it sent one.
+    Closed(Option<u16>),
+    /// The connection could not be established or failed.
+    Failed(String),
+}
+
+/// Measurements of a single connection.
+struct ConnectionResult {
+    handshake: Option<Duration>,
+    messages: u64,
+    bytes: u64,
+    ending: Ending,
+}
+
+/// The report of one node.
+#[derive(Serialize)]
+struct NodeReport {
+    domain: String,
+    connections: usize,
+    connected: usize,
+    handshake_p50_ms: Option<f64>,
+    handshake_p95_ms: Option<f64>,
+    handshake_max_ms: Option<f64>,
+    messages: u64,
+    bytes: u64,
+    messages_per_sec: f64,
+    bytes_per_sec: f64,
+    /// Connections that stayed open until the end.
+    open_at_end: usize,
+    /// Connections closed by the node before the end, by Close code.
+    closed_by_node: BTreeMap<String, usize>,
+    failed: usize,
+    errors: Vec<String>,
+}
+
+impl NodeReport {
+    fn new(domain: String, results: &[ConnectionResult], duration: Duration) -> Self {
+        let mut handshakes: Vec<Duration> = results.iter().filter_map(|r| r.handshake).collect();
+        handshakes.sort();
+        let percentile = |p: f64| {
+            let index = ((handshakes.len() as f64 * p).ceil() as usize).max(1) - 1;
+            handshakes.get(index).map(|d| d.as_secs_f64() * 1000.0)
+        };
+        let messages = results.iter().map(|r| r.messages).sum();
+        let bytes = results.iter().map(|r| r.bytes).sum();
+        let mut closed_by_node = BTreeMap::new();
+        let mut errors = Vec::new();
+        let mut failed = 0;
+        for result in results {
+            match &result.ending {
+                Ending::Open => {}
+                Ending::Closed(code) => {
+                    let code = code.map_or("none".to_string(), |code| code.to_string());
+                    *closed_by_node.entry(code).or_default() += 1;
+                }
+                Ending::Failed(error) => {
+                    failed += 1;
+                    if errors.len() < MAX_LISTED_ERRORS && !errors.contains(error) {
+                        errors.push(error.clone());
+                    }
+                }
+            }
+        }
+        Self {
+            domain,
+            connections: results.len(),
+            connected: handshakes.len(),
+            handshake_p50_ms: percentile(0.5),
+            handshake_p95_ms: percentile(0.95),
+            handshake_max_ms: handshakes.last().map(|d| d.as_secs_f64() * 1000.0),
+            messages,
+            bytes,
+            messages_per_sec: messages as f64 / duration.as_secs_f64(),
+            bytes_per_sec: bytes as f64 / duration.as_secs_f64(),
+            open_at_end: results
+                .iter()
+                .filter(|r| matches!(r.ending, Ending::Open))
+                .count(),
+            closed_by_node,
+            failed,
+            errors,
+        }
+    }
+}
+
+/// Settings shared by all benchmark connections.
+struct Target {
+    canister_id: String,
+    endpoint: PathTemplate,
+    proxy: Option<Url>,
+    tls: TlsSettings,
+    resolver: Resolver,
+    compression: Compression,
+}
+
+/// Runs the benchmark and prints the report.
+pub async fn run(args: &BenchArgs) -> Result<(), Error> {
+    let proxy = proxy::resolve(args.proxy.as_deref())?;
+    let domains = if args.node.is_empty() {
+        let http_client = proxy::http_client(proxy.as_ref())?;
+        nodes::fetch(http_client, &args.ic.endpoints(), args.all_subnets)
+            .await?
+            .into_iter()
+            .map(|node| node.domain)
+            .collect()
+    } else {
+        args.node.clone()
+    };
+    if domains.is_empty() {
+        return Err("no API boundary nodes found".into());
+    }
+
+    let target = Arc::new(Target {
+        canister_id: args.canister_id.clone(),
+        endpoint: args.endpoint_path_template.clone(),
+        proxy,
+        tls: TlsSettings::new(&[], None, None, false)?,
+        resolver: Resolver::new(Vec::new(), None)?,
+        compression: args.compression,
+    });
+    let connections = args.connections.get();
+    info!(
+        "Opening {connections} connections to each of {} nodes for {}.",
+        domains.len(),
+        humantime::format_duration(args.duration)
+    );
+    let start = Instant::now();
+    let end = start + args.duration;
+    let mut tasks = JoinSet::new();
+    for (node, domain) in domains.iter().enumerate() {
+        for _ in 0..connections {
+            let target = target.clone();
+            let domain = domain.clone();
+            tasks.spawn(async move { (node, measure(&domain, &target, end).await) });
+        }
+    }
+    let mut results: Vec<Vec<ConnectionResult>> = domains.iter().map(|_| Vec::new()).collect();
+    while let Some(joined) = tasks.join_next().await {
+        let (node, result) = joined?;
+        results[node].push(result);
+    }
+
+    let reports: Vec<NodeReport> = domains
+        .into_iter()
+        .zip(&results)
+        .map(|(domain, results)| NodeReport::new(domain, results, args.duration))
+        .collect();
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        print_table(&reports);
+    }
+    Ok(())
+}
+
+/// Opens a connection and reads from it until the end of the benchmark.
+async fn measure(domain: &str, target: &Target, end: Instant) -> ConnectionResult {
+    let mut result = ConnectionResult {
+        handshake: None,
+        messages: 0,
+        bytes: 0,
+        ending: Ending::Open,
+    };
+    let url = match target.endpoint.url(domain, &target.canister_id) {
+        Ok(url) => url,
+        Err(e) => {
+            result.ending = Ending::Failed(e);
+            return result;
+        }
+    };
+    let started = Instant::now();
+    let connection = connection::connect_websocket(
+        &url,
+        target.proxy.as_ref(),
+        Upgrade {
+            compression: target.compression,
+            headers: &[],
+        },
+        None,
+        &target.tls,
+        &target.resolver,
+        ConnectTimeouts::DEFAULT,
+    );
+    let mut stream: WsStream = match timeout_at(end, connection).await {
+        Ok(Ok((stream, _))) => stream,
+        Ok(Err(e)) => {
+            result.ending = Ending::Failed(e.to_string());
+            return result;
+        }
+        Err(_) => {
+            result.ending = Ending::Failed("handshake did not complete in time".to_string());
+            return result;
+        }
+    };
+    result.handshake = Some(started.elapsed());
+
+    loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(bin))) => {
+                    result.messages += 1;
+                    result.bytes += bin.len() as u64;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    result.messages += 1;
+                    result.bytes += text.len() as u64;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    result.ending = Ending::Closed(frame.map(|frame| u16::from(frame.code)));
+                    return result;
+                }
+                // Pings are answered by the WebSocket library while reading.
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    result.ending = Ending::Failed(e.to_string());
+                    return result;
+                }
+                None => {
+                    result.ending = Ending::Closed(None);
+                    return result;
+                }
+            },
+            _ = sleep_until(end) => {
+                let _ = stream.close(None).await;
+                return result;
+            }
+        }
+    }
+}
+
+fn print_table(reports: &[NodeReport]) {
+    let width = reports
+        .iter()
+        .map(|report| report.domain.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let millis = |ms: Option<f64>| ms.map_or("-".to_string(), |ms| format!("{ms:.0}"));
+    println!(
+        "{:<width$}  {:>9}  {:>7}  {:>7}  {:>7}  {:>9}  {:>8}  {:>11}  {:>6}  {:>6}  {:>6}",
+        "NODE",
+        "CONNECTED",
+        "P50 MS",
+        "P95 MS",
+        "MAX MS",
+        "MESSAGES",
+        "MSG/S",
+        "BYTES/S",
+        "OPEN",
+        "CLOSED",
+        "FAILED",
+    );
+    for report in reports {
+        println!(
+            "{:<width$}  {:>9}  {:>7}  {:>7}  {:>7}  {:>9}  {:>8.1}  {:>11.0}  {:>6}  {:>6}  {:>6}",
+            report.domain,
+            format!("{}/{}", report.connected, report.connections),
+            millis(report.handshake_p50_ms),
+            millis(report.handshake_p95_ms),
+            millis(report.handshake_max_ms),
+            report.messages,
+            report.messages_per_sec,
+            report.bytes_per_sec,
+            report.open_at_end,
+            report.closed_by_node.values().sum::<usize>(),
+            report.failed,
+        );
+    }
+    for report in reports {
+        if !report.closed_by_node.is_empty() {
+            let codes: Vec<String> = report
+                .closed_by_node
+                .iter()
+                .map(|(code, count)| format!("{count} with code {code}"))
+                .collect();
+            println!(
+                "{}: closed by the node: {}",
+                report.domain,
+                codes.join(", ")
+            );
+        }
+        for error in &report.errors {
+            println!("{}: failed: {error}", report.domain);
+        }
+    }
+}
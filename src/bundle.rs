@@ -0,0 +1,318 @@
+//! Support bundles recorded by the `capture` subcommand, for attaching to support tickets.
+//!
+//! A capture tails the logs like the `tail` subcommand for `--duration`, and then writes a
+//! gzip-compressed tar archive to `--out` holding, in a directory named after the archive:
+//!
+//! - `frames.icblog`, a capture of the raw frames, as written by `--record`, which the
+//!   `replay` subcommand reads,
+//! - `diagnostics.jsonl`, the client's own messages at the info level or above, as with
+//!   `--self-log-format json`, including the connection attempts and errors of every node,
+//! - `stats.json`, the statistics of every node, as written by `--stats-file`,
+//! - `nodes.json`, the API boundary nodes listed by the registry when the capture started,
+//! - `config.json`, the version and platform of the client, the options given on the command
+//!   line or in the environment, and the `--config` file.
+//!
+//! Everything is sanitized with the `--redact` and `--redact-builtin` patterns before it is
+//! written to disk: the frames byte for byte, as with `--record-redacted`, and the other files
+//! in their string values. Options that hold credentials, such as tokens, API keys, headers,
+//! and webhook URLs, are replaced entirely, and user names and passwords removed from URLs.
+//! The files are staged in a private temporary directory, which is removed afterwards.
+
+use crate::error::Error;
+use crate::exit::ExitReason;
+use crate::proxy;
+use crate::redact::{Redactor, MASK};
+use crate::selflog::{self, SelfLogFormat};
+use crate::{nodes, CaptureArgs, Cli, Client, Source, TailArgs};
+use chrono::{SecondsFormat, Utc};
+use clap::parser::ValueSource;
+use clap::CommandFactory;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::{Builder, Header};
+use tokio::time::{timeout, Duration};
+use url::Url;
+
+const FRAMES: &str = "frames.icblog";
+const DIAGNOSTICS: &str = "diagnostics.jsonl";
+const STATS: &str = "stats.json";
+const NODES: &str = "nodes.json";
+const CONFIG: &str = "config.json";
+
+/// Time within which the registry must list the nodes for the inventory.
+const INVENTORY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options whose values are credentials even though they cannot be given in the environment
+/// with hidden values.
+const SECRET_OPTIONS: &[&str] = &["headers", "otlp_header", "dedup_redis"];
+
+/// Records a support bundle.
+pub async fn capture(
+    args: CaptureArgs,
+    format: SelfLogFormat,
+    verbose: u8,
+    client: Client,
+) -> Result<ExitCode, Error> {
+    let staging = Staging::create()?;
+    let diagnostics = File::create(staging.path(DIAGNOSTICS))
+        .map_err(Error::io("failed to create the diagnostics file"))?;
+    selflog::init_with_copy(format, verbose, diagnostics);
+    crate::init(format, verbose);
+
+    let mut tail = args.tail;
+    if tail.redact.is_empty() && tail.redact_builtin.is_empty() {
+        warn!("No --redact or --redact-builtin patterns are given, so the bundle is not redacted.");
+    }
+    let redactor = Redactor::new(tail.redact.clone(), &tail.redact_builtin);
+    let started = Utc::now();
+    let mut config = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "target": env!("TARGET"),
+        "os": std::env::consts::OS,
+        "started": started.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "duration_secs": args.duration.as_secs_f64(),
+        "options": options(&redactor),
+        "config_file": tail.config.as_deref().map(|path| config_file(path, &redactor)),
+    });
+    let mut nodes = inventory(&tail).await;
+    redactor.redact_value(&mut nodes);
+
+    let exit_after = tail.exit_after;
+    tail.exit_after = Some(exit_after.map_or(args.duration, |after| after.min(args.duration)));
+    tail.record = Some(staging.path(FRAMES));
+    tail.record_redacted = true;
+    tail.stats_file = Some(staging.path(STATS));
+    info!(
+        "Capturing for {} into {}.",
+        humantime::format_duration(args.duration),
+        args.out.display()
+    );
+    let result = crate::tail(tail, Source::Nodes, client).await;
+    config["result"] = match &result {
+        Ok(_) => "completed".into(),
+        Err(e) => e.to_string().into(),
+    };
+    log::logger().flush();
+
+    staging
+        .write(CONFIG, &config)
+        .and_then(|()| staging.write(NODES, &nodes))
+        .and_then(|()| staging.redact_diagnostics(&redactor))
+        .and_then(|()| archive(&staging, &args.out))
+        .map_err(Error::io(format!(
+            "failed to write the support bundle {}",
+            args.out.display()
+        )))?;
+    info!("Wrote the support bundle {}.", args.out.display());
+
+    match result {
+        // The capture ending after --duration is its success.
+        Ok(code) if code == ExitReason::TimedOut.code() && exit_after.is_none() => {
+            Ok(ExitCode::SUCCESS)
+        }
+        result => result,
+    }
+}
+
+/// Returns the options given on the command line or in the environment, with credentials
+/// masked.
+fn options(redactor: &Redactor) -> Value {
+    let command = Cli::command();
+    let Ok(matches) = command.clone().try_get_matches_from(std::env::args_os()) else {
+        return Value::Null;
+    };
+    let (Some(capture), Some(args)) = (
+        command.find_subcommand("capture"),
+        matches.subcommand_matches("capture"),
+    ) else {
+        return Value::Null;
+    };
+    let mut options = serde_json::Map::new();
+    let mut from_environment = Vec::new();
+    for arg in capture.get_arguments() {
+        let id = arg.get_id().as_str();
+        let source = args.value_source(id);
+        if !matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let name = match arg.get_long() {
+            Some(long) => format!("--{long}"),
+            None => id.to_string(),
+        };
+        let secret =
+            arg.is_hide_env_values_set() || SECRET_OPTIONS.contains(&id) || id.ends_with("webhook");
+        let values: Vec<Value> = args
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|value| {
+                if secret {
+                    return MASK.into();
+                }
+                let value = value.to_string_lossy();
+                let value = match Url::parse(&value) {
+                    Ok(mut url) if !url.username().is_empty() || url.password().is_some() => {
+                        let _ = url.set_username("");
+                        let _ = url.set_password(None);
+                        url.to_string()
+                    }
+                    _ => value.into_owned(),
+                };
+                redactor.redact_text(&value).into_owned().into()
+            })
+            .collect();
+        if source == Some(ValueSource::EnvVariable) {
+            from_environment.push(Value::from(name.clone()));
+        }
+        options.insert(
+            name,
+            match <[Value; 1]>::try_from(values) {
+                Ok([value]) => value,
+                Err(values) => values.into(),
+            },
+        );
+    }
+    json!({"given": options, "from_environment": from_environment})
+}
+
+/// Returns the contents of the config file, with credentials masked.
+fn config_file(path: &Path, redactor: &Redactor) -> Value {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => return json!({"error": format!("failed to read {}: {e}", path.display())}),
+    };
+    let mut contents: Value = match serde_json::from_str(&contents) {
+        Ok(contents) => contents,
+        Err(e) => return json!({"error": format!("invalid JSON in {}: {e}", path.display())}),
+    };
+    mask_secrets(&mut contents);
+    redactor.redact_value(&mut contents);
+    contents
+}
+
+/// Masks the values of the keys that name credentials, such as the webhooks of the alerts.
+fn mask_secrets(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                if [
+                    "webhook", "token", "key", "password", "secret", "header", "redis",
+                ]
+                .iter()
+                .any(|secret| key.contains(secret))
+                {
+                    *value = MASK.into();
+                } else {
+                    mask_secrets(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+/// Lists the API boundary nodes, or the reason they could not be listed.
+async fn inventory(args: &TailArgs) -> Value {
+    let fetched = async {
+        let proxy = proxy::resolve(args.proxy.as_deref())?;
+        let client = proxy::http_client(proxy.as_ref())?;
+        Ok::<_, Error>(nodes::fetch(client, &args.ic.endpoints(), args.all_subnets).await?)
+    };
+    match timeout(INVENTORY_TIMEOUT, fetched).await {
+        Ok(Ok(nodes)) => json!({"nodes": nodes}),
+        Ok(Err(e)) => json!({"error": e.to_string()}),
+        Err(_) => json!({"error": "timed out listing the API boundary nodes"}),
+    }
+}
+
+/// A private temporary directory for the files of the bundle, removed when dropped.
+struct Staging {
+    dir: PathBuf,
+}
+
+impl Staging {
+    fn create() -> Result<Self, Error> {
+        let dir = std::env::temp_dir().join(format!("ic-bn-logs-bundle-{}", std::process::id()));
+        #[cfg(unix)]
+        let created = fs::DirBuilder::new().mode(0o700).create(&dir);
+        #[cfg(not(unix))]
+        let created = fs::create_dir(&dir);
+        created.map_err(Error::io(format!(
+            "failed to create the staging directory {}",
+            dir.display()
+        )))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn write(&self, name: &str, value: &Value) -> io::Result<()> {
+        fs::write(self.path(name), serde_json::to_string_pretty(value)? + "\n")
+    }
+
+    /// Masks the patterns in the messages of the diagnostics.
+    fn redact_diagnostics(&self, redactor: &Redactor) -> io::Result<()> {
+        let path = self.path(DIAGNOSTICS);
+        let mut redacted = String::new();
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let mut line: Value = serde_json::from_str(&line?)?;
+            redactor.redact_value(&mut line);
+            redacted.push_str(&line.to_string());
+            redacted.push('\n');
+        }
+        fs::write(path, redacted)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Writes the staged files that exist into a gzip-compressed tar archive.
+fn archive(staging: &Staging, out: &Path) -> io::Result<()> {
+    let name = out
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = name
+        .strip_suffix(".tar.gz")
+        .or_else(|| name.strip_suffix(".tgz"))
+        .unwrap_or(&name);
+    let dir = if dir.is_empty() { "bundle" } else { dir };
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut tar = Builder::new(GzEncoder::new(File::create(out)?, Compression::default()));
+    for name in [CONFIG, NODES, STATS, DIAGNOSTICS, FRAMES] {
+        let file = match File::open(staging.path(name)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        let mut header = Header::new_ustar();
+        header.set_size(len);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        tar.append_data(&mut header, format!("{dir}/{name}"), file.take(len))?;
+    }
+    tar.into_inner()?.finish()?.sync_all()
+}
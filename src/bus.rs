@@ -0,0 +1,441 @@
+//! The event bus that carries the log events from their sources through the processing stages
+//! to the output.
+//!
+//! The sources, i.e. the connections to the nodes, confirmation, and backfill, publish their
+//! events to the bus. Every processing stage runs in a task of its own and passes the events
+//! it keeps on to the next one through a bounded channel: the rates and upgrades of the
+//! canisters are observed, the lines are sampled, checked for injection attempts, annotated,
+//! run through the scripts, and redacted. The last stage routes them through the filters,
+//! context windows, alerts, and exit conditions to the output scheduler or the output, which
+//! hands them to the queue of every sink, see [`Output`](crate::output::Output).
+//!
+//! The events leave every stage in the order they were published, even from stages that
+//! process several at once. A stage that falls behind fills the channel before it, holding up
+//! the stages before it and finally the sources, while the stages after it go on with the
+//! events they already have.
+
+use crate::annotate::Annotator;
+use crate::connection::ConnectionConfig;
+use crate::context::Unmatched;
+use crate::event::LogEvent;
+use crate::filter::Verdict;
+use crate::redact::Redactor;
+use crate::sample::Sampler;
+use crate::script::ScriptPipeline;
+use crate::taint;
+use futures_util::stream::{FuturesOrdered, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Most messages waiting in the channel before every stage.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// What passes along the stages.
+enum Message {
+    Event(LogEvent),
+    /// Answered by the last stage once all events published before it are routed.
+    Flush(oneshot::Sender<()>),
+}
+
+/// A processing stage of the events.
+pub trait Stage: Send + Sync + 'static {
+    /// Most events processed at once.
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// Processes an event, returning the event to pass on, or None to drop it.
+    fn process(&self, event: LogEvent) -> impl Future<Output = Option<LogEvent>> + Send;
+}
+
+/// The end of the bus to which the sources publish their events.
+pub struct Bus {
+    sender: mpsc::Sender<Message>,
+}
+
+impl Bus {
+    /// Publishes an event, waiting while the first stage is behind.
+    pub async fn publish(&self, event: LogEvent) {
+        // The stages run as long as the runtime.
+        let _ = self.sender.send(Message::Event(event)).await;
+    }
+
+    /// Waits until the events published so far are routed, e.g. before shutting down the
+    /// output.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+/// The events published to the bus, until they are passed to the stages.
+pub struct Published(mpsc::Receiver<Message>);
+
+/// Creates the bus.
+pub fn channel() -> (Bus, Published) {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    (Bus { sender }, Published(receiver))
+}
+
+/// The stages of the bus, started one by one in the order they are added.
+pub struct Pipeline {
+    events: mpsc::Receiver<Message>,
+    config: Arc<ConnectionConfig>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline of the published events without any stages.
+    pub fn new(published: Published, config: Arc<ConnectionConfig>) -> Self {
+        Self {
+            events: published.0,
+            config,
+        }
+    }
+
+    /// Starts a stage processing the events passed on by the stages added before.
+    pub fn with_stage(self, stage: impl Stage) -> Self {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = self.config.clone();
+        tokio::spawn(run(stage, self.events, sender, move |node| {
+            config.stats.record_filtered(node)
+        }));
+        Self {
+            events: receiver,
+            config: self.config,
+        }
+    }
+
+    /// Starts the last stage, which routes the events passed on by the other stages to the
+    /// output.
+    pub fn route(self) {
+        let Self {
+            mut events,
+            config,
+        } = self;
+        tokio::spawn(async move {
+            while let Some(message) = events.recv().await {
+                match message {
+                    Message::Event(event) => {
+                        room_for(&event.canister_id, &config).await;
+                        route(event, &config).await;
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Runs a stage until the stages before it are gone, calling `filtered` with the node of
+/// every event it drops.
+async fn run(
+    stage: impl Stage,
+    mut input: mpsc::Receiver<Message>,
+    output: mpsc::Sender<Message>,
+    filtered: impl Fn(&str),
+) {
+    let process = |message| {
+        let stage = &stage;
+        async move {
+            match message {
+                Message::Event(event) => {
+                    let node = event.node.clone();
+                    stage.process(event).await.map(Message::Event).ok_or(node)
+                }
+                flush => Ok(flush),
+            }
+        }
+    };
+    let concurrency = stage.concurrency().max(1);
+    let mut pending = FuturesOrdered::new();
+    let mut closed = false;
+    loop {
+        tokio::select! {
+            message = input.recv(), if !closed && pending.len() < concurrency => match message {
+                Some(message) => pending.push_back(process(message)),
+                None => closed = true,
+            },
+            Some(processed) = pending.next() => match processed {
+                Ok(message) => {
+                    if output.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                Err(node) => filtered(&node),
+            },
+            else => return,
+        }
+    }
+}
+
+/// Learns the line rates of the canisters and notices their upgrades, before any line is
+/// sampled or dropped by the scripts or filters.
+pub struct Observe(pub Arc<ConnectionConfig>);
+
+impl Stage for Observe {
+    async fn process(&self, event: LogEvent) -> Option<LogEvent> {
+        let config = &self.0;
+        if let Some(anomalies) = &config.anomalies {
+            anomalies.record(&event);
+        }
+        // Markers are annotated even if the filters drop them, as the epochs concern all lines.
+        if let Some(upgrades) = &config.upgrades {
+            upgrades.observe(&event, &config.output);
+        }
+        Some(event)
+    }
+}
+
+/// Drops all but a sample of the lines.
+pub struct Sample(pub Sampler);
+
+impl Stage for Sample {
+    async fn process(&self, event: LogEvent) -> Option<LogEvent> {
+        self.0.keeps(&event.message).then_some(event)
+    }
+}
+
+/// Marks the lines that look like injection attempts, and runs the annotation hooks.
+pub struct Annotate {
+    pub detect_injection: bool,
+    pub annotator: Option<Annotator>,
+}
+
+impl Stage for Annotate {
+    fn concurrency(&self) -> usize {
+        self.annotator
+            .as_ref()
+            .map_or(1, Annotator::max_concurrent)
+    }
+
+    async fn process(&self, mut event: LogEvent) -> Option<LogEvent> {
+        if self.detect_injection {
+            event.taint = taint::detect(&event.message);
+        }
+        if let Some(annotator) = &self.annotator {
+            annotator.annotate(&mut event).await;
+        }
+        Some(event)
+    }
+}
+
+/// Runs the scripts, which may drop the lines, or change them before the filters see them.
+pub struct Script(pub Arc<ScriptPipeline>);
+
+impl Stage for Script {
+    async fn process(&self, event: LogEvent) -> Option<LogEvent> {
+        self.0.apply(event)
+    }
+}
+
+/// Masks sensitive data in the lines.
+pub struct Redact(pub Arc<Redactor>);
+
+impl Stage for Redact {
+    async fn process(&self, mut event: LogEvent) -> Option<LogEvent> {
+        self.0.redact(&mut event);
+        Some(event)
+    }
+}
+
+/// Waits with `--buffer-drop-policy block` until the queues that the lines of the canister
+/// are written to have room, so that slow destinations hold up the connections and the bus.
+///
+/// The connections wait before publishing a line, so that a canister that floods mostly holds
+/// up its own connections, and the last stage again before routing it, so that the lines
+/// published while there was room do not overfill the queues.
+pub async fn room_for(canister_id: &str, config: &ConnectionConfig) {
+    match &config.scheduler {
+        Some(scheduler) => scheduler.ready(canister_id).await,
+        None => config.output.ready().await,
+    }
+}
+
+/// Passes a line through the filters and context windows to the outputs and alerts.
+async fn route(event: LogEvent, config: &ConnectionConfig) {
+    let alerter = config.alerter.read().unwrap().clone();
+    let verdict = config.filters.check(&event);
+    match &config.context {
+        // Lines matching an alert pattern are written with their context even if the include
+        // filters drop them.
+        Some(context)
+            if verdict == Verdict::Accepted
+                || verdict == Verdict::NotIncluded
+                    && alerter
+                        .as_ref()
+                        .is_some_and(|alerter| alerter.matches(&event.message)) =>
+        {
+            for preceding in context.matched(&event) {
+                write(&preceding, config).await;
+            }
+            if !write(&event, config).await {
+                return;
+            }
+        }
+        Some(context) if verdict == Verdict::NotIncluded => {
+            match context.unmatched(event.clone()) {
+                Unmatched::Following(event) => {
+                    write(&event, config).await;
+                }
+                Unmatched::Held(Some(dropped)) => config.stats.record_filtered(&dropped.node),
+                Unmatched::Held(None) => {}
+            }
+        }
+        _ if verdict == Verdict::Accepted => {
+            if !write(&event, config).await {
+                return;
+            }
+        }
+        _ => config.stats.record_filtered(&event.node),
+    }
+    if let Some(alerter) = alerter {
+        alerter.check(&event);
+    }
+}
+
+/// Writes a line that passed the filters to the output, unless the exit conditions stop it.
+async fn write(event: &LogEvent, config: &ConnectionConfig) -> bool {
+    if let Some(exit) = &config.exit
+        && !exit.admit(&event.message)
+    {
+        return false;
+    }
+    if let Some(summarizer) = &config.summarizer {
+        summarizer.record(event);
+    }
+    if let Some(counter) = &config.counter {
+        counter.record(event);
+    }
+    if let Some(counter) = &config.line_counter {
+        counter.record(event);
+    }
+    match &config.scheduler {
+        Some(scheduler) => {
+            for dropped in scheduler.submit(event.clone()) {
+                config.stats.record_dropped(&dropped.node);
+            }
+        }
+        None => config.output.write(event).await,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotate;
+    use crate::sample::SampleRate;
+    use crate::taint::Taint;
+    use regex::Regex;
+    use std::sync::Mutex;
+    use tokio::time::{sleep, Duration};
+
+    fn event(message: &str) -> LogEvent {
+        LogEvent::received("node", "canister", message.to_string(), false)
+    }
+
+    /// Drops the lines saying "drop", and takes longer for the earlier lines.
+    struct Slow;
+
+    impl Stage for Slow {
+        fn concurrency(&self) -> usize {
+            4
+        }
+
+        async fn process(&self, event: LogEvent) -> Option<LogEvent> {
+            let delay: u64 = event.message.trim_start_matches("drop ").parse().unwrap();
+            sleep(Duration::from_millis(10 * (5 - delay))).await;
+            (!event.message.starts_with("drop")).then_some(event)
+        }
+    }
+
+    #[tokio::test]
+    async fn run_keeps_the_order_and_reports_dropped_lines() {
+        let (input, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (sender, mut output) = mpsc::channel(CHANNEL_CAPACITY);
+        let filtered = Arc::new(Mutex::new(Vec::new()));
+        let reported = filtered.clone();
+        tokio::spawn(run(Slow, receiver, sender, move |node| {
+            reported.lock().unwrap().push(node.to_string())
+        }));
+        for message in ["0", "drop 1", "2", "3", "drop 4", "5"] {
+            input.send(Message::Event(event(message))).await.unwrap();
+        }
+        let (done, _flushed) = oneshot::channel();
+        input.send(Message::Flush(done)).await.unwrap();
+        drop(input);
+
+        let mut messages = Vec::new();
+        let mut flushed = false;
+        while let Some(message) = output.recv().await {
+            match message {
+                Message::Event(event) => {
+                    assert!(!flushed, "{} passed the flush", event.message);
+                    messages.push(event.message);
+                }
+                Message::Flush(_) => flushed = true,
+            }
+        }
+        assert_eq!(messages, ["0", "2", "3", "5"]);
+        assert!(flushed);
+        assert_eq!(*filtered.lock().unwrap(), ["node", "node"]);
+    }
+
+    #[tokio::test]
+    async fn sample_keeps_all_lines_at_full_rate() {
+        let sample = Sample(Sampler::new(SampleRate::parse("1/1").unwrap(), None));
+        assert!(sample.process(event("line")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn sample_keeps_or_drops_the_lines_of_a_key_together() {
+        let key = Regex::new(r"request (\d+)").unwrap();
+        let sample = Sample(Sampler::new(SampleRate::parse("1/2").unwrap(), Some(key)));
+        for request in 0..20 {
+            let first = sample.process(event(&format!("request {request} started"))).await;
+            let second = sample.process(event(&format!("request {request} ended"))).await;
+            assert_eq!(first.is_some(), second.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn annotate_marks_injection_attempts() {
+        let annotate = Annotate {
+            detect_injection: true,
+            annotator: None,
+        };
+        let marked = annotate.process(event("ok\x07")).await.unwrap();
+        assert_eq!(marked.taint, [Taint::ControlCharacters]);
+        let clean = annotate.process(event("ok")).await.unwrap();
+        assert!(clean.taint.is_empty());
+    }
+
+    #[tokio::test]
+    async fn annotate_runs_the_hooks() {
+        let mut annotator = Annotator::new(8);
+        annotator.register(annotate::fixed_fields(vec![(
+            "tenant".to_string(),
+            "acme".to_string(),
+        )]));
+        let annotate = Annotate {
+            detect_injection: false,
+            annotator: Some(annotator),
+        };
+        assert_eq!(annotate.concurrency(), 8);
+        let event = annotate.process(event("line")).await.unwrap();
+        assert_eq!(event.fields.unwrap()["tenant"], "acme");
+    }
+
+    #[tokio::test]
+    async fn redact_masks_the_lines() {
+        let redactor = Redactor::new(vec![Regex::new(r"token=\w+").unwrap()], &[]);
+        let redact = Redact(Arc::new(redactor));
+        let event = redact.process(event("login token=abc123 ok")).await.unwrap();
+        assert_eq!(event.message, format!("login {} ok", crate::redact::MASK));
+    }
+}
@@ -0,0 +1,196 @@
+//! Validation of canister IDs and resolution of canister names and dapp URLs.
+//!
+//! Names are resolved from a mapping file, or from a dfx project: `canister_ids.json` holds the
+//! IDs of the canisters deployed to the IC, and `dfx.json` those of remote canisters.
+//!
+//! URLs are resolved from their hostname, e.g. `<canister_id>.icp0.io` or
+//! `<canister_id>.raw.icp0.io`, or from a `canisterId` query parameter. Custom domains are
+//! looked up through the HTTP gateway, which names the canister serving a domain in the
+//! `x-ic-canister-id` header of its responses.
+
+use candid::Principal;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+/// The dfx network whose canister IDs are used, since logs are streamed from the IC.
+const NETWORK: &str = "ic";
+
+/// Domains of the HTTP gateways that serve canisters under `<canister_id>.<domain>`.
+const GATEWAY_DOMAINS: &[&str] = &[
+    "icp0.io",
+    "raw.icp0.io",
+    "ic0.app",
+    "raw.ic0.app",
+    "icp-api.io",
+    "localhost",
+];
+
+/// The `--canister-id` that selects all canisters of the `--subnet`.
+pub const ALL: &str = "all";
+
+/// Header in which the HTTP gateway names the canister that served a response.
+const CANISTER_ID_HEADER: &str = "x-ic-canister-id";
+
+/// Timeout of looking up a custom domain through the HTTP gateway.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parses a canister ID, returning it in its canonical textual form.
+pub fn parse_canister_id(value: &str) -> Result<String, String> {
+    let principal = Principal::from_text(value.trim()).map_err(|e| {
+        format!(
+            "{value} is not a valid canister ID ({e}); \
+             canister IDs look like ryjl3-tyaaa-aaaaa-aaaba-cai"
+        )
+    })?;
+    // Canister IDs are opaque principals, whose last byte is 0x01.
+    if principal.as_slice().last() != Some(&0x01) {
+        return Err(format!(
+            "{value} is a principal, but not a canister ID; \
+             canister IDs look like ryjl3-tyaaa-aaaaa-aaaba-cai"
+        ));
+    }
+    Ok(principal.to_text())
+}
+
+/// Parses a canister ID, or a selection of the canisters of the `--subnet`: `all`, or a prefix
+/// of their IDs followed by `*`, e.g. `rdmx6-*`.
+pub fn parse_canister_selector(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value == ALL {
+        return Ok(ALL.to_string());
+    }
+    if let Some(prefix) = value.strip_suffix('*') {
+        let prefix = prefix.to_ascii_lowercase();
+        if prefix
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '2'..='7' | '-'))
+        {
+            return Ok(format!("{prefix}*"));
+        }
+        return Err(format!(
+            "{value} is not a valid canister ID prefix; prefixes look like ryjl3-*"
+        ));
+    }
+    parse_canister_id(value)
+}
+
+/// Returns whether a `--canister-id` value selects canisters of the `--subnet` instead of
+/// naming one.
+pub fn is_selector(value: &str) -> bool {
+    value == ALL || value.ends_with('*')
+}
+
+/// Returns whether a canister is selected by `all` or a prefix pattern.
+pub fn selects(selector: &str, canister_id: &str) -> bool {
+    selector == ALL
+        || selector
+            .strip_suffix('*')
+            .is_some_and(|prefix| canister_id.starts_with(prefix))
+}
+
+/// Resolves the URL or hostname of a dapp to the ID of the canister serving it.
+pub async fn resolve_url(value: &str, client: &reqwest::Client) -> Result<String, String> {
+    let url = if value.contains("://") {
+        Url::parse(value)
+    } else {
+        Url::parse(&format!("https://{value}"))
+    }
+    .map_err(|e| format!("{value} is not a valid URL: {e}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("{value} has no hostname"))?;
+
+    if let Some((_, canister_id)) = url.query_pairs().find(|(name, _)| name == "canisterId") {
+        return parse_canister_id(&canister_id);
+    }
+    if let Some((label, domain)) = host.split_once('.')
+        && GATEWAY_DOMAINS.contains(&domain)
+        && let Ok(canister_id) = parse_canister_id(label)
+    {
+        return Ok(canister_id);
+    }
+
+    let response = client
+        .get(url.clone())
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("failed to look up the canister of {host}: {e}"))?;
+    let canister_id = response
+        .headers()
+        .get(CANISTER_ID_HEADER)
+        .and_then(|canister_id| canister_id.to_str().ok())
+        .ok_or_else(|| {
+            format!(
+                "{host} is not served by a canister: the response has no {CANISTER_ID_HEADER} \
+                 header; pass --canister-id instead"
+            )
+        })?;
+    parse_canister_id(canister_id).map_err(|e| format!("{host}: {e}"))
+}
+
+/// Resolves a canister name to its ID, from the mapping file if given, else from the dfx
+/// project in `project`.
+pub fn resolve(name: &str, mapping: Option<&Path>, project: &Path) -> Result<String, String> {
+    let id = match mapping {
+        Some(mapping) => lookup(&read_json(mapping)?, name)
+            .ok_or_else(|| format!("canister {name} is not listed in {}", mapping.display()))?,
+        None => resolve_in_project(name, project)?,
+    };
+    parse_canister_id(&id).map_err(|e| format!("canister {name}: {e}"))
+}
+
+fn resolve_in_project(name: &str, project: &Path) -> Result<String, String> {
+    let canister_ids = project.join("canister_ids.json");
+    if canister_ids.exists()
+        && let Some(id) = lookup(&read_json(&canister_ids)?, name)
+    {
+        return Ok(id);
+    }
+
+    let dfx_json = project.join("dfx.json");
+    if !dfx_json.exists() {
+        return Err(format!(
+            "cannot resolve canister {name}: {} contains neither canister_ids.json nor dfx.json",
+            project.display()
+        ));
+    }
+    let dfx = read_json(&dfx_json)?;
+    let Some(canister) = dfx
+        .get("canisters")
+        .and_then(|canisters| canisters.get(name))
+    else {
+        return Err(format!(
+            "canister {name} is not defined in {}",
+            dfx_json.display()
+        ));
+    };
+    canister
+        .pointer(&format!("/remote/id/{NETWORK}"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            format!(
+                "canister {name} has no ID on the {NETWORK} network; \
+                 deploy it with 'dfx deploy --network {NETWORK}' or pass --canister-id"
+            )
+        })
+}
+
+/// Looks up a name in a mapping of names to IDs, or to IDs per network as in
+/// `canister_ids.json`.
+fn lookup(mapping: &Value, name: &str) -> Option<String> {
+    match mapping.get(name)? {
+        Value::String(id) => Some(id.clone()),
+        networks => networks.get(NETWORK)?.as_str().map(str::to_string),
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    serde_json::from_str(&contents).map_err(|e| format!("failed to parse {}: {e}", path.display()))
+}
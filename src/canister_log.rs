@@ -0,0 +1,229 @@
+//! The log records that the Internet Computer retains for a canister, fetched from the
+//! management canister with `fetch_canister_logs`.
+//!
+//! With `--backfill`, the retained records of every canister are written as backfill, with
+//! their index and timestamp, before the connections to the nodes start. The nodes may then
+//! deliver some of the same records live, so live lines that repeat a fetched record are
+//! dropped as duplicates: records with an `idx` field up to the last fetched index, and at the
+//! start of every stream, lines whose message equals that of a fetched record, until the first
+//! line that does not.
+
+use crate::ansi;
+use crate::event::LogEvent;
+use crate::logfmt::LineParser;
+use crate::nodes::Endpoints;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use futures_util::future::join_all;
+use ic_agent::{Agent, AgentError};
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+/// Longest time fetching the records of a canister may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The argument of `fetch_canister_logs`.
+#[derive(CandidType)]
+struct FetchCanisterLogsArgs {
+    canister_id: Principal,
+}
+
+/// The result of `fetch_canister_logs`.
+#[derive(CandidType, Deserialize)]
+struct FetchCanisterLogsResult {
+    canister_log_records: Vec<CanisterLogRecord>,
+}
+
+/// A record of the canister log.
+#[derive(CandidType, Deserialize)]
+pub struct CanisterLogRecord {
+    /// Position of the record in the log of the canister, increasing by one per record.
+    pub idx: u64,
+    pub timestamp_nanos: u64,
+    pub content: Vec<u8>,
+}
+
+/// Fetches the records retained for a canister, oldest first, as an anonymous caller. This
+/// succeeds only if the log visibility of the canister is public.
+pub async fn fetch(
+    agent: &Agent,
+    canister_id: Principal,
+) -> Result<Vec<CanisterLogRecord>, AgentError> {
+    let arg =
+        Encode!(&FetchCanisterLogsArgs { canister_id }).expect("the arguments are valid Candid");
+    let reply = agent
+        .query(&Principal::management_canister(), "fetch_canister_logs")
+        .with_effective_canister_id(canister_id)
+        .with_arg(arg)
+        .call()
+        .await?;
+    Ok(Decode!(&reply, FetchCanisterLogsResult)?.canister_log_records)
+}
+
+/// The fetched records of the canisters, to be written before the live lines.
+pub struct Backfill {
+    /// The records as events, per canister in the order given.
+    events: Vec<LogEvent>,
+    overlap: Overlap,
+}
+
+impl Backfill {
+    /// Fetches the records of all canisters, at most the `tail` most recent ones of each if
+    /// given. Canisters whose records cannot be fetched are reported and left out.
+    pub async fn fetch(
+        http_client: reqwest::Client,
+        endpoints: &Endpoints,
+        canister_ids: &[String],
+        tail: Option<u64>,
+        parser: Option<LineParser>,
+        keep_ansi: bool,
+    ) -> Self {
+        let mut backfill = Self {
+            events: Vec::new(),
+            overlap: Overlap::default(),
+        };
+        let agent = match endpoints.agent(http_client).await {
+            Ok(agent) => agent,
+            Err(e) => {
+                warn!("Skipped the backfill from the canister logs: {e}");
+                return backfill;
+            }
+        };
+        // The records are attributed to the API endpoint they are fetched from.
+        let node = endpoints
+            .urls
+            .first()
+            .and_then(|url| url.host_str())
+            .unwrap_or("ic")
+            .to_string();
+        let agent = &agent;
+        let fetched = join_all(canister_ids.iter().map(|canister_id| async move {
+            let records = match Principal::from_text(canister_id) {
+                Ok(principal) => match timeout(FETCH_TIMEOUT, fetch(agent, principal)).await {
+                    Ok(records) => records.map_err(|e| e.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                },
+                Err(e) => Err(format!("invalid canister ID: {e}")),
+            };
+            (canister_id, records)
+        }))
+        .await;
+        for (canister_id, records) in fetched {
+            let mut records = match records {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!("Could not fetch the log records of canister {canister_id}: {e}");
+                    continue;
+                }
+            };
+            if let Some(tail) = tail {
+                let skipped = records.len().saturating_sub(tail as usize);
+                records.drain(..skipped);
+            }
+            match (records.first(), records.last()) {
+                (Some(first), Some(last)) => info!(
+                    "Backfilling {} records of canister {canister_id}, {} to {}, logged from {} \
+                     to {}.",
+                    records.len(),
+                    first.idx,
+                    last.idx,
+                    humantime::format_rfc3339_millis(timestamp(first)),
+                    humantime::format_rfc3339_millis(timestamp(last)),
+                ),
+                _ => info!("Canister {canister_id} has no retained log records."),
+            }
+            for record in records {
+                let event = backfill.event(&node, canister_id, record, parser, keep_ansi);
+                backfill.events.push(event);
+            }
+        }
+        backfill
+    }
+
+    /// Turns a record into an event, with the index and timestamp of the record in its fields.
+    fn event(
+        &mut self,
+        node: &str,
+        canister_id: &str,
+        record: CanisterLogRecord,
+        parser: Option<LineParser>,
+        keep_ansi: bool,
+    ) -> LogEvent {
+        let message = ansi::clean(
+            String::from_utf8_lossy(&record.content).into_owned(),
+            keep_ansi,
+        );
+        let mut fields = parser
+            .and_then(|parser| parser.parse(&message))
+            .unwrap_or_default();
+        fields.insert("idx".into(), record.idx.into());
+        fields.insert("timestamp_nanos".into(), record.timestamp_nanos.into());
+        self.overlap.fetched(canister_id, record.idx, &message);
+        let mut event =
+            LogEvent::received(node, canister_id, message, true).with_fields(Some(fields));
+        event.timestamp = timestamp(&record);
+        event
+    }
+
+    /// Returns the events of the fetched records and what is needed to drop their repetitions.
+    pub fn into_parts(self) -> (Vec<LogEvent>, Overlap) {
+        (self.events, self.overlap)
+    }
+}
+
+/// Returns the time at which a record was logged.
+fn timestamp(record: &CanisterLogRecord) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(record.timestamp_nanos)
+}
+
+/// Recognizes live lines that repeat the fetched records.
+#[derive(Default)]
+pub struct Overlap {
+    /// The last fetched index per canister.
+    last_idx: HashMap<String, u64>,
+    /// The messages of the fetched records per canister.
+    messages: HashMap<String, HashSet<String>>,
+    /// Streams, by node and canister, that delivered a line that was not fetched.
+    caught_up: Mutex<HashSet<(String, String)>>,
+}
+
+impl Overlap {
+    fn fetched(&mut self, canister_id: &str, idx: u64, message: &str) {
+        self.last_idx.insert(canister_id.to_string(), idx);
+        self.messages
+            .entry(canister_id.to_string())
+            .or_default()
+            .insert(message.to_string());
+    }
+
+    /// Returns true if a live line of a node repeats a fetched record.
+    pub fn repeats(&self, event: &LogEvent) -> bool {
+        if let Some(idx) = event
+            .fields
+            .as_ref()
+            .and_then(|fields| fields.get("idx"))
+            .and_then(Value::as_u64)
+        {
+            return self
+                .last_idx
+                .get(&event.canister_id)
+                .is_some_and(|last| idx <= *last);
+        }
+        let Some(messages) = self.messages.get(&event.canister_id) else {
+            return false;
+        };
+        let stream = (event.node.clone(), event.canister_id.clone());
+        let mut caught_up = self.caught_up.lock().unwrap();
+        if caught_up.contains(&stream) {
+            return false;
+        }
+        if messages.contains(&event.message) {
+            return true;
+        }
+        caught_up.insert(stream);
+        false
+    }
+}
@@ -0,0 +1,588 @@
+//! Capture files of the frames received from the nodes, recorded with `--record` and fed back
+//! through the pipeline by the `replay` subcommand, e.g. to debug filters offline or to share
+//! the data of an incident.
+//!
+//! A capture starts with the magic bytes `ICBLOG`, a format version byte, and a compression
+//! byte (2 for zstd, or 1 for gzip in captures of earlier versions of the client). The frames follow in blocks, in the order they were received. Every
+//! block starts with the length of its compressed records as a big-endian u32, the receive
+//! time of its first frame in microseconds since the Unix epoch as a big-endian u64, and its
+//! number of frames as a big-endian u32. Decompressed, the block holds one record per frame,
+//! which starts with its length as a big-endian u32, followed by:
+//!
+//! - the receive time in microseconds since the Unix epoch, as a big-endian u64,
+//! - the node domain, the canister ID, and the codec name, each as a big-endian u16 length
+//!   followed by UTF-8 bytes,
+//! - the frame, as received before chunk reassembly and decoding, in the rest of the record.
+//!
+//! A block is written once it holds 1 MiB of records or its first frame is 10 seconds old, so
+//! that the capture stays small while a killed client loses at most the last seconds. When the
+//! client exits, an index of the blocks follows: the length `0xFFFFFFFF`, the number of blocks
+//! as a big-endian u32, and the offset and first receive time of every block as big-endian
+//! u64s. The file ends with the offset of the index as a big-endian u64 and the magic bytes
+//! `ICBIDX`, so that a replay of a time range can seek to its first block. Captures of format
+//! version 1, whose records follow the header without blocks and compression, are still read.
+
+use crate::connection::{CapturedStream, ConnectionConfig};
+use flate2::read::GzDecoder;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, sleep_until, Instant};
+use tokio_tungstenite::tungstenite::Bytes;
+
+/// Magic bytes at the start of every capture.
+const MAGIC: &[u8] = b"ICBLOG";
+/// Version of the capture format.
+const VERSION: u8 = 2;
+/// Version of the capture format without blocks, which is still read.
+const VERSION_UNCOMPRESSED: u8 = 1;
+/// Compression of the blocks: gzip, as written by earlier versions of the client.
+const COMPRESSION_GZIP: u8 = 1;
+/// Compression of the blocks: zstd.
+const COMPRESSION_ZSTD: u8 = 2;
+/// zstd compression level of the blocks, the default of the zstd tool.
+const ZSTD_LEVEL: i32 = 3;
+/// Largest record accepted when reading, so that a corrupt length cannot exhaust memory.
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+/// Size of the records of a block above which it is written.
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// Age of the first frame of a block at which it is written.
+const BLOCK_AGE: Duration = Duration::from_secs(10);
+/// Block length that marks the start of the index.
+const INDEX_MARKER: u32 = u32::MAX;
+/// Magic bytes at the end of a capture with an index.
+const INDEX_MAGIC: &[u8] = b"ICBIDX";
+
+/// A frame read from a capture.
+pub struct CapturedFrame {
+    pub received_at: SystemTime,
+    pub node: String,
+    pub canister_id: String,
+    pub codec: String,
+    pub frame: Bytes,
+}
+
+/// The time range of a capture to replay.
+#[derive(Clone, Copy, Default)]
+pub struct TimeRange {
+    /// Frames received before this time are skipped.
+    pub from: Option<SystemTime>,
+    /// Frames received after this time end the replay.
+    pub to: Option<SystemTime>,
+}
+
+/// Returns the receive time in microseconds since the Unix epoch.
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Writes the frames received from all nodes to a capture file.
+pub struct CaptureWriter {
+    file: Arc<Mutex<BlockWriter>>,
+}
+
+/// The capture file with the block being filled.
+struct BlockWriter {
+    file: File,
+    /// Offset at which the next block is written.
+    offset: u64,
+    /// The compressed records of the current block.
+    block: zstd::Encoder<'static, Vec<u8>>,
+    /// Size of the records of the current block before compression.
+    block_size: usize,
+    /// Frames in the current block.
+    frames: u32,
+    /// Receive time of the first frame of the current block, and when it was received.
+    started: Option<(u64, Instant)>,
+    /// Offset and first receive time of every block written.
+    index: Vec<(u64, u64)>,
+    /// Whether the index was written, after which frames are no longer recorded.
+    finished: bool,
+}
+
+impl CaptureWriter {
+    /// Creates the capture file, replacing an existing file, and starts a task that writes the
+    /// current block once its first frame is old enough.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION, COMPRESSION_ZSTD])?;
+        let file = Arc::new(Mutex::new(BlockWriter {
+            file,
+            offset: MAGIC.len() as u64 + 2,
+            block: zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+            block_size: 0,
+            frames: 0,
+            started: None,
+            index: Vec::new(),
+            finished: false,
+        }));
+        tokio::spawn(write_aged_blocks(Arc::downgrade(&file)));
+        Ok(Self { file })
+    }
+
+    /// Appends a frame received from the node.
+    pub fn write(&self, node: &str, canister_id: &str, codec: &str, frame: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        // The receive time is taken under the lock, so that the times in the file only grow.
+        file.record(SystemTime::now(), node, canister_id, codec, frame);
+    }
+
+    /// Appends a frame received from the node at an earlier time, e.g. one held in memory,
+    /// which must not be earlier than the frames appended before.
+    pub fn write_received(
+        &self,
+        received_at: SystemTime,
+        node: &str,
+        canister_id: &str,
+        codec: &str,
+        frame: &[u8],
+    ) {
+        let mut file = self.file.lock().unwrap();
+        file.record(received_at, node, canister_id, codec, frame);
+    }
+
+    /// Writes the last block and the index, e.g. before exiting.
+    pub fn finish(&self) {
+        let mut file = self.file.lock().unwrap();
+        if file.finished {
+            return;
+        }
+        file.finished = true;
+        if let Err(e) = file.write_block().and_then(|()| file.write_index()) {
+            error!("Failed to finish the capture: {e}");
+        }
+    }
+}
+
+impl BlockWriter {
+    /// Appends the record of a frame with its receive time and origin, unless the capture is
+    /// finished.
+    fn record(
+        &mut self,
+        received_at: SystemTime,
+        node: &str,
+        canister_id: &str,
+        codec: &str,
+        frame: &[u8],
+    ) {
+        if self.finished {
+            return;
+        }
+        let received_at = micros(received_at);
+        let mut record = Vec::with_capacity(frame.len() + 64);
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(&received_at.to_be_bytes());
+        for field in [node, canister_id, codec] {
+            record.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            record.extend_from_slice(field.as_bytes());
+        }
+        record.extend_from_slice(frame);
+        let len = (record.len() - 4) as u32;
+        record[..4].copy_from_slice(&len.to_be_bytes());
+        if let Err(e) = self.append(received_at, &record) {
+            error!("[{node}] Failed to record frame: {e}");
+        }
+    }
+
+    /// Adds a record to the current block, writing the block once it is full.
+    fn append(&mut self, received_at: u64, record: &[u8]) -> io::Result<()> {
+        self.started.get_or_insert((received_at, Instant::now()));
+        self.block.write_all(record)?;
+        self.block_size += record.len();
+        self.frames += 1;
+        if self.block_size >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the current block and writes it to the file.
+    fn write_block(&mut self) -> io::Result<()> {
+        let Some((first, _)) = self.started.take() else {
+            return Ok(());
+        };
+        let block = std::mem::replace(
+            &mut self.block,
+            zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+        )
+        .finish()?;
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        header.extend_from_slice(&first.to_be_bytes());
+        header.extend_from_slice(&self.frames.to_be_bytes());
+        self.file.write_all(&header)?;
+        self.file.write_all(&block)?;
+        self.index.push((self.offset, first));
+        self.offset += (header.len() + block.len()) as u64;
+        self.block_size = 0;
+        self.frames = 0;
+        Ok(())
+    }
+
+    /// Writes the index of the blocks and the trailer that locates it.
+    fn write_index(&mut self) -> io::Result<()> {
+        let mut index = Vec::with_capacity(self.index.len() * 16 + 22);
+        index.extend_from_slice(&INDEX_MARKER.to_be_bytes());
+        index.extend_from_slice(&(self.index.len() as u32).to_be_bytes());
+        for (offset, first) in &self.index {
+            index.extend_from_slice(&offset.to_be_bytes());
+            index.extend_from_slice(&first.to_be_bytes());
+        }
+        index.extend_from_slice(&self.offset.to_be_bytes());
+        index.extend_from_slice(INDEX_MAGIC);
+        self.file.write_all(&index)
+    }
+}
+
+/// Writes the current block once its first frame is older than [`BLOCK_AGE`], until the
+/// capture is dropped.
+async fn write_aged_blocks(file: Weak<Mutex<BlockWriter>>) {
+    let mut ticker = interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        let Some(file) = file.upgrade() else {
+            return;
+        };
+        let mut file = file.lock().unwrap();
+        if !file.finished
+            && file
+                .started
+                .is_some_and(|(_, started)| started.elapsed() >= BLOCK_AGE)
+            && let Err(e) = file.write_block()
+        {
+            error!("Failed to record frames: {e}");
+        }
+    }
+}
+
+/// Reads the frames of a capture file in order.
+pub struct CaptureReader {
+    reader: BufReader<File>,
+    version: u8,
+    /// Compression of the blocks, if the capture has blocks.
+    compression: u8,
+    /// The decompressed records of the current block not read yet.
+    block: Cursor<Vec<u8>>,
+}
+
+impl CaptureReader {
+    /// Opens a capture file, checking its header.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0; MAGIC.len() + 1];
+        if reader.read_exact(&mut header).is_err() || &header[..MAGIC.len()] != MAGIC {
+            return Err(format!("{} is not a capture file", path.display()));
+        }
+        let version = header[MAGIC.len()];
+        let mut compression = [0];
+        match version {
+            VERSION_UNCOMPRESSED => {}
+            VERSION => {
+                reader
+                    .read_exact(&mut compression)
+                    .map_err(|_| format!("{} is not a capture file", path.display()))?;
+                if !matches!(compression[0], COMPRESSION_ZSTD | COMPRESSION_GZIP) {
+                    return Err(format!(
+                        "{} has the unsupported compression {}",
+                        path.display(),
+                        compression[0]
+                    ));
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "{} has the unsupported capture format version {version}",
+                    path.display()
+                ));
+            }
+        }
+        Ok(Self {
+            reader,
+            version,
+            compression: compression[0],
+            block: Cursor::new(Vec::new()),
+        })
+    }
+
+    /// Reads the next frame, or returns `None` at the end of the capture.
+    ///
+    /// A record or block cut off at the end, as left by a client that was killed while
+    /// writing, counts as the end of the capture.
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>, String> {
+        if self.version == VERSION_UNCOMPRESSED {
+            return read_record(&mut self.reader);
+        }
+        while self.block.position() >= self.block.get_ref().len() as u64 {
+            if !self.next_block()? {
+                return Ok(None);
+            }
+        }
+        match read_record(&mut self.block)? {
+            Some(frame) => Ok(Some(frame)),
+            None => Err("corrupt capture block".to_string()),
+        }
+    }
+
+    /// Reads and decompresses the next block, returning false at the end of the blocks.
+    fn next_block(&mut self) -> Result<bool, String> {
+        let Some((len, _)) = read_block_header(&mut self.reader)? else {
+            return Ok(false);
+        };
+        let mut compressed = vec![0; len as usize];
+        match self.reader.read_exact(&mut compressed) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                warn!("The capture ends with an incomplete block, which is skipped.");
+                return Ok(false);
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+        let mut records = Vec::new();
+        let decompressed = if self.compression == COMPRESSION_GZIP {
+            GzDecoder::new(compressed.as_slice()).read_to_end(&mut records)
+        } else {
+            zstd::Decoder::new(compressed.as_slice()).and_then(|mut decoder| {
+                decoder.read_to_end(&mut records)
+            })
+        };
+        decompressed.map_err(|e| format!("corrupt capture block: {e}"))?;
+        self.block = Cursor::new(records);
+        Ok(true)
+    }
+
+    /// Moves to the block that holds the first frame received at or after the given time, so
+    /// that the blocks before it are neither read nor decompressed. The index is used if the
+    /// capture has one, and otherwise the headers of the blocks.
+    pub fn seek(&mut self, from: SystemTime) -> Result<(), String> {
+        if self.version == VERSION_UNCOMPRESSED {
+            return Ok(());
+        }
+        let from = micros(from);
+        let start = self.reader.stream_position().map_err(|e| e.to_string())?;
+        let blocks = match self.read_index().map_err(|e| e.to_string())? {
+            Some(blocks) => blocks,
+            None => self.scan_blocks(start)?,
+        };
+        // The frames received at the time may begin in the block before the first block
+        // that starts after it.
+        let target = blocks
+            .iter()
+            .take_while(|(_, first)| *first < from)
+            .last()
+            .map_or(start, |(offset, _)| *offset);
+        self.reader
+            .seek(SeekFrom::Start(target))
+            .map_err(|e| e.to_string())?;
+        self.block = Cursor::new(Vec::new());
+        Ok(())
+    }
+
+    /// Reads the index at the end of the capture, if it has one.
+    fn read_index(&mut self) -> io::Result<Option<Vec<(u64, u64)>>> {
+        let trailer_len = 8 + INDEX_MAGIC.len() as u64;
+        let end = self.reader.seek(SeekFrom::End(0))?;
+        if end < trailer_len {
+            return Ok(None);
+        }
+        self.reader.seek(SeekFrom::Start(end - trailer_len))?;
+        let mut trailer = vec![0; trailer_len as usize];
+        self.reader.read_exact(&mut trailer)?;
+        if &trailer[8..] != INDEX_MAGIC {
+            return Ok(None);
+        }
+        let offset = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 8];
+        self.reader.read_exact(&mut header)?;
+        if u32::from_be_bytes(header[..4].try_into().unwrap()) != INDEX_MARKER {
+            return Ok(None);
+        }
+        let count = u32::from_be_bytes(header[4..].try_into().unwrap()) as u64;
+        if offset + 8 + count * 16 + trailer_len != end {
+            return Ok(None);
+        }
+        let mut entries = vec![0; count as usize * 16];
+        self.reader.read_exact(&mut entries)?;
+        Ok(Some(
+            entries
+                .chunks_exact(16)
+                .map(|entry| {
+                    (
+                        u64::from_be_bytes(entry[..8].try_into().unwrap()),
+                        u64::from_be_bytes(entry[8..].try_into().unwrap()),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    /// Collects the offsets and first receive times of the blocks from their headers, for a
+    /// capture without an index.
+    fn scan_blocks(&mut self, start: u64) -> Result<Vec<(u64, u64)>, String> {
+        let mut blocks = Vec::new();
+        let mut offset = start;
+        self.reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        while let Some((len, first)) = read_block_header(&mut self.reader)? {
+            blocks.push((offset, first));
+            offset += 16 + len as u64;
+            self.reader
+                .seek(SeekFrom::Start(offset))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(blocks)
+    }
+}
+
+/// Reads the header of a block, returning the length of its compressed records and the
+/// receive time of its first frame, or `None` at the end of the blocks.
+fn read_block_header(reader: &mut impl Read) -> Result<Option<(u32, u64)>, String> {
+    let mut header = [0; 16];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+    if len == INDEX_MARKER {
+        return Ok(None);
+    }
+    if len > MAX_RECORD_SIZE {
+        return Err(format!("corrupt capture block of {len} bytes"));
+    }
+    Ok(Some((
+        len,
+        u64::from_be_bytes(header[4..12].try_into().unwrap()),
+    )))
+}
+
+/// Reads a record, or returns `None` at the end of the input.
+fn read_record(reader: &mut impl Read) -> Result<Option<CapturedFrame>, String> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_RECORD_SIZE {
+        return Err(format!("corrupt capture record of {len} bytes"));
+    }
+    let mut record = vec![0; len as usize];
+    match reader.read_exact(&mut record) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            warn!("The capture ends with an incomplete record, which is skipped.");
+            return Ok(None);
+        }
+        Err(e) => return Err(e.to_string()),
+    }
+    parse_record(record)
+        .map(Some)
+        .ok_or_else(|| "corrupt capture record".to_string())
+}
+
+fn parse_record(record: Vec<u8>) -> Option<CapturedFrame> {
+    let micros = u64::from_be_bytes(record.get(..8)?.try_into().ok()?);
+    let mut pos = 8;
+    let mut fields = Vec::with_capacity(3);
+    for _ in 0..3 {
+        let len = u16::from_be_bytes(record.get(pos..pos + 2)?.try_into().ok()?) as usize;
+        pos += 2;
+        fields.push(String::from_utf8(record.get(pos..pos + len)?.to_vec()).ok()?);
+        pos += len;
+    }
+    let codec = fields.pop()?;
+    let canister_id = fields.pop()?;
+    let node = fields.pop()?;
+    Some(CapturedFrame {
+        received_at: UNIX_EPOCH + Duration::from_micros(micros),
+        node,
+        canister_id,
+        codec,
+        frame: Bytes::from(record).slice(pos..),
+    })
+}
+
+/// Returns the canisters whose frames are in the capture, in the order they first appear.
+pub fn canisters(path: &Path) -> Result<Vec<String>, String> {
+    let mut reader = CaptureReader::open(path)?;
+    let mut seen = HashSet::new();
+    let mut canister_ids = Vec::new();
+    while let Some(frame) = reader.next_frame()? {
+        if seen.insert(frame.canister_id.clone()) {
+            canister_ids.push(frame.canister_id);
+        }
+    }
+    Ok(canister_ids)
+}
+
+/// Feeds the frames of the monitored canisters in the capture through the pipeline.
+///
+/// The pauses between the frames are those of the recording divided by `speed`; with a speed
+/// of 0, the frames follow each other without pauses.
+pub async fn replay(
+    path: &Path,
+    speed: f64,
+    range: TimeRange,
+    config: &ConnectionConfig,
+) -> Result<(), String> {
+    let mut reader = CaptureReader::open(path)?;
+    if let Some(from) = range.from {
+        reader.seek(from)?;
+    }
+    let mut streams: HashMap<(String, String), CapturedStream> = HashMap::new();
+    let mut start: Option<(SystemTime, Instant)> = None;
+    let mut frames = 0u64;
+    while let Some(captured) = reader.next_frame()? {
+        if range.to.is_some_and(|to| captured.received_at > to) {
+            break;
+        }
+        if range.from.is_some_and(|from| captured.received_at < from)
+            || !config.canister_ids.borrow().contains(&captured.canister_id)
+        {
+            continue;
+        }
+        if speed > 0.0 {
+            let (recorded_start, replay_start) =
+                *start.get_or_insert((captured.received_at, Instant::now()));
+            let offset = captured
+                .received_at
+                .duration_since(recorded_start)
+                .unwrap_or_default();
+            sleep_until(replay_start + offset.div_f64(speed)).await;
+        }
+        let stream = streams
+            .entry((captured.node.clone(), captured.canister_id.clone()))
+            .or_insert_with(|| {
+                CapturedStream::new(
+                    &captured.node,
+                    &captured.canister_id,
+                    &captured.codec,
+                    config,
+                )
+            });
+        stream
+            .push(&captured.node, captured.frame, captured.received_at, config)
+            .await;
+        frames += 1;
+    }
+    for ((node, _), stream) in &mut streams {
+        stream.finish(node, config).await;
+    }
+    info!(
+        "Replayed {frames} frames of {} streams from {}.",
+        streams.len(),
+        path.display()
+    );
+    Ok(())
+}
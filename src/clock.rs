@@ -0,0 +1,80 @@
+//! Process-wide time settings: the monotonic capture clock and the display timezone.
+//!
+//! All rendered timestamps, time-based file names, and rotation boundaries use the same
+//! timezone, which defaults to UTC rather than the ambient system setting. Times taken from
+//! the records of the nodes are moved onto the local clock with the estimated offsets of the
+//! clocks of the nodes.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde_json::Value;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+/// Monotonic reference point of the capture.
+static CAPTURE_START: OnceLock<Instant> = OnceLock::new();
+
+/// Timezone of all rendered timestamps.
+static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+/// Starts the monotonic capture clock. Called once at startup so that offsets count from
+/// the start of the process rather than from the first event.
+pub fn start_capture_clock() {
+    CAPTURE_START.get_or_init(Instant::now);
+}
+
+/// Returns the monotonic time elapsed since the start of the capture.
+pub fn capture_offset() -> Duration {
+    CAPTURE_START.get_or_init(Instant::now).elapsed()
+}
+
+/// Sets the timezone of all rendered timestamps. Only the first call has an effect.
+pub fn set_timezone(timezone: Tz) {
+    let _ = TIMEZONE.set(timezone);
+}
+
+/// Converts a wall-clock time into the configured timezone.
+pub fn local_time(time: SystemTime) -> DateTime<Tz> {
+    let timezone = TIMEZONE.get().copied().unwrap_or(Tz::UTC);
+    DateTime::<Utc>::from(time).with_timezone(&timezone)
+}
+
+/// Reads the time a node gave a record: an RFC 3339 timestamp, or an integer number of
+/// nanoseconds since the Unix epoch as in the canister log API.
+pub fn parse_node_time(value: &Value) -> Option<SystemTime> {
+    match value {
+        Value::String(time) => DateTime::parse_from_rfc3339(time)
+            .ok()
+            .map(SystemTime::from),
+        Value::Number(nanos) => Some(UNIX_EPOCH + Duration::from_nanos(nanos.as_u64()?)),
+        _ => None,
+    }
+}
+
+/// Moves a time of a node onto the local clock, given how far the clock of the node is ahead
+/// in milliseconds.
+pub fn correct_node_time(time: SystemTime, clock_offset_ms: f64) -> SystemTime {
+    let offset = Duration::from_secs_f64(clock_offset_ms.abs() / 1000.0);
+    let corrected = if clock_offset_ms >= 0.0 {
+        time.checked_sub(offset)
+    } else {
+        time.checked_add(offset)
+    };
+    corrected.unwrap_or(time)
+}
+
+/// Parses an IANA timezone name such as `Europe/Zurich` or `UTC`.
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.parse()
+        .map_err(|_| format!("unknown IANA timezone: {name}"))
+}
+
+/// Returns an error if the strftime pattern contains invalid specifiers.
+pub fn validate_strftime(pattern: &str) -> Result<(), String> {
+    if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid strftime pattern: {pattern}"));
+    }
+    Ok(())
+}
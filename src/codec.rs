@@ -0,0 +1,153 @@
+//! Codecs that turn the payload of a frame into log records.
+//!
+//! The client advertises the codecs it supports in the `x-log-codecs` request header when it
+//! connects. A boundary node that sends something other than plain text records names the
+//! codec of its frames in the `x-log-codec` response header; otherwise the codec chosen on the
+//! command line is used. New payload formats only need a new codec here, since the filters and
+//! sinks only ever see decoded records.
+
+use crate::decode::{self, DecodeError, DecodedRecord};
+use log::{info, warn};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+
+/// Request header listing the codecs the client supports.
+pub const ACCEPT_HEADER: &str = "x-log-codecs";
+/// Response header naming the codec of the frames a node sends.
+pub const CODEC_HEADER: &str = "x-log-codec";
+
+/// Decodes the payload of a frame into log records.
+pub trait Codec: Send + Sync {
+    /// Name used on the command line and in capability negotiation.
+    fn name(&self) -> &'static str;
+
+    /// Decodes a frame, which may carry several records.
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError>;
+}
+
+/// Detects plain text, CBOR, and Candid records.
+struct AutoCodec;
+
+impl Codec for AutoCodec {
+    fn name(&self) -> &'static str {
+        "auto"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+        decode::decode(payload).map(|record| vec![record])
+    }
+}
+
+/// Plain text records, as with `--raw`.
+struct TextCodec;
+
+impl Codec for TextCodec {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+        decode::decode_text(payload).map(|record| vec![record])
+    }
+}
+
+/// One CBOR record per frame.
+struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+        decode::decode_cbor_record(payload).map(|record| vec![record])
+    }
+}
+
+/// Batched frames holding a sequence of CBOR records.
+struct CborSequenceCodec;
+
+impl Codec for CborSequenceCodec {
+    fn name(&self) -> &'static str {
+        "cbor-seq"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+        decode::decode_cbor_sequence(payload)
+    }
+}
+
+/// One Candid record per frame.
+struct CandidCodec;
+
+impl Codec for CandidCodec {
+    fn name(&self) -> &'static str {
+        "candid"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+        decode::decode_candid_record(payload).map(|record| vec![record])
+    }
+}
+
+/// All supported codecs.
+pub const CODECS: &[&dyn Codec] = &[
+    &AutoCodec,
+    &TextCodec,
+    &CborCodec,
+    &CborSequenceCodec,
+    &CandidCodec,
+];
+
+/// Looks up a codec by name.
+pub fn find(name: &str) -> Option<&'static dyn Codec> {
+    CODECS.iter().copied().find(|codec| codec.name() == name)
+}
+
+/// Parses a codec name given on the command line.
+pub fn parse_codec(value: &str) -> Result<&'static dyn Codec, String> {
+    find(value).ok_or_else(|| {
+        let names: Vec<_> = CODECS.iter().map(|codec| codec.name()).collect();
+        format!(
+            "unknown codec {value}, expected one of {}",
+            names.join(", ")
+        )
+    })
+}
+
+/// Returns the value of the request header advertising the supported codecs.
+pub fn accepted() -> String {
+    CODECS
+        .iter()
+        .map(|codec| codec.name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Selects the codec of a connection from the handshake response, falling back to the
+/// default if the node does not name one or names one that is not supported.
+pub fn negotiate(
+    domain: &str,
+    response: &Response,
+    default: &'static dyn Codec,
+) -> &'static dyn Codec {
+    let Some(name) = response
+        .headers()
+        .get(CODEC_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return default;
+    };
+    match find(name.trim()) {
+        Some(codec) => {
+            info!("[{domain}] Node sends {} frames.", codec.name());
+            codec
+        }
+        None => {
+            warn!(
+                "[{domain}] Node announced unsupported codec {name}, using {}.",
+                default.name()
+            );
+            default
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Colors of the lines printed to stdout: by severity, and for matches of `--highlight`.
+
+use crate::event::LogEvent;
+use crate::severity::Severity;
+use clap::ValueEnum;
+use regex::Regex;
+use std::io::{self, IsTerminal};
+
+/// Colors of the highlight patterns, in the order they are given; black or white text on
+/// yellow, cyan, magenta, green, blue, and red.
+const HIGHLIGHT_COLORS: &[&str] = &[
+    "1;30;43", "1;30;46", "1;30;45", "1;30;42", "1;37;44", "1;37;41",
+];
+const RESET: &str = "\x1b[0m";
+
+/// When the lines printed to stdout are colored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Color when stdout is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Returns whether lines are colored.
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Auto => io::stdout().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Colors lines by severity and highlights the matches of patterns within them.
+pub struct Painter {
+    highlights: Vec<Regex>,
+}
+
+impl Painter {
+    /// Creates a painter highlighting the patterns, each in its own color.
+    pub fn new(highlights: Vec<Regex>) -> Self {
+        Self { highlights }
+    }
+
+    /// Colors a rendered line: errors are bold red and warnings yellow, and matches of the
+    /// highlight patterns get the background of their pattern. Where matches overlap, the
+    /// earlier match wins, and of matches starting together the earlier pattern.
+    pub fn paint(&self, event: &LogEvent, line: &str) -> String {
+        let base = match Severity::of(event) {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[33m",
+            Severity::Info => "",
+        };
+        let text = line.strip_suffix('\n').unwrap_or(line);
+
+        let mut matches: Vec<(usize, usize, usize)> = self
+            .highlights
+            .iter()
+            .enumerate()
+            .flat_map(|(index, pattern)| {
+                pattern
+                    .find_iter(text)
+                    .filter(|m| !m.is_empty())
+                    .map(move |m| (m.start(), index, m.end()))
+            })
+            .collect();
+        matches.sort_unstable();
+
+        let mut out = String::with_capacity(line.len() + 16);
+        out.push_str(base);
+        let mut pos = 0;
+        for (start, index, end) in matches {
+            if start < pos {
+                continue;
+            }
+            let color = HIGHLIGHT_COLORS[index % HIGHLIGHT_COLORS.len()];
+            out.push_str(&text[pos..start]);
+            out.push_str(&format!("\x1b[{color}m{}{RESET}{base}", &text[start..end]));
+            pos = end;
+        }
+        out.push_str(&text[pos..]);
+        if !base.is_empty() {
+            out.push_str(RESET);
+        }
+        if text.len() < line.len() {
+            out.push('\n');
+        }
+        out
+    }
+}
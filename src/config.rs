@@ -0,0 +1,366 @@
+//! The `--config` file, whose settings are applied again at runtime whenever the file changes
+//! or on SIGHUP, without reconnecting to the nodes.
+//!
+//! The file is a JSON object with the optional keys `canisters`, `include`, `exclude`, `where`,
+//! `alerts`, and `elasticsearch`. Every key it contains replaces the corresponding command line
+//! options, and removing a key from the file restores them. The `alerts` object replaces all
+//! alert options; besides `patterns`, it takes `webhook`, `slack_webhook`, `discord_webhook`,
+//! `template`, `min_interval`, and `dedup_window`. The `elasticsearch` object retargets the
+//! sink started with `--elasticsearch-url` with its `url`, `index`, and `api_key`. The `profiles`
+//! key is only read at startup, see [`crate::profile`].
+//!
+//! Changes are noticed through the file system notifications of the directory of the file,
+//! since editors and deployment tools often replace the file rather than write to it.
+
+use crate::alert::{self, Alerter, Destination};
+use crate::canister;
+use crate::connection::ConnectionConfig;
+use crate::query::Query;
+use crate::sinks::elasticsearch::{self, ElasticsearchSink, ElasticsearchTarget};
+use crate::template::Template;
+use crate::timespec;
+use log::{error, info};
+use notify::{EventKind, RecursiveMode, Watcher};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use url::Url;
+
+/// Time to wait after a change for further ones, as a file is often written in several steps.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Default of `index` in the `elasticsearch` object, as for --elasticsearch-index.
+const DEFAULT_ELASTICSEARCH_INDEX: &str = "ic-bn-logs-%Y.%m.%d";
+
+/// Default of `min_interval` in the `alerts` object, as for --alert-min-interval.
+const DEFAULT_ALERT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileContents {
+    canisters: Option<Vec<String>>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    #[serde(rename = "where")]
+    condition: Option<String>,
+    alerts: Option<FileAlerts>,
+    elasticsearch: Option<FileElasticsearch>,
+    /// Read only at startup, see [`crate::profile`].
+    #[serde(rename = "profiles")]
+    _profiles: Option<serde::de::IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAlerts {
+    patterns: Vec<String>,
+    webhook: Option<String>,
+    slack_webhook: Option<String>,
+    discord_webhook: Option<String>,
+    template: Option<String>,
+    min_interval: Option<String>,
+    dedup_window: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileElasticsearch {
+    url: String,
+    index: Option<String>,
+    api_key: Option<String>,
+}
+
+/// The alert rules and destinations in effect.
+#[derive(Clone)]
+pub struct AlertSettings {
+    pub patterns: Vec<Regex>,
+    pub webhook: Option<Url>,
+    pub slack_webhook: Option<Url>,
+    pub discord_webhook: Option<Url>,
+    pub template: Template,
+    pub min_interval: Duration,
+    pub dedup_window: Option<Duration>,
+}
+
+impl AlertSettings {
+    /// Creates the alerter, or None if there are no patterns or no destinations.
+    pub fn build(&self, client: &reqwest::Client) -> Option<Alerter> {
+        let destinations: Vec<Destination> = [
+            self.webhook.clone().map(Destination::webhook),
+            self.slack_webhook.clone().map(Destination::slack),
+            self.discord_webhook.clone().map(Destination::discord),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        (!self.patterns.is_empty() && !destinations.is_empty()).then(|| {
+            Alerter::new(
+                self.patterns.clone(),
+                destinations,
+                self.template.clone(),
+                self.min_interval,
+                self.dedup_window,
+                client.clone(),
+            )
+        })
+    }
+
+    fn same_as(&self, other: &Self) -> bool {
+        same_patterns(&self.patterns, &other.patterns)
+            && self.webhook == other.webhook
+            && self.slack_webhook == other.slack_webhook
+            && self.discord_webhook == other.discord_webhook
+            && self.template == other.template
+            && self.min_interval == other.min_interval
+            && self.dedup_window == other.dedup_window
+    }
+}
+
+/// The settings that the file may change.
+#[derive(Clone)]
+pub struct Settings {
+    pub canisters: Vec<String>,
+    pub include: Vec<Regex>,
+    pub exclude: Vec<Regex>,
+    /// The `--where` expression.
+    pub condition: Option<Query>,
+    pub alerts: AlertSettings,
+    /// Target of the Elasticsearch sink, if there is a single one that can be retargeted.
+    pub elasticsearch: Option<ElasticsearchTarget>,
+}
+
+impl Settings {
+    /// Reads the file and returns these settings with the keys of the file applied.
+    pub fn with_file(&self, path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let contents: FileContents = serde_json::from_str(&text)
+            .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+        let mut settings = self.clone();
+        if let Some(canisters) = contents.canisters {
+            if canisters.is_empty() {
+                return Err("the canisters of the config file must not be empty".into());
+            }
+            settings.canisters.clear();
+            for canister_id in &canisters {
+                let canister_id = canister::parse_canister_id(canister_id)?;
+                if !settings.canisters.contains(&canister_id) {
+                    settings.canisters.push(canister_id);
+                }
+            }
+        }
+        if let Some(include) = contents.include {
+            settings.include = compile(&include)?;
+        }
+        if let Some(exclude) = contents.exclude {
+            settings.exclude = compile(&exclude)?;
+        }
+        if let Some(condition) = contents.condition {
+            settings.condition = (!condition.trim().is_empty())
+                .then(|| Query::parse(&condition))
+                .transpose()?;
+        }
+        if let Some(alerts) = contents.alerts {
+            let template = match &alerts.template {
+                Some(template) => Template::parse(template)?,
+                None => Template::parse(alert::DEFAULT_ALERT_TEMPLATE)
+                    .expect("the default alert template is valid"),
+            };
+            settings.alerts = AlertSettings {
+                patterns: compile(&alerts.patterns)?,
+                webhook: parse_url(alerts.webhook.as_deref())?,
+                slack_webhook: parse_url(alerts.slack_webhook.as_deref())?,
+                discord_webhook: parse_url(alerts.discord_webhook.as_deref())?,
+                template,
+                min_interval: match &alerts.min_interval {
+                    Some(value) => timespec::parse_duration(value)?,
+                    None => DEFAULT_ALERT_MIN_INTERVAL,
+                },
+                dedup_window: alerts
+                    .dedup_window
+                    .as_deref()
+                    .map(timespec::parse_positive_duration)
+                    .transpose()?,
+            };
+        }
+        if let Some(target) = contents.elasticsearch {
+            if self.elasticsearch.is_none() {
+                return Err("the elasticsearch key of the config file requires \
+                            --elasticsearch-url, without --mirror-elasticsearch-url"
+                    .into());
+            }
+            settings.elasticsearch = Some(ElasticsearchTarget {
+                url: Url::parse(&target.url)
+                    .map_err(|e| format!("invalid Elasticsearch URL {}: {e}", target.url))?,
+                index_pattern: elasticsearch::parse_index_pattern(
+                    target
+                        .index
+                        .as_deref()
+                        .unwrap_or(DEFAULT_ELASTICSEARCH_INDEX),
+                )?,
+                api_key: target.api_key,
+            });
+        }
+        if settings.canisters.is_empty() {
+            return Err(format!(
+                "{} lists no canisters, and none are given on the command line",
+                path.display()
+            ));
+        }
+        Ok(settings)
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(|e| format!("invalid pattern {pattern}: {e}")))
+        .collect()
+}
+
+fn parse_url(url: Option<&str>) -> Result<Option<Url>, String> {
+    url.map(|url| Url::parse(url).map_err(|e| format!("invalid webhook URL {url}: {e}")))
+        .transpose()
+}
+
+fn same_patterns(a: &[Regex], b: &[Regex]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.as_str() == b.as_str())
+}
+
+/// Applies the file again whenever it changes.
+pub struct LiveConfig {
+    path: PathBuf,
+    /// The settings of the command line, on which the file is applied.
+    base: Settings,
+    /// The settings in effect.
+    applied: Mutex<Settings>,
+    http_client: reqwest::Client,
+    /// The Elasticsearch sink that the file retargets, unless the events are mirrored.
+    elasticsearch: Option<Arc<ElasticsearchSink>>,
+}
+
+impl LiveConfig {
+    /// Takes over the settings applied at startup from the file.
+    pub fn new(
+        path: PathBuf,
+        base: Settings,
+        applied: Settings,
+        http_client: reqwest::Client,
+        elasticsearch: Option<Arc<ElasticsearchSink>>,
+    ) -> Self {
+        Self {
+            path,
+            base,
+            applied: Mutex::new(applied),
+            http_client,
+            elasticsearch,
+        }
+    }
+
+    /// Reads the file again and applies what changed. An invalid file is reported and the
+    /// previous settings stay in effect.
+    pub fn reload(&self, config: &ConnectionConfig) {
+        let settings = match self.base.with_file(&self.path) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("Keeping the previous configuration: {e}");
+                return;
+            }
+        };
+        let mut applied = self.applied.lock().unwrap();
+        let mut changes = Vec::new();
+        if settings.canisters != applied.canisters {
+            if let Some(scheduler) = &config.scheduler {
+                for canister_id in &settings.canisters {
+                    scheduler.add_canister(canister_id);
+                }
+            }
+            config.canister_ids.send_replace(settings.canisters.clone());
+            changes.push(format!("canisters {}", settings.canisters.join(", ")));
+        }
+        if !same_patterns(&settings.include, &applied.include)
+            || !same_patterns(&settings.exclude, &applied.exclude)
+        {
+            config
+                .filters
+                .replace(settings.include.clone(), settings.exclude.clone());
+            changes.push(format!(
+                "{} include and {} exclude filters",
+                settings.include.len(),
+                settings.exclude.len()
+            ));
+        }
+        if settings.condition != applied.condition {
+            config.filters.set_condition(settings.condition.clone());
+            match &settings.condition {
+                Some(condition) => changes.push(format!("where {condition}")),
+                None => changes.push("no where expression".to_string()),
+            }
+        }
+        if !settings.alerts.same_as(&applied.alerts) {
+            *config.alerter.write().unwrap() =
+                settings.alerts.build(&self.http_client).map(Arc::new);
+            changes.push(format!("{} alert patterns", settings.alerts.patterns.len()));
+        }
+        if settings.elasticsearch != applied.elasticsearch
+            && let Some(target) = &settings.elasticsearch
+            && let Some(sink) = &self.elasticsearch
+        {
+            sink.retarget(target.clone());
+            changes.push(format!("Elasticsearch at {}", target.url));
+        }
+        if changes.is_empty() {
+            info!("Configuration reloaded without changes.");
+        } else {
+            info!("Configuration reloaded: {}.", changes.join("; "));
+        }
+        *applied = settings;
+    }
+
+    /// Starts a task that reloads the file whenever it changes.
+    pub fn spawn_watch(self: Arc<Self>, config: Arc<ConnectionConfig>) -> io::Result<()> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let name = self.path.file_name().map(ToOwned::to_owned);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event)
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                        && event
+                            .paths
+                            .iter()
+                            .any(|path| path.file_name() == name.as_deref()) =>
+                {
+                    let _ = sender.send(());
+                }
+                Ok(_) => {}
+                Err(e) => error!("Failed to watch the config file: {e}"),
+            })
+            .map_err(io::Error::other)?;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(io::Error::other)?;
+        tokio::spawn(async move {
+            // The watcher stops when it is dropped.
+            let _watcher = watcher;
+            while receiver.recv().await.is_some() {
+                sleep(SETTLE_TIME).await;
+                while receiver.try_recv().is_ok() {}
+                // While the file is replaced, it may be missing for a moment.
+                if self.path.exists() {
+                    info!("{} changed, reloading it.", self.path.display());
+                    self.reload(&config);
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Confirmation of log lines by several boundary nodes, as a defense against a single
+//! misbehaving or malicious node injecting lines that the canister never logged.
+//!
+//! Every node relays the same lines, so a genuine line arrives from every connected node. In
+//! confirmation mode, a line is held back until it has been received from a number of distinct
+//! nodes, and then emitted once, which also deduplicates it. Lines are identified like in
+//! dedup mode, by their canister, content, and occurrence count. A line that is not confirmed
+//! within the window is emitted when the window ends, flagged as suspect.
+
+use crate::event::LogEvent;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// A line received from fewer nodes than required so far, or already emitted.
+struct Observation {
+    first_seen: Instant,
+    nodes: HashSet<String>,
+    /// The first copy of the line, until the line is emitted.
+    event: Option<LogEvent>,
+}
+
+/// Holds back lines until enough nodes confirm them.
+pub struct Confirmer {
+    required: usize,
+    window: Duration,
+    observations: Mutex<HashMap<String, Observation>>,
+}
+
+impl Confirmer {
+    /// Creates a confirmer requiring each line from `required` distinct nodes within `window`.
+    pub fn new(required: usize, window: Duration) -> Self {
+        Self {
+            required,
+            window,
+            observations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the confirmation window.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Records a copy of the line with the key, returning the line if this copy confirms it.
+    ///
+    /// Copies received after the line was confirmed are absorbed until the window ends.
+    pub fn observe(&self, key: &str, event: LogEvent) -> Option<LogEvent> {
+        let mut observations = self.observations.lock().unwrap();
+        let observation = observations
+            .entry(key.to_string())
+            .or_insert_with(|| Observation {
+                first_seen: Instant::now(),
+                nodes: HashSet::new(),
+                event: None,
+            });
+        let first_copy = observation.nodes.is_empty();
+        observation.nodes.insert(event.node.clone());
+        if first_copy {
+            observation.event = Some(event);
+        }
+        if observation.nodes.len() >= self.required {
+            observation.event.take()
+        } else {
+            None
+        }
+    }
+
+    /// Removes the lines whose window has ended, returning those that were never confirmed,
+    /// flagged as suspect, in the order they were first received.
+    pub fn take_expired(&self) -> Vec<LogEvent> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.observations.lock().unwrap().retain(|_, observation| {
+            if now.duration_since(observation.first_seen) < self.window {
+                return true;
+            }
+            if let Some(mut event) = observation.event.take() {
+                event.suspect = true;
+                expired.push((observation.first_seen, event));
+            }
+            false
+        });
+        expired.sort_by_key(|(first_seen, _)| *first_seen);
+        expired.into_iter().map(|(_, event)| event).collect()
+    }
+
+    /// Removes all held lines, flagged as suspect, e.g. on shutdown.
+    pub fn drain(&self) -> Vec<LogEvent> {
+        let mut held: Vec<_> = self
+            .observations
+            .lock()
+            .unwrap()
+            .drain()
+            .filter_map(|(_, observation)| {
+                let mut event = observation.event?;
+                event.suspect = true;
+                Some((observation.first_seen, event))
+            })
+            .collect();
+        held.sort_by_key(|(first_seen, _)| *first_seen);
+        held.into_iter().map(|(_, event)| event).collect()
+    }
+}
@@ -0,0 +1,1292 @@
+//! WebSocket connections to individual API boundary nodes.
+//!
+//! Each connection has two stages: its read loop receives the frames, answers pings, and
+//! reassembles chunked records, and its processing stage, a task of its own, decodes the
+//! records, strips their escape sequences, parses their fields, passes them through dedup, and
+//! publishes them to the event bus, see [`bus`]. The read loop thus keeps reading
+//! and answering pings while a burst of large or structured records is decoded, and a slow
+//! bus holds up the read loop only once the queue between the stages is full. The read loop
+//! then stops reading from the socket, so that the node sees a slow reader, until the
+//! processing stage catches up; with `--buffer-drop-policy block`, the processing stage in turn
+//! waits for room in the queues of the outputs, so that slow sinks hold up the connections
+//! instead of losing lines.
+
+use crate::alert::Alerter;
+use crate::anomaly::AnomalyDetector;
+use crate::bus::{self, Bus};
+use crate::ansi;
+use crate::canister_log::Overlap;
+use crate::capture::CaptureWriter;
+use crate::clock;
+use crate::codec::{self, Codec};
+use crate::confirm::Confirmer;
+use crate::context::ContextWindows;
+use crate::counts::{FieldCounter, LineCounter};
+use crate::decode::{self, DecodeError, DecodedRecord, InvalidUtf8};
+use crate::dedup::{Deduplicator, OccurrenceCounter, PropagationTracker};
+use crate::deflate::{self, Compression, DeflateStream};
+use crate::endpoint::{self, PathTemplate};
+use crate::event::LogEvent;
+use crate::exit::ExitPolicy;
+use crate::filter::FilterSet;
+use crate::flight::FlightRecorder;
+use crate::framing::{LineFraming, LineSplitter};
+use crate::health::HealthRegistry;
+use crate::logfmt::LineParser;
+use crate::output::Output;
+use crate::panic::{Grouped, PanicGrouper};
+use crate::ping::AdaptivePing;
+use crate::quarantine::Quarantine;
+use crate::reassembly::{ChunkLimits, Continuation, Joined, Joiner, Reassembler};
+use crate::redact::Redactor;
+use crate::replay::{Backfill, ReplayRequest};
+use crate::resolve::Resolver;
+use crate::resume::{self, Session, SessionRegistry};
+use crate::scheduler::FairScheduler;
+use crate::script::ScriptPipeline;
+use crate::selflog;
+use crate::sequence::SequenceTracker;
+use crate::stats::StatsRegistry;
+use crate::summary::Summarizer;
+use crate::tee::RawTee;
+use crate::throttle::{ReadLimiter, Throttled};
+use crate::tls::TlsSettings;
+use crate::upgrades::UpgradeAnnotator;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep_until, timeout, Duration, Instant};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{
+    client_async_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        handshake::client::Response,
+        http::{HeaderName, HeaderValue, StatusCode},
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Bytes, Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
+
+/// Interval at which partially reassembled and continued records are checked for expiry.
+const CHUNK_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval at which established connections note the progress of their session, if the
+/// sessions are saved in the state directory.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most records received by the read loop of a connection that wait for its processing stage.
+const PROCESSING_QUEUE_CAPACITY: usize = 256;
+/// Estimated offset of the clock of a node, in milliseconds, above which a warning is logged.
+const CLOCK_OFFSET_WARNING_MS: f64 = 1000.0;
+
+/// Settings shared by all WebSocket connections.
+pub struct ConnectionConfig {
+    /// The canisters whose logs are streamed; each node is connected once per canister. The
+    /// list may change at runtime when the configuration is reloaded.
+    pub canister_ids: watch::Sender<Vec<String>>,
+    /// Limits for reassembling chunked records, if chunking is enabled.
+    pub chunk_limits: Option<ChunkLimits>,
+    /// How the text of the frames is divided into log lines.
+    pub line_framing: LineFraming,
+    /// How records continued in the next record are recognized, if they are joined.
+    pub continuation: Option<Continuation>,
+    /// Whether the lines of traps and panics are grouped into one record per block.
+    pub group_panics: bool,
+    /// Field of the structured records with the time the node gave them, if the events take
+    /// their timestamps from it.
+    pub node_time_field: Option<String>,
+    /// Proxy through which connections are tunneled.
+    pub proxy: Option<Url>,
+    /// TLS settings of the connections to the nodes.
+    pub tls: TlsSettings,
+    /// Headers added to the WebSocket upgrade requests, e.g. for authentication.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Selects the addresses of the nodes.
+    pub resolver: Resolver,
+    /// Whether the permessage-deflate extension is offered.
+    pub compression: Compression,
+    /// Most bytes per second read from each node, over all its connections, if limited.
+    pub max_read_rate: Option<NonZeroU64>,
+    /// Shortest interval between keep-alive pings.
+    pub min_ping_interval: Duration,
+    /// Longest interval between keep-alive pings.
+    pub max_ping_interval: Duration,
+    /// How long a connection may go without a pong or other message after a ping before it
+    /// is closed as dead.
+    pub pong_timeout: Duration,
+    /// Limits for the phases of opening a connection.
+    pub connect_timeouts: ConnectTimeouts,
+    /// Historical lines requested before live tailing starts; changed when parked connections
+    /// are re-established.
+    pub replay: Mutex<ReplayRequest>,
+    /// Quality metrics reported by the connections.
+    pub health: HealthRegistry,
+    /// Whether the connections are parked outside the active hours.
+    pub parked: AtomicBool,
+    /// Number of nodes the pool keeps a connection to, connected or not, without standbys.
+    pub pool_size: AtomicUsize,
+    /// Carries the received lines through the processing stages to the output.
+    pub bus: Bus,
+    /// Destinations of the received log lines.
+    pub output: Output,
+    /// Suppresses lines already delivered by another node, if enabled.
+    pub dedup: Option<Deduplicator>,
+    /// Holds back lines until enough nodes delivered them, if enabled; replaces dedup.
+    pub confirmer: Option<Confirmer>,
+    /// Times the arrivals of each line from the nodes, if confirmation or dedup is enabled.
+    pub propagation: Option<PropagationTracker>,
+    /// Fires alerts for matching lines, if configured; replaced when the configuration is
+    /// reloaded.
+    pub alerter: RwLock<Option<Arc<Alerter>>>,
+    /// Selects the lines delivered to the output.
+    pub filters: FilterSet,
+    /// Lines written around the lines that pass the filters, if configured.
+    pub context: Option<ContextWindows>,
+    /// Template of the path of the log streams.
+    pub endpoint: PathTemplate,
+    /// Codec of the frames of nodes that do not announce one.
+    pub codec: &'static dyn Codec,
+    /// What happens to payloads that are not valid UTF-8 and not structured records.
+    pub binary: InvalidUtf8,
+    /// Directory into which undecodable payloads are written with `--binary raw-file`.
+    pub binary_dir: Option<PathBuf>,
+    /// Format of plain text lines to parse into fields, if any.
+    pub parser: Option<LineParser>,
+    /// Whether the escape sequences that set colors and text styles are kept in the lines.
+    pub keep_ansi: bool,
+    /// Detects missed records from their sequence numbers, if configured.
+    pub sequence: Option<SequenceTracker>,
+    /// Whether a connection is re-established when its records skip sequence numbers.
+    pub reconnect_on_sequence_gap: bool,
+    /// Holds back the lines of nodes that keep sending bad frames, if enabled.
+    pub quarantine: Option<Quarantine>,
+    /// Recognizes the live lines that repeat the records fetched with `--backfill`.
+    pub overlap: Option<Overlap>,
+    /// Keeps a copy of the raw frames of each node, if configured.
+    pub tee: Option<RawTee>,
+    /// Records the frames of all nodes for replaying them later, if configured.
+    pub capture: Option<CaptureWriter>,
+    /// Learns the line rates of the canisters and reports their anomalies, if enabled.
+    pub anomalies: Option<AnomalyDetector>,
+    /// Keeps the recent frames in memory and records them around anomalies, if enabled.
+    pub flight_recorder: Option<FlightRecorder>,
+    /// Whether the recorded frames are masked with the redaction patterns.
+    pub redact_capture: bool,
+    /// Counters of all nodes, summarized on shutdown.
+    pub stats: StatsRegistry,
+    /// Sessions of ended connections, resumed when reconnecting, and with `--state-dir` also
+    /// when the client is restarted.
+    pub sessions: SessionRegistry,
+    /// Schedules the output of several canisters fairly, if enabled.
+    pub scheduler: Option<FairScheduler>,
+    /// Scripts that drop, modify, or annotate lines, if given; shared with their stage of the
+    /// bus.
+    pub scripts: Option<Arc<ScriptPipeline>>,
+    /// Masks sensitive data in the lines, if configured; shared with its stage of the bus.
+    pub redactor: Option<Arc<Redactor>>,
+    /// Counts the delivered lines for the periodic summaries.
+    pub summarizer: Option<Summarizer>,
+    /// Annotates upgrades and restarts of the canisters, if enabled.
+    pub upgrades: Option<UpgradeAnnotator>,
+    /// Counts the delivered lines by the fields of the `--count-by` patterns.
+    pub counter: Option<FieldCounter>,
+    /// Counts the delivered lines for `--count`.
+    pub line_counter: Option<LineCounter>,
+    /// Conditions that end the client, if any are given.
+    pub exit: Option<ExitPolicy>,
+}
+
+/// A WebSocket connection to a node.
+pub type WsStream = WebSocketStream<DeflateStream<Throttled<MaybeTlsStream<TcpStream>>>>;
+/// The sending half of a WebSocket connection.
+type WsWrite = SplitSink<WsStream, Message>;
+
+/// How a connection to a node ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The connection could not be established.
+    Failed,
+    /// The connection ended after it was established; connecting again may succeed.
+    Closed,
+    /// The node refused the connection for a reason that retrying will not fix.
+    Rejected(String),
+}
+
+/// Per-connection state of the read loop.
+struct StreamState {
+    canister_id: String,
+    /// Whether received frames are discarded, as on standby connections.
+    muted: Arc<AtomicBool>,
+    codec: &'static dyn Codec,
+    ping: AdaptivePing,
+    reassembler: Option<Reassembler>,
+    /// How the connection ended, once the node has sent a Close frame.
+    disconnect: Option<Disconnect>,
+    /// The session to resume after a reconnect, if the node supports resumption.
+    session: Option<Session>,
+    /// Number of replayed records still expected at the start of a resumed stream.
+    resumed: u64,
+    /// Receive time of the frame being replayed from a capture, which the events keep.
+    captured_at: Option<SystemTime>,
+    /// Whether a warning about the offset of the clock of the node was logged.
+    clock_warned: bool,
+}
+
+/// A record received by the read loop, on its way to the processing stage.
+struct Received {
+    record: Bytes,
+    /// Whether the node replayed the record when resuming the stream.
+    resumed: bool,
+    captured_at: Option<SystemTime>,
+}
+
+/// Per-connection state of the processing stage.
+struct RecordState {
+    canister_id: String,
+    codec: &'static dyn Codec,
+    lines: Option<LineSplitter>,
+    joiner: Option<Joiner>,
+    panics: Option<PanicGrouper>,
+    backfill: Backfill,
+    occurrences: Option<OccurrenceCounter>,
+    /// Receive time of the record being processed, if replayed from a capture.
+    captured_at: Option<SystemTime>,
+    /// Whether a record with a sequence number was received on this connection.
+    sequenced: bool,
+    /// Whether the connection is closed to reconnect, after its records skipped sequence
+    /// numbers.
+    reconnecting: bool,
+    /// Wakes the read loop to close the connection for the reconnect.
+    reconnect: Arc<Notify>,
+}
+
+impl RecordState {
+    fn new(
+        canister_id: String,
+        codec: &'static dyn Codec,
+        replay: &ReplayRequest,
+        config: &ConnectionConfig,
+    ) -> Self {
+        Self {
+            canister_id,
+            codec,
+            lines: LineSplitter::new(config.line_framing),
+            joiner: config.continuation.clone().map(Joiner::new),
+            panics: config.group_panics.then(PanicGrouper::default),
+            backfill: Backfill::new(replay),
+            occurrences: occurrence_counter(config),
+            captured_at: None,
+            sequenced: false,
+            reconnecting: false,
+            reconnect: Arc::new(Notify::new()),
+        }
+    }
+}
+
+/// Connects to a node once for every canister, returning when all connections have ended.
+///
+/// Canisters added to the list at runtime are connected, and the connections of removed
+/// canisters are closed. The node counts as rejecting the client only if it rejected the
+/// connections of all canisters. While `muted` is set, received frames are discarded, which
+/// keeps the connections of standby nodes established without delivering their lines.
+pub async fn handle_node(
+    domain: String,
+    config: Arc<ConnectionConfig>,
+    muted: Arc<AtomicBool>,
+) -> Disconnect {
+    // The connections of the node share its read limit.
+    let limiter = config.max_read_rate.map(ReadLimiter::new);
+    let mut canister_ids = config.canister_ids.subscribe();
+    let mut connections = JoinSet::new();
+    let mut running = HashMap::new();
+    let mut ended = HashSet::new();
+    let mut outcomes = Vec::new();
+    loop {
+        {
+            let wanted = canister_ids.borrow_and_update();
+            running.retain(|canister_id, connection: &mut tokio::task::AbortHandle| {
+                let keep = wanted.contains(canister_id);
+                if !keep {
+                    info!("[{domain}] Closing the connection of removed canister {canister_id}.");
+                    connection.abort();
+                }
+                keep
+            });
+            ended.retain(|canister_id| wanted.contains(canister_id));
+            for canister_id in wanted.iter() {
+                if running.contains_key(canister_id) || ended.contains(canister_id) {
+                    continue;
+                }
+                let connection = selflog::in_connection(
+                    domain.clone(),
+                    canister_id.clone(),
+                    handle_websocket_connection(
+                        domain.clone(),
+                        canister_id.clone(),
+                        config.clone(),
+                        muted.clone(),
+                        limiter.clone(),
+                    ),
+                );
+                let canister_id = canister_id.clone();
+                let handle = connections.spawn({
+                    let canister_id = canister_id.clone();
+                    async move { (canister_id, connection.await) }
+                });
+                running.insert(canister_id, handle);
+            }
+        }
+        if running.is_empty() {
+            break;
+        }
+        tokio::select! {
+            Some(joined) = connections.join_next() => {
+                // Connections of removed canisters end as cancelled and are not counted.
+                if let Ok((canister_id, outcome)) = joined {
+                    running.remove(&canister_id);
+                    ended.insert(canister_id);
+                    outcomes.push(outcome);
+                }
+            }
+            _ = canister_ids.changed() => {}
+        }
+    }
+    if outcomes.contains(&Disconnect::Closed) {
+        Disconnect::Closed
+    } else if outcomes.contains(&Disconnect::Failed) {
+        Disconnect::Failed
+    } else {
+        outcomes.into_iter().next().unwrap_or(Disconnect::Closed)
+    }
+}
+
+/// Handles a single WebSocket connection, sending pings and printing messages.
+async fn handle_websocket_connection(
+    domain: String,
+    canister_id: String,
+    config: Arc<ConnectionConfig>,
+    muted: Arc<AtomicBool>,
+    limiter: Option<ReadLimiter>,
+) -> Disconnect {
+    // Construct the WebSocket URL.
+    let mut url = match config.endpoint.url(&domain, &canister_id) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("[{domain}] {e}");
+            return Disconnect::Rejected(e);
+        }
+    };
+
+    // Advertise chunking support if reassembly is enabled, and either resume the previous
+    // session or request historical lines.
+    if config.chunk_limits.is_some() {
+        endpoint::set_query_param(&mut url, "chunked", "1");
+    }
+    let previous_session = config.sessions.take(&domain, &canister_id);
+    let replay = match &previous_session {
+        Some(session) => {
+            session.append_to(&mut url);
+            ReplayRequest::default()
+        }
+        None => {
+            let replay = *config.replay.lock().unwrap();
+            replay.append_to(&mut url);
+            replay
+        }
+    };
+
+    info!("[{domain}] Attempting to connect to: {url}");
+
+    // Attempt to connect to the WebSocket server with configuration.
+    let connection = connect_websocket(
+        &url,
+        config.proxy.as_ref(),
+        Upgrade {
+            compression: config.compression,
+            headers: &config.headers,
+        },
+        limiter,
+        &config.tls,
+        &config.resolver,
+        config.connect_timeouts,
+    );
+    let (ws_stream, response) = match connection.await {
+        Ok((stream, response)) => {
+            info!(
+                "[{domain}] WebSocket handshake successful! Response: {:?}",
+                response.status()
+            );
+            if deflate::negotiated(&response) {
+                info!("[{domain}] Node compresses frames with permessage-deflate.");
+            }
+            (stream, response)
+        }
+        Err(ConnectError::Handshake(tungstenite::Error::Http(response)))
+            if response.status().is_client_error()
+                && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+        {
+            error!(
+                "[{domain}] Connection refused with status {}.",
+                response.status()
+            );
+            return Disconnect::Rejected(format!("status {}", response.status()));
+        }
+        Err(e) => {
+            error!("[{domain}] Failed to connect: {e}");
+            // The session may still be resumed by the next attempt.
+            if let Some(session) = previous_session {
+                config.sessions.store(&domain, &canister_id, session);
+            }
+            return Disconnect::Failed;
+        }
+    };
+
+    config.health.set_connected(&domain, true);
+    config.stats.record_connected(&domain);
+
+    // Split the WebSocket stream into a sender and a receiver.
+    let (mut write, mut read) = ws_stream.split();
+
+    let (session, resumed) = resume::negotiate(&domain, &response, previous_session);
+    let codec = codec::negotiate(&domain, &response, config.codec);
+    let mut state = StreamState {
+        canister_id: canister_id.clone(),
+        muted,
+        codec,
+        // Schedule pings adaptively, starting with the shortest interval.
+        ping: AdaptivePing::new(config.min_ping_interval, config.max_ping_interval),
+        reassembler: config.chunk_limits.map(Reassembler::new),
+        disconnect: None,
+        session,
+        resumed,
+        captured_at: None,
+        clock_warned: false,
+    };
+    let records = RecordState::new(canister_id.clone(), codec, &replay, &config);
+    let reconnect = records.reconnect.clone();
+    let (queue, received) = mpsc::channel(PROCESSING_QUEUE_CAPACITY);
+    let processing = tokio::spawn(selflog::in_connection(
+        domain.clone(),
+        canister_id,
+        process(domain.clone(), received, records, config.clone()),
+    ));
+    let mut chunk_expiry = interval(CHUNK_EXPIRY_CHECK_INTERVAL);
+    let mut checkpoint = interval(CHECKPOINT_INTERVAL);
+    let persisted = config.sessions.is_persisted();
+
+    info!("[{domain}] Starting message and ping loop...");
+    // Since when reading is held up because the processing stage is behind.
+    let mut held_since: Option<Instant> = None;
+
+    // Loop indefinitely to handle incoming messages and send pings.
+    loop {
+        let held = queue.capacity() == 0;
+        match held_since {
+            None if held => {
+                debug!("[{domain}] Processing is behind, pausing reading.");
+                held_since = Some(Instant::now());
+            }
+            Some(since) if !held => {
+                debug!(
+                    "[{domain}] Processing caught up, resuming reading after {}ms.",
+                    since.elapsed().as_millis()
+                );
+                held_since = None;
+                // Pongs waited unread meanwhile, and the node must not be taken for dead.
+                state.ping.restart();
+            }
+            _ => {}
+        }
+        let dead = state.ping.dead_deadline(config.pong_timeout);
+        tokio::select! {
+            // Handle incoming WebSocket messages while the processing stage has room for them.
+            message = read.next(), if !held => {
+                if !handle_incoming_message(&domain, message, &mut write, &mut state, &queue, &config).await {
+                    break;
+                }
+            },
+            // Wake up once the processing stage took a record from the full queue.
+            _ = queue.reserve(), if held => {}
+            // Close the connection to reconnect once its records skipped sequence numbers.
+            _ = reconnect.notified() => break,
+            // Send a ping message when the connection has been quiet for too long.
+            _ = sleep_until(state.ping.deadline()), if !held => {
+                let (payload, unanswered) = state.ping.start_ping();
+                if unanswered {
+                    warn!("[{domain}] Previous PING is still unanswered.");
+                    config.health.record_stall(&domain);
+                }
+                if !send_ping_message(&domain, &mut write, payload).await {
+                    break;
+                }
+                let next = state.ping.schedule_next();
+                debug!("[{domain}] Next PING in {}s.", next.as_secs());
+            }
+            // Close the connection once the node stopped answering, so that it is reconnected.
+            _ = sleep_until(dead.unwrap_or_else(Instant::now)), if dead.is_some() && !held => {
+                warn!(
+                    "[{domain}] No PONG or other message for {} after a PING, closing the dead \
+                     connection.",
+                    humantime::format_duration(config.pong_timeout)
+                );
+                break;
+            }
+            // Drop chunked records whose remaining chunks did not arrive in time.
+            _ = chunk_expiry.tick(), if state.reassembler.is_some() && !held => {
+                if let Some(reassembler) = state.reassembler.as_mut() {
+                    for id in reassembler.expire() {
+                        warn!("[{domain}] Dropped incomplete chunked record {id}: timed out.");
+                        config.stats.record_dropped(&domain);
+                    }
+                }
+            }
+            // Note the progress of the session, so that it is saved as far as it got.
+            _ = checkpoint.tick(), if persisted && state.session.is_some() => {
+                if let Some(session) = &state.session {
+                    config.sessions.store(&domain, &state.canister_id, session.clone());
+                }
+            }
+        }
+    }
+    // Let the processing stage finish the records received so far.
+    drop(queue);
+    if let Err(e) = processing.await {
+        error!("[{domain}] The processing of the records failed: {e}");
+    }
+
+    config.health.set_connected(&domain, false);
+    config.stats.record_disconnected(&domain);
+    info!("[{domain}] Disconnected.");
+    if let Some(session) = state.session {
+        config.sessions.store(&domain, &state.canister_id, session);
+    }
+    state.disconnect.unwrap_or(Disconnect::Closed)
+}
+
+/// Limits for the phases of opening a connection to a node, so that a node that does not
+/// answer, e.g. because its packets are dropped, cannot hold up a connection attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectTimeouts {
+    /// Limit for resolving the domain and for opening the TCP connection, each.
+    pub connect: Duration,
+    /// Limit for the TLS handshake and for the WebSocket handshake, each.
+    pub handshake: Duration,
+}
+
+impl ConnectTimeouts {
+    /// The limits of the connections whose limits cannot be configured.
+    pub const DEFAULT: Self = Self {
+        connect: Duration::from_secs(10),
+        handshake: Duration::from_secs(10),
+    };
+}
+
+/// A phase of opening a connection to a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectPhase {
+    Resolve,
+    Connect,
+    Tls,
+    Handshake,
+}
+
+impl fmt::Display for ConnectPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Resolve => "DNS resolution",
+            Self::Connect => "TCP connection",
+            Self::Tls => "TLS handshake",
+            Self::Handshake => "WebSocket handshake",
+        })
+    }
+}
+
+/// Why a connection to a node could not be opened.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// A phase before the WebSocket handshake failed.
+    Io(ConnectPhase, io::Error),
+    /// The WebSocket handshake failed, e.g. because the node rejected the request.
+    Handshake(tungstenite::Error),
+    /// A phase did not complete within its limit.
+    TimedOut(ConnectPhase, Duration),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(phase, e) => write!(f, "{phase} failed: {e}"),
+            Self::Handshake(e) => write!(f, "{} failed: {e}", ConnectPhase::Handshake),
+            Self::TimedOut(phase, limit) => write!(
+                f,
+                "{phase} timed out after {}",
+                humantime::format_duration(*limit)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Runs a phase of opening a connection within its limit.
+async fn within<T>(
+    phase: ConnectPhase,
+    limit: Duration,
+    future: impl Future<Output = io::Result<T>>,
+) -> Result<T, ConnectError> {
+    match timeout(limit, future).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(ConnectError::Io(phase, e)),
+        Err(_) => Err(ConnectError::TimedOut(phase, limit)),
+    }
+}
+
+/// What the WebSocket upgrade request asks for besides the log stream.
+#[derive(Clone, Copy)]
+pub struct Upgrade<'a> {
+    /// Whether permessage-deflate compression is offered.
+    pub compression: Compression,
+    /// Headers added to the request, e.g. for authentication.
+    pub headers: &'a [(HeaderName, HeaderValue)],
+}
+
+/// Opens a WebSocket connection, tunneling it through the proxy if one is configured.
+pub async fn connect_websocket(
+    url: &Url,
+    proxy: Option<&Url>,
+    upgrade: Upgrade<'_>,
+    limiter: Option<ReadLimiter>,
+    tls: &TlsSettings,
+    resolver: &Resolver,
+    timeouts: ConnectTimeouts,
+) -> Result<(WsStream, Response), ConnectError> {
+    // Advertise the supported codecs, so that nodes can send other payload formats.
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(ConnectError::Handshake)?;
+    request.headers_mut().insert(
+        codec::ACCEPT_HEADER,
+        HeaderValue::from_str(&codec::accepted()).expect("codec names are valid header values"),
+    );
+    if upgrade.compression == Compression::On {
+        request.headers_mut().insert(
+            deflate::EXTENSIONS_HEADER,
+            HeaderValue::from_static(deflate::OFFER),
+        );
+    }
+    for (name, value) in upgrade.headers {
+        request.headers_mut().append(name, value.clone());
+    }
+
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses = within(
+        ConnectPhase::Resolve,
+        timeouts.connect,
+        resolver.resolve(host, port, proxy),
+    )
+    .await?;
+    let stream = within(
+        ConnectPhase::Connect,
+        timeouts.connect,
+        resolver.connect(host, port, addresses, proxy),
+    )
+    .await?;
+    // TLS is set up here rather than by the WebSocket library, so that compressed frames can
+    // be inflated between the two. Reads are throttled on the encrypted stream, which counts
+    // the bytes as they come over the network.
+    let stream = match url.scheme() {
+        "wss" => MaybeTlsStream::Rustls(
+            within(
+                ConnectPhase::Tls,
+                timeouts.handshake,
+                tls_connect(host, stream, tls),
+            )
+            .await?,
+        ),
+        _ => MaybeTlsStream::Plain(stream),
+    };
+    // Configure WebSocket with message size limits for security
+    let mut ws_config = WebSocketConfig::default();
+    ws_config.max_message_size = Some(5 * 1024); // 5KB limit
+    ws_config.max_frame_size = Some(5 * 1024); // 5KB frame limit
+    let handshake = client_async_with_config(
+        request,
+        DeflateStream::new(Throttled::new(stream, limiter), upgrade.compression),
+        Some(ws_config),
+    );
+    match timeout(timeouts.handshake, handshake).await {
+        Ok(result) => result.map_err(ConnectError::Handshake),
+        Err(_) => Err(ConnectError::TimedOut(
+            ConnectPhase::Handshake,
+            timeouts.handshake,
+        )),
+    }
+}
+
+/// Opens a TLS session to the host, verifying its certificate as configured.
+async fn tls_connect(
+    host: &str,
+    stream: TcpStream,
+    tls: &TlsSettings,
+) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let server_name = match &tls.server_name {
+        Some(server_name) => server_name.clone(),
+        None => ServerName::try_from(host.to_string()).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{host}: {e}"))
+        })?,
+    };
+    TlsConnector::from(tls.client_config.clone())
+        .connect(server_name, stream)
+        .await
+}
+
+/// Handles an incoming WebSocket message, queueing the records it carries for the processing
+/// stage.
+async fn handle_incoming_message(
+    domain: &str,
+    message: Option<Result<Message, tungstenite::Error>>,
+    write: &mut WsWrite,
+    state: &mut StreamState,
+    queue: &mpsc::Sender<Received>,
+    config: &ConnectionConfig,
+) -> bool {
+    match message {
+        Some(Ok(message @ (Message::Binary(_) | Message::Text(_)))) => {
+            if state.muted.load(Ordering::Relaxed) {
+                state.ping.record_traffic();
+                return true;
+            }
+            match receive_frame(domain, message.into_data(), state, config) {
+                Some(received) => queue.send(received).await.is_ok(),
+                None => true,
+            }
+        }
+        Some(Ok(Message::Pong(payload))) => {
+            if let Some(pong) = state.ping.record_pong(&payload) {
+                debug!("[{domain}] Received PONG after {}ms.", pong.rtt.as_millis());
+                config.health.record_rtt(domain, pong.rtt);
+                if let Some(offset_ms) = pong.clock_offset_ms {
+                    let offset_ms = config.health.record_clock_offset(domain, offset_ms);
+                    if offset_ms.abs() > CLOCK_OFFSET_WARNING_MS && !state.clock_warned {
+                        warn!("[{domain}] The clock of the node is off by {offset_ms:+.0}ms.");
+                        state.clock_warned = true;
+                    }
+                }
+            }
+            true
+        }
+        Some(Ok(Message::Ping(_))) => {
+            // The Pong reply is queued when the Ping is read; flush it right away instead of
+            // waiting for the next write.
+            debug!("[{domain}] Received PING, replying.");
+            state.ping.record_traffic();
+            if let Err(e) = write.flush().await {
+                error!("[{domain}] Error sending PONG: {e}");
+                return false;
+            }
+            true
+        }
+        Some(Ok(Message::Close(frame))) => {
+            // The Close reply is sent automatically; the stream ends once it has been sent.
+            state.disconnect = Some(close_outcome(domain, frame));
+            true
+        }
+        Some(Ok(msg)) => {
+            debug!("[{domain}] Received unexpected message: {msg:?}");
+            true
+        }
+        Some(Err(e)) => {
+            if !matches!(e, tungstenite::Error::Capacity(_)) || record_bad_frame(domain, config) {
+                error!("[{domain}] Error receiving message: {e}");
+            }
+            false
+        }
+        None => {
+            info!("[{domain}] WebSocket connection closed by remote.");
+            false
+        }
+    }
+}
+
+/// The message path of a stream replayed from a capture rather than received from a node,
+/// which processes each frame before the next one is replayed.
+pub struct CapturedStream {
+    state: StreamState,
+    records: RecordState,
+}
+
+impl CapturedStream {
+    /// Creates the stream of a node and canister, decoding the frames with the recorded codec.
+    pub fn new(node: &str, canister_id: &str, codec: &str, config: &ConnectionConfig) -> Self {
+        let codec = codec::find(codec).unwrap_or_else(|| {
+            warn!(
+                "[{node}] Unknown codec {codec} in the capture, using {}.",
+                config.codec.name()
+            );
+            config.codec
+        });
+        Self {
+            state: StreamState {
+                canister_id: canister_id.to_string(),
+                muted: Arc::new(AtomicBool::new(false)),
+                codec,
+                ping: AdaptivePing::new(config.min_ping_interval, config.max_ping_interval),
+                reassembler: config.chunk_limits.map(Reassembler::new),
+                disconnect: None,
+                session: None,
+                resumed: 0,
+                captured_at: None,
+                clock_warned: false,
+            },
+            // Backfilled lines cannot be told apart after the fact, so all lines count as live.
+            records: RecordState::new(
+                canister_id.to_string(),
+                codec,
+                &ReplayRequest::default(),
+                config,
+            ),
+        }
+    }
+
+    /// Passes a recorded frame through the pipeline as if it had just been received.
+    pub async fn push(
+        &mut self,
+        node: &str,
+        frame: Bytes,
+        received_at: SystemTime,
+        config: &ConnectionConfig,
+    ) {
+        self.state.captured_at = Some(received_at);
+        if let Some(received) = receive_frame(node, frame, &mut self.state, config) {
+            process_record(node, received, &mut self.records, config).await;
+        }
+    }
+
+    /// Passes on the records held back for their continuation at the end of the capture.
+    pub async fn finish(&mut self, node: &str, config: &ConnectionConfig) {
+        finish_records(node, &mut self.records, config).await;
+    }
+}
+
+/// The processing stage of a connection: passes the records queued by the read loop on, in
+/// order, until the read loop has ended and the queue is empty.
+async fn process(
+    domain: String,
+    mut queue: mpsc::Receiver<Received>,
+    mut records: RecordState,
+    config: Arc<ConnectionConfig>,
+) {
+    let mut continuation_expiry = interval(CHUNK_EXPIRY_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            received = queue.recv() => match received {
+                Some(received) => {
+                    bus::room_for(&records.canister_id, &config).await;
+                    process_record(&domain, received, &mut records, &config).await;
+                }
+                None => break,
+            },
+            // Pass on partial lines and continued records whose continuation did not arrive in
+            // time, and trap and panic blocks that no line continued for a while.
+            _ = continuation_expiry.tick(), if records.lines.is_some()
+                || records.joiner.is_some()
+                || records.panics.is_some() => {
+                if let Some((record, resumed)) = records.lines.as_mut().and_then(LineSplitter::expire) {
+                    join_record(&domain, record, resumed, &mut records, &config).await;
+                }
+                if let Some((record, resumed)) = records.joiner.as_mut().and_then(Joiner::expire) {
+                    debug!("[{domain}] The continuation of a record did not arrive in time.");
+                    group_record(&domain, record, resumed, &mut records, &config).await;
+                }
+                if let Some((record, resumed, panic)) =
+                    records.panics.as_mut().and_then(PanicGrouper::expire)
+                {
+                    handle_record(&domain, record, resumed, panic, &mut records, &config).await;
+                }
+            }
+        }
+    }
+    finish_records(&domain, &mut records, &config).await;
+}
+
+/// Passes on the records held back for their continuation when the connection ends.
+async fn finish_records(domain: &str, records: &mut RecordState, config: &ConnectionConfig) {
+    if let Some((record, resumed)) = records.lines.as_mut().and_then(LineSplitter::finish) {
+        join_record(domain, record, resumed, records, config).await;
+    }
+    if let Some((record, resumed)) = records.joiner.as_mut().and_then(Joiner::finish) {
+        group_record(domain, record, resumed, records, config).await;
+    }
+    if let Some((record, resumed, panic)) = records.panics.as_mut().and_then(PanicGrouper::finish) {
+        handle_record(domain, record, resumed, panic, records, config).await;
+    }
+}
+
+/// Passes a binary frame through reassembly, returning the record it completes, if any.
+fn receive_frame(
+    domain: &str,
+    bin: Bytes,
+    state: &mut StreamState,
+    config: &ConnectionConfig,
+) -> Option<Received> {
+    if let Some(tee) = &config.tee {
+        tee.write(domain, &bin);
+    }
+    let recorded = match &config.redactor {
+        Some(redactor) if config.redact_capture => redactor.redact_frame(&bin),
+        _ => Cow::Borrowed(&bin[..]),
+    };
+    if let Some(capture) = &config.capture {
+        capture.write(domain, &state.canister_id, state.codec.name(), &recorded);
+    }
+    if let Some(recorder) = &config.flight_recorder {
+        recorder.record(domain, &state.canister_id, state.codec.name(), &recorded);
+    }
+    config.stats.record_frame(domain, bin.len());
+
+    // Log traffic keeps the connection alive, so pings can be sent less often.
+    state.ping.record_traffic();
+
+    // Reassemble chunked records, waiting until all chunks have arrived.
+    let record = match state.reassembler.as_mut() {
+        Some(reassembler) => match reassembler.push(&bin) {
+            Ok(Some(record)) => Bytes::from(record),
+            Ok(None) => return None,
+            Err(e) => {
+                if record_bad_frame(domain, config) {
+                    warn!("[{domain}] Dropped chunked record: {e}");
+                }
+                config.stats.record_dropped(domain);
+                return None;
+            }
+        },
+        None => bin,
+    };
+    if let Some(session) = state.session.as_mut() {
+        session.received += 1;
+    }
+    let resumed = state.resumed > 0;
+    state.resumed = state.resumed.saturating_sub(1);
+    Some(Received {
+        record,
+        resumed,
+        captured_at: state.captured_at,
+    })
+}
+
+/// Decodes a record, which may hold several log records, and passes them on.
+async fn process_record(
+    domain: &str,
+    received: Received,
+    state: &mut RecordState,
+    config: &ConnectionConfig,
+) {
+    let Received {
+        record,
+        resumed,
+        captured_at,
+    } = received;
+    state.captured_at = captured_at;
+    let decoded = state.codec.decode(&record);
+    if decoded.is_err() {
+        record_bad_frame(domain, config);
+    }
+    match decoded {
+        Ok(records) => {
+            for decoded in records {
+                split_record(domain, decoded, resumed, state, config).await;
+            }
+        }
+        Err(DecodeError::Unrecognized)
+            if let Some(dir) = &config.binary_dir
+                && config.binary == InvalidUtf8::RawFile =>
+        {
+            match decode::write_payload(dir, &state.canister_id, &record) {
+                Ok(decoded) => split_record(domain, decoded, resumed, state, config).await,
+                Err(e) => {
+                    warn!(
+                        "[{domain}] Failed to write a binary payload of {} bytes to {}: {e}",
+                        record.len(),
+                        dir.display()
+                    );
+                    config.stats.record_dropped(domain);
+                }
+            }
+        }
+        Err(DecodeError::Unrecognized) if let Some(decoded) = config.binary.recover(&record) => {
+            split_record(domain, decoded, resumed, state, config).await;
+        }
+        Err(e) => {
+            debug!("[{domain}] Received BINARY ({} bytes, {e})", record.len());
+            config.stats.record_dropped(domain);
+        }
+    }
+}
+
+/// Records an undecodable or oversized frame of a node, returning whether it is worth logging,
+/// which it is not once the node is quarantined.
+fn record_bad_frame(domain: &str, config: &ConnectionConfig) -> bool {
+    config
+        .quarantine
+        .as_ref()
+        .is_none_or(|quarantine| quarantine.record_bad_frame(domain))
+}
+
+/// Splits a decoded record into one record per line, if the lines are framed, and passes the
+/// complete lines on.
+async fn split_record(
+    domain: &str,
+    decoded: DecodedRecord,
+    resumed: bool,
+    state: &mut RecordState,
+    config: &ConnectionConfig,
+) {
+    let Some(lines) = state.lines.as_mut() else {
+        return join_record(domain, decoded, resumed, state, config).await;
+    };
+    for (record, resumed) in lines.push(decoded, resumed) {
+        join_record(domain, record, resumed, state, config).await;
+    }
+}
+
+/// Strips the escape sequences of a decoded record, joins it with the records it continues or
+/// that continue it, if continued records are joined, and passes the complete records on.
+async fn join_record(
+    domain: &str,
+    mut decoded: DecodedRecord,
+    resumed: bool,
+    state: &mut RecordState,
+    config: &ConnectionConfig,
+) {
+    decoded.message = ansi::clean(decoded.message, config.keep_ansi);
+    let Some(joiner) = state.joiner.as_mut() else {
+        return group_record(domain, decoded, resumed, state, config).await;
+    };
+    match joiner.push(decoded, resumed) {
+        Joined::Pending => {}
+        Joined::Complete(record, resumed) => {
+            group_record(domain, record, resumed, state, config).await;
+        }
+        Joined::TooLarge(record, resumed) => {
+            warn!(
+                "[{domain}] A continued record exceeds the size limit of {} bytes; writing it \
+                 cut at the limit.",
+                config
+                    .continuation
+                    .as_ref()
+                    .map_or(0, |continuation| continuation.max_record_size)
+            );
+            group_record(domain, record, resumed, state, config).await;
+        }
+    }
+}
+
+/// Groups the lines of traps and panics into one record per block, if they are grouped, and
+/// passes the complete records on.
+async fn group_record(
+    domain: &str,
+    record: DecodedRecord,
+    resumed: bool,
+    state: &mut RecordState,
+    config: &ConnectionConfig,
+) {
+    let Some(panics) = state.panics.as_mut() else {
+        return handle_record(domain, record, resumed, false, state, config).await;
+    };
+    if let Grouped::Complete(records) = panics.push(record, resumed) {
+        for (record, resumed, panic) in records {
+            handle_record(domain, record, resumed, panic, state, config).await;
+        }
+    }
+}
+
+/// Passes a decoded record through confirmation or dedup, and publishes it to the event bus.
+///
+/// Records replayed when resuming a stream count as backfill, and `panic` marks the grouped
+/// blocks of traps and panics.
+async fn handle_record(
+    domain: &str,
+    decoded: DecodedRecord,
+    resumed: bool,
+    panic: bool,
+    state: &mut RecordState,
+    config: &ConnectionConfig,
+) {
+    let backfilling = state.backfill.is_active();
+    let backfill = state.backfill.classify() || resumed;
+    // Structured records keep their own fields; plain text lines may be parsed into fields.
+    let fields = decoded.fields.or_else(|| {
+        config
+            .parser
+            .and_then(|parser| parser.parse(&decoded.message))
+    });
+    let mut event = LogEvent::received(domain, &state.canister_id, decoded.message, backfill)
+        .with_fields(fields)
+        .with_resumed(resumed)
+        .with_panic(panic);
+    if let Some(captured_at) = state.captured_at {
+        event.timestamp = captured_at;
+    }
+    // The time the node gave the record orders lines across nodes better than the time they
+    // arrived, once the offset of the clock of the node is taken out.
+    if let Some(name) = &config.node_time_field
+        && let Some(time) = event
+            .fields
+            .as_ref()
+            .and_then(|fields| fields.get(name))
+            .and_then(clock::parse_node_time)
+    {
+        event.timestamp = match config.health.clock_offset_ms(domain) {
+            Some(offset_ms) => clock::correct_node_time(time, offset_ms),
+            None => time,
+        };
+    }
+    if backfilling && !state.backfill.is_active() {
+        info!(
+            "[{domain}] Backfill complete after {} lines, tailing live logs.",
+            state.backfill.received()
+        );
+    }
+
+    if let Some(sequence) = &config.sequence {
+        if let Some(gap) = sequence.observe(domain, &state.canister_id, event.fields.as_ref()) {
+            warn!(
+                "[{domain}] Missed {} messages between {} and {}.",
+                gap.missed(),
+                gap.after,
+                gap.before
+            );
+            config.stats.record_gap(domain, gap.missed());
+            // Lines missed before this connection was established are what the reconnect
+            // already tried to recover, so reconnecting again would not help.
+            if config.reconnect_on_sequence_gap && state.sequenced && !state.reconnecting {
+                info!("[{domain}] Reconnecting to recover the missed messages.");
+                state.reconnecting = true;
+                state.reconnect.notify_one();
+            }
+        }
+        state.sequenced = true;
+    }
+
+    config.health.record_line(domain, &event.message);
+    config
+        .stats
+        .record_message(domain, &state.canister_id, event.timestamp);
+    if let Some(quarantine) = &config.quarantine
+        && quarantine.holds_back(domain)
+    {
+        config.stats.record_quarantined(domain);
+        return;
+    }
+    if let Some(overlap) = &config.overlap
+        && overlap.repeats(&event)
+    {
+        config.stats.record_duplicate(domain);
+        return;
+    }
+    // Confirmation holds the line back until enough nodes delivered it, which also
+    // deduplicates it.
+    if let (Some(confirmer), Some(occurrences)) = (&config.confirmer, state.occurrences.as_mut()) {
+        let key = occurrences.key(&state.canister_id, &event.message);
+        record_propagation(domain, &key, config);
+        match confirmer.observe(&key, event) {
+            Some(event) => config.bus.publish(event).await,
+            None => config.stats.record_duplicate(domain),
+        }
+        return;
+    }
+    if let (Some(dedup), Some(occurrences)) = (&config.dedup, state.occurrences.as_mut()) {
+        let key = occurrences.key(&state.canister_id, &event.message);
+        record_propagation(domain, &key, config);
+        if !dedup.first_seen(&key).await {
+            config.stats.record_duplicate(domain);
+            return;
+        }
+    }
+    config.bus.publish(event).await;
+}
+
+/// Records how long after the first node the node delivered the line with the dedup key.
+fn record_propagation(domain: &str, key: &str, config: &ConnectionConfig) {
+    if let Some(propagation) = &config.propagation {
+        config.stats.record_delay(domain, propagation.arrived(key));
+    }
+}
+
+/// Returns the counter of repeated lines that confirmation or dedup keys lines with.
+fn occurrence_counter(config: &ConnectionConfig) -> Option<OccurrenceCounter> {
+    let window = match (&config.confirmer, &config.dedup) {
+        (Some(confirmer), _) => confirmer.window(),
+        (None, Some(dedup)) => dedup.window(),
+        (None, None) => return None,
+    };
+    Some(OccurrenceCounter::new(window))
+}
+
+/// Reports a Close frame of the node, deciding from its code whether to reconnect.
+fn close_outcome(domain: &str, frame: Option<CloseFrame>) -> Disconnect {
+    let Some(frame) = frame else {
+        info!("[{domain}] Node closed the connection without a code.");
+        return Disconnect::Closed;
+    };
+    let code = u16::from(frame.code);
+    let reason = if frame.reason.is_empty() {
+        format!("code {code}")
+    } else {
+        format!("code {code}: {}", frame.reason)
+    };
+    match frame.code {
+        CloseCode::Normal | CloseCode::Away => {
+            info!("[{domain}] Node closed the connection ({reason}).");
+            Disconnect::Closed
+        }
+        // The node objects to the request itself, so connecting again would fail the same way.
+        CloseCode::Protocol
+        | CloseCode::Unsupported
+        | CloseCode::Invalid
+        | CloseCode::Policy
+        | CloseCode::Size
+        | CloseCode::Extension => {
+            error!("[{domain}] Node rejected the connection ({reason}).");
+            Disconnect::Rejected(reason)
+        }
+        _ => {
+            warn!("[{domain}] Node closed the connection ({reason}).");
+            Disconnect::Closed
+        }
+    }
+}
+
+/// Sends a ping message to keep the WebSocket connection alive
+async fn send_ping_message(domain: &str, write: &mut WsWrite, payload: Vec<u8>) -> bool {
+    let ping_message = Message::Ping(Bytes::from(payload));
+    match write.send(ping_message).await {
+        Ok(_) => {
+            debug!("[{domain}] Sent PING.");
+            true
+        }
+        Err(e) => {
+            error!("[{domain}] Error sending PING: {e}");
+            false
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Grep-style context around matching lines.
+//!
+//! With include filters or alert patterns, a matching line alone often says little, e.g. an
+//! error without the request that caused it. The lines of each node and canister that the
+//! filters drop are therefore held back in a ring buffer, and written before the next matching
+//! line; the lines following a match are written as they arrive. Lines dropped by exclude
+//! filters are never written as context.
+
+use crate::event::LogEvent;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The context of the lines of a single node and canister.
+#[derive(Default)]
+struct Window {
+    /// The latest lines that did not match, oldest first.
+    preceding: VecDeque<LogEvent>,
+    /// Lines still to write after the last match.
+    following: usize,
+}
+
+/// What happens to a line that did not match.
+pub enum Unmatched {
+    /// The line follows a match closely enough to be written as context.
+    Following(LogEvent),
+    /// The line is held back, and the line that no longer fits into the buffer is dropped: the
+    /// oldest held back line, or the line itself without preceding context.
+    Held(Option<LogEvent>),
+}
+
+/// Context windows of all nodes and canisters.
+pub struct ContextWindows {
+    before: usize,
+    after: usize,
+    windows: Mutex<HashMap<(String, String), Window>>,
+}
+
+impl ContextWindows {
+    /// Creates windows of `before` preceding and `after` following lines, or None if both
+    /// are zero.
+    pub fn new(before: usize, after: usize) -> Option<Self> {
+        (before > 0 || after > 0).then(|| Self {
+            before,
+            after,
+            windows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Records a matching line, returning the held back lines to write before it.
+    pub fn matched(&self, event: &LogEvent) -> Vec<LogEvent> {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows
+            .entry((event.node.clone(), event.canister_id.clone()))
+            .or_default();
+        window.following = self.after;
+        window.preceding.drain(..).collect()
+    }
+
+    /// Records a line that did not match.
+    pub fn unmatched(&self, event: LogEvent) -> Unmatched {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows
+            .entry((event.node.clone(), event.canister_id.clone()))
+            .or_default();
+        if window.following > 0 {
+            window.following -= 1;
+            return Unmatched::Following(event);
+        }
+        if self.before == 0 {
+            return Unmatched::Held(Some(event));
+        }
+        let displaced = if window.preceding.len() == self.before {
+            window.preceding.pop_front()
+        } else {
+            None
+        };
+        window.preceding.push_back(event);
+        Unmatched::Held(displaced)
+    }
+}
@@ -0,0 +1,322 @@
+//! HTTP control interface through which other tools change the monitored canisters at
+//! runtime, e.g. a dashboard following the canister an engineer selects.
+//!
+//! The interface speaks a minimal subset of HTTP/1.1, one request per connection:
+//!
+//! - `GET /canisters` lists the monitored canisters.
+//! - `PUT /canisters/<CANISTER_ID>` starts monitoring a canister on all connected nodes.
+//! - `DELETE /canisters/<CANISTER_ID>` stops monitoring a canister; the last one cannot be
+//!   removed.
+//! - `GET /pause` tells whether printing to stdout is paused.
+//! - `POST /pause` pauses printing to stdout, holding back the lines up to the pause buffer
+//!   size, and `POST /resume` prints the held back lines and resumes printing.
+//! - `GET /counts` returns the `--count-by` counters.
+//! - `GET /anomalies` returns the baselines and states of the `--anomaly-factor` detection.
+//! - `GET /reconnects` returns the reconnects deferred by the restart budget.
+//! - `GET /where` returns the `--where` expression, `PUT /where` replaces it with the
+//!   expression in the request body, and `DELETE /where` removes it.
+//!
+//! Responses are JSON objects with the resulting `canisters`, pause state, counters, baselines,
+//! or expression, or an `error`.
+//!
+//! The interface has no authentication, so it should only listen on a loopback address. It
+//! then only answers requests for a loopback host name, so that websites cannot reach it by
+//! rebinding their domain names to the loopback address. Requests that change anything must
+//! have the content type `application/json` or an `X-Requested-With` header, which browsers
+//! only send cross-site after a CORS preflight that the interface never approves, so that web
+//! pages cannot make the browsers of their visitors send them.
+
+use crate::canister;
+use crate::connection::ConnectionConfig;
+use crate::query::Query;
+use crate::server;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+/// Largest request head and body accepted.
+const MAX_REQUEST_BYTES: usize = 8192;
+/// Time within which a client must send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts the control interface on the address.
+pub async fn serve(addr: SocketAddr, config: Arc<ConnectionConfig>) -> io::Result<()> {
+    // Bind before returning, so that an unavailable address is reported as a startup error.
+    let listener = TcpListener::bind(addr).await?;
+    info!("Control interface listening on {}.", listener.local_addr()?);
+    let loopback = addr.ip().is_loopback();
+    if !loopback {
+        warn!(
+            "The control interface on {addr} has no authentication; anyone who can reach it \
+             can change the monitored canisters."
+        );
+    }
+    server::spawn_accept_loop(listener, "Control interface", move |stream, peer| {
+        let config = config.clone();
+        async move {
+            if let Err(e) = handle(stream, &config, loopback).await {
+                warn!("Control request from {peer} failed: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Handles a request; `loopback` tells whether the interface listens on a loopback address,
+/// in which case requests must name a loopback host.
+async fn handle(
+    mut stream: TcpStream,
+    config: &ConnectionConfig,
+    loopback: bool,
+) -> io::Result<()> {
+    let (status, body) = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some((head, body)))) => match check_origin(&head, loopback) {
+            Some(refusal) => refusal,
+            None => respond(head.lines().next().unwrap_or_default(), &body, config),
+        },
+        Ok(Ok(None)) => (400, json!({"error": "malformed request"})),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => (408, json!({"error": "request timed out"})),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        reason(status),
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Refuses requests that a web page may have made through the browser of its visitor: those
+/// for a host name other than a loopback one if `loopback` is set, and those that change
+/// anything without the content type `application/json` or an `X-Requested-With` header.
+fn check_origin(head: &str, loopback: bool) -> Option<(u16, Value)> {
+    let header = |wanted: &str| {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim())
+    };
+    if loopback && !header("host").is_some_and(is_loopback_host) {
+        return Some((
+            403,
+            json!({"error": "the host name is not a loopback name"}),
+        ));
+    }
+    let method = head.split_whitespace().next().unwrap_or_default();
+    let json_body = header("content-type").is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+    });
+    if !matches!(method, "GET" | "HEAD") && !json_body && header("x-requested-with").is_none() {
+        return Some((
+            403,
+            json!({
+                "error": "requests that change anything need the content type application/json \
+                          or an X-Requested-With header"
+            }),
+        ));
+    }
+    None
+}
+
+/// Reads the request and returns its head and body, or None if the request is malformed.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<(String, String)>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let head_end = loop {
+        if let Some(at) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if request.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..read]);
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>());
+    let content_length = match content_length {
+        None => 0,
+        Some(Ok(length)) if head_end + length <= MAX_REQUEST_BYTES => length,
+        Some(_) => return Ok(None),
+    };
+    while request.len() < head_end + content_length {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let body = String::from_utf8_lossy(&request[head_end..head_end + content_length]);
+    Ok(Some((head, body.into_owned())))
+}
+
+/// Carries out a request and returns the status and body of the response.
+fn respond(request_line: &str, body: &str, config: &ConnectionConfig) -> (u16, Value) {
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return (400, json!({"error": "malformed request"}));
+    };
+    let canister_ids = || json!({"canisters": *config.canister_ids.borrow()});
+    let pause_state = || {
+        let (buffered, dropped) = config.output.backlog();
+        json!({"paused": config.output.is_paused(), "buffered": buffered, "dropped": dropped})
+    };
+    let condition =
+        || json!({"where": config.filters.condition().map(|condition| condition.to_string())});
+    match (method, path.trim_end_matches('/')) {
+        ("GET", "/canisters") => (200, canister_ids()),
+        ("GET", "/pause") => (200, pause_state()),
+        ("GET", "/counts") => match &config.counter {
+            Some(counter) => (200, counter.to_json()),
+            None => (404, json!({"error": "no --count-by pattern is given"})),
+        },
+        ("GET", "/anomalies") => match &config.anomalies {
+            Some(anomalies) => (200, anomalies.to_json()),
+            None => (404, json!({"error": "no --anomaly-factor is given"})),
+        },
+        ("GET", "/reconnects") => (200, config.stats.reconnects_json()),
+        ("GET", "/where") => (200, condition()),
+        ("PUT", "/where") if body.trim().is_empty() => (
+            400,
+            json!({"error": "the request body must hold an expression"}),
+        ),
+        ("PUT", "/where") => match Query::parse(body) {
+            Ok(query) => {
+                info!("Only printing lines where {query}, as requested.");
+                config.filters.set_condition(Some(query));
+                (200, condition())
+            }
+            Err(e) => (400, json!({"error": e})),
+        },
+        ("DELETE", "/where") => {
+            if config.filters.condition().is_some() {
+                config.filters.set_condition(None);
+                info!("Removed the --where expression as requested.");
+            }
+            (200, condition())
+        }
+        ("POST", "/pause") => {
+            if !config.output.is_paused() {
+                config.output.pause();
+                info!("Output paused as requested.");
+            }
+            (200, pause_state())
+        }
+        ("POST", "/resume") if !config.output.is_paused() => (200, pause_state()),
+        ("POST", "/resume") => {
+            let resumed = config.output.resume();
+            info!(
+                "Output resumed as requested with {} held back lines.",
+                resumed.printed
+            );
+            (
+                200,
+                json!({"paused": false, "printed": resumed.printed, "dropped": resumed.dropped}),
+            )
+        }
+        (method, path) if path.starts_with("/canisters/") => {
+            let canister_id = match canister::parse_canister_id(&path["/canisters/".len()..]) {
+                Ok(canister_id) => canister_id,
+                Err(e) => return (400, json!({"error": e})),
+            };
+            match method {
+                "PUT" => {
+                    if let Some(scheduler) = &config.scheduler {
+                        scheduler.add_canister(&canister_id);
+                    }
+                    let added = config.canister_ids.send_if_modified(|canister_ids| {
+                        let added = !canister_ids.contains(&canister_id);
+                        if added {
+                            canister_ids.push(canister_id.clone());
+                        }
+                        added
+                    });
+                    if added {
+                        info!("Monitoring canister {canister_id} as requested.");
+                    }
+                    (if added { 201 } else { 200 }, canister_ids())
+                }
+                "DELETE" => {
+                    let mut status = 200;
+                    config.canister_ids.send_if_modified(|canister_ids| {
+                        match canister_ids.iter().position(|id| *id == canister_id) {
+                            None => status = 404,
+                            Some(_) if canister_ids.len() == 1 => status = 409,
+                            Some(index) => {
+                                canister_ids.remove(index);
+                            }
+                        }
+                        status == 200
+                    });
+                    match status {
+                        404 => (
+                            404,
+                            json!({"error": format!("canister {canister_id} is not monitored")}),
+                        ),
+                        409 => (409, json!({"error": "the last canister cannot be removed"})),
+                        _ => {
+                            info!("Stopped monitoring canister {canister_id} as requested.");
+                            (200, canister_ids())
+                        }
+                    }
+                }
+                _ => (
+                    405,
+                    json!({"error": format!("method {method} is not allowed")}),
+                ),
+            }
+        }
+        ("GET", _) | ("PUT", _) | ("POST", _) | ("DELETE", _) => {
+            (404, json!({"error": "not found"}))
+        }
+        _ => (
+            405,
+            json!({"error": format!("method {method} is not allowed")}),
+        ),
+    }
+}
+
+/// Tells whether the Host header of a request names the loopback interface.
+pub fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        _ => "Error",
+    }
+}
@@ -0,0 +1,301 @@
+//! Live counters of the delivered lines, keyed by a field extracted with a regular expression.
+//!
+//! Every `--count-by` pattern counts the lines it matches by the text of its capture groups,
+//! e.g. the status code or the method and path of HTTP requests logged by a canister. The
+//! counters run from the start of the client; the table is printed among the lines on stdout
+//! at an interval and on exit, and served by the control interface.
+//!
+//! `--count` counts the lines in total, per node, per severity, and per `--count-pattern`
+//! instead, printed on exit and optionally at an interval, so that with `--quiet` a script
+//! learns how much a canister logs without reading the lines.
+
+use crate::connection::ConnectionConfig;
+use crate::event::LogEvent;
+use crate::severity::Severity;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{interval_at, Duration, Instant};
+
+/// Most distinct keys counted per pattern; lines with further keys are counted together.
+/// Interval at which the `--count-by` table is printed unless `--count-interval` is given.
+pub const DEFAULT_COUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The severities counted by `--count`.
+const LEVELS: [Severity; 3] = [Severity::Error, Severity::Warning, Severity::Info];
+
+const MAX_KEYS: usize = 10_000;
+
+/// Key under which the lines beyond the most distinct keys are counted.
+const OTHER_KEY: &str = "(other)";
+
+/// Parses a `--count-by` pattern, which needs a capture group to take the key from.
+pub fn parse_pattern(value: &str) -> Result<Regex, String> {
+    let pattern = Regex::new(value).map_err(|e| e.to_string())?;
+    if pattern.captures_len() < 2 {
+        return Err("the pattern needs a capture group, e.g. 'status=(\\d+)'".to_string());
+    }
+    Ok(pattern)
+}
+
+/// The counters of one pattern.
+#[derive(Default)]
+struct Table {
+    /// Lines the pattern matched.
+    lines: u64,
+    counts: HashMap<String, u64>,
+}
+
+/// The most frequent keys of a pattern.
+struct Ranking<'a> {
+    pattern: &'a Regex,
+    /// Lines the pattern matched.
+    lines: u64,
+    /// Keys with their counts, most frequent first.
+    top: Vec<(String, u64)>,
+}
+
+/// Counts the delivered lines by the fields extracted with the `--count-by` patterns.
+pub struct FieldCounter {
+    patterns: Vec<Regex>,
+    top: usize,
+    json: bool,
+    started: SystemTime,
+    tables: Mutex<Vec<Table>>,
+}
+
+impl FieldCounter {
+    /// Creates a counter listing the `top` most frequent keys per pattern, as JSON objects or
+    /// text.
+    pub fn new(patterns: Vec<Regex>, top: usize, json: bool) -> Self {
+        let tables = Mutex::new(patterns.iter().map(|_| Table::default()).collect());
+        Self {
+            patterns,
+            top,
+            json,
+            started: SystemTime::now(),
+            tables,
+        }
+    }
+
+    /// Counts a line delivered to the outputs.
+    pub fn record(&self, event: &LogEvent) {
+        let mut tables = self.tables.lock().unwrap();
+        for (pattern, table) in self.patterns.iter().zip(tables.iter_mut()) {
+            let Some(captures) = pattern.captures(&event.message) else {
+                continue;
+            };
+            // Groups that took no part in the match, e.g. of an alternative, are left out.
+            let key: Vec<&str> = captures
+                .iter()
+                .skip(1)
+                .flatten()
+                .map(|group| group.as_str())
+                .collect();
+            let key = key.join(" ");
+            table.lines += 1;
+            if let Some(count) = table.counts.get_mut(&key) {
+                *count += 1;
+            } else if table.counts.len() < MAX_KEYS {
+                table.counts.insert(key, 1);
+            } else {
+                *table.counts.entry(OTHER_KEY.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Returns the `top` most frequent keys of every pattern with their counts.
+    fn rank(&self) -> Vec<Ranking<'_>> {
+        let tables = self.tables.lock().unwrap();
+        self.patterns
+            .iter()
+            .zip(tables.iter())
+            .map(|(pattern, table)| {
+                let mut counts: Vec<(String, u64)> = table
+                    .counts
+                    .iter()
+                    .map(|(key, count)| (key.clone(), *count))
+                    .collect();
+                counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                counts.truncate(self.top);
+                Ranking {
+                    pattern,
+                    lines: table.lines,
+                    top: counts,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the counters as JSON, as served by the control interface.
+    pub fn to_json(&self) -> Value {
+        let patterns: Vec<Value> = self
+            .rank()
+            .into_iter()
+            .map(|ranking| {
+                let top: Vec<Value> = ranking
+                    .top
+                    .into_iter()
+                    .map(|(key, lines)| json!({"key": key, "lines": lines}))
+                    .collect();
+                json!({"pattern": ranking.pattern.as_str(), "lines": ranking.lines, "top": top})
+            })
+            .collect();
+        json!({
+            "since": humantime::format_rfc3339_seconds(self.started).to_string(),
+            "until": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "patterns": patterns,
+        })
+    }
+
+    /// Renders the table of the counters.
+    pub fn render(&self) -> String {
+        if self.json {
+            return format!("{}\n", json!({"counts": self.to_json()}));
+        }
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "--- Counts until {}, since {}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            humantime::format_rfc3339_seconds(self.started),
+        );
+        for ranking in self.rank() {
+            let _ = writeln!(text, "  {}: {} lines", ranking.pattern, ranking.lines);
+            for (key, count) in ranking.top {
+                let _ = writeln!(text, "    {count:>9}  {key}");
+            }
+        }
+        text
+    }
+}
+
+/// The line counts of `--count`.
+#[derive(Default)]
+struct LineCounts {
+    total: u64,
+    nodes: BTreeMap<String, u64>,
+    /// Lines per severity, in the order of `LEVELS`.
+    levels: [u64; 3],
+    /// Lines matching each `--count-pattern`.
+    patterns: Vec<u64>,
+}
+
+/// Counts the delivered lines in total, per node, per severity, and per pattern.
+pub struct LineCounter {
+    patterns: Vec<Regex>,
+    json: bool,
+    started: SystemTime,
+    counts: Mutex<LineCounts>,
+}
+
+impl LineCounter {
+    /// Creates a counter that also counts the lines matching each pattern, rendering the
+    /// counts as JSON objects or text.
+    pub fn new(patterns: Vec<Regex>, json: bool) -> Self {
+        let counts = LineCounts {
+            patterns: vec![0; patterns.len()],
+            ..LineCounts::default()
+        };
+        Self {
+            patterns,
+            json,
+            started: SystemTime::now(),
+            counts: Mutex::new(counts),
+        }
+    }
+
+    /// Counts a line delivered to the outputs.
+    pub fn record(&self, event: &LogEvent) {
+        let severity = Severity::of(event);
+        let level = LEVELS.iter().position(|level| *level == severity).unwrap();
+        let mut counts = self.counts.lock().unwrap();
+        counts.total += 1;
+        if let Some(lines) = counts.nodes.get_mut(&event.node) {
+            *lines += 1;
+        } else {
+            counts.nodes.insert(event.node.clone(), 1);
+        }
+        counts.levels[level] += 1;
+        for (pattern, lines) in self.patterns.iter().zip(counts.patterns.iter_mut()) {
+            if pattern.is_match(&event.message) {
+                *lines += 1;
+            }
+        }
+    }
+
+    /// Returns the counts as JSON.
+    pub fn to_json(&self) -> Value {
+        let counts = self.counts.lock().unwrap();
+        let levels: Map<String, Value> = LEVELS
+            .iter()
+            .zip(counts.levels)
+            .map(|(level, lines)| (level.name().to_string(), lines.into()))
+            .collect();
+        let patterns: Vec<Value> = self
+            .patterns
+            .iter()
+            .zip(&counts.patterns)
+            .map(|(pattern, lines)| json!({"pattern": pattern.as_str(), "lines": lines}))
+            .collect();
+        json!({
+            "since": humantime::format_rfc3339_seconds(self.started).to_string(),
+            "until": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "total": counts.total,
+            "nodes": counts.nodes,
+            "levels": levels,
+            "patterns": patterns,
+        })
+    }
+
+    /// Renders the counts.
+    pub fn render(&self) -> String {
+        if self.json {
+            return format!("{}\n", json!({"line_counts": self.to_json()}));
+        }
+        let counts = self.counts.lock().unwrap();
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "--- Line counts until {}, since {}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            humantime::format_rfc3339_seconds(self.started),
+        );
+        let _ = writeln!(text, "  Total: {} lines", counts.total);
+        let _ = writeln!(text, "  Nodes:");
+        for (node, lines) in &counts.nodes {
+            let _ = writeln!(text, "    {lines:>9}  {node}");
+        }
+        let _ = writeln!(text, "  Levels:");
+        for (level, lines) in LEVELS.iter().zip(counts.levels) {
+            let _ = writeln!(text, "    {lines:>9}  {}", level.name());
+        }
+        if !self.patterns.is_empty() {
+            let _ = writeln!(text, "  Patterns:");
+            for (pattern, lines) in self.patterns.iter().zip(&counts.patterns) {
+                let _ = writeln!(text, "    {lines:>9}  {pattern}");
+            }
+        }
+        text
+    }
+}
+
+/// Starts a task that prints the `--count-by` table, and the `--count` counts if `lines` is
+/// set, among the lines on stdout at the given interval.
+pub fn spawn(config: Arc<ConnectionConfig>, every: Duration, lines: bool) {
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + every, every);
+        loop {
+            ticker.tick().await;
+            if let Some(counter) = &config.counter {
+                config.output.write_text(&counter.render());
+            }
+            if lines && let Some(counter) = &config.line_counter {
+                config.output.write_text(&counter.render());
+            }
+        }
+    });
+}
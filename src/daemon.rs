@@ -0,0 +1,214 @@
+//! Running as a traditional background service with `--daemon`, on hosts without systemd.
+//!
+//! Before the async runtime starts, the client forks twice: the first child starts a new
+//! session, so that the daemon has no controlling terminal, and the second, the daemon, can
+//! never acquire one. The daemon reads stdin from `/dev/null`, writes stdout to `/dev/null`
+//! and stderr to the `--log-file`, and writes its process ID to the `--pid-file`. It keeps the
+//! working directory, so that relative paths in the options and the config file keep working.
+//!
+//! The process started from the shell waits until the daemon has written its PID file, and
+//! exits with success once it has, or with the error and exit code of the daemon if it could
+//! not start, e.g. because the PID file names another instance that is still running. Errors
+//! after that, e.g. failing to discover the nodes, end up in the log file.
+
+use crate::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+
+/// The log file of the daemon, reopened on SIGHUP.
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Exit code of the process started from the shell if the daemon ended without a word.
+const DAEMON_DIED_STATUS: u8 = 70;
+
+/// Detaches into the background with `daemon`, and writes the PID file if one is given.
+/// Only the daemon returns; the process started from the shell exits once the daemon runs.
+/// The PID file is removed when the returned guard is dropped.
+pub fn start(
+    daemon: bool,
+    pid_file: Option<&Path>,
+    log_file: Option<&Path>,
+) -> Result<Option<PidFile>, Error> {
+    match (daemon, pid_file, log_file) {
+        (true, Some(pid_file), Some(log_file)) => detach(pid_file, log_file).map(Some),
+        (true, ..) => Err("--daemon requires --pid-file and --log-file".into()),
+        (false, Some(pid_file), _) => PidFile::create(pid_file).map(Some),
+        (false, None, _) => Ok(None),
+    }
+}
+
+/// Opens the log file again, e.g. after it was rotated.
+pub fn reopen_log() -> Result<(), Error> {
+    match LOG_FILE.get() {
+        Some(path) => redirect_stderr(path),
+        None => Ok(()),
+    }
+}
+
+/// A PID file, removed on drop unless another process wrote it meanwhile.
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Writes the ID of the process to the file, failing if the file names another process
+    /// that is still running.
+    fn create(path: &Path) -> Result<Self, Error> {
+        let pid = process::id();
+        if let Ok(contents) = fs::read_to_string(path)
+            && let Ok(other) = contents.trim().parse::<u32>()
+            && other != pid
+            && is_running(other)
+        {
+            return Err(format!(
+                "another instance is running as process {other}, according to {}",
+                path.display()
+            )
+            .into());
+        }
+        fs::write(path, format!("{pid}\n")).map_err(Error::io(format!(
+            "failed to write the PID file {}",
+            path.display()
+        )))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            pid,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if fs::read_to_string(&self.path)
+            .is_ok_and(|contents| contents.trim() == self.pid.to_string())
+        {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Returns whether a process with the ID exists, including one of another user.
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn detach(pid_file: &Path, log_file: &Path) -> Result<PidFile, Error> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe writes two new file descriptors into the array, which the files own.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::io("failed to create a pipe to the daemon")(
+            io::Error::last_os_error(),
+        ));
+    }
+    let (reader, mut writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    if fork()? != 0 {
+        drop(writer);
+        process::exit(wait_for_daemon(reader).into());
+    }
+    drop(reader);
+    // SAFETY: the process is single-threaded, so it can start a session and fork.
+    if unsafe { libc::setsid() } < 0 {
+        fail(
+            &mut writer,
+            Error::io("failed to start a session")(io::Error::last_os_error()),
+        );
+    }
+    match fork() {
+        Ok(0) => {}
+        // SAFETY: the first child ends without running the destructors of the parent's state.
+        Ok(_) => unsafe { libc::_exit(0) },
+        Err(e) => fail(&mut writer, e),
+    }
+
+    let started = redirect_stderr(log_file)
+        .and_then(|()| redirect_to_null())
+        .and_then(|()| PidFile::create(pid_file));
+    match started {
+        Ok(pid_file) => {
+            let _ = LOG_FILE.set(log_file.to_path_buf());
+            let _ = writer.write_all(b"ok");
+            Ok(pid_file)
+        }
+        Err(e) => fail(&mut writer, e),
+    }
+}
+
+/// Forks the process, returning 0 in the child and the ID of the child in the parent.
+fn fork() -> Result<libc::pid_t, Error> {
+    // SAFETY: the async runtime has not started yet, so the process is single-threaded.
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::io("failed to fork")(io::Error::last_os_error())),
+        pid => Ok(pid),
+    }
+}
+
+/// Reports an error of the daemon to the process started from the shell, and exits.
+fn fail(writer: &mut File, error: Error) -> ! {
+    let _ = write!(writer, "{}\t{error}", error.status());
+    process::exit(error.status().into());
+}
+
+/// Waits until the daemon reports that it started, and returns the exit code of the process
+/// started from the shell.
+fn wait_for_daemon(mut reader: File) -> u8 {
+    let mut report = String::new();
+    let _ = reader.read_to_string(&mut report);
+    if report == "ok" {
+        return 0;
+    }
+    match report.split_once('\t') {
+        Some((status, message)) => {
+            eprintln!("Error: {message}");
+            status.parse().unwrap_or(DAEMON_DIED_STATUS)
+        }
+        None => {
+            eprintln!("Error: the daemon exited while starting");
+            DAEMON_DIED_STATUS
+        }
+    }
+}
+
+/// Appends stderr to the file.
+fn redirect_stderr(path: &Path) -> Result<(), Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(Error::io(format!(
+            "failed to open the log file {}",
+            path.display()
+        )))?;
+    dup2(&file, libc::STDERR_FILENO)
+}
+
+/// Connects stdin and stdout to `/dev/null`.
+fn redirect_to_null() -> Result<(), Error> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(Error::io("failed to open /dev/null"))?;
+    dup2(&null, libc::STDIN_FILENO)?;
+    dup2(&null, libc::STDOUT_FILENO)
+}
+
+fn dup2(file: &File, fd: libc::c_int) -> Result<(), Error> {
+    // SAFETY: dup2 replaces a standard file descriptor with a copy of an open one.
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(Error::io("failed to redirect a standard stream")(
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
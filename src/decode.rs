@@ -0,0 +1,366 @@
+//! Decoding of log records that are sent as CBOR or Candid instead of plain text.
+//!
+//! Candid payloads are recognised by their `DIDL` magic bytes, CBOR payloads by the
+//! self-describe tag or, failing that, by not being valid UTF-8. Records that decode to a map
+//! keep their fields; the log message is taken from the first of the [`MESSAGE_FIELDS`] that
+//! holds text, and otherwise is the whole record rendered as JSON.
+//!
+//! Candid payloads carry field names only as hashes, so well-known field names are restored
+//! from their hashes; other fields are named `_<hash>`.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use serde_json::{json, Map, Number, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+
+/// Magic bytes at the start of every Candid message.
+const CANDID_MAGIC: &[u8] = b"DIDL";
+/// Self-describe tag (55799) that may prefix a CBOR item.
+const CBOR_SELF_DESCRIBE: &[u8] = &[0xd9, 0xd9, 0xf7];
+
+/// Fields holding the log message, in order of preference.
+const MESSAGE_FIELDS: &[&str] = &["message", "msg", "line", "content", "text"];
+/// Field names restored from their hashes in Candid records.
+const KNOWN_FIELDS: &[&str] = &[
+    "message",
+    "msg",
+    "line",
+    "content",
+    "text",
+    "level",
+    "severity",
+    "timestamp",
+    "time",
+    "idx",
+    "index",
+    "canister_id",
+    "caller",
+    "method",
+    "module",
+    "target",
+    "file",
+    "error",
+];
+
+/// A log record decoded from a binary frame.
+pub struct DecodedRecord {
+    /// The log message as sent, before ANSI escape sequences are stripped.
+    pub message: String,
+    /// Fields of a structured record, if the record was a CBOR map or Candid record.
+    pub fields: Option<Map<String, Value>>,
+}
+
+impl DecodedRecord {
+    fn text(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let message = String::from_utf8_lossy(bytes).into_owned();
+        Ok(Self {
+            message,
+            fields: None,
+        })
+    }
+
+    fn structured(value: Value) -> Self {
+        let message = match &value {
+            Value::Object(fields) => MESSAGE_FIELDS
+                .iter()
+                .find_map(|name| fields.get(*name).and_then(Value::as_str))
+                .map(str::to_string),
+            Value::String(text) => Some(text.clone()),
+            _ => None,
+        }
+        .unwrap_or_else(|| value.to_string());
+        let fields = match value {
+            Value::Object(fields) => Some(fields),
+            _ => None,
+        };
+        Self { message, fields }
+    }
+}
+
+/// What happens to payloads that are neither valid UTF-8 nor a structured record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InvalidUtf8 {
+    /// Drop the payload and count it as dropped
+    Skip,
+    /// Replace invalid sequences with U+FFFD
+    Lossy,
+    /// Print the payload as hex
+    Hex,
+    /// Wrap the payload, encoded as base64, into a JSON record with a `base64` field
+    Base64,
+    /// Write the payload to a file in the --binary-dir and print a record naming the file
+    RawFile,
+}
+
+impl InvalidUtf8 {
+    /// Turns an undecodable payload into a record according to the policy, or returns `None`
+    /// if it is skipped or written to a file with [`write_payload`].
+    pub fn recover(self, payload: &[u8]) -> Option<DecodedRecord> {
+        match self {
+            Self::Skip | Self::RawFile => None,
+            Self::Lossy => Some(DecodedRecord {
+                message: String::from_utf8_lossy(payload).into_owned(),
+                fields: None,
+            }),
+            Self::Hex => Some(DecodedRecord {
+                message: hex::encode(payload),
+                fields: None,
+            }),
+            Self::Base64 => Some(DecodedRecord::structured(
+                json!({ "base64": STANDARD.encode(payload) }),
+            )),
+        }
+    }
+}
+
+/// Writes an undecodable payload to `<dir>/<canister_id>/<sha256>.bin` and returns a record
+/// naming the file. The same payload delivered by several nodes is written once.
+pub fn write_payload(dir: &Path, canister_id: &str, payload: &[u8]) -> io::Result<DecodedRecord> {
+    let sha256 = hex::encode(Sha256::digest(payload));
+    let dir = dir.join(canister_id);
+    let path = dir.join(format!("{sha256}.bin"));
+    if !path.exists() {
+        fs::create_dir_all(&dir)?;
+        // Write under a temporary name, so that readers of the directory never see a partial file.
+        let partial = dir.join(format!("{sha256}.bin.partial"));
+        fs::write(&partial, payload)?;
+        fs::rename(&partial, &path)?;
+    }
+    let file = path.display().to_string();
+    let message = format!(
+        "binary payload of {} bytes written to {file}",
+        payload.len()
+    );
+    let fields = json!({ "file": file, "bytes": payload.len(), "sha256": sha256 });
+    Ok(DecodedRecord {
+        message,
+        fields: fields.as_object().cloned(),
+    })
+}
+
+/// Why a binary frame could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The payload has the Candid magic bytes but is not a valid Candid message.
+    Candid(String),
+    /// The payload has the CBOR self-describe tag but is not a valid CBOR item.
+    Cbor(String),
+    /// The payload is neither valid UTF-8, CBOR, nor Candid.
+    Unrecognized,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Candid(e) => write!(f, "invalid Candid payload: {e}"),
+            Self::Cbor(e) => write!(f, "invalid CBOR payload: {e}"),
+            Self::Unrecognized => write!(f, "not valid UTF-8, CBOR, or Candid"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes a log record, treating it as plain text unless it is recognised as CBOR or Candid.
+pub fn decode(payload: &[u8]) -> Result<DecodedRecord, DecodeError> {
+    if payload.starts_with(CANDID_MAGIC) {
+        return decode_candid(payload).map(DecodedRecord::structured);
+    }
+    if payload.starts_with(CBOR_SELF_DESCRIBE) {
+        return decode_cbor(payload).map(DecodedRecord::structured);
+    }
+    if std::str::from_utf8(payload).is_ok() {
+        return DecodedRecord::text(payload);
+    }
+    decode_cbor(payload)
+        .map(DecodedRecord::structured)
+        .map_err(|_| DecodeError::Unrecognized)
+}
+
+/// Decodes the bytes of a record as plain text, as with `--raw`.
+pub fn decode_text(payload: &[u8]) -> Result<DecodedRecord, DecodeError> {
+    DecodedRecord::text(payload)
+}
+
+/// Decodes a record that must be a Candid message.
+pub fn decode_candid_record(payload: &[u8]) -> Result<DecodedRecord, DecodeError> {
+    decode_candid(payload).map(DecodedRecord::structured)
+}
+
+/// Decodes a record that must be a single CBOR item.
+pub fn decode_cbor_record(payload: &[u8]) -> Result<DecodedRecord, DecodeError> {
+    decode_cbor(payload).map(DecodedRecord::structured)
+}
+
+/// Decodes a batch of records sent as a CBOR sequence (RFC 8742), i.e. concatenated items.
+pub fn decode_cbor_sequence(payload: &[u8]) -> Result<Vec<DecodedRecord>, DecodeError> {
+    let mut reader = Cursor::new(payload);
+    let mut records = Vec::new();
+    while (reader.position() as usize) < payload.len() {
+        let value: ciborium::Value =
+            ciborium::from_reader(&mut reader).map_err(|e| DecodeError::Cbor(e.to_string()))?;
+        records.push(DecodedRecord::structured(cbor_to_json(value)));
+    }
+    Ok(records)
+}
+
+fn decode_candid(payload: &[u8]) -> Result<Value, DecodeError> {
+    let args =
+        candid::IDLArgs::from_bytes(payload).map_err(|e| DecodeError::Candid(e.to_string()))?;
+    let mut values: Vec<Value> = args.args.iter().map(candid_to_json).collect();
+    Ok(if values.len() == 1 {
+        values.remove(0)
+    } else {
+        Value::Array(values)
+    })
+}
+
+fn decode_cbor(payload: &[u8]) -> Result<Value, DecodeError> {
+    let mut reader = Cursor::new(payload);
+    let value: ciborium::Value =
+        ciborium::from_reader(&mut reader).map_err(|e| DecodeError::Cbor(e.to_string()))?;
+    if reader.position() as usize != payload.len() {
+        return Err(DecodeError::Cbor(
+            "trailing bytes after the first item".to_string(),
+        ));
+    }
+    Ok(cbor_to_json(value))
+}
+
+fn candid_to_json(value: &candid::IDLValue) -> Value {
+    use candid::IDLValue;
+    match value {
+        IDLValue::Bool(b) => Value::Bool(*b),
+        IDLValue::Null | IDLValue::None | IDLValue::Reserved => Value::Null,
+        IDLValue::Text(text) => Value::String(text.clone()),
+        IDLValue::Number(n) => Value::String(n.clone()),
+        IDLValue::Int(n) => Value::String(n.to_string()),
+        IDLValue::Nat(n) => Value::String(n.to_string()),
+        IDLValue::Nat8(n) => Value::from(*n),
+        IDLValue::Nat16(n) => Value::from(*n),
+        IDLValue::Nat32(n) => Value::from(*n),
+        IDLValue::Nat64(n) => Value::from(*n),
+        IDLValue::Int8(n) => Value::from(*n),
+        IDLValue::Int16(n) => Value::from(*n),
+        IDLValue::Int32(n) => Value::from(*n),
+        IDLValue::Int64(n) => Value::from(*n),
+        IDLValue::Float32(f) => float(f64::from(*f)),
+        IDLValue::Float64(f) => float(*f),
+        IDLValue::Opt(inner) => candid_to_json(inner),
+        IDLValue::Vec(items) => Value::Array(items.iter().map(candid_to_json).collect()),
+        IDLValue::Blob(bytes) => Value::String(hex::encode(bytes)),
+        IDLValue::Principal(id) | IDLValue::Service(id) => Value::String(id.to_text()),
+        IDLValue::Func(id, method) => Value::String(format!("{}.{method}", id.to_text())),
+        IDLValue::Record(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|field| (field_name(&field.id), candid_to_json(&field.val)))
+                .collect(),
+        ),
+        IDLValue::Variant(variant) => {
+            let field = &variant.0;
+            let mut object = Map::new();
+            object.insert(field_name(&field.id), candid_to_json(&field.val));
+            Value::Object(object)
+        }
+    }
+}
+
+fn field_name(label: &candid::types::Label) -> String {
+    use candid::types::Label;
+    match label {
+        Label::Named(name) => name.clone(),
+        Label::Unnamed(index) => index.to_string(),
+        Label::Id(hash) => KNOWN_FIELDS
+            .iter()
+            .find(|name| candid::idl_hash(name) == *hash)
+            .map_or_else(|| format!("_{hash}"), |name| name.to_string()),
+    }
+}
+
+fn cbor_to_json(value: ciborium::Value) -> Value {
+    use ciborium::Value as Cbor;
+    match value {
+        Cbor::Integer(n) => {
+            let n = i128::from(n);
+            i64::try_from(n)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(n).map(Value::from))
+                .unwrap_or_else(|_| Value::String(n.to_string()))
+        }
+        Cbor::Bytes(bytes) => Value::String(hex::encode(bytes)),
+        Cbor::Float(f) => float(f),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Null => Value::Null,
+        Cbor::Tag(_, inner) => cbor_to_json(*inner),
+        Cbor::Array(items) => Value::Array(items.into_iter().map(cbor_to_json).collect()),
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Cbor::Text(text) => text,
+                        key => cbor_to_json(key).to_string(),
+                    };
+                    (key, cbor_to_json(value))
+                })
+                .collect(),
+        ),
+        _ => Value::Null,
+    }
+}
+
+/// Converts a float to JSON, mapping NaN and infinities (which JSON lacks) to null.
+fn float(f: f64) -> Value {
+    Number::from_f64(f).map_or(Value::Null, Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Neither UTF-8 nor a CBOR item, as 0xff is a CBOR break code.
+    const UNDECODABLE: &[u8] = b"\xff\x00ok";
+
+    #[test]
+    fn decode_rejects_payloads_that_are_neither_text_nor_records() {
+        assert!(matches!(
+            decode(UNDECODABLE),
+            Err(DecodeError::Unrecognized)
+        ));
+    }
+
+    #[test]
+    fn lossy_replaces_invalid_sequences() {
+        let record = InvalidUtf8::Lossy.recover(b"ok \xff done").unwrap();
+        assert_eq!(record.message, "ok \u{FFFD} done");
+        assert!(record.fields.is_none());
+    }
+
+    #[test]
+    fn hex_prints_the_bytes() {
+        let record = InvalidUtf8::Hex.recover(UNDECODABLE).unwrap();
+        assert_eq!(record.message, "ff006f6b");
+        assert!(record.fields.is_none());
+    }
+
+    #[test]
+    fn base64_wraps_the_bytes_into_a_record() {
+        let record = InvalidUtf8::Base64.recover(UNDECODABLE).unwrap();
+        let fields = record.fields.unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields["base64"], "/wBvaw==");
+        assert_eq!(record.message, r#"{"base64":"/wBvaw=="}"#);
+    }
+
+    #[test]
+    fn skip_and_raw_file_recover_nothing() {
+        assert!(InvalidUtf8::Skip.recover(UNDECODABLE).is_none());
+        assert!(InvalidUtf8::RawFile.recover(UNDECODABLE).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Suppression of log lines delivered by more than one boundary node.
+//!
+//! Every boundary node relays the same log lines, so each line normally arrives once per
+//! connection. A line is identified by its canister, a hash of its content, and how many
+//! times the delivering node has seen that content within the dedup window. The occurrence
+//! count keeps lines that a canister legitimately logs repeatedly: the second identical line
+//! from one node matches the second identical line from every other node.
+//!
+//! The set of seen keys lives in a pluggable store. The in-memory store deduplicates within a
+//! single process; the Redis store shares the state between several processes, e.g. tailers
+//! in different regions that write into one shared sink.
+//!
+//! Independently of the store, the arrivals of each line are timed, so that the delay of every
+//! node behind the first node to deliver a line shows which nodes have lagging log pipelines.
+
+use log::warn;
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// The time each key was first seen within the window, with expired keys removed once per
+/// window rather than on every lookup.
+struct FirstSeen {
+    window: Duration,
+    times: HashMap<String, Instant>,
+    last_prune: Instant,
+}
+
+impl FirstSeen {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            times: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Returns when the key was first seen within the window, or records it as first seen now.
+    fn get_or_insert(&mut self, key: &str, now: Instant) -> Option<Instant> {
+        let window = self.window;
+        if now.duration_since(self.last_prune) >= window {
+            self.times
+                .retain(|_, time| now.duration_since(*time) < window);
+            self.last_prune = now;
+        }
+        match self.times.get(key) {
+            Some(time) if now.duration_since(*time) < window => Some(*time),
+            _ => {
+                self.times.insert(key.to_string(), now);
+                None
+            }
+        }
+    }
+}
+
+/// Where the keys of already emitted lines are stored.
+enum DedupStore {
+    /// Keys are kept in the memory of this process.
+    Memory(Mutex<FirstSeen>),
+    /// Keys are shared through a Redis server.
+    Redis {
+        connection: ConnectionManager,
+        /// Set while Redis is failing, to avoid logging every failed lookup.
+        failing: AtomicBool,
+    },
+}
+
+/// Deduplicates lines across connections using a shared store.
+pub struct Deduplicator {
+    store: DedupStore,
+    namespace: String,
+    window: Duration,
+}
+
+impl Deduplicator {
+    /// Creates a deduplicator that keeps its state in memory.
+    pub fn in_memory(window: Duration) -> Self {
+        Self {
+            store: DedupStore::Memory(Mutex::new(FirstSeen::new(window))),
+            namespace: String::new(),
+            window,
+        }
+    }
+
+    /// Creates a deduplicator that shares its state through Redis.
+    pub async fn redis(url: &str, namespace: String, window: Duration) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(Self {
+            store: DedupStore::Redis {
+                connection,
+                failing: AtomicBool::new(false),
+            },
+            namespace,
+            window,
+        })
+    }
+
+    /// Returns the dedup window.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Records the key and returns true if it was not seen within the window.
+    ///
+    /// If the shared store is unavailable, lines are emitted rather than lost.
+    pub async fn first_seen(&self, key: &str) -> bool {
+        match &self.store {
+            DedupStore::Memory(seen) => seen
+                .lock()
+                .unwrap()
+                .get_or_insert(key, Instant::now())
+                .is_none(),
+            DedupStore::Redis {
+                connection,
+                failing,
+            } => {
+                let result: redis::RedisResult<Option<String>> = redis::cmd("SET")
+                    .arg(format!("{}:{key}", self.namespace))
+                    .arg(1)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(self.window.as_millis() as u64)
+                    .query_async(&mut connection.clone())
+                    .await;
+                match result {
+                    Ok(reply) => {
+                        if failing.swap(false, Ordering::Relaxed) {
+                            warn!("Redis dedup store is available again.");
+                        }
+                        reply.is_some()
+                    }
+                    Err(e) => {
+                        if !failing.swap(true, Ordering::Relaxed) {
+                            warn!("Redis dedup store failed, emitting lines without dedup: {e}");
+                        }
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Times the arrivals of each line at this process, whichever store dedups them.
+pub struct PropagationTracker {
+    first: Mutex<FirstSeen>,
+}
+
+impl PropagationTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            first: Mutex::new(FirstSeen::new(window)),
+        }
+    }
+
+    /// Records the arrival of a line with the dedup key and returns how long after its first
+    /// arrival it arrived, or None if this is its first arrival.
+    pub fn arrived(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let first = self.first.lock().unwrap().get_or_insert(key, now)?;
+        Some(now.duration_since(first))
+    }
+}
+
+/// Counts how often a connection received each distinct line within the dedup window.
+pub struct OccurrenceCounter {
+    window: Duration,
+    seen: HashMap<[u8; 16], VecDeque<Instant>>,
+    last_prune: Instant,
+}
+
+impl OccurrenceCounter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Returns the dedup key of a line received by this connection.
+    pub fn key(&mut self, canister_id: &str, line: &str) -> String {
+        let hash: [u8; 16] = Sha256::digest(line.as_bytes())[..16]
+            .try_into()
+            .expect("digest is longer than 16 bytes");
+        let now = Instant::now();
+        let window = self.window;
+
+        if now.duration_since(self.last_prune) >= window {
+            self.seen.retain(|_, times| {
+                times.retain(|time| now.duration_since(*time) < window);
+                !times.is_empty()
+            });
+            self.last_prune = now;
+        }
+
+        let times = self.seen.entry(hash).or_default();
+        while times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= window)
+        {
+            times.pop_front();
+        }
+        times.push_back(now);
+
+        format!("{canister_id}:{}:{}", hex::encode(hash), times.len())
+    }
+}
@@ -0,0 +1,520 @@
+//! The `permessage-deflate` WebSocket extension (RFC 7692), which compresses the frames a
+//! boundary node sends.
+//!
+//! The WebSocket library does not implement extensions and rejects compressed frames, so they
+//! are inflated by [`DeflateStream`] beneath it: the stream passes the handshake through, then
+//! replaces every compressed data frame with a frame carrying the inflated payload. Control
+//! frames are never compressed and pass through unchanged, as do the frames the client sends,
+//! which the extension allows to stay uncompressed.
+
+use clap::ValueEnum;
+use flate2::{Decompress, FlushDecompress, Status};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+
+/// Value of the `Sec-WebSocket-Extensions` request header offering the extension.
+pub const OFFER: &str = "permessage-deflate";
+/// Request and response header negotiating extensions.
+pub const EXTENSIONS_HEADER: &str = "sec-websocket-extensions";
+
+/// Trailer that the sender strips from the end of every compressed message.
+const MESSAGE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Largest compressed frame that is buffered for inflating.
+const MAX_COMPRESSED_FRAME: u64 = 1024 * 1024;
+/// Largest payload a single frame may inflate to; larger frames would be rejected by the
+/// WebSocket size limits anyway.
+const MAX_INFLATED_FRAME: usize = 1024 * 1024;
+/// Size of the reads from the underlying stream.
+const READ_SIZE: usize = 8 * 1024;
+
+/// Whether WebSocket compression is offered to the nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// Offer permessage-deflate; nodes that do not support it send uncompressed frames.
+    On,
+    /// Do not offer compression.
+    Off,
+}
+
+/// Returns whether the node accepted the extension in its handshake response.
+pub fn negotiated(response: &Response) -> bool {
+    response
+        .headers()
+        .get_all(EXTENSIONS_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|extension| extension.split(';').next().map(str::trim) == Some(OFFER))
+}
+
+/// Where the reader is in the incoming byte stream.
+enum ReadState {
+    /// Within the HTTP response of the handshake.
+    Handshake,
+    /// At the start of a frame.
+    Frames,
+    /// Within the payload of a frame passed through unchanged, with the bytes remaining.
+    PassThrough(u64),
+    /// Passing everything through, because compression is off or the handshake failed.
+    Transparent,
+}
+
+/// A stream that inflates the compressed frames it reads from the underlying stream.
+pub struct DeflateStream<S> {
+    inner: S,
+    state: ReadState,
+    inflater: Decompress,
+    /// Whether the data message being received is compressed.
+    compressed_message: bool,
+    /// Bytes read from the underlying stream but not processed yet.
+    pending: Vec<u8>,
+    /// Processed bytes ready to be read.
+    ready: Vec<u8>,
+    ready_pos: usize,
+    eof: bool,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wraps a stream on which the WebSocket handshake is yet to be made.
+    pub fn new(inner: S, compression: Compression) -> Self {
+        Self {
+            inner,
+            state: match compression {
+                Compression::On => ReadState::Handshake,
+                Compression::Off => ReadState::Transparent,
+            },
+            inflater: Decompress::new(false),
+            compressed_message: false,
+            pending: Vec::new(),
+            ready: Vec::new(),
+            ready_pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Moves as many processed bytes from `pending` to `ready` as possible.
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            match self.state {
+                ReadState::Handshake => {
+                    let Some(end) = find(&self.pending, b"\r\n\r\n") else {
+                        return Ok(());
+                    };
+                    // Frames only follow a successful upgrade.
+                    let upgraded = self.pending.starts_with(b"HTTP/1.1 101");
+                    self.emit(end + 4);
+                    self.state = if upgraded {
+                        ReadState::Frames
+                    } else {
+                        ReadState::Transparent
+                    };
+                }
+                ReadState::Transparent => {
+                    self.emit(self.pending.len());
+                    return Ok(());
+                }
+                ReadState::PassThrough(remaining) => {
+                    let n = remaining.min(self.pending.len() as u64) as usize;
+                    self.emit(n);
+                    if remaining > n as u64 {
+                        self.state = ReadState::PassThrough(remaining - n as u64);
+                        return Ok(());
+                    }
+                    self.state = ReadState::Frames;
+                }
+                ReadState::Frames => {
+                    let Some(header) = FrameHeader::parse(&self.pending) else {
+                        return Ok(());
+                    };
+                    let compressed = match header.opcode {
+                        // A text or binary frame starts a message.
+                        0x1 | 0x2 => header.rsv1,
+                        // Continuation frames belong to the current message.
+                        0x0 => self.compressed_message,
+                        _ => false,
+                    };
+                    if !compressed {
+                        if header.opcode < 0x8 {
+                            self.compressed_message = false;
+                        }
+                        self.emit(header.len);
+                        self.state = ReadState::PassThrough(header.payload_len);
+                        continue;
+                    }
+                    if header.payload_len > MAX_COMPRESSED_FRAME {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "compressed frame too large",
+                        ));
+                    }
+                    let frame_len = header.len + header.payload_len as usize;
+                    if self.pending.len() < frame_len {
+                        return Ok(());
+                    }
+                    self.compressed_message = !header.fin;
+                    let mut payload: Vec<u8> =
+                        self.pending.drain(..frame_len).skip(header.len).collect();
+                    if let Some(mask) = header.mask {
+                        for (i, byte) in payload.iter_mut().enumerate() {
+                            *byte ^= mask[i % 4];
+                        }
+                    }
+                    if header.fin {
+                        payload.extend_from_slice(&MESSAGE_TRAILER);
+                    }
+                    let inflated = self.inflate(&payload)?;
+                    // The inflated frame keeps FIN and the opcode, without RSV1 and mask.
+                    self.ready
+                        .push(if header.fin { 0x80 } else { 0x00 } | header.opcode);
+                    match inflated.len() {
+                        len @ 0..=125 => self.ready.push(len as u8),
+                        len @ 126..=0xffff => {
+                            self.ready.push(126);
+                            self.ready.extend_from_slice(&(len as u16).to_be_bytes());
+                        }
+                        len => {
+                            self.ready.push(127);
+                            self.ready.extend_from_slice(&(len as u64).to_be_bytes());
+                        }
+                    }
+                    self.ready.extend_from_slice(&inflated);
+                }
+            }
+        }
+    }
+
+    /// Moves `n` pending bytes to the ready bytes unchanged.
+    fn emit(&mut self, n: usize) {
+        self.ready.extend(self.pending.drain(..n));
+    }
+
+    fn inflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() * 4);
+        let start = self.inflater.total_in();
+        loop {
+            let consumed = (self.inflater.total_in() - start) as usize;
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(READ_SIZE));
+            }
+            let produced = output.len();
+            let status = self
+                .inflater
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let consumed_now = (self.inflater.total_in() - start) as usize;
+            if output.len() > MAX_INFLATED_FRAME {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed frame inflates beyond the size limit",
+                ));
+            }
+            let done = consumed_now == input.len() && output.len() < output.capacity();
+            let stuck = consumed_now == consumed && output.len() == produced;
+            if status == Status::StreamEnd {
+                // The sender ended the deflate stream; the next message starts a new one.
+                self.inflater.reset(false);
+                return Ok(output);
+            }
+            if done || stuck {
+                return Ok(output);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.ready_pos < this.ready.len() {
+                let n = (this.ready.len() - this.ready_pos).min(buf.remaining());
+                buf.put_slice(&this.ready[this.ready_pos..this.ready_pos + n]);
+                this.ready_pos += n;
+                if this.ready_pos == this.ready.len() {
+                    this.ready.clear();
+                    this.ready_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                // Leftovers of a truncated frame are passed on for the WebSocket library to
+                // report.
+                this.ready.append(&mut this.pending);
+                if this.ready.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            if matches!(this.state, ReadState::Transparent) && this.pending.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            let mut chunk = [0; READ_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.pending.extend_from_slice(chunk.filled());
+                this.process()?;
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The header of a WebSocket frame.
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header itself.
+    len: usize,
+    payload_len: u64,
+}
+
+impl FrameHeader {
+    /// Parses the header at the start of the bytes, if they hold all of it.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&first, &second) = (bytes.first()?, bytes.get(1)?);
+        let mut len = 2;
+        let payload_len = match second & 0x7f {
+            126 => {
+                len += 2;
+                u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?) as u64
+            }
+            127 => {
+                len += 8;
+                u64::from_be_bytes(bytes.get(2..10)?.try_into().ok()?)
+            }
+            n => n as u64,
+        };
+        let mask = if second & 0x80 != 0 {
+            let mask = bytes.get(len..len + 4)?.try_into().ok()?;
+            len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            len,
+            payload_len,
+        })
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, FlushCompress};
+    use tokio::io::AsyncReadExt;
+
+    const UPGRADED: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn compressor() -> Compress {
+        Compress::new(flate2::Compression::default(), false)
+    }
+
+    /// Compresses a message as a sender of the extension does, keeping the context.
+    fn deflate(compressor: &mut Compress, message: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(message.len() + 64);
+        compressor
+            .compress_vec(message, &mut output, FlushCompress::Sync)
+            .unwrap();
+        assert!(output.ends_with(&MESSAGE_TRAILER));
+        output.truncate(output.len() - MESSAGE_TRAILER.len());
+        output
+    }
+
+    fn frame(first: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0x00 };
+        let mut frame = vec![first];
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    async fn read(input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        DeflateStream::new(input, Compression::On)
+            .read_to_end(&mut output)
+            .await?;
+        Ok(output)
+    }
+
+    /// Splits the frames after the handshake into their first bytes and payloads.
+    fn frames(output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut rest = output.strip_prefix(UPGRADED).expect("handshake passed on");
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            let header = FrameHeader::parse(rest).expect("complete frame");
+            assert!(header.mask.is_none());
+            let end = header.len + header.payload_len as usize;
+            frames.push((rest[0], rest[header.len..end].to_vec()));
+            rest = &rest[end..];
+        }
+        frames
+    }
+
+    #[test]
+    fn frame_header_parses_payload_lengths() {
+        let header = FrameHeader::parse(&[0xc1, 5]).unwrap();
+        assert!(header.fin && header.rsv1 && header.mask.is_none());
+        assert_eq!((header.opcode, header.len, header.payload_len), (1, 2, 5));
+
+        let header = FrameHeader::parse(&[0x02, 126, 0x01, 0x00]).unwrap();
+        assert!(!header.fin && !header.rsv1);
+        assert_eq!((header.opcode, header.len, header.payload_len), (2, 4, 256));
+
+        let header = FrameHeader::parse(&[0x80, 127, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap();
+        assert_eq!((header.opcode, header.len, header.payload_len), (0, 10, 1 << 32));
+    }
+
+    #[test]
+    fn frame_header_parses_masks() {
+        let header = FrameHeader::parse(&[0x89, 0x80 | 126, 0x01, 0x00, 1, 2, 3, 4]).unwrap();
+        assert_eq!(header.mask, Some([1, 2, 3, 4]));
+        assert_eq!((header.opcode, header.len, header.payload_len), (9, 8, 256));
+    }
+
+    #[test]
+    fn frame_header_waits_for_all_of_it() {
+        for bytes in [
+            &[][..],
+            &[0x81],
+            &[0x81, 126, 0x01],
+            &[0x81, 127, 0, 0, 0, 0, 0, 0, 1],
+            &[0x81, 0x85, 1, 2, 3],
+        ] {
+            assert!(FrameHeader::parse(bytes).is_none(), "{bytes:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn inflates_compressed_frames() {
+        let mut compressor = compressor();
+        let compressed = deflate(&mut compressor, b"hello world");
+        let input = [
+            UPGRADED,
+            &frame(0xc1, &compressed, Some(MASK)),
+            &frame(0x82, b"plain", None),
+        ]
+        .concat();
+        assert_eq!(
+            frames(&read(&input).await.unwrap()),
+            [(0x81, b"hello world".to_vec()), (0x82, b"plain".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn inflates_messages_split_across_continuation_frames() {
+        let mut compressor = compressor();
+        let message = b"a message that is compressed and split into three frames".repeat(4);
+        let compressed = deflate(&mut compressor, &message);
+        let (first, rest) = compressed.split_at(compressed.len() / 3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+        let input = [
+            UPGRADED,
+            &frame(0x41, first, None),
+            &frame(0x00, second, None),
+            // A control frame may come between the frames of a message.
+            &frame(0x89, b"ping", None),
+            &frame(0x80, third, None),
+        ]
+        .concat();
+        let frames = frames(&read(&input).await.unwrap());
+        let opcodes: Vec<u8> = frames.iter().map(|(first, _)| *first).collect();
+        assert_eq!(opcodes, [0x01, 0x00, 0x89, 0x80]);
+        assert_eq!(frames[2].1, b"ping");
+        let inflated = [&frames[0].1[..], &frames[1].1, &frames[3].1].concat();
+        assert_eq!(inflated, message);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_context_across_messages() {
+        let mut compressor = compressor();
+        let message = b"the same line, over and over again";
+        let first = deflate(&mut compressor, message);
+        let second = deflate(&mut compressor, message);
+        // The second message refers back to the first instead of repeating it.
+        assert!(second.len() < first.len());
+        let input = [
+            UPGRADED,
+            &frame(0xc1, &first, None),
+            &frame(0xc1, &second, None),
+        ]
+        .concat();
+        assert_eq!(
+            frames(&read(&input).await.unwrap()),
+            [(0x81, message.to_vec()), (0x81, message.to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_inflating_beyond_the_limit() {
+        let mut compressor = compressor();
+        let compressed = deflate(&mut compressor, &vec![0; MAX_INFLATED_FRAME + 1]);
+        let input = [UPGRADED, &frame(0xc2, &compressed, None)].concat();
+        let error = read(&input).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn passes_failed_handshakes_through() {
+        let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 2\r\n\r\n";
+        let input = [&response[..], &[0xc1, 0x00]].concat();
+        assert_eq!(read(&input).await.unwrap(), input);
+    }
+}
@@ -0,0 +1,76 @@
+//! Encodings of the lines written to stdout and the output file, for consoles and legacy
+//! collectors that cannot handle UTF-8.
+//!
+//! The encoding is applied to the rendered line as the very last step, so templates, filters,
+//! scripts, and remote sinks all work on the original text.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+
+/// Character encoding of the lines written to stdout and the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputEncoding {
+    /// UTF-8, unchanged
+    #[value(name = "utf-8")]
+    Utf8,
+    /// ISO 8859-1; other characters are transliterated, e.g. curly quotes to straight ones,
+    /// or replaced by '?'
+    #[value(name = "latin-1")]
+    Latin1,
+    /// ASCII, with other characters escaped as \uXXXX (as in JSON)
+    #[value(name = "escape-non-ascii")]
+    EscapeNonAscii,
+}
+
+impl OutputEncoding {
+    /// Encodes a rendered line.
+    pub fn encode(self, line: &str) -> Cow<'_, [u8]> {
+        if self == Self::Utf8 || line.is_ascii() {
+            return Cow::Borrowed(line.as_bytes());
+        }
+        let mut encoded = Vec::with_capacity(line.len());
+        for c in line.chars() {
+            match self {
+                Self::Utf8 => unreachable!("UTF-8 lines are returned unchanged"),
+                Self::Latin1 => match u8::try_from(c as u32) {
+                    Ok(byte) => encoded.push(byte),
+                    Err(_) => encoded.extend_from_slice(transliterate(c).as_bytes()),
+                },
+                Self::EscapeNonAscii if c.is_ascii() => encoded.push(c as u8),
+                Self::EscapeNonAscii => {
+                    let mut units = [0; 2];
+                    for unit in c.encode_utf16(&mut units) {
+                        encoded.extend_from_slice(format!("\\u{unit:04x}").as_bytes());
+                    }
+                }
+            }
+        }
+        Cow::Owned(encoded)
+    }
+}
+
+/// Returns the ASCII replacement of a character outside ISO 8859-1.
+fn transliterate(c: char) -> &'static str {
+    match c {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' | '\u{2032}' => "'",
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' | '\u{2033}' => "\"",
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => "-",
+        '\u{2026}' => "...",
+        '\u{2022}' | '\u{2023}' | '\u{2043}' => "*",
+        '\u{2000}'..='\u{200a}' | '\u{202f}' | '\u{205f}' | '\u{3000}' => " ",
+        '\u{200b}'..='\u{200d}' | '\u{2060}' | '\u{feff}' => "",
+        '\u{2039}' => "<",
+        '\u{203a}' => ">",
+        '\u{2190}' => "<-",
+        '\u{2192}' => "->",
+        '\u{2194}' => "<->",
+        '\u{21d2}' => "=>",
+        '\u{2264}' => "<=",
+        '\u{2265}' => ">=",
+        '\u{2260}' => "!=",
+        '\u{2248}' => "~",
+        '\u{20ac}' => "EUR",
+        '\u{2122}' => "(TM)",
+        _ => "?",
+    }
+}
@@ -0,0 +1,244 @@
+//! Construction of the URLs of the log streams of the boundary nodes.
+//!
+//! The path of the log stream of a canister follows a template, `/logs/canister/{canister_id}`
+//! by default, so that a changed boundary node API, e.g. a versioned path or fixed query
+//! parameters, can be followed with `--endpoint-path-template` instead of a new release. Every
+//! connection attempt builds its URL afresh from the template, and parameters are set rather
+//! than appended, so that retries and resumptions never accumulate duplicate parameters.
+//!
+//! Boundary nodes or reverse proxies in front of them that require authentication get the
+//! `--header` and `--bearer-token` headers with every WebSocket upgrade request.
+
+use crate::codec;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use url::Url;
+
+/// Path of the log stream of a canister in the current boundary node API.
+pub const DEFAULT_PATH_TEMPLATE: &str = "/logs/canister/{canister_id}";
+
+/// Placeholder of the canister ID in path templates.
+const CANISTER_ID: &str = "{canister_id}";
+
+/// Canister ID with which templates are checked when they are parsed.
+const SAMPLE_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// Template of the path, and optionally the query, of the log stream of a canister.
+#[derive(Clone, Debug)]
+pub struct PathTemplate(String);
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self(DEFAULT_PATH_TEMPLATE.to_string())
+    }
+}
+
+impl PathTemplate {
+    /// Parses a template, which must start with a single `/` and contain `{canister_id}`.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if !template.starts_with('/') {
+            return Err(format!("the path template {template} must start with /"));
+        }
+        // Resolved as a relative URL, as proxies and logs may do, `//` would start a host.
+        if template.starts_with("//") {
+            return Err(format!(
+                "the path template {template} must start with a single /"
+            ));
+        }
+        if !template.contains(CANISTER_ID) {
+            return Err(format!(
+                "the path template {template} must contain the placeholder {CANISTER_ID}"
+            ));
+        }
+        if template.replace(CANISTER_ID, "").contains(['{', '}']) {
+            return Err(format!(
+                "the path template {template} may only contain the placeholder {CANISTER_ID}"
+            ));
+        }
+        if template.contains('#') {
+            return Err(format!(
+                "the path template {template} must not contain a fragment"
+            ));
+        }
+        let template = Self(template.to_string());
+        let url = template.url("node.example", SAMPLE_CANISTER_ID)?;
+        if url.scheme() != "wss" || url.host_str() != Some("node.example") {
+            return Err(format!(
+                "the path template {} changes the scheme or host of the URL",
+                template.0
+            ));
+        }
+        Ok(template)
+    }
+
+    /// Returns the URL of the log stream of a canister on a node, with the query parameters of
+    /// the template.
+    pub fn url(&self, domain: &str, canister_id: &str) -> Result<Url, String> {
+        let path = self.0.replace(CANISTER_ID, canister_id);
+        let url = format!("wss://{domain}{path}");
+        Url::parse(&url).map_err(|e| format!("invalid log stream URL {url}: {e}"))
+    }
+}
+
+/// Headers that the WebSocket handshake sets itself, which cannot be given with `--header`.
+const RESERVED_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    codec::ACCEPT_HEADER,
+];
+
+/// Parses a header of the upgrade requests given as `Name: value`.
+pub fn parse_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = value.split_once(':').ok_or("expected 'Name: value'")?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name.trim()))?;
+    if RESERVED_HEADERS.contains(&name.as_str()) {
+        return Err(format!(
+            "the {name} header is set by the WebSocket handshake and cannot be replaced"
+        ));
+    }
+    let mut value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value of header {name}"))?;
+    // Keeps the value out of the debug output of the request, as it may be a credential.
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
+/// Returns the headers of the upgrade requests: the `--header` headers, and the
+/// `Authorization` header of a bearer token.
+pub fn handshake_headers(
+    headers: &[(HeaderName, HeaderValue)],
+    bearer_token: Option<&str>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let mut headers = headers.to_vec();
+    if let Some(token) = bearer_token {
+        if headers.iter().any(|(name, _)| *name == AUTHORIZATION) {
+            return Err("--bearer-token conflicts with an Authorization --header".to_string());
+        }
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+            .map_err(|_| "the bearer token is not a valid header value".to_string())?;
+        value.set_sensitive(true);
+        headers.push((AUTHORIZATION, value));
+    }
+    Ok(headers)
+}
+
+/// Sets a query parameter of a URL, replacing any values the parameter had.
+pub fn set_query_param(url: &mut Url, name: &str, value: &str) {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != name)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANISTER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        for (template, reason) in [
+            ("logs/canister/{canister_id}", "must start with /"),
+            ("wss://evil.example/{canister_id}", "must start with /"),
+            ("//evil.example/{canister_id}", "single /"),
+            ("/logs/canister", "must contain the placeholder"),
+            (
+                "/logs/{node}/{canister_id}",
+                "may only contain the placeholder",
+            ),
+            ("/logs/{canister_id}/{", "may only contain the placeholder"),
+            ("/logs/{canister_id}#tail", "must not contain a fragment"),
+        ] {
+            let error = PathTemplate::parse(template).unwrap_err();
+            assert!(error.contains(reason), "{template}: {error}");
+        }
+    }
+
+    #[test]
+    fn parse_keeps_the_scheme_and_host() {
+        for template in [
+            "/\\evil.example/{canister_id}",
+            "/@evil.example/{canister_id}",
+            "/{canister_id}@evil.example:443",
+            "/redirect?to=https://evil.example/{canister_id}",
+        ] {
+            if let Ok(parsed) = PathTemplate::parse(template) {
+                let url = parsed.url("node.example", CANISTER).unwrap();
+                assert_eq!(url.scheme(), "wss", "{template}");
+                assert_eq!(url.host_str(), Some("node.example"), "{template}");
+            }
+        }
+    }
+
+    #[test]
+    fn default_template_builds_the_stream_url() {
+        let url = PathTemplate::default()
+            .url("node.example", CANISTER)
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "wss://node.example/logs/canister/ryjl3-tyaaa-aaaaa-aaaba-cai"
+        );
+    }
+
+    #[test]
+    fn url_keeps_the_query_of_the_template() {
+        let template =
+            PathTemplate::parse("/v2/logs/{canister_id}?format=json&label=a%20b").unwrap();
+        let url = template.url("node.example", CANISTER).unwrap();
+        assert_eq!(url.path(), "/v2/logs/ryjl3-tyaaa-aaaaa-aaaba-cai");
+        assert_eq!(url.query(), Some("format=json&label=a%20b"));
+        let pairs: Vec<_> = url.query_pairs().collect();
+        assert_eq!(pairs[1].1, "a b");
+
+        let template = PathTemplate::parse("/logs?canister={canister_id}").unwrap();
+        let url = template.url("node.example", CANISTER).unwrap();
+        assert_eq!(
+            url.query_pairs().next().unwrap().1,
+            "ryjl3-tyaaa-aaaaa-aaaba-cai"
+        );
+    }
+
+    #[test]
+    fn url_percent_encodes_the_path() {
+        let template = PathTemplate::parse("/logs/my logs/{canister_id}").unwrap();
+        let url = template.url("node.example", CANISTER).unwrap();
+        assert_eq!(url.path(), "/logs/my%20logs/ryjl3-tyaaa-aaaaa-aaaba-cai");
+    }
+
+    #[test]
+    fn set_query_param_replaces_the_parameter() {
+        let template = PathTemplate::parse("/logs/{canister_id}?format=json").unwrap();
+        let mut url = template.url("node.example", CANISTER).unwrap();
+        set_query_param(&mut url, "from", "10");
+        // A retry sets the parameter again.
+        set_query_param(&mut url, "from", "25");
+        assert_eq!(url.query(), Some("format=json&from=25"));
+    }
+
+    #[test]
+    fn set_query_param_encodes_the_value() {
+        let mut url = PathTemplate::default()
+            .url("node.example", CANISTER)
+            .unwrap();
+        set_query_param(&mut url, "since", "2024-06-01T13:00:00+02:00 & more");
+        assert_eq!(
+            url.query(),
+            Some("since=2024-06-01T13%3A00%3A00%2B02%3A00+%26+more")
+        );
+        let pairs: Vec<_> = url.query_pairs().collect();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].1, "2024-06-01T13:00:00+02:00 & more");
+    }
+}
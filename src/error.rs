@@ -0,0 +1,90 @@
+//! The errors that end the client, and their exit codes.
+//!
+//! The exit codes of errors follow the BSD sysexits convention, so that scripts can tell an
+//! invalid command line from an unreachable network or a failing disk without parsing the
+//! message. They do not overlap with the codes of the exit conditions.
+
+use ic_agent::AgentError;
+use std::io;
+use std::process::ExitCode;
+use thiserror::Error;
+
+/// An error that ends the client.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The options or the config file are invalid or contradict each other.
+    #[error("{0}")]
+    Config(String),
+    /// The canisters of a subnet could not be enumerated.
+    #[error("failed to enumerate the canisters of the subnet: {0}")]
+    Subnet(String),
+    /// The API boundary nodes could not be listed.
+    #[error("failed to discover the API boundary nodes: {0}")]
+    Discovery(#[from] AgentError),
+    /// The HTTP client could not be created, e.g. because of the proxy.
+    #[error("failed to create the HTTP client: {0}")]
+    HttpClient(#[from] reqwest::Error),
+    /// The shared dedup state is unavailable.
+    #[error("failed to connect to Redis: {0}")]
+    Redis(#[from] redis::RedisError),
+    /// A file, pipe, or socket could not be opened or written.
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A capture could not be replayed.
+    #[error("failed to replay the capture: {0}")]
+    Replay(String),
+    /// A task ended unexpectedly.
+    #[error("a task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    /// The client could not be updated from its releases.
+    #[error("failed to update: {0}")]
+    Update(String),
+    /// A report could not be serialized.
+    #[error("failed to serialize the report: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Returns a function that wraps an I/O error, stating what failed.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| Self::Io { context, source }
+    }
+
+    /// Returns the exit code of the process.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.status())
+    }
+
+    /// Returns the exit code as a number, e.g. to pass it on from another process.
+    pub fn status(&self) -> u8 {
+        match self {
+            // EX_USAGE
+            Self::Config(_) => 64,
+            // EX_DATAERR
+            Self::Replay(_) => 65,
+            // EX_UNAVAILABLE
+            Self::Discovery(_) | Self::Subnet(_) | Self::Redis(_) | Self::Update(_) => 69,
+            // EX_SOFTWARE
+            Self::HttpClient(_) | Self::Task(_) | Self::Json(_) => 70,
+            // EX_IOERR
+            Self::Io { .. } => 74,
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::Config(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::Config(message.to_string())
+    }
+}
@@ -0,0 +1,117 @@
+//! The log event passed from the connections to the outputs.
+
+use crate::clock;
+use crate::taint::Taint;
+use chrono::{DateTime, SecondsFormat};
+use chrono_tz::Tz;
+use serde_json::{json, Map, Value};
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// A single log line received from a boundary node.
+#[derive(Clone, Debug)]
+pub struct LogEvent {
+    /// Wall-clock time at which the line was received.
+    pub timestamp: SystemTime,
+    /// Monotonic time since the start of the capture at which the line was received.
+    ///
+    /// Unlike the wall-clock time, this is unaffected by clock adjustments (e.g. NTP steps)
+    /// during long captures.
+    pub monotonic_offset: Duration,
+    /// Domain of the boundary node that delivered the line.
+    pub node: String,
+    /// The canister that logged the line.
+    pub canister_id: String,
+    /// The log line with ANSI escape sequences stripped.
+    pub message: String,
+    /// Whether the line was replayed from history rather than received live.
+    pub backfill: bool,
+    /// Whether the line was missed while disconnected and replayed when the stream was
+    /// resumed.
+    pub resumed: bool,
+    /// Whether the line was not confirmed by enough nodes in confirmation mode.
+    pub suspect: bool,
+    /// Reasons the line looks like an injection attempt, if detection is enabled.
+    pub taint: Vec<Taint>,
+    /// Whether the line is the block of a canister trap or panic, grouped with `--group-panics`.
+    pub panic: bool,
+    /// Fields of a structured (CBOR or Candid) log record.
+    pub fields: Option<Map<String, Value>>,
+}
+
+impl LogEvent {
+    /// Creates an event for a line received now, taking both clock readings together.
+    pub fn received(node: &str, canister_id: &str, message: String, backfill: bool) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            monotonic_offset: clock::capture_offset(),
+            node: node.to_string(),
+            canister_id: canister_id.to_string(),
+            message,
+            backfill,
+            resumed: false,
+            suspect: false,
+            taint: Vec::new(),
+            panic: false,
+            fields: None,
+        }
+    }
+
+    /// Attaches the fields of a structured log record.
+    pub fn with_fields(mut self, fields: Option<Map<String, Value>>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Marks the line as replayed when resuming the stream.
+    pub fn with_resumed(mut self, resumed: bool) -> Self {
+        self.resumed = resumed;
+        self
+    }
+
+    /// Marks the line as the grouped block of a trap or panic.
+    pub fn with_panic(mut self, panic: bool) -> Self {
+        self.panic = panic;
+        self
+    }
+
+    /// Returns the wall-clock receive time in the configured timezone.
+    pub fn local_time(&self) -> DateTime<Tz> {
+        clock::local_time(self.timestamp)
+    }
+
+    /// Returns the wall-clock receive time as an RFC 3339 timestamp in the configured timezone.
+    pub fn timestamp_rfc3339(&self) -> String {
+        self.local_time()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    /// Returns the names of the reasons the line looks like an injection attempt.
+    pub fn taint_names(&self) -> Vec<&'static str> {
+        self.taint.iter().map(|taint| taint.as_str()).collect()
+    }
+
+    /// Returns the structured representation used by JSON outputs.
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "timestamp": self.timestamp_rfc3339(),
+            "monotonic_offset_us": self.monotonic_offset.as_micros() as u64,
+            "node": self.node,
+            "canister_id": self.canister_id,
+            "message": self.message,
+            "backfill": self.backfill,
+            "resumed": self.resumed,
+            "suspect": self.suspect,
+        });
+        if !self.taint.is_empty() {
+            value["taint"] = self.taint_names().into();
+        }
+        if self.panic {
+            value["panic"] = true.into();
+        }
+        if let Some(fields) = &self.fields {
+            value["fields"] = Value::Object(fields.clone());
+        }
+        value
+    }
+}
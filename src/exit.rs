@@ -0,0 +1,130 @@
+//! Conditions that end the client on their own, for scripts and CI jobs, e.g. waiting until a
+//! canister logs that its upgrade completed, with a timeout.
+//!
+//! The first condition met decides the exit code. Lines delivered after that are dropped, so
+//! that exactly the lines up to the match or the line limit are written.
+
+use regex::Regex;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+
+/// Why the client exits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// A line matched `--exit-on-match`.
+    Matched,
+    /// `--exit-after` elapsed.
+    TimedOut,
+    /// `--exit-after-lines` lines were written.
+    LineLimit,
+    /// The streams ended, or a signal stopped the client, before any condition was met.
+    Ended,
+}
+
+impl ExitReason {
+    /// Returns the exit code of the process.
+    pub fn code(self) -> ExitCode {
+        match self {
+            Self::Matched => ExitCode::SUCCESS,
+            Self::TimedOut => ExitCode::from(2),
+            Self::LineLimit => ExitCode::from(3),
+            Self::Ended => ExitCode::from(4),
+        }
+    }
+
+    /// Returns a description for the log.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Matched => "a line matched --exit-on-match",
+            Self::TimedOut => "--exit-after elapsed",
+            Self::LineLimit => "--exit-after-lines lines were written",
+            Self::Ended => "the client stopped before any exit condition was met",
+        }
+    }
+}
+
+/// The exit conditions, checked for every line that passes the filters.
+pub struct ExitPolicy {
+    on_match: Option<Regex>,
+    max_lines: Option<u64>,
+    after: Option<Duration>,
+    /// Lines admitted so far.
+    lines: Mutex<u64>,
+    reason: watch::Sender<Option<ExitReason>>,
+}
+
+impl ExitPolicy {
+    /// Creates a policy that ends the client on the first line matching `on_match`, after
+    /// `max_lines` lines, or `after` the client started waiting, whichever comes first.
+    pub fn new(on_match: Option<Regex>, max_lines: Option<u64>, after: Option<Duration>) -> Self {
+        Self {
+            on_match,
+            max_lines,
+            after,
+            lines: Mutex::new(0),
+            reason: watch::Sender::new(None),
+        }
+    }
+
+    /// Records a line about to be written, returning whether it may still be written.
+    pub fn admit(&self, message: &str) -> bool {
+        // The lock keeps lines of concurrent connections from overshooting the line limit.
+        let mut lines = self.lines.lock().unwrap();
+        if self.reason.borrow().is_some() {
+            return false;
+        }
+        *lines += 1;
+        if self
+            .on_match
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(message))
+        {
+            self.trigger(ExitReason::Matched);
+        } else if self.max_lines.is_some_and(|max_lines| *lines >= max_lines) {
+            self.trigger(ExitReason::LineLimit);
+        }
+        true
+    }
+
+    /// Sets the reason to exit, unless one is already set.
+    fn trigger(&self, reason: ExitReason) {
+        self.reason.send_if_modified(|current| {
+            let unset = current.is_none();
+            if unset {
+                *current = Some(reason);
+            }
+            unset
+        });
+    }
+
+    /// Waits until a condition is met, starting the `--exit-after` timer.
+    pub async fn wait(&self) -> ExitReason {
+        let mut receiver = self.reason.subscribe();
+        let timeout = async {
+            match self.after {
+                Some(after) => sleep(after).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            Ok(reason) = receiver.wait_for(Option::is_some) => reason.unwrap(),
+            () = timeout => self.settle(ExitReason::TimedOut),
+        }
+    }
+
+    /// Stops admitting lines and returns the reason to exit, which is [`ExitReason::Ended`] if
+    /// no condition was met.
+    pub fn finish(&self) -> ExitReason {
+        self.settle(ExitReason::Ended)
+    }
+
+    /// Sets the reason to exit unless one is already set, and returns the reason.
+    fn settle(&self, reason: ExitReason) -> ExitReason {
+        // Holding the lock while setting the reason admits no line after it.
+        let _lines = self.lines.lock().unwrap();
+        self.trigger(reason);
+        self.reason.borrow().unwrap()
+    }
+}
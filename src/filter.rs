@@ -0,0 +1,156 @@
+//! Include and exclude filters on log lines, changeable at runtime.
+//!
+//! A line passes if it matches at least one include filter (or there are none), matches
+//! no exclude filter, and satisfies the `--where` expression, if there is one.
+
+use crate::event::LogEvent;
+use crate::query::Query;
+use regex::Regex;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Whether a filter selects or removes matching lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    Include,
+    Exclude,
+}
+
+impl fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Include => write!(f, "include"),
+            Self::Exclude => write!(f, "exclude"),
+        }
+    }
+}
+
+/// What the filters decide about a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    /// The line matches none of the include filters, or does not satisfy the expression.
+    NotIncluded,
+    /// The line matches an exclude filter.
+    Excluded,
+}
+
+/// A single filter.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub pattern: Regex,
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.pattern)
+    }
+}
+
+/// The active filters, shared by all connections.
+#[derive(Default)]
+pub struct FilterSet {
+    filters: RwLock<Vec<Filter>>,
+    /// The `--where` expression.
+    condition: RwLock<Option<Query>>,
+    rejected: AtomicU64,
+}
+
+impl FilterSet {
+    pub fn new(includes: Vec<Regex>, excludes: Vec<Regex>, condition: Option<Query>) -> Self {
+        let filters = includes
+            .into_iter()
+            .map(|pattern| Filter {
+                kind: FilterKind::Include,
+                pattern,
+            })
+            .chain(excludes.into_iter().map(|pattern| Filter {
+                kind: FilterKind::Exclude,
+                pattern,
+            }))
+            .collect();
+        Self {
+            filters: RwLock::new(filters),
+            condition: RwLock::new(condition),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether the line passes the filters, and which filters reject it.
+    pub fn check(&self, event: &LogEvent) -> Verdict {
+        let line = event.message.as_str();
+        let filters = self.filters.read().unwrap();
+        let mut has_include = false;
+        let mut included = false;
+        for filter in filters.iter() {
+            match filter.kind {
+                FilterKind::Exclude if filter.pattern.is_match(line) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Verdict::Excluded;
+                }
+                FilterKind::Exclude => {}
+                FilterKind::Include => {
+                    has_include = true;
+                    included = included || filter.pattern.is_match(line);
+                }
+            }
+        }
+        let satisfied = || {
+            self.condition
+                .read()
+                .unwrap()
+                .as_ref()
+                .is_none_or(|condition| condition.matches(event))
+        };
+        if has_include && !included || !satisfied() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Verdict::NotIncluded;
+        }
+        Verdict::Accepted
+    }
+
+    /// Adds a filter.
+    pub fn add(&self, filter: Filter) {
+        self.filters.write().unwrap().push(filter);
+    }
+
+    /// Removes the filter at the given position, as shown by [`FilterSet::list`].
+    pub fn remove(&self, index: usize) -> Option<Filter> {
+        let mut filters = self.filters.write().unwrap();
+        (index < filters.len()).then(|| filters.remove(index))
+    }
+
+    /// Replaces all filters, e.g. when the configuration is reloaded.
+    pub fn replace(&self, includes: Vec<Regex>, excludes: Vec<Regex>) {
+        let replacement = Self::new(includes, excludes, None);
+        *self.filters.write().unwrap() = replacement.filters.into_inner().unwrap();
+    }
+
+    /// Removes all filters and the expression.
+    pub fn clear(&self) {
+        self.filters.write().unwrap().clear();
+        *self.condition.write().unwrap() = None;
+    }
+
+    /// Replaces the expression, or removes it with None.
+    pub fn set_condition(&self, condition: Option<Query>) {
+        *self.condition.write().unwrap() = condition;
+    }
+
+    /// Returns the expression.
+    pub fn condition(&self) -> Option<Query> {
+        self.condition.read().unwrap().clone()
+    }
+
+    /// Returns the active filters.
+    pub fn list(&self) -> Vec<Filter> {
+        self.filters.read().unwrap().clone()
+    }
+
+    /// Returns the number of lines rejected by the filters.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
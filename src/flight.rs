@@ -0,0 +1,185 @@
+//! Recording of the frames around anomalies, with `--anomaly-record-dir`.
+//!
+//! A capture of the whole session is rarely at hand when an anomaly is reported, and the lines
+//! written to the outputs may be sampled or filtered. The flight recorder keeps the frames of
+//! all nodes received within the last `--anomaly-record-before` in memory, as received before
+//! decoding, sampling, and filters. When the anomaly detection reports a spike or a drop, the
+//! frames in memory are written to a new capture file in the directory, followed by the frames
+//! of the next `--anomaly-record-after`; a further anomaly meanwhile extends the recording. The
+//! captures can be fed through the pipeline again with the `replay` subcommand.
+
+use crate::capture::CaptureWriter;
+use crate::connection::ConnectionConfig;
+use chrono::Utc;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{interval, Duration, Instant};
+
+/// Most bytes of frames kept in memory; older frames are dropped beyond it.
+const MAX_RING_BYTES: usize = 64 * 1024 * 1024;
+/// Interval at which a recording is checked for its end, also without frames.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A frame kept in memory.
+struct Frame {
+    received_at: SystemTime,
+    node: String,
+    canister_id: String,
+    codec: &'static str,
+    frame: Vec<u8>,
+}
+
+/// A capture being written after an anomaly.
+struct Recording {
+    writer: CaptureWriter,
+    path: PathBuf,
+    until: Instant,
+}
+
+/// The frames in memory and the recording in progress.
+#[derive(Default)]
+struct Inner {
+    ring: VecDeque<Frame>,
+    ring_bytes: usize,
+    recording: Option<Recording>,
+}
+
+/// Keeps the recent frames in memory and records them when an anomaly is reported.
+pub struct FlightRecorder {
+    dir: PathBuf,
+    before: Duration,
+    after: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl FlightRecorder {
+    /// Creates a recorder writing to the directory, keeping the frames of the `before` time
+    /// and recording those of the `after` time following an anomaly.
+    pub fn new(dir: PathBuf, before: Duration, after: Duration) -> Self {
+        Self {
+            dir,
+            before,
+            after,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Adds a frame received from a node, to the recording in progress or to memory.
+    pub fn record(&self, node: &str, canister_id: &str, codec: &'static str, frame: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(recording) = &inner.recording {
+            recording.writer.write(node, canister_id, codec, frame);
+            return;
+        }
+        let received_at = SystemTime::now();
+        inner.ring_bytes += frame.len();
+        inner.ring.push_back(Frame {
+            received_at,
+            node: node.to_string(),
+            canister_id: canister_id.to_string(),
+            codec,
+            frame: frame.to_vec(),
+        });
+        while let Some(oldest) = inner.ring.front() {
+            let expired = received_at
+                .duration_since(oldest.received_at)
+                .is_ok_and(|age| age > self.before);
+            if !expired && inner.ring_bytes <= MAX_RING_BYTES {
+                break;
+            }
+            inner.ring_bytes -= oldest.frame.len();
+            inner.ring.pop_front();
+        }
+    }
+
+    /// Starts recording after an anomaly of a canister, writing the frames in memory first, or
+    /// extends the recording in progress.
+    pub fn trigger(&self, canister_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let until = Instant::now() + self.after;
+        if let Some(recording) = &mut inner.recording {
+            recording.until = until;
+            info!(
+                "Extended the recording {} to {} after the anomaly of canister {canister_id}.",
+                recording.path.display(),
+                humantime::format_duration(self.after)
+            );
+            return;
+        }
+        let name = format!(
+            "anomaly-{}-{canister_id}.icblog",
+            Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        let path = self.dir.join(name);
+        let writer = match fs::create_dir_all(&self.dir).and_then(|()| CaptureWriter::create(&path))
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!(
+                    "Failed to create {} to record the anomaly of canister {canister_id}: {e}",
+                    path.display()
+                );
+                return;
+            }
+        };
+        warn!(
+            "Recording the frames around the anomaly of canister {canister_id} to {}.",
+            path.display()
+        );
+        for frame in inner.ring.drain(..) {
+            writer.write_received(
+                frame.received_at,
+                &frame.node,
+                &frame.canister_id,
+                frame.codec,
+                &frame.frame,
+            );
+        }
+        inner.ring_bytes = 0;
+        inner.recording = Some(Recording {
+            writer,
+            path,
+            until,
+        });
+    }
+
+    /// Finishes the recording in progress if its time is up, or in any case with `force`,
+    /// e.g. when the client exits.
+    pub fn finish(&self, force: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner
+            .recording
+            .as_ref()
+            .is_some_and(|recording| force || recording.until <= Instant::now())
+        {
+            return;
+        }
+        if let Some(recording) = inner.recording.take() {
+            recording.writer.finish();
+            info!(
+                "Finished the anomaly recording {}.",
+                recording.path.display()
+            );
+        }
+    }
+}
+
+/// Starts a task that finishes the recordings once their time is up.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    if config.flight_recorder.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Some(recorder) = &config.flight_recorder {
+                recorder.finish(false);
+            }
+        }
+    });
+}
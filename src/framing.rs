@@ -0,0 +1,115 @@
+//! Line framing of plain text records, with `--line-framing`.
+//!
+//! Nodes frame log lines differently: some send several lines in one frame, some split long
+//! lines across frames, and some end lines with CRLF. With a framing other than `frame`, the
+//! text records of a connection are split at newlines into one record per line, with CRLF
+//! normalized, trailing whitespace trimmed, and blank lines dropped. With `stream`, the frames
+//! are pieces of a stream of lines, so text after the last newline of a frame is held back
+//! until the next frame continues it, or the connection stays quiet for a moment. Structured
+//! records pass unchanged.
+
+use crate::decode::DecodedRecord;
+use clap::ValueEnum;
+use tokio::time::{Duration, Instant};
+
+/// Time after the last frame without further frames after which a partial line is complete.
+const QUIET_TIME: Duration = Duration::from_secs(1);
+/// Longest partial line held back, in bytes; longer ones are passed on as they are.
+const MAX_PARTIAL_BYTES: usize = 64 * 1024;
+
+/// How the text of the frames is divided into log lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LineFraming {
+    /// Every frame is one record, as the node sent it
+    #[default]
+    Frame,
+    /// Every line of a frame is a record; a frame ends its last line
+    Split,
+    /// Frames are pieces of a stream of lines, which end at newlines
+    Stream,
+}
+
+/// A line whose end has not arrived yet.
+struct Partial {
+    text: String,
+    resumed: bool,
+    last: Instant,
+}
+
+/// Splits the text records of a single connection into one record per line.
+pub struct LineSplitter {
+    framing: LineFraming,
+    partial: Option<Partial>,
+}
+
+impl LineSplitter {
+    /// Creates a splitter, or returns `None` if every frame is one record.
+    pub fn new(framing: LineFraming) -> Option<Self> {
+        (framing != LineFraming::Frame).then_some(Self {
+            framing,
+            partial: None,
+        })
+    }
+
+    /// Processes a record, and whether it was replayed when resuming the stream, returning the
+    /// complete lines, each with whether it was resumed.
+    pub fn push(&mut self, record: DecodedRecord, resumed: bool) -> Vec<(DecodedRecord, bool)> {
+        if record.fields.is_some() {
+            let mut records: Vec<_> = self.finish().into_iter().collect();
+            records.push((record, resumed));
+            return records;
+        }
+        // A line continued from an earlier frame counts as resumed if its start was.
+        let (mut text, line_resumed) = match self.partial.take() {
+            Some(partial) => (partial.text + record.message.as_str(), partial.resumed),
+            None => (record.message, resumed),
+        };
+        text = text.replace("\r\n", "\n");
+        if self.framing == LineFraming::Stream && !text.ends_with('\n') {
+            let start = text.rfind('\n').map_or(0, |newline| newline + 1);
+            if text.len() - start <= MAX_PARTIAL_BYTES {
+                let rest = text.split_off(start);
+                self.partial = Some(Partial {
+                    text: rest,
+                    resumed: if start == 0 { line_resumed } else { resumed },
+                    last: Instant::now(),
+                });
+            }
+        }
+        let mut records = Vec::new();
+        for (index, line) in text.split('\n').enumerate() {
+            if let Some(record) = line_record(line) {
+                records.push((record, if index == 0 { line_resumed } else { resumed }));
+            }
+        }
+        records
+    }
+
+    /// Returns the held back partial line if no frame continued it for the quiet time.
+    pub fn expire(&mut self) -> Option<(DecodedRecord, bool)> {
+        if self
+            .partial
+            .as_ref()
+            .is_some_and(|partial| partial.last.elapsed() >= QUIET_TIME)
+        {
+            return self.finish();
+        }
+        None
+    }
+
+    /// Returns the held back partial line, e.g. when the connection ends.
+    pub fn finish(&mut self) -> Option<(DecodedRecord, bool)> {
+        let partial = self.partial.take()?;
+        line_record(&partial.text).map(|record| (record, partial.resumed))
+    }
+}
+
+/// Turns a line into a record with its trailing whitespace trimmed, or returns `None` if the
+/// line is blank.
+fn line_record(line: &str) -> Option<DecodedRecord> {
+    let line = line.trim_end();
+    (!line.is_empty()).then(|| DecodedRecord {
+        message: line.to_string(),
+        fields: None,
+    })
+}
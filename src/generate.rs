@@ -0,0 +1,75 @@
+//! Generation of shell completions and man pages from the command line definition.
+//!
+//! Both are printed to stdout, or written to a directory for packaging, in which case the
+//! completions are written for every shell and the man pages for every subcommand as well.
+
+use clap::{Args, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What to generate.
+#[derive(Subcommand)]
+pub enum Target {
+    /// Print a completion script for the given shell
+    Completions(CompletionsArgs),
+    /// Print the man page in roff format
+    Man {
+        /// Write the pages of the command and of every subcommand to files in this directory
+        /// instead of stdout
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+/// The shell completions to generate, also accepted by the top-level completions subcommand.
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// The shell to generate completions for; all shells with --out-dir
+    #[arg(required_unless_present = "out_dir")]
+    shell: Option<Shell>,
+    /// Write the scripts to files in this directory instead of stdout
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+}
+
+impl Target {
+    /// Returns the directory the files are written to, if not stdout.
+    pub fn out_dir(&self) -> Option<&Path> {
+        match self {
+            Self::Completions(CompletionsArgs { out_dir, .. }) | Self::Man { out_dir } => {
+                out_dir.as_deref()
+            }
+        }
+    }
+}
+
+/// Writes the requested artifact for the command to stdout, or to files in the directory.
+pub fn run(target: &Target, mut command: clap::Command) -> io::Result<()> {
+    let name = command.get_name().to_string();
+    match target {
+        Target::Completions(CompletionsArgs {
+            shell,
+            out_dir: None,
+        }) => {
+            let shell = shell.expect("a shell is required without --out-dir");
+            clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+            Ok(())
+        }
+        Target::Completions(CompletionsArgs {
+            shell,
+            out_dir: Some(dir),
+        }) => {
+            let shells = match shell {
+                Some(shell) => std::slice::from_ref(shell),
+                None => Shell::value_variants(),
+            };
+            for shell in shells {
+                clap_complete::generate_to(*shell, &mut command, &name, dir)?;
+            }
+            Ok(())
+        }
+        Target::Man { out_dir: None } => clap_mangen::Man::new(command).render(&mut io::stdout()),
+        Target::Man { out_dir: Some(dir) } => clap_mangen::generate_to(command, dir),
+    }
+}
@@ -0,0 +1,157 @@
+//! Connection quality scoring for API boundary nodes.
+//!
+//! Every connection reports its ping round-trip times, the lag with which it delivers log
+//! lines compared to the fastest node, and stall incidents (pings left unanswered until the
+//! next ping was due). The metrics are combined into a single score; lower is better. Nodes
+//! that tell their time in pongs also report the offset of their clock, which does not count
+//! for the score.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Weight of a new sample in the exponentially weighted moving averages.
+const EWMA_WEIGHT: f64 = 0.2;
+/// Score penalty of a single stall incident, in milliseconds.
+const STALL_PENALTY_MS: f64 = 5_000.0;
+/// How long the first arrival of a line is remembered to compute the lag of other nodes.
+const LINE_MEMORY: Duration = Duration::from_secs(30);
+
+/// Quality metrics of a single node.
+#[derive(Clone, Debug, Default)]
+pub struct NodeHealth {
+    /// Smoothed ping round-trip time in milliseconds.
+    pub rtt_ms: Option<f64>,
+    /// Smoothed delay in milliseconds between the first arrival of a line from any node and
+    /// its arrival from this node.
+    pub lag_ms: Option<f64>,
+    /// Smoothed estimate in milliseconds of how far the clock of the node is ahead of the
+    /// local clock, if the node tells its time in pongs.
+    pub clock_offset_ms: Option<f64>,
+    /// Stall incidents, decayed over time.
+    pub stalls: f64,
+    /// Whether the node is currently connected.
+    pub connected: bool,
+    /// Number of log lines received from the node.
+    pub lines: u64,
+    /// When the node was connected, if it is.
+    pub connected_since: Option<Instant>,
+    /// When the node last delivered a log line.
+    pub last_line: Option<Instant>,
+}
+
+impl NodeHealth {
+    /// Combines the metrics into a single score; lower is better.
+    pub fn score(&self) -> f64 {
+        self.rtt_ms.unwrap_or(0.0) + self.lag_ms.unwrap_or(0.0) + self.stalls * STALL_PENALTY_MS
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    nodes: HashMap<String, NodeHealth>,
+    first_arrivals: HashMap<u64, Instant>,
+}
+
+/// Health metrics of all nodes, shared by the connection tasks.
+#[derive(Default)]
+pub struct HealthRegistry {
+    inner: Mutex<Inner>,
+}
+
+impl HealthRegistry {
+    /// Records the round-trip time of an answered ping.
+    pub fn record_rtt(&self, domain: &str, rtt: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let node = inner.nodes.entry(domain.to_string()).or_default();
+        node.rtt_ms = Some(ewma(node.rtt_ms, rtt.as_secs_f64() * 1000.0));
+    }
+
+    /// Records an estimate of the offset of the clock of a node, returning the smoothed
+    /// offset.
+    pub fn record_clock_offset(&self, domain: &str, offset_ms: f64) -> f64 {
+        let mut inner = self.inner.lock().unwrap();
+        let node = inner.nodes.entry(domain.to_string()).or_default();
+        let offset_ms = ewma(node.clock_offset_ms, offset_ms);
+        node.clock_offset_ms = Some(offset_ms);
+        offset_ms
+    }
+
+    /// Returns the estimated offset of the clock of a node, in milliseconds.
+    pub fn clock_offset_ms(&self, domain: &str) -> Option<f64> {
+        let inner = self.inner.lock().unwrap();
+        inner.nodes.get(domain)?.clock_offset_ms
+    }
+
+    /// Records a ping that was still unanswered when the next one was due.
+    pub fn record_stall(&self, domain: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.nodes.entry(domain.to_string()).or_default().stalls += 1.0;
+    }
+
+    /// Records the arrival of a log line and updates the delivery lag of the node.
+    pub fn record_line(&self, domain: &str, line: &str) {
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        let key = hasher.finish();
+        let now = Instant::now();
+
+        let mut inner = self.inner.lock().unwrap();
+        let first = *inner.first_arrivals.entry(key).or_insert(now);
+        let lag_ms = now.duration_since(first).as_secs_f64() * 1000.0;
+        let node = inner.nodes.entry(domain.to_string()).or_default();
+        node.lag_ms = Some(ewma(node.lag_ms, lag_ms));
+        node.lines += 1;
+        node.last_line = Some(now);
+    }
+
+    /// Records whether the node is currently connected.
+    pub fn set_connected(&self, domain: &str, connected: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let node = inner.nodes.entry(domain.to_string()).or_default();
+        node.connected = connected;
+        node.connected_since = connected.then(Instant::now);
+        if connected {
+            // Stalls of an earlier connection say nothing about the new one.
+            node.stalls = 0.0;
+        }
+    }
+
+    /// Returns the metrics of all nodes, sorted by domain.
+    pub fn snapshot(&self) -> Vec<(String, NodeHealth)> {
+        let inner = self.inner.lock().unwrap();
+        let mut nodes: Vec<_> = inner
+            .nodes
+            .iter()
+            .map(|(domain, health)| (domain.clone(), health.clone()))
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes
+    }
+
+    /// Returns the current metrics of a node.
+    pub fn get(&self, domain: &str) -> Option<NodeHealth> {
+        self.inner.lock().unwrap().nodes.get(domain).cloned()
+    }
+
+    /// Halves the stall counts so that old incidents gradually stop counting, and forgets
+    /// line arrivals that are too old to matter for the lag computation.
+    pub fn decay(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        for node in inner.nodes.values_mut() {
+            node.stalls /= 2.0;
+        }
+        inner
+            .first_arrivals
+            .retain(|_, first| first.elapsed() < LINE_MEMORY);
+    }
+}
+
+fn ewma(current: Option<f64>, sample: f64) -> f64 {
+    match current {
+        Some(current) => current + EWMA_WEIGHT * (sample - current),
+        None => sample,
+    }
+}
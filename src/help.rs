@@ -0,0 +1,799 @@
+//! Topic help pages with examples.
+//!
+//! Each topic lists the flags it covers by their argument IDs; their descriptions, value names,
+//! defaults, and environment variables are taken from the command line definition, so the
+//! pages cannot drift from the actual flags.
+
+use clap::Command;
+use std::fmt::Write;
+
+/// A help page about one area of functionality.
+pub struct Topic {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub description: &'static str,
+    /// IDs of the arguments covered by the topic.
+    pub flags: &'static [&'static str],
+    /// Descriptions and command lines of examples.
+    pub examples: &'static [(&'static str, &'static str)],
+}
+
+/// IDs of the arguments that exist only on some platforms, which topics may list regardless.
+const PLATFORM_FLAGS: &[&str] = &[
+    "split_output_fifo",
+    "daemon",
+    "pid_file",
+    "log_file",
+    "event_log_source",
+    "os_log",
+];
+
+pub const TOPICS: &[Topic] = &[
+    Topic {
+        name: "output",
+        summary: "Formatting log lines for stdout and files",
+        description: "\
+Every log line is printed to stdout, formatted with a template or as a JSON object. Lines can
+also be appended to a file whose path may contain strftime specifiers to rotate files by time;
+on Unix, SIGHUP reopens the output files for external rotation tools and reloads the scripts.
+Binary CBOR and Candid records are decoded unless --raw is given, escape sequences are stripped
+except colors with --keep-ansi, and --parse logfmt parses the key=value pairs of text lines
+into fields; --annotate attaches fixed fields to every line, and --detect-injection flags lines
+that look crafted to deceive. --tee-raw keeps an untouched copy of the frames of each node as
+evidence, regardless of filters and dedup. Statistics are printed to stderr on exit, and
+continuously with --stats-view. --preset incident bundles the view for on-call engineers:
+dedup, timestamps and node prefixes, and colors by severity. --preset pipe guarantees scripts
+one JSON object per line on stdout and nothing else. --highlight colors the matches of regular
+expressions within lines without filtering them. --output-encoding converts the lines printed
+and written to files for consoles and collectors that cannot handle UTF-8. --batch-lines and
+--flush-interval write stdout in batches for throughput at high rates. Printing can be paused
+with Enter in --interactive mode or through the --control-addr; up to --pause-buffer-size lines
+are held back and printed on resuming. --max-buffer-mb limits the memory of the events waiting
+in all queues, and --buffer-drop-policy decides which are dropped, or with block, that the
+connections wait for room instead. --summary-interval prints periodic summaries of line rates,
+severities, and frequent lines. --count-by counts the lines by a field captured with a regular
+expression, e.g. the status code, and prints the table at --count-interval. --quiet prints no
+lines, and --count counts the lines in total, per node, per severity, and per --count-pattern,
+printed on exit, e.g. to check in scripts whether a canister logs at all. --annotate-upgrades
+writes a line before the markers of canister upgrades and restarts, such as post_upgrade logs,
+and when the module hash of a canister changes, so that the lines of different versions of the
+code are set apart.
+The client's own diagnostics go to stderr, filtered by RUST_LOG; without RUST_LOG, only errors
+are shown; -v adds the client's informational messages, such as connection attempts, -vv its
+debug and -vvv its trace messages. In a terminal, a single line such as \"Connected to 12/13
+boundary nodes.\" tells instead that the stream is live, and is printed again when the number
+changes. --self-log-format json writes the diagnostics as one JSON object per line for
+container log collectors. On Unix hosts without systemd, --daemon runs the client in the
+background, writing its process ID to --pid-file and its diagnostics to --log-file, which
+SIGHUP reopens.",
+        flags: &[
+            "preset",
+            "format",
+            "json",
+            "color",
+            "highlight",
+            "output_file",
+            "output_file_atomic",
+            "output_file_fsync",
+            "output_encoding",
+            "batch_lines",
+            "flush_interval",
+            "pause_buffer_size",
+            "max_buffer_mb",
+            "buffer_drop_policy",
+            "tee_raw",
+            "timezone",
+            "raw",
+            "codec",
+            "binary",
+            "binary_dir",
+            "parse",
+            "keep_ansi",
+            "annotate",
+            "detect_injection",
+            "stats_file",
+            "stats_view",
+            "stats_view_interval",
+            "summary_interval",
+            "summary_only",
+            "summary_pattern",
+            "summary_top",
+            "count_by",
+            "count_interval",
+            "count_top",
+            "quiet",
+            "count",
+            "count_pattern",
+            "annotate_upgrades",
+            "upgrade_pattern",
+            "upgrade_poll_interval",
+            "daemon",
+            "pid_file",
+            "log_file",
+        ],
+        examples: &[
+            (
+                "Prefix each line with its receive time and node",
+                "ic-bn-logs-client -c <CANISTER_ID> --format '{ts} {node} {msg}'",
+            ),
+            (
+                "Write one JSON file per day in Zurich time",
+                "ic-bn-logs-client -c <CANISTER_ID> --json --output-file logs/%Y-%m-%d.jsonl \
+                 --timezone Europe/Zurich",
+            ),
+            (
+                "Count the requests by status code every 30 seconds",
+                "ic-bn-logs-client -c <CANISTER_ID> --count-by 'status=(\\d{3})' \
+                 --count-interval 30s",
+            ),
+            (
+                "Count the lines and errors of a canister in the next 30 seconds",
+                "ic-bn-logs-client -c <CANISTER_ID> --quiet --count --count-pattern panicked \
+                 --exit-after 30s",
+            ),
+        ],
+    },
+    Topic {
+        name: "canisters",
+        summary: "Merging the logs of several canisters fairly",
+        description: "\
+-c can be given several times to stream the logs of several canisters at once. Their lines are
+merged in weighted round-robin order, so a canister that floods only delays its own lines. A
+rate cap per canister holds back lines above it; when too many lines wait, the oldest are
+dropped and counted in the statistics. In a dfx project, canisters can be given by name with
+--canister-name instead of by ID, and --url takes the URL of a dapp, including custom domains.
+--canister-id all --subnet monitors every canister on a subnet whose logs are public, or a
+prefix pattern such as --canister-id 'rdmx6-*' those whose IDs start with it, with every line
+labeled with its canister. --split-output additionally writes the lines of each canister to its own
+file, or named pipe with --split-output-fifo, in a directory. The canisters, filters, alert
+rules, and the Elasticsearch target can also come from a --config file, which is applied again
+as soon as it changes and on SIGHUP, connecting added canisters and disconnecting removed ones
+without restarting. Its profiles bundle the options of environments, e.g. the --ic-url, subnet,
+nodes, and sinks of a testnet, selected with --profile. With --control-addr, canisters are
+added and removed at runtime over HTTP, e.g. by a dashboard. --dry-run validates all options
+and prints the nodes, URLs, filters, and destinations after the config file is applied, without
+connecting.",
+        flags: &[
+            "canister_id",
+            "canister_name",
+            "url",
+            "canister_map",
+            "project",
+            "subnet",
+            "subnet_max_canisters",
+            "subnet_max_empty_batches",
+            "config",
+            "profile",
+            "dry_run",
+            "control_addr",
+            "canister_weight",
+            "canister_rate_limit",
+            "split_output",
+            "split_output_fifo",
+        ],
+        examples: &[
+            (
+                "Give the frontend twice the share of the backend, at most 100 lines/s each",
+                "ic-bn-logs-client -c <BACKEND_ID> -c <FRONTEND_ID> \
+                 --canister-weight <FRONTEND_ID>=2 --canister-rate-limit 100",
+            ),
+            (
+                "Follow the backend canister of the dfx project in ./app",
+                "ic-bn-logs-client --canister-name backend --project ./app",
+            ),
+            (
+                "Follow the canister serving a dapp",
+                "ic-bn-logs-client tail --url https://myapp.icp0.io",
+            ),
+            (
+                "Watch for errors across all canisters of a subnet",
+                "ic-bn-logs-client -c all --subnet <SUBNET_ID> --include '(?i)error|panic'",
+            ),
+            (
+                "Check a config file and print what the client would connect to",
+                "ic-bn-logs-client --config canisters.json --dry-run",
+            ),
+            (
+                "Tail a canister on the testnet defined in the profiles of a config file",
+                "ic-bn-logs-client --config envs.json --profile testnet -c <CANISTER_ID>",
+            ),
+            (
+                "Let a dashboard add canisters at runtime with PUT /canisters/<ID>",
+                "ic-bn-logs-client -c <CANISTER_ID> --control-addr 127.0.0.1:9090",
+            ),
+        ],
+    },
+    Topic {
+        name: "filters",
+        summary: "Selecting which log lines are printed",
+        description: "\
+A line is printed if it matches at least one --include pattern (or none are given), no
+--exclude pattern, and every --where expression. With --interactive, filters can be listed,
+added, and removed at runtime by typing 'filter' commands on stdin. Alerts see all lines,
+regardless of the filters.
+
+A --where expression combines conditions on the fields node, canister, msg, level, backfill,
+resumed, suspect, tainted, panic, and fields.<NAME> with &&, ||, !, and parentheses. Conditions
+use == and != for equal text, ~ and !~ for regular expressions, contains for substrings, and <,
+<=, >, and >= for levels (info < warn < error) and numbers; a field on its own holds if it is
+true or present. The expression can also be set with the 'where' key of the config file, with
+'filter where' in interactive mode, and with PUT /where on the --control-addr interface.
+
+Like grep, --before and --after also print the lines of the same node and canister around
+every line that passes the filters or matches an --alert-pattern, e.g. the request leading up
+to an error. The preceding lines are held back in a buffer of --before lines per node and
+canister; lines dropped by --exclude are never printed as context.
+
+For needs beyond regular expressions, Rhai --script files see each line as the map 'event'
+before the filters do. A script can rewrite event.message, add entries to event.fields, or
+drop the line by evaluating to false; lines dropped by scripts do not trigger alerts.
+
+--redact and --redact-builtin mask sensitive data, such as principals, email addresses, and
+hex secrets, in the lines and their fields after the scripts, so that neither the filters nor
+the alerts and outputs see it.
+
+--sample keeps a share of the lines, e.g. 1/100, of very chatty canisters before anything else
+sees them. With --sample-keyed, lines are sampled by a key such as a request ID, so that the
+lines of a request are kept or dropped together.",
+        flags: &[
+            "include",
+            "exclude",
+            "condition",
+            "before",
+            "after",
+            "interactive",
+            "script",
+            "redact",
+            "redact_builtin",
+            "sample",
+            "sample_keyed",
+        ],
+        examples: &[
+            (
+                "Mask user identifiers before forwarding the lines",
+                "ic-bn-logs-client -c <CANISTER_ID> --redact-builtin principals,emails",
+            ),
+            (
+                "Redact secrets and drop debug lines with a script",
+                "ic-bn-logs-client -c <CANISTER_ID> --script redact.rhai",
+            ),
+            (
+                "Only print errors, except for timeouts",
+                "ic-bn-logs-client -c <CANISTER_ID> --include '(?i)error' --exclude timeout",
+            ),
+            (
+                "Print warnings and errors about timeouts from one node",
+                "ic-bn-logs-client -c <CANISTER_ID> --where 'node ~ fr1 && msg contains timeout && level >= warn'",
+            ),
+            (
+                "Start without filters and add them while watching",
+                "ic-bn-logs-client -c <CANISTER_ID> --interactive",
+            ),
+            (
+                "Print errors with the five lines leading up to them",
+                "ic-bn-logs-client -c <CANISTER_ID> --include '(?i)error' -B 5",
+            ),
+        ],
+    },
+    Topic {
+        name: "sinks",
+        summary: "Shipping log lines to remote storage",
+        description: "\
+Besides stdout, log events can be indexed into Elasticsearch or OpenSearch with the bulk API.
+Batches that cannot be delivered are retried with exponential backoff; with --spool-dir they
+are buffered on disk instead, so they survive outages and restarts. A second cluster can be
+written to at the same time, with periodic cross-checks that both accepted the same documents.
+With --s3-bucket, events are archived as gzip-compressed NDJSON objects in an S3-compatible
+bucket, one per canister and hour, e.g. canister=<id>/dt=2024-06-01-13/part-0001.ndjson.gz.
+With --forward, events are streamed as length-prefixed JSON to a central collector over a
+persistent mutual TLS connection, which is re-established after failures; with --spool-dir,
+the events meanwhile are buffered on disk.
+With --otlp-endpoint, events are exported as OpenTelemetry log records over OTLP/HTTP, with the
+canister and boundary node as resource attributes, to a collector or an OTel-native backend.
+With --csv-out and --parquet-out, events are written as rows with the timestamp, node,
+canister, level, message, and the fields of structured records as a JSON object, for loading
+into pandas or DuckDB. The Parquet file is complete only once the client has exited.
+With --grpc-addr, other services can subscribe to the aggregated stream over gRPC, and with
+--serve-ws, browser dashboards and other consumers over a local WebSocket relay. --web-ui
+serves a dashboard of its own, with the live stream, the status of the nodes, and filter
+controls, in the browser. On Windows, --event-log-source also reports the lines to the Windows
+Event Log, and on macOS --os-log logs them to the unified logging system.",
+        flags: &[
+            "elasticsearch_url",
+            "elasticsearch_index",
+            "elasticsearch_api_key",
+            "elasticsearch_batch_size",
+            "elasticsearch_flush_interval",
+            "mirror_elasticsearch_url",
+            "mirror_elasticsearch_api_key",
+            "mirror_bucket",
+            "mirror_grace",
+            "s3_bucket",
+            "s3_endpoint",
+            "s3_region",
+            "s3_prefix",
+            "s3_part_size_mb",
+            "s3_flush_interval",
+            "forward",
+            "forward_client_cert",
+            "forward_client_key",
+            "forward_ca_cert",
+            "otlp_endpoint",
+            "otlp_header",
+            "csv_out",
+            "parquet_out",
+            "spool_dir",
+            "spool_max_mb",
+            "grpc_addr",
+            "serve_ws",
+            "serve_ws_allow_origin",
+            "web_ui",
+            "event_log_source",
+            "os_log",
+        ],
+        examples: &[
+            (
+                "Index into a local cluster, spooling to disk during outages",
+                "ic-bn-logs-client -c <CANISTER_ID> --elasticsearch-url http://localhost:9200 \
+                 --spool-dir /var/spool/ic-bn-logs",
+            ),
+            (
+                "Archive into a MinIO bucket",
+                "ic-bn-logs-client -c <CANISTER_ID> --s3-bucket logs \
+                 --s3-endpoint http://localhost:9000",
+            ),
+            (
+                "Forward to a central collector that authenticates every shipper",
+                "ic-bn-logs-client -c <CANISTER_ID> --forward tls://collector.example.com:6514 \
+                 --forward-client-cert edge.pem --forward-client-key edge.key",
+            ),
+            (
+                "Export to a local OpenTelemetry Collector",
+                "ic-bn-logs-client -c <CANISTER_ID> --otlp-endpoint http://localhost:4318",
+            ),
+            (
+                "Watch the stream in the browser at http://127.0.0.1:3000",
+                "ic-bn-logs-client -c <CANISTER_ID> --web-ui 127.0.0.1:3000",
+            ),
+            (
+                "Save a replayed session for analysis in DuckDB",
+                "ic-bn-logs-client replay session.icblog --parquet-out session.parquet",
+            ),
+        ],
+    },
+    Topic {
+        name: "dedup",
+        summary: "Printing each line once across boundary nodes",
+        description: "\
+Every boundary node delivers the same log lines. With --dedup, a line is printed only by the
+first node that delivers it. Several instances can share the dedup state through Redis.
+
+With --confirm-nodes, a line is printed only once it was received from that many distinct
+nodes, as a defense against a single node injecting lines; lines that are not confirmed
+within --confirm-window are printed flagged as suspect.
+
+Either way, the statistics summary on exit shows per node how far it lags behind the first
+node delivering each line, as percentiles of a histogram of the delays.",
+        flags: &[
+            "dedup",
+            "dedup_window",
+            "dedup_redis",
+            "dedup_namespace",
+            "confirm_nodes",
+            "confirm_window",
+        ],
+        examples: &[(
+            "Deduplicate across two instances writing into the same sink",
+            "ic-bn-logs-client -c <CANISTER_ID> --dedup-redis redis://cache:6379",
+        )],
+    },
+    Topic {
+        name: "alerts",
+        summary: "Webhook, Slack, and Discord alerts for matching lines and unusual rates",
+        description: "\
+Lines matching an --alert-pattern are sent to the webhook as a JSON POST, and to Slack and
+Discord as a message rendered from --alert-template, at most once per pattern and interval.
+Matches in between are counted and reported with the next alert. --alert-dedup-window also
+holds back alerts for a line that already fired within the window. Messages to Slack and
+Discord are sent one at a time, at most one per second, and delayed when the service asks to
+slow down. Rates that change without a known pattern are caught with --anomaly-factor: the
+lines and error lines of each canister are counted every --anomaly-window and compared with
+their moving averages over --anomaly-baseline; counts that many times above, or for all lines
+below, are logged as anomalies, counted in the statistics, listed by GET /anomalies of the
+control interface, and sent to the --anomaly-webhook, as are their recoveries. Counts below
+--anomaly-min-lines are never reported. With --anomaly-record-dir, the frames of the last
+--anomaly-record-before are kept in memory, and an anomaly writes them and those of the next
+--anomaly-record-after to a capture file, at full fidelity even with sampling.",
+        flags: &[
+            "alert_pattern",
+            "alert_webhook",
+            "slack_webhook",
+            "discord_webhook",
+            "alert_template",
+            "alert_min_interval",
+            "alert_dedup_window",
+            "anomaly_factor",
+            "anomaly_window",
+            "anomaly_baseline",
+            "anomaly_min_lines",
+            "anomaly_webhook",
+            "anomaly_record_dir",
+            "anomaly_record_before",
+            "anomaly_record_after",
+        ],
+        examples: &[
+            (
+                "Alert on panics",
+                "ic-bn-logs-client -c <CANISTER_ID> --alert-pattern panicked \
+                 --alert-webhook https://hooks.example.com/ic",
+            ),
+            (
+                "Post errors to a Slack channel, once per line and hour",
+                "ic-bn-logs-client -c <CANISTER_ID> --alert-pattern ERROR \
+                 --slack-webhook https://hooks.slack.com/services/<PATH> --alert-dedup-window 1h",
+            ),
+            (
+                "Report canisters logging three times more or fewer lines than usual",
+                "ic-bn-logs-client -c <CANISTER_ID> --anomaly-factor 3 \
+                 --anomaly-webhook https://hooks.example.com/ic",
+            ),
+        ],
+    },
+    Topic {
+        name: "connections",
+        summary: "Boundary node connections, proxies, and keep-alive",
+        description: "\
+The client connects to every API boundary node listed by the NNS subnet, or by any subnet with
+--all-subnets, or only to a few with --nodes-strategy quorum or single, or --max-connections.
+Nodes that are not connected are kept as candidates: they replace connections that end, fall
+behind, or stop delivering lines for --gap-timeout while other nodes deliver. Otherwise,
+connections that end are re-established with backoff, unless the node rejected the client, e.g.
+with a policy-violation Close code. With --standby, some candidates stay connected but muted,
+and are promoted without a reconnect gap. Reconnects across all nodes are limited to
+--restart-budget per minute, at most --restart-burst at once, so that a network blip does not
+reconnect all nodes together, and a node that fails to connect five times in a row is left
+alone for --breaker-cooldown.
+
+--nearest N measures the round trip of a ping to every node before connecting, and connects
+only to the N fastest, with the others as candidates in order of latency. With
+--nearest-refresh, the nodes are measured again periodically, and a connection is swapped for
+a candidate that has become clearly nearer.
+
+Keep-alive pings adapt to the log traffic, or are sent every --ping-interval; unanswered pings
+count against the health of a node, and a connection that receives nothing for --pong-timeout
+after a ping is closed as dead and re-established. Each phase of opening a connection is
+limited: resolving and connecting by --connect-timeout, the TLS and WebSocket handshakes by
+--handshake-timeout; failed attempts name the phase. --line-framing splits frames holding
+several lines at their newlines, and with stream also joins lines split across frames,
+normalizing CRLF and trailing whitespace. Large log records can be split into chunks by the
+nodes and reassembled by the client, and records marked as continued in the next one, e.g. by a
+trailing backslash, are joined with --continuation-suffix. --group-panics joins the lines of a
+canister trap or panic, such as its backtrace, into one line flagged as a panic. With
+--compression on, nodes that support it compress their frames with permessage-deflate.
+--max-bytes-per-sec-per-node throttles the reads from each node, so one cannot flood the rest.
+The --tls-* options trust additional root certificates, authenticate the client with a
+certificate, or override the server name, e.g. for testnets and mutual TLS setups. --header and
+--bearer-token add headers, e.g. the credentials of a reverse proxy, to the WebSocket upgrade
+requests. --resolve pins a node to an address, and --ip-version restricts the connections to
+IPv4 or IPv6. --endpoint-path-template follows a changed log stream path of the nodes; the
+parameters the client adds, such as tail, replace those of the template.
+
+Records that carry a sequence number, named with --sequence-field, are checked for gaps, which
+are reported as missed lines and counted in the statistics; --reconnect-on-sequence-gap
+reconnects to recover them. With --quarantine-threshold, a node that keeps sending undecodable
+or oversized frames is quarantined: its lines are counted but no longer written, its bad frames
+are no longer logged, and it is reported every minute until it has sent no bad frame for five
+minutes. Pings carry the time they were sent, and nodes that append their own time to the pongs
+have the offset of their clocks estimated; offsets above a second are logged, and the
+interactive nodes command and the web UI show them. --node-time-field takes the timestamps of
+the lines from the time the node gave the records, corrected by that offset, so that lines
+merged from several nodes are ordered by when they were logged rather than when they arrived.
+
+The node list is read from the first --ic-url endpoint that answers; failed fetches are retried
+with backoff, and with --node-cache, the list saved by the previous run is used if all fail.
+--only-node and --exclude-node narrow the list down to the nodes whose domains match glob
+patterns, e.g. '*fr1*' for a region. Before connecting, the client checks that the canisters
+exist and that their logs are public, failing with a clear error instead of a handshake failure
+per node; --no-preflight skips this.
+
+With --active-hours, all connections are parked outside the given daily hours, and
+re-established from a freshly fetched node list when the hours begin, replaying the lines
+logged meanwhile with --active-hours-backfill.",
+        flags: &[
+            "proxy",
+            "endpoint_path_template",
+            "ip_version",
+            "resolve",
+            "tls_ca_cert",
+            "tls_client_cert",
+            "tls_client_key",
+            "tls_server_name",
+            "insecure_skip_verify",
+            "headers",
+            "bearer_token",
+            "all_subnets",
+            "ic_url",
+            "fetch_root_key",
+            "discovery_retries",
+            "node_cache",
+            "no_preflight",
+            "compression",
+            "max_bytes_per_sec_per_node",
+            "nodes_strategy",
+            "only_node",
+            "exclude_node",
+            "gap_timeout",
+            "restart_budget",
+            "restart_burst",
+            "breaker_cooldown",
+            "sequence_field",
+            "reconnect_on_sequence_gap",
+            "quarantine_threshold",
+            "standby",
+            "active_hours",
+            "active_hours_backfill",
+            "ping_interval",
+            "pong_timeout",
+            "connect_timeout",
+            "handshake_timeout",
+            "min_ping_interval",
+            "max_ping_interval",
+            "max_connections",
+            "nearest",
+            "nearest_refresh",
+            "rebalance_interval",
+            "reassemble_chunks",
+            "line_framing",
+            "continuation_suffix",
+            "group_panics",
+            "node_time_field",
+            "max_record_size",
+            "chunk_timeout",
+        ],
+        examples: &[
+            (
+                "Connect to a single node, with a warm standby for instant failover",
+                "ic-bn-logs-client -c <CANISTER_ID> --nodes-strategy single --standby 1",
+            ),
+            (
+                "Connect to the three best nodes through a SOCKS proxy",
+                "ic-bn-logs-client -c <CANISTER_ID> --max-connections 3 \
+                 --proxy socks5h://localhost:1080",
+            ),
+            (
+                "Connect to the two nearest nodes, measuring again every ten minutes",
+                "ic-bn-logs-client -c <CANISTER_ID> --nearest 2 --nearest-refresh 10m",
+            ),
+            (
+                "Stay within a region, skipping a node under maintenance",
+                "ic-bn-logs-client -c <CANISTER_ID> --only-node '*fr1*' \
+                 --exclude-node 'api-bn-fr1-02.*'",
+            ),
+        ],
+    },
+    Topic {
+        name: "replay",
+        summary: "Replaying historical lines before tailing",
+        description: "\
+Like tail -f, the client follows the logs until it is stopped; -f is accepted for familiarity.
+Boundary nodes can replay recent log lines before streaming live ones, e.g. the last 100 with
+-n 100 (--tail or --lines). Replayed lines are marked as backfill in structured outputs and
+with the {backfill} template field. Nodes that support resumption replay the lines missed while
+reconnecting; these are marked as both backfill and resumed. With --state-dir, the sessions of
+the nodes are saved, so that a restarted client also resumes the streams where it stopped.
+
+--backfill does not depend on the nodes: it fetches the records the IC retains for the
+canisters from the management canister and writes them first, marked with their index, e.g.
+[backfill #42], and timestamped with the time they were logged. Live lines that repeat them are
+dropped. With -n, at most that many of the most recent records of each canister are written.",
+        flags: &["since", "tail", "follow", "backfill", "state_dir"],
+        examples: &[
+            (
+                "Show the last 15 minutes, then follow",
+                "ic-bn-logs-client -c <CANISTER_ID> --since 15m",
+            ),
+            (
+                "Show the last 100 lines, then follow",
+                "ic-bn-logs-client -c <CANISTER_ID> -n 100",
+            ),
+            (
+                "Show the records the IC retains, with their time, then follow",
+                "ic-bn-logs-client -c <CANISTER_ID> --backfill --format \"{ts} {backfill}{msg}\"",
+            ),
+        ],
+    },
+    Topic {
+        name: "capture",
+        summary: "Recording sessions, replaying them offline, and support bundles",
+        description: "\
+--record writes every frame received from the nodes, with its node, canister, codec, and
+receive time, into a capture file. The replay subcommand feeds a capture back through decoding,
+dedup, scripts, filters, formats, and sinks, with the options of tail, so filters can be
+debugged offline and incident data shared. Frames are replayed in real time by default, faster
+with --speed, and without pauses with --speed 0; events keep their recorded receive times.
+Captures are compressed with zstd in blocks and end with an index, so replay --from and --to
+seek straight to a time range of a multi-hour capture. With --record-redacted, the --redact and
+--redact-builtin patterns are masked in the recorded frames too, byte for byte with asterisks,
+also in those of the flight recorder of --anomaly-record-dir. For support tickets, the capture
+subcommand tails for --duration and writes a single archive to --out with the redacted frames,
+the client's diagnostics, the statistics of every node, the node list, and the options, with
+credentials masked.",
+        flags: &["record", "record_redacted"],
+        examples: &[
+            (
+                "Record a session while tailing",
+                "ic-bn-logs-client -c <CANISTER_ID> --record incident.icblog",
+            ),
+            (
+                "Record a session with principals masked in the recorded frames",
+                "ic-bn-logs-client -c <CANISTER_ID> --record incident.icblog \
+                 --redact-builtin principals --record-redacted",
+            ),
+            (
+                "Try a filter on the recording, ten times faster than real time",
+                "ic-bn-logs-client replay incident.icblog --speed 10 --include 'timeout'",
+            ),
+            (
+                "Replay five minutes of a long recording without pauses",
+                "ic-bn-logs-client replay incident.icblog --speed 0 --from 2024-06-01T13:00:00Z \
+                 --to 2024-06-01T13:05:00Z",
+            ),
+            (
+                "Record a support bundle for ten minutes, masking principals and emails",
+                "ic-bn-logs-client capture -c <CANISTER_ID> --duration 10m --out bundle.tar.gz \
+                 --redact-builtin principals,emails",
+            ),
+        ],
+    },
+    Topic {
+        name: "exit",
+        summary: "Exiting on a match, a line count, or a timeout",
+        description: "\
+For scripts and CI jobs, the client can exit on its own when a printed line matches
+--exit-on-match (code 0), when it has printed --exit-after-lines lines (code 3), or when
+--exit-after has elapsed (code 2), whichever comes first. The end of all streams or a signal
+before any condition was met exits with code 4, and the reader of stdout going away, e.g. head,
+with code 141 unless --ignore-broken-pipe keeps the other outputs going. No line is printed
+after the one that met a condition. Errors exit with the codes of sysexits.h: 64 for invalid
+options, 65 for a capture that cannot be replayed, 69 when the boundary nodes, Redis, or the
+releases of self-update are unavailable, 70 for internal errors, and 74 for files and sockets
+that cannot be opened.",
+        flags: &[
+            "exit_on_match",
+            "exit_after_lines",
+            "exit_after",
+            "ignore_broken_pipe",
+        ],
+        examples: &[
+            (
+                "Wait until the upgrade completes, failing after five minutes",
+                "ic-bn-logs-client -c <CANISTER_ID> --exit-on-match 'upgrade complete' \
+                 --exit-after 5m",
+            ),
+            (
+                "Print the last 100 lines and exit",
+                "ic-bn-logs-client -c <CANISTER_ID> --tail 100 --exit-after-lines 100",
+            ),
+        ],
+    },
+];
+
+/// Looks up a topic by name.
+pub fn find(name: &str) -> Option<&'static Topic> {
+    TOPICS.iter().find(|topic| topic.name == name)
+}
+
+/// Renders the list of topics.
+pub fn render_index() -> String {
+    let mut text =
+        String::from("Help topics (show one with 'ic-bn-logs-client help <TOPIC>'):\n\n");
+    for topic in TOPICS {
+        writeln!(text, "  {:<12} {}", topic.name, topic.summary).unwrap();
+    }
+    text
+}
+
+/// Renders a topic page, taking the flag descriptions from the command.
+pub fn render(topic: &Topic, command: &Command) -> String {
+    let mut text = format!("{}\n\n{}\n", topic.summary, topic.description);
+
+    text.push_str("\nOptions:\n");
+    for id in topic.flags {
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id) else {
+            debug_assert!(
+                PLATFORM_FLAGS.contains(id),
+                "help topic {} refers to unknown flag {id}",
+                topic.name
+            );
+            continue;
+        };
+        let mut usage = format!("--{}", arg.get_long().unwrap_or(id));
+        if arg.get_action().takes_values() {
+            for name in arg.get_value_names().unwrap_or_default() {
+                write!(usage, " <{name}>").unwrap();
+            }
+        }
+        writeln!(text, "  {usage}").unwrap();
+        if let Some(help) = arg.get_help() {
+            writeln!(text, "      {help}").unwrap();
+        }
+        let defaults: Vec<_> = arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy())
+            .collect();
+        if !defaults.is_empty() {
+            writeln!(text, "      [default: {}]", defaults.join(", ")).unwrap();
+        }
+        if let Some(env) = arg.get_env() {
+            writeln!(text, "      [env: {}]", env.to_string_lossy()).unwrap();
+        }
+    }
+
+    if !topic.examples.is_empty() {
+        text.push_str("\nExamples:\n");
+        for (description, command_line) in topic.examples {
+            writeln!(text, "  # {description}\n  {command_line}\n").unwrap();
+        }
+    }
+    text
+}
+
+/// Renders the full help followed by all topic pages.
+pub fn render_all(command: &mut Command) -> String {
+    let mut text = command.render_long_help().to_string();
+    for topic in TOPICS {
+        write!(
+            text,
+            "\n{}\n{}\n\n{}",
+            topic.name.to_uppercase(),
+            "=".repeat(topic.name.len()),
+            render(topic, command)
+        )
+        .unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, TailArgs};
+    use clap::{Args, CommandFactory};
+
+    #[test]
+    fn topics_refer_to_existing_flags() {
+        let command = Cli::command();
+        for topic in TOPICS {
+            for id in topic.flags {
+                assert!(
+                    command.get_arguments().any(|arg| arg.get_id() == id)
+                        || PLATFORM_FLAGS.contains(id),
+                    "help topic {} refers to unknown flag {id}",
+                    topic.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_tail_flag_is_in_a_topic() {
+        let command = TailArgs::augment_args(Command::new("tail"));
+        let missing: Vec<_> = command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| !TOPICS.iter().any(|topic| topic.flags.contains(id)))
+            .collect();
+        assert!(missing.is_empty(), "flags in no help topic: {missing:?}");
+    }
+
+    #[test]
+    fn topics_render() {
+        let command = Cli::command();
+        for topic in TOPICS {
+            let text = render(topic, &command);
+            assert!(text.starts_with(topic.summary));
+        }
+    }
+}
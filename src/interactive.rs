@@ -0,0 +1,193 @@
+//! Interactive commands read from stdin while logs are streaming.
+//!
+//! Every line on stdin is a command that changes the behaviour of the running client, e.g. to
+//! narrow down the output with a filter or to pause printing while reading a stack trace.
+//! Pressing Enter on an empty line pauses or resumes printing, so that the terminal can be
+//! scrolled back while the lines keep arriving. Responses are written to stderr so that they do
+//! not mix with the log lines on stdout.
+
+use crate::connection::ConnectionConfig;
+use crate::filter::{Filter, FilterKind};
+use crate::query::Query;
+use log::debug;
+use regex::Regex;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const HELP: &str = "\
+Commands:
+  filter list                        Show the active filters
+  filter add include|exclude <REGEX> Add a filter
+  filter remove <N>                  Remove the filter with number N
+  filter where <EXPR>                Only print lines satisfying the expression
+  filter where                       Remove the expression
+  filter clear                       Remove all filters and the expression
+  pause                              Stop printing log lines to stdout, holding them back
+  resume                             Print the held back lines and resume printing
+  <Enter>                            Pause or resume
+  nodes                              Show the state of each boundary node
+  stats                              Show line counters
+  help                               Show this help";
+
+/// Starts a task that reads commands from stdin until it is closed.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        eprintln!("Interactive mode: type 'help' for a list of commands.");
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Err(e) = execute(&config, &line) {
+                        eprintln!("{e}");
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to read command: {e}");
+                    break;
+                }
+            }
+        }
+        debug!("Stdin closed, interactive mode ended.");
+    });
+}
+
+/// Executes a single command.
+fn execute(config: &ConnectionConfig, line: &str) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        None if config.output.is_paused() => resume(config),
+        None => pause(config),
+        Some("help") => eprintln!("{HELP}"),
+        Some("filter") => filter_command(config, line, words.next(), words.collect())?,
+        Some("pause") => pause(config),
+        Some("resume") => resume(config),
+        Some("nodes") => {
+            for (domain, health) in config.health.snapshot() {
+                eprintln!(
+                    "{domain}: {}, {} line(s), rtt {}, lag {}, clock offset {}, stalls {:.1}",
+                    if health.connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    },
+                    health.lines,
+                    format_ms(health.rtt_ms),
+                    format_ms(health.lag_ms),
+                    health
+                        .clock_offset_ms
+                        .map_or_else(|| "-".to_string(), |ms| format!("{ms:+.0}ms")),
+                    health.stalls,
+                );
+            }
+        }
+        Some("stats") => {
+            let nodes = config.health.snapshot();
+            let connected = nodes.iter().filter(|(_, health)| health.connected).count();
+            let received: u64 = nodes.iter().map(|(_, health)| health.lines).sum();
+            eprintln!(
+                "{connected} of {} node(s) connected, {received} line(s) received, {} written, \
+                 {} filtered out",
+                nodes.len(),
+                config.output.written(),
+                config.filters.rejected(),
+            );
+        }
+        Some(command) => return Err(format!("Unknown command '{command}'; try 'help'.")),
+    }
+    Ok(())
+}
+
+fn pause(config: &ConnectionConfig) {
+    config.output.pause();
+    eprintln!("Output paused; press Enter or type 'resume' to continue.");
+}
+
+fn resume(config: &ConnectionConfig) {
+    let resumed = config.output.resume();
+    if resumed.dropped > 0 {
+        eprintln!(
+            "Output resumed with {} held back line(s); {} line(s) did not fit into the pause \
+             buffer and were not printed.",
+            resumed.printed, resumed.dropped
+        );
+    } else {
+        eprintln!("Output resumed with {} held back line(s).", resumed.printed);
+    }
+}
+
+fn filter_command(
+    config: &ConnectionConfig,
+    line: &str,
+    action: Option<&str>,
+    args: Vec<&str>,
+) -> Result<(), String> {
+    match action {
+        None | Some("list") => {
+            let filters = config.filters.list();
+            let condition = config.filters.condition();
+            if filters.is_empty() && condition.is_none() {
+                eprintln!("No filters.");
+            }
+            for (index, filter) in filters.iter().enumerate() {
+                eprintln!("{}: {filter}", index + 1);
+            }
+            if let Some(condition) = condition {
+                eprintln!("where {condition}");
+            }
+        }
+        Some("add") => {
+            let kind = match args.first() {
+                Some(&"include") => FilterKind::Include,
+                Some(&"exclude") => FilterKind::Exclude,
+                _ => return Err("Usage: filter add include|exclude <REGEX>".to_string()),
+            };
+            let pattern = args[1..].join(" ");
+            if pattern.is_empty() {
+                return Err("Usage: filter add include|exclude <REGEX>".to_string());
+            }
+            let pattern = Regex::new(&pattern).map_err(|e| e.to_string())?;
+            let filter = Filter { kind, pattern };
+            eprintln!("Added filter: {filter}");
+            config.filters.add(filter);
+        }
+        Some("remove") => {
+            let index: usize = args
+                .first()
+                .and_then(|n| n.parse().ok())
+                .ok_or("Usage: filter remove <N>")?;
+            let filter = index
+                .checked_sub(1)
+                .and_then(|index| config.filters.remove(index))
+                .ok_or(format!("No filter with number {index}."))?;
+            eprintln!("Removed filter: {filter}");
+        }
+        Some("where") => {
+            // The expression is taken as typed, as its strings may hold several spaces.
+            let expression = line
+                .trim_start()
+                .strip_prefix("filter")
+                .and_then(|rest| rest.trim_start().strip_prefix("where"))
+                .unwrap_or("")
+                .trim();
+            if expression.is_empty() {
+                config.filters.set_condition(None);
+                eprintln!("Removed the expression.");
+            } else {
+                let condition = Query::parse(expression)?;
+                eprintln!("Only printing lines where {condition}");
+                config.filters.set_condition(Some(condition));
+            }
+        }
+        Some("clear") => {
+            config.filters.clear();
+            eprintln!("Removed all filters and the expression.");
+        }
+        Some(action) => return Err(format!("Unknown filter command '{action}'; try 'help'.")),
+    }
+    Ok(())
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map_or_else(|| "-".to_string(), |ms| format!("{ms:.0}ms"))
+}
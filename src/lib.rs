@@ -0,0 +1,2919 @@
+//! Client for the canister logs that the API boundary nodes of the Internet Computer stream.
+//!
+//! The `ic-bn-logs-client` binary runs a [`Client`] as it is. Applications that embed the
+//! client run it themselves, with annotation hooks of their own that attach metadata such as
+//! the deployment version or tenant to every event before the scripts, filters, and outputs
+//! see it:
+//!
+//! ```no_run
+//! use ic_bn_logs_client::{Annotator, Client};
+//! use serde_json::Value;
+//! use std::process::ExitCode;
+//!
+//! fn main() -> ExitCode {
+//!     let mut annotator = Annotator::new(16);
+//!     annotator.register(|event| {
+//!         Box::pin(async move {
+//!             let fields = event.fields.get_or_insert_with(Default::default);
+//!             fields.insert("tenant".to_string(), Value::from("acme"));
+//!         })
+//!     });
+//!     Client::new().annotator(annotator).run()
+//! }
+//! ```
+//!
+//! With the `extra-sinks` feature, they also add sinks of their own with `Client::sink`, which
+//! receive the events through the [`LogSink`] trait like the built-in sinks.
+
+mod alert;
+mod annotate;
+mod anomaly;
+mod ansi;
+mod bench;
+mod bundle;
+mod bus;
+mod canister;
+mod canister_log;
+mod capture;
+mod clock;
+mod codec;
+mod color;
+mod config;
+mod confirm;
+mod connection;
+mod context;
+mod control;
+mod counts;
+#[cfg(unix)]
+mod daemon;
+mod decode;
+mod dedup;
+mod deflate;
+mod encoding;
+mod endpoint;
+mod error;
+mod event;
+mod exit;
+mod filter;
+mod flight;
+mod framing;
+mod generate;
+mod health;
+mod help;
+mod interactive;
+mod logfmt;
+mod memory;
+mod mirror;
+mod nearest;
+mod nodes;
+mod output;
+mod panic;
+mod parking;
+mod ping;
+mod plan;
+mod pool;
+mod preflight;
+mod preset;
+mod profile;
+mod proxy;
+mod quarantine;
+mod query;
+mod reassembly;
+mod redact;
+mod replay;
+mod resolve;
+mod resume;
+mod sample;
+mod scheduler;
+mod script;
+mod selflog;
+mod sequence;
+mod server;
+mod severity;
+mod signal;
+mod sinks;
+mod split;
+mod spool;
+mod stats;
+mod status;
+mod subnet;
+mod summary;
+mod supervisor;
+#[cfg(unix)]
+mod systemd;
+mod taint;
+mod tee;
+mod template;
+mod throttle;
+mod timespec;
+mod tls;
+mod top;
+mod update;
+mod upgrades;
+mod verify;
+mod webui;
+mod writer;
+
+use anomaly::{AnomalyDetector, AnomalySettings};
+use canister_log::Backfill;
+use capture::{CaptureWriter, TimeRange};
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, Subcommand};
+use codec::Codec;
+use color::{ColorMode, Painter};
+use config::{AlertSettings, LiveConfig, Settings};
+use confirm::Confirmer;
+use connection::{ConnectTimeouts, ConnectionConfig};
+use context::ContextWindows;
+use counts::{FieldCounter, LineCounter, DEFAULT_COUNT_INTERVAL};
+use decode::InvalidUtf8;
+use dedup::{Deduplicator, PropagationTracker};
+use deflate::Compression;
+use encoding::OutputEncoding;
+use endpoint::PathTemplate;
+use error::Error;
+use exit::ExitPolicy;
+use filter::FilterSet;
+use flight::FlightRecorder;
+use framing::LineFraming;
+use health::HealthRegistry;
+use log::{error, info, warn};
+use logfmt::LineParser;
+use memory::DropPolicy;
+use mirror::{MirrorLedger, MirrorSide};
+use nodes::{Discovery, Endpoints, NodePattern, NodeSelection};
+use output::{FsyncPolicy, LineFormat, Output};
+use parking::ActiveHours;
+use pool::{NodesStrategy, Pool};
+use preset::Preset;
+use quarantine::Quarantine;
+use query::Query;
+use reassembly::{ChunkLimits, Continuation};
+use redact::{BuiltinPattern, Redactor};
+use regex::Regex;
+use replay::ReplayRequest;
+use resolve::{IpVersion, Resolver};
+use resume::SessionRegistry;
+use rustls::crypto::ring;
+use sample::{SampleRate, Sampler};
+use scheduler::FairScheduler;
+use script::ScriptPipeline;
+use selflog::SelfLogFormat;
+use sequence::SequenceTracker;
+use signal::{Signal, Signals};
+use sinks::csv::CsvWriter;
+use sinks::elasticsearch::{ElasticsearchConfig, ElasticsearchSink, ElasticsearchTarget};
+use sinks::forward::{Collector, ForwardSink};
+use sinks::otlp::{OtlpConfig, OtlpSink};
+use sinks::parquet::ParquetWriter;
+use sinks::s3::{S3Config, S3Credentials, S3Sink};
+use sinks::table::TableSink;
+use split::SplitOutput;
+use spool::Spool;
+use stats::StatsRegistry;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use summary::Summarizer;
+use supervisor::Supervisor;
+use tee::RawTee;
+use template::Template;
+use tls::TlsSettings;
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use upgrades::UpgradeAnnotator;
+use url::Url;
+use webui::WebUiSink;
+use writer::Batching;
+
+pub use annotate::Annotator;
+pub use event::LogEvent;
+pub use futures_util::future::BoxFuture;
+pub use sinks::LogSink;
+
+#[derive(Parser)]
+#[command(name = "ic-bn-logs-client")]
+#[command(about = "A WebSocket client for Internet Computer API boundary node logs")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(disable_help_subcommand = true)]
+#[command(after_help = "See 'ic-bn-logs-client help' for topic pages with examples.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print help for all options followed by all topic pages
+    #[arg(long, exclusive = true)]
+    help_all: bool,
+
+    /// Format of the client's own diagnostics on stderr; json writes one object per line, so
+    /// that log collectors can tell them apart from the log lines on stdout
+    #[arg(
+        long,
+        global = true,
+        env = "SELF_LOG_FORMAT",
+        value_enum,
+        default_value_t = SelfLogFormat::Text
+    )]
+    self_log_format: SelfLogFormat,
+
+    /// Show the client's own informational messages, such as connection attempts, on stderr;
+    /// repeat for debug (-vv) and trace (-vvv) messages. Without it and RUST_LOG, only errors
+    /// are shown
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Without a subcommand, the logs are tailed as with the tail subcommand
+    #[command(flatten)]
+    tail: TailArgs,
+}
+
+#[derive(clap::Args)]
+#[command(group(
+    ArgGroup::new("alert_destination")
+        .args(["alert_webhook", "slack_webhook", "discord_webhook"])
+        .multiple(true)
+))]
+#[command(group(
+    ArgGroup::new("reassembly")
+        .args(["reassemble_chunks", "continuation_suffix"])
+        .multiple(true)
+))]
+#[command(group(
+    ArgGroup::new("recording")
+        .args(["record", "anomaly_record_dir"])
+        .multiple(true)
+))]
+#[command(group(
+    ArgGroup::new("counting")
+        .args(["count_by", "count"])
+        .multiple(true)
+))]
+struct TailArgs {
+    /// The canister ID to monitor logs for (repeatable to merge the logs of several canisters),
+    /// or with --subnet, `all` or a prefix of the IDs of its canisters followed by `*`
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["canister_name", "url", "config"],
+        value_parser = canister::parse_canister_selector
+    )]
+    canister_id: Vec<String>,
+
+    /// URL or hostname of a dapp whose canister to monitor, e.g. https://<CANISTER_ID>.icp0.io
+    /// or a custom domain, which is looked up through the HTTP gateway (repeatable)
+    #[arg(long)]
+    url: Vec<String>,
+
+    /// Name of a canister to monitor, resolved to its ID from --canister-map or from the
+    /// canister_ids.json and dfx.json of the dfx project (repeatable)
+    #[arg(long)]
+    canister_name: Vec<String>,
+
+    /// JSON file mapping canister names to IDs, either directly or per network as in
+    /// canister_ids.json
+    #[arg(long, requires = "canister_name")]
+    canister_map: Option<PathBuf>,
+
+    /// Monitor the canisters on this subnet that --canister-id all or a prefix pattern selects
+    /// and whose logs are public, enumerated from its certified state at startup
+    #[arg(long, value_name = "SUBNET_ID")]
+    subnet: Option<String>,
+
+    /// Most canisters of the --subnet to monitor, in the order of their IDs
+    #[arg(long, default_value_t = 1000, requires = "subnet")]
+    subnet_max_canisters: usize,
+
+    /// Number of batches of 64 canister IDs in a row without a canister after which the
+    /// enumeration of a canister ID range of the --subnet stops; canisters past such a gap are
+    /// missed
+    #[arg(long, value_name = "N", default_value_t = 4, requires = "subnet")]
+    subnet_max_empty_batches: usize,
+
+    /// Directory of the dfx project in which canister names are resolved
+    #[arg(long, default_value = ".")]
+    project: PathBuf,
+
+    /// JSON file with canisters, filters, alert rules, and the Elasticsearch target that replace
+    /// those of the command line; applied again whenever it changes and on SIGHUP
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Profile of the --config file whose options, e.g. the --ic-url and sinks of an
+    /// environment, fill in those the command line leaves open
+    #[arg(long, requires = "config")]
+    profile: Option<String>,
+
+    /// Discover the nodes, validate all options, print the nodes and URLs that would be
+    /// connected and where the lines would go, and exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Share of the merged output of a canister, as CANISTER=WEIGHT: in each round of the
+    /// output scheduler, a canister may deliver as many lines as its weight (default: 1)
+    #[arg(long, value_parser = scheduler::parse_weight)]
+    canister_weight: Vec<(String, u32)>,
+
+    /// Maximum number of lines per second printed for each canister; lines above the cap are
+    /// held back, and the oldest are dropped when too many are waiting
+    #[arg(long)]
+    canister_rate_limit: Option<f64>,
+
+    /// Memory in MiB that the log events waiting in the output queues, the queues of the
+    /// sinks, and the pause backlog may take together; beyond it, events are dropped
+    /// (default: unlimited)
+    #[arg(long, value_name = "MIB")]
+    max_buffer_mb: Option<NonZeroU64>,
+
+    /// Which events a full queue drops: its oldest events, so that the latest ones get
+    /// through, the newest, or with block none, reading from the nodes only as fast as the
+    /// destinations take the events
+    #[arg(long, value_enum, default_value_t = DropPolicy::Oldest)]
+    buffer_drop_policy: DropPolicy,
+
+    /// Advertise support for chunked log records and reassemble them
+    #[arg(long)]
+    reassemble_chunks: bool,
+
+    /// How the text of the frames is divided into log lines: frame, one line per frame as
+    /// sent; split, one line per newline; or stream, also joining lines split across frames
+    #[arg(long, value_enum, default_value_t = LineFraming::Frame)]
+    line_framing: LineFraming,
+
+    /// Join log records ending with a match of this regular expression with the next record
+    /// of the same connection, removing the match, e.g. '\\' for a trailing backslash
+    #[arg(long, value_name = "REGEX")]
+    continuation_suffix: Option<Regex>,
+
+    /// Group the lines of a canister trap or panic, such as its backtrace, into one line
+    /// flagged as a panic
+    #[arg(long)]
+    group_panics: bool,
+
+    /// Take the timestamps of the lines from this field of structured records, an RFC 3339
+    /// time or nanoseconds since the Unix epoch, corrected by the estimated offset of the
+    /// clock of the node
+    #[arg(long, value_name = "NAME")]
+    node_time_field: Option<String>,
+
+    /// Maximum size in bytes of a reassembled or joined log record
+    #[arg(long, default_value_t = 64 * 1024, requires = "reassembly")]
+    max_record_size: usize,
+
+    /// Time to wait for the missing chunks of a log record before dropping it, or for the
+    /// continuation of a record before writing it as it is
+    #[arg(
+        long,
+        default_value = "5s",
+        value_parser = timespec::parse_duration,
+        requires = "reassembly"
+    )]
+    chunk_timeout: Duration,
+
+    /// Proxy for all outgoing connections (http://, socks5://, or socks5h://).
+    /// Defaults to the HTTPS_PROXY environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Path of the log stream of a canister on the nodes, with the placeholder {canister_id},
+    /// optionally with query parameters, e.g. /v2/logs/canister/{canister_id}
+    #[arg(
+        long,
+        default_value = endpoint::DEFAULT_PATH_TEMPLATE,
+        value_parser = PathTemplate::parse
+    )]
+    endpoint_path_template: PathTemplate,
+
+    /// PEM file with root certificates to trust in addition to the public roots when
+    /// connecting to the nodes, e.g. for testnets with self-signed certificates (repeatable)
+    #[arg(long)]
+    tls_ca_cert: Vec<PathBuf>,
+
+    /// PEM file with the client certificate chain presented to the nodes, for mutual TLS
+    #[arg(long, requires = "tls_client_key")]
+    tls_client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of --tls-client-cert
+    #[arg(long, requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+
+    /// Server name to send in SNI and verify the certificates of the nodes against, instead of
+    /// the node domain
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Header added to the WebSocket upgrade requests to the nodes, as 'Name: value', e.g.
+    /// for reverse proxies that require authentication (repeatable)
+    #[arg(long = "header", value_name = "HEADER", value_parser = endpoint::parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Token sent as 'Authorization: Bearer <TOKEN>' with the WebSocket upgrade requests to
+    /// the nodes
+    #[arg(long, env = "BN_BEARER_TOKEN", hide_env_values = true)]
+    bearer_token: Option<String>,
+
+    /// Only connect to the nodes over this IP version
+    #[arg(long, value_enum)]
+    ip_version: Option<IpVersion>,
+
+    /// Connect to a node at this address instead of resolving its domain, as DOMAIN:IP, e.g.
+    /// to bypass broken DNS or test a specific address (repeatable)
+    #[arg(long, value_parser = resolve::parse_override)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Accept the certificates of the nodes without verifying them, e.g. in lab environments.
+    /// Insecure: anyone on the network path can read and forge the logs
+    #[arg(long, conflicts_with = "tls_ca_cert")]
+    insecure_skip_verify: bool,
+
+    /// Offer permessage-deflate compression to the nodes, to reduce bandwidth
+    #[arg(long, value_enum, default_value_t = Compression::Off)]
+    compression: Compression,
+
+    /// Most bytes per second read from each node over all its connections, so that a flooding
+    /// node cannot starve the others or saturate a slow uplink (default: unlimited)
+    #[arg(long)]
+    max_bytes_per_sec_per_node: Option<NonZeroU64>,
+
+    /// Discover the API boundary nodes from the state of every subnet instead of only the NNS
+    /// subnet, and merge them into one pool
+    #[arg(long)]
+    all_subnets: bool,
+
+    #[command(flatten)]
+    ic: IcArgs,
+
+    /// How often fetching the API boundary nodes at startup is retried, with backoff, before
+    /// giving up or falling back to --node-cache
+    #[arg(long, default_value_t = 5)]
+    discovery_retries: u32,
+
+    /// Save the fetched list of API boundary nodes to this file, and use the saved list when
+    /// the nodes cannot be fetched
+    #[arg(long)]
+    node_cache: Option<PathBuf>,
+
+    /// Do not check before connecting that the canisters exist and that their logs are public
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Shortest interval between keep-alive pings, used while the connection is quiet
+    #[arg(long, default_value = "10s", value_parser = timespec::parse_positive_duration)]
+    min_ping_interval: Duration,
+
+    /// Longest interval between keep-alive pings, used while logs keep arriving
+    #[arg(long, default_value = "60s", value_parser = timespec::parse_positive_duration)]
+    max_ping_interval: Duration,
+
+    /// Send keep-alive pings at this fixed interval instead of adapting it, e.g. on networks
+    /// that drop idle connections quickly
+    #[arg(
+        long,
+        value_parser = timespec::parse_positive_duration,
+        conflicts_with_all = ["min_ping_interval", "max_ping_interval"]
+    )]
+    ping_interval: Option<Duration>,
+
+    /// Time after a keep-alive ping without a pong or any other message, after which the
+    /// connection is closed as dead and re-established
+    #[arg(long, default_value = "30s", value_parser = timespec::parse_positive_duration)]
+    pong_timeout: Duration,
+
+    /// Time allowed for resolving the domain of a node and for opening the TCP connection to
+    /// it, each, before the attempt fails
+    #[arg(long, default_value = "10s", value_parser = timespec::parse_positive_duration)]
+    connect_timeout: Duration,
+
+    /// Time allowed for the TLS handshake and for the WebSocket handshake with a node, each,
+    /// before the attempt fails
+    #[arg(long, default_value = "10s", value_parser = timespec::parse_positive_duration)]
+    handshake_timeout: Duration,
+
+    /// Replay historical lines logged since a duration ago (15m, 1h30m) or an RFC 3339
+    /// timestamp before tailing live logs
+    #[arg(long, value_parser = timespec::parse_time)]
+    since: Option<SystemTime>,
+
+    /// Replay at most this many of the most recent lines before tailing live logs, where the
+    /// nodes support it; with --backfill, also backfill at most this many records per canister
+    #[arg(short = 'n', long, visible_alias = "lines", value_name = "N")]
+    tail: Option<u64>,
+
+    /// Follow the logs, as with tail -f; the client always follows, so this is accepted for
+    /// familiarity only
+    #[arg(short = 'f', long = "follow", id = "follow")]
+    _follow: bool,
+
+    /// Before tailing, print the log records the IC retains for the canisters, fetched from
+    /// the management canister, and drop the live lines that repeat them
+    #[arg(long)]
+    backfill: bool,
+
+    /// Keep the resumable sessions of the nodes in this directory, so that a restarted client
+    /// continues the streams where it stopped instead of missing or repeating lines
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Which boundary nodes to connect to: all of them, a quorum of a few nodes, or a single
+    /// node; nodes that stop delivering are replaced by others
+    #[arg(long, value_enum, default_value_t = NodesStrategy::All)]
+    nodes_strategy: NodesStrategy,
+
+    /// Connect only to the discovered boundary nodes whose domain matches this glob pattern,
+    /// e.g. '*fr1*' for a region (repeatable, any may match)
+    #[arg(long, value_name = "GLOB", value_parser = NodePattern::parse)]
+    only_node: Vec<NodePattern>,
+
+    /// Do not connect to the discovered boundary nodes whose domain matches this glob pattern,
+    /// e.g. nodes under maintenance (repeatable)
+    #[arg(long, value_name = "GLOB", value_parser = NodePattern::parse)]
+    exclude_node: Vec<NodePattern>,
+
+    /// Connect to at most this many boundary nodes, keeping the others as candidates that
+    /// replace connections which end or fall behind (the size of the quorum, default: 3)
+    #[arg(long)]
+    max_connections: Option<NonZeroUsize>,
+
+    /// Measure the latency of all boundary nodes, and connect only to this many of the
+    /// fastest, keeping the others as candidates by latency
+    #[arg(long, value_name = "N", conflicts_with_all = ["max_connections", "nodes_strategy"])]
+    nearest: Option<NonZeroUsize>,
+
+    /// Measure the latency of the nodes again at this interval, and swap connections to nodes
+    /// that are no longer among the --nearest for nearer ones
+    #[arg(long, requires = "nearest", value_parser = timespec::parse_positive_duration)]
+    nearest_refresh: Option<Duration>,
+
+    /// Time between checks for a lagging connection to swap out when only some nodes are
+    /// connected
+    #[arg(long, default_value = "60s", value_parser = timespec::parse_positive_duration)]
+    rebalance_interval: Duration,
+
+    /// Daily hours, in the --timezone, outside of which the connections are closed, e.g.
+    /// 08:00-20:00; the node list is fetched again when the hours begin
+    #[arg(long, value_parser = ActiveHours::parse)]
+    active_hours: Option<ActiveHours>,
+
+    /// Replay the lines logged outside the --active-hours when the connections resume
+    #[arg(long, requires = "active_hours")]
+    active_hours_backfill: bool,
+
+    /// Number of additional nodes to keep connected but muted when only some nodes are
+    /// connected, so that a connection that ends is replaced without reconnecting
+    #[arg(long, default_value_t = 0)]
+    standby: usize,
+
+    /// Time without lines from a connected node, while other nodes deliver lines, after which
+    /// it is replaced by another node
+    #[arg(long, default_value = "30s", value_parser = timespec::parse_positive_duration)]
+    gap_timeout: Duration,
+
+    /// Maximum number of reconnects to nodes per minute, across all nodes; further reconnects
+    /// are deferred
+    #[arg(long, default_value_t = NonZeroU32::new(60).unwrap())]
+    restart_budget: NonZeroU32,
+
+    /// Maximum number of reconnects at once, e.g. after a network blip; further reconnects
+    /// are spread out at the rate of the restart budget
+    #[arg(long, value_name = "N", default_value_t = NonZeroU32::new(5).unwrap())]
+    restart_burst: NonZeroU32,
+
+    /// Time for which a node is not connected after five failed connections in a row
+    #[arg(long, default_value = "5m", value_parser = timespec::parse_positive_duration)]
+    breaker_cooldown: Duration,
+
+    /// Bundle of options for a common way of watching logs; explicit options take precedence
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Template for each output line, with the fields {ts}, {mono}, {node}, {canister},
+    /// {msg}, {backfill}, and {suspect} (default: {backfill}{suspect}{msg})
+    #[arg(long, value_parser = Template::parse)]
+    format: Option<Template>,
+
+    /// Print each log event as a JSON object instead of a formatted line
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// Color the lines printed to stdout by severity and highlight matches (default: auto with
+    /// --highlight, never otherwise)
+    #[arg(long, value_enum)]
+    color: Option<ColorMode>,
+
+    /// Highlight the matches of this regular expression in the printed lines, without
+    /// filtering (repeatable; each pattern gets its own color)
+    #[arg(long)]
+    highlight: Vec<Regex>,
+
+    /// Also append the formatted log lines to this file. strftime specifiers in the path
+    /// (e.g. logs/%Y-%m-%d.log) start a new file whenever the rendered path changes
+    #[arg(long, value_parser = output::parse_file_pattern)]
+    output_file: Option<String>,
+
+    /// Write the current output file under a .partial name and rename it once it is finished,
+    /// on rotation or exit, so that files under their final names are always complete
+    #[arg(long, requires = "output_file")]
+    output_file_atomic: bool,
+
+    /// When to flush the output file to disk: never (leave it to the operating system), when a
+    /// file is finished (rotate), or after every line (always)
+    #[arg(long, value_enum, default_value = "never", requires = "output_file")]
+    output_file_fsync: FsyncPolicy,
+
+    /// Character encoding of the lines printed to stdout and written to the output file, for
+    /// consoles and collectors that cannot handle UTF-8; remote sinks always receive UTF-8
+    #[arg(long, value_enum, default_value = "utf-8")]
+    output_encoding: OutputEncoding,
+
+    /// Write the lines to stdout in batches of this many lines instead of one by one, for
+    /// throughput at high message rates
+    #[arg(long)]
+    batch_lines: Option<NonZeroUsize>,
+
+    /// Longest time a line waits before the lines batched for stdout are written (default: 1s
+    /// with --batch-lines); without --batch-lines, batches are only limited by time
+    #[arg(long, value_parser = timespec::parse_positive_duration)]
+    flush_interval: Option<Duration>,
+
+    /// Keep delivering the lines to the output files and sinks when the reader of stdout goes
+    /// away, instead of shutting down with exit code 141
+    #[arg(long)]
+    ignore_broken_pipe: bool,
+
+    /// Also write the lines of every canister to its own file, <CANISTER_ID>.log, in this
+    /// directory, created when the canister's first line arrives
+    #[arg(long)]
+    split_output: Option<PathBuf>,
+
+    /// Create named pipes, <CANISTER_ID>.pipe, instead of files in the --split-output
+    /// directory; lines for a pipe are dropped while it has no reader or its reader falls behind
+    #[cfg(unix)]
+    #[arg(long, requires = "split_output")]
+    split_output_fifo: bool,
+
+    /// Also write the raw frames of each node, before any processing, to this file. {node} in
+    /// the path is replaced by the node domain, which is otherwise added before the extension
+    #[arg(long)]
+    tee_raw: Option<String>,
+
+    /// Record the frames of all nodes with their receive times into this capture file, which
+    /// the replay subcommand feeds back through the filters, formats, and sinks
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Mask the matches of the --redact and --redact-builtin patterns in the frames recorded
+    /// with --record and --anomaly-record-dir too, byte for byte with asterisks, so that binary
+    /// frames stay decodable
+    #[arg(long, requires = "recording")]
+    record_redacted: bool,
+
+    /// IANA timezone of all rendered timestamps, time-based file names, and index names
+    #[arg(long, default_value = "UTC", value_parser = clock::parse_timezone)]
+    timezone: chrono_tz::Tz,
+
+    /// Index log events into this Elasticsearch or OpenSearch cluster
+    #[arg(long, env = "ELASTICSEARCH_URL")]
+    elasticsearch_url: Option<Url>,
+
+    /// strftime pattern for the index names, evaluated on the event time
+    #[arg(
+        long,
+        default_value = "ic-bn-logs-%Y.%m.%d",
+        value_parser = sinks::elasticsearch::parse_index_pattern
+    )]
+    elasticsearch_index: String,
+
+    /// API key for the Elasticsearch cluster
+    #[arg(long, env = "ELASTICSEARCH_API_KEY", hide_env_values = true)]
+    elasticsearch_api_key: Option<String>,
+
+    /// Number of log events per Elasticsearch bulk request
+    #[arg(long, default_value_t = 500)]
+    elasticsearch_batch_size: usize,
+
+    /// Longest time a log event waits before being sent to Elasticsearch
+    #[arg(long, default_value = "5s", value_parser = timespec::parse_positive_duration)]
+    elasticsearch_flush_interval: Duration,
+
+    /// Also index log events into this second cluster and cross-check that both clusters
+    /// accept the same documents, e.g. while migrating to a new cluster
+    #[arg(long, env = "MIRROR_ELASTICSEARCH_URL", requires = "elasticsearch_url")]
+    mirror_elasticsearch_url: Option<Url>,
+
+    /// API key for the mirror Elasticsearch cluster
+    #[arg(long, env = "MIRROR_ELASTICSEARCH_API_KEY", hide_env_values = true)]
+    mirror_elasticsearch_api_key: Option<String>,
+
+    /// Length of the time buckets in which the documents of both clusters are counted and
+    /// hashed
+    #[arg(
+        long,
+        default_value = "60s",
+        value_parser = timespec::parse_positive_duration,
+        requires = "mirror_elasticsearch_url"
+    )]
+    mirror_bucket: Duration,
+
+    /// Time after the end of a bucket before it is cross-checked, to let retried batches
+    /// arrive
+    #[arg(
+        long,
+        default_value = "5m",
+        value_parser = timespec::parse_duration,
+        requires = "mirror_elasticsearch_url"
+    )]
+    mirror_grace: Duration,
+
+    /// Archive log events as gzip-compressed NDJSON objects, one per canister and hour, in
+    /// this S3 bucket
+    #[arg(long, env = "S3_BUCKET", value_parser = sinks::s3::parse_bucket)]
+    s3_bucket: Option<String>,
+
+    /// URL of an S3-compatible object store, e.g. http://localhost:9000 for MinIO; defaults
+    /// to AWS S3 in the --s3-region
+    #[arg(long, env = "S3_ENDPOINT", requires = "s3_bucket")]
+    s3_endpoint: Option<Url>,
+
+    /// Region that S3 requests are signed for
+    #[arg(long, env = "AWS_REGION", default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Prefix of the keys of the archived objects, e.g. logs/
+    #[arg(long, default_value = "", requires = "s3_bucket")]
+    s3_prefix: String,
+
+    /// Size in MiB of the parts of multipart uploads; larger objects are uploaded in parts
+    #[arg(long, default_value = "8", value_parser = sinks::s3::parse_part_size)]
+    s3_part_size_mb: u64,
+
+    /// Longest time a log event waits before its object is uploaded to S3
+    #[arg(long, default_value = "5m", value_parser = timespec::parse_positive_duration)]
+    s3_flush_interval: Duration,
+
+    /// Forward log events as length-prefixed JSON over mutual TLS to a collector, e.g.
+    /// tls://collector.example.com:6514
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = sinks::forward::parse_collector,
+        requires_all = ["forward_client_cert", "forward_client_key"]
+    )]
+    forward: Option<Collector>,
+
+    /// PEM file with the certificate chain that authenticates the client to the --forward
+    /// collector
+    #[arg(long, requires = "forward")]
+    forward_client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the --forward-client-cert
+    #[arg(long, requires = "forward")]
+    forward_client_key: Option<PathBuf>,
+
+    /// PEM file with root certificates to trust in addition to the public roots when
+    /// verifying the --forward collector (repeatable)
+    #[arg(long, requires = "forward")]
+    forward_ca_cert: Vec<PathBuf>,
+
+    /// Export log events as OpenTelemetry log records to this OTLP/HTTP endpoint, e.g.
+    /// http://localhost:4318
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        value_name = "URL",
+        value_parser = sinks::otlp::parse_endpoint
+    )]
+    otlp_endpoint: Option<Url>,
+
+    /// Header sent with every OTLP export request, e.g. for authentication, as KEY=VALUE
+    /// (repeatable)
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_HEADERS",
+        hide_env_values = true,
+        value_delimiter = ',',
+        value_parser = sinks::otlp::parse_header,
+        requires = "otlp_endpoint"
+    )]
+    otlp_header: Vec<(String, String)>,
+
+    /// Also append the log events as rows to this CSV file, for pandas, DuckDB, or a
+    /// spreadsheet
+    #[arg(long, value_name = "PATH")]
+    csv_out: Option<PathBuf>,
+
+    /// Also write the log events to this Parquet file, which is replaced and only complete
+    /// once the client exits
+    #[arg(long, value_name = "PATH")]
+    parquet_out: Option<PathBuf>,
+
+    /// Buffer batches on disk in this directory while a remote sink is unreachable, and
+    /// deliver them once it recovers, also after a restart
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+
+    /// Maximum size in MiB of the spool of each sink; the oldest batches are dropped beyond it
+    #[arg(long, default_value_t = 1024, requires = "spool_dir")]
+    spool_max_mb: u64,
+
+    /// Serve the aggregated log stream over gRPC on this address, e.g. 127.0.0.1:50051
+    #[arg(long)]
+    grpc_addr: Option<SocketAddr>,
+
+    /// Relay the aggregated log stream to WebSocket clients on this address, e.g.
+    /// 127.0.0.1:8080
+    #[arg(long)]
+    serve_ws: Option<SocketAddr>,
+
+    /// Origin of web pages allowed to connect to --serve-ws, e.g. http://localhost:3000
+    /// (repeatable); connections without an Origin header, i.e. not from browsers, are
+    /// always allowed
+    #[arg(long, requires = "serve_ws")]
+    serve_ws_allow_origin: Vec<String>,
+
+    /// Accept requests to add and remove canisters at runtime over HTTP on this address, e.g.
+    /// 127.0.0.1:9090; there is no authentication, so keep it on a loopback address
+    #[arg(long)]
+    control_addr: Option<SocketAddr>,
+
+    /// Serve a dashboard with the live log stream, the status of the nodes, and filter
+    /// controls on this address, e.g. 127.0.0.1:3000; there is no authentication, so keep it
+    /// on a loopback address
+    #[arg(long)]
+    web_ui: Option<SocketAddr>,
+
+    /// Also report log events to the Windows Event Log under this source in the Application
+    /// log, registering the source if needed
+    #[cfg(windows)]
+    #[arg(long)]
+    event_log_source: Option<String>,
+
+    /// Also log events to the unified logging system, with the canister as subsystem and the
+    /// node as category
+    #[cfg(target_os = "macos")]
+    #[arg(long)]
+    os_log: bool,
+
+    /// Print each log line only once, even though every boundary node delivers it
+    #[arg(long)]
+    dedup: bool,
+
+    /// Time within which identical lines from different nodes are treated as duplicates
+    #[arg(long, default_value = "60s", value_parser = timespec::parse_positive_duration)]
+    dedup_window: Duration,
+
+    /// Share the dedup state with other instances through this Redis server (implies --dedup)
+    #[arg(long, env = "DEDUP_REDIS_URL")]
+    dedup_redis: Option<String>,
+
+    /// Prefix of the keys stored in Redis, to separate independent capture setups
+    #[arg(long, default_value = "ic-bn-logs", requires = "dedup_redis")]
+    dedup_namespace: String,
+
+    /// Print each line only once it was received from this many distinct nodes, as a defense
+    /// against a single node injecting lines; lines received from fewer nodes are printed when
+    /// the confirmation window ends, flagged as suspect. Implies deduplication
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u16).range(2..),
+        conflicts_with = "dedup_redis"
+    )]
+    confirm_nodes: Option<u16>,
+
+    /// Time within which a line must be received from the --confirm-nodes nodes
+    #[arg(
+        long,
+        default_value = "10s",
+        value_parser = timespec::parse_positive_duration,
+        requires = "confirm_nodes"
+    )]
+    confirm_window: Duration,
+
+    /// Fire an alert when a log line matches this regular expression (repeatable)
+    #[arg(long, requires = "alert_destination")]
+    alert_pattern: Vec<Regex>,
+
+    /// Webhook that receives a JSON POST for every alert
+    #[arg(long, env = "ALERT_WEBHOOK_URL", requires = "alert_pattern")]
+    alert_webhook: Option<Url>,
+
+    /// Slack incoming webhook that receives a message for every alert
+    #[arg(long, env = "SLACK_WEBHOOK_URL", requires = "alert_pattern")]
+    slack_webhook: Option<Url>,
+
+    /// Discord channel webhook that receives a message for every alert
+    #[arg(long, env = "DISCORD_WEBHOOK_URL", requires = "alert_pattern")]
+    discord_webhook: Option<Url>,
+
+    /// Template of the Slack and Discord alert messages, with the fields of --format
+    /// [default: "{canister} on {node}: {msg}"]
+    #[arg(long, value_parser = Template::parse, requires = "alert_pattern")]
+    alert_template: Option<Template>,
+
+    /// Minimum time between two alerts for the same pattern; matches in between are counted
+    /// and reported with the next alert
+    #[arg(long, default_value = "60s", value_parser = timespec::parse_duration)]
+    alert_min_interval: Duration,
+
+    /// Send at most one alert per pattern for identical lines within this time
+    #[arg(long, value_parser = timespec::parse_positive_duration, requires = "alert_pattern")]
+    alert_dedup_window: Option<Duration>,
+
+    /// Report a canister whose line or error line count in a window is this many times its
+    /// baseline, or whose line count is this many times below it, e.g. 3
+    #[arg(long, value_name = "FACTOR", value_parser = anomaly::parse_factor)]
+    anomaly_factor: Option<f64>,
+
+    /// Window in which the lines are counted and compared with the baselines
+    #[arg(
+        long,
+        default_value = "1m",
+        value_parser = timespec::parse_positive_duration,
+        requires = "anomaly_factor"
+    )]
+    anomaly_window: Duration,
+
+    /// Time over which the baselines average the counts of the windows
+    #[arg(
+        long,
+        default_value = "1h",
+        value_parser = timespec::parse_positive_duration,
+        requires = "anomaly_factor"
+    )]
+    anomaly_baseline: Duration,
+
+    /// Fewest lines in a window that make a spike, and smallest baseline that makes a drop
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10,
+        requires = "anomaly_factor"
+    )]
+    anomaly_min_lines: u64,
+
+    /// Webhook that receives a JSON POST for every anomaly and recovery
+    #[arg(long, env = "ANOMALY_WEBHOOK_URL", requires = "anomaly_factor")]
+    anomaly_webhook: Option<Url>,
+
+    /// Keep the frames of all nodes in memory and, when an anomaly is reported, write those
+    /// of the last --anomaly-record-before and the next --anomaly-record-after to a capture
+    /// file in this directory, unaffected by sampling and filters
+    #[arg(long, value_name = "DIR", requires = "anomaly_factor")]
+    anomaly_record_dir: Option<PathBuf>,
+
+    /// Time of frames before an anomaly kept in memory for --anomaly-record-dir
+    #[arg(
+        long,
+        default_value = "5m",
+        value_parser = timespec::parse_positive_duration,
+        requires = "anomaly_record_dir"
+    )]
+    anomaly_record_before: Duration,
+
+    /// Time after an anomaly whose frames are recorded with --anomaly-record-dir
+    #[arg(
+        long,
+        default_value = "5m",
+        value_parser = timespec::parse_positive_duration,
+        requires = "anomaly_record_dir"
+    )]
+    anomaly_record_after: Duration,
+
+    /// Annotate upgrades and restarts of the canisters in the output, recognized by marker
+    /// lines and by changes of their module hashes
+    #[arg(long)]
+    annotate_upgrades: bool,
+
+    /// Recognize lines matching this regular expression as markers of an upgrade or restart
+    /// instead of the default markers, e.g. post_upgrade (repeatable)
+    #[arg(long, value_name = "REGEX", requires = "annotate_upgrades")]
+    upgrade_pattern: Vec<Regex>,
+
+    /// Interval at which the module hashes of the canisters are read
+    #[arg(
+        long,
+        default_value = "1m",
+        value_parser = timespec::parse_positive_duration,
+        requires = "annotate_upgrades"
+    )]
+    upgrade_poll_interval: Duration,
+
+    /// Only print log lines matching this regular expression (repeatable)
+    #[arg(long)]
+    include: Vec<Regex>,
+
+    /// Do not print log lines matching this regular expression (repeatable)
+    #[arg(long)]
+    exclude: Vec<Regex>,
+
+    /// Only print log lines satisfying this expression, e.g. 'node ~ "fr1" && level >= warn'
+    /// (repeatable, all must hold)
+    #[arg(long = "where", value_name = "EXPR", value_parser = Query::parse)]
+    condition: Vec<Query>,
+
+    /// Also print this many lines of the same node and canister before every line that
+    /// passes the filters or matches an alert pattern, like grep -B
+    #[arg(short = 'B', long, value_name = "N", default_value_t = 0)]
+    before: usize,
+
+    /// Also print this many lines of the same node and canister after every line that passes
+    /// the filters or matches an alert pattern, like grep -A
+    #[arg(short = 'A', long, value_name = "N", default_value_t = 0)]
+    after: usize,
+
+    /// Mask matches of this regular expression as [REDACTED] in the lines and their fields
+    /// before the filters, alerts, and outputs see them (repeatable)
+    #[arg(long)]
+    redact: Vec<Regex>,
+
+    /// Mask built-in kinds of sensitive data: principals, emails, or hex-secrets (repeatable,
+    /// or comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    redact_builtin: Vec<BuiltinPattern>,
+
+    /// Keep only this share of the lines, chosen at random, e.g. 1/100 for one line in a
+    /// hundred
+    #[arg(long, value_parser = SampleRate::parse)]
+    sample: Option<SampleRate>,
+
+    /// Sample by the key this regular expression extracts from each line, its first capture
+    /// group or else its whole match, e.g. a request ID, keeping all lines of a key or none
+    #[arg(long, requires = "sample")]
+    sample_keyed: Option<Regex>,
+
+    /// Check every line for signs of injection attempts, such as control characters or
+    /// imitations of the client's own output, and list them in the taint field of structured
+    /// outputs
+    #[arg(long)]
+    detect_injection: bool,
+
+    /// Field to attach to every line, as KEY=VALUE, e.g. the deployment version or tenant
+    /// (repeatable)
+    #[arg(long, value_parser = annotate::parse_field)]
+    annotate: Vec<(String, String)>,
+
+    /// Rhai script that can drop, modify, or annotate each line before the filters and
+    /// outputs see it (repeatable; the scripts run in order)
+    #[arg(long)]
+    script: Vec<PathBuf>,
+
+    /// Exit with code 0 after the first printed line matching this regular expression
+    #[arg(long)]
+    exit_on_match: Option<Regex>,
+
+    /// Exit with code 3 after printing this many lines
+    #[arg(long)]
+    exit_after_lines: Option<NonZeroU64>,
+
+    /// Exit with code 2 after this long, e.g. 5m or 1h30m
+    #[arg(long, value_parser = timespec::parse_positive_duration)]
+    exit_after: Option<Duration>,
+
+    /// Print binary frames as plain text instead of decoding CBOR and Candid log records
+    /// (same as --codec text)
+    #[arg(long, conflicts_with = "codec")]
+    raw: bool,
+
+    /// Codec of the binary frames of nodes that do not announce one: auto, text, cbor,
+    /// cbor-seq (batches of CBOR records), or candid
+    #[arg(long, default_value = "auto", value_parser = codec::parse_codec)]
+    codec: &'static dyn Codec,
+
+    /// What to do with binary payloads that are neither valid UTF-8 nor CBOR or Candid
+    /// records
+    #[arg(
+        long,
+        alias = "invalid-utf8",
+        value_enum,
+        default_value_t = InvalidUtf8::Skip
+    )]
+    binary: InvalidUtf8,
+
+    /// Directory into which --binary raw-file writes the payloads, one file per canister and
+    /// payload
+    #[arg(long, required_if_eq("binary", "raw-file"))]
+    binary_dir: Option<PathBuf>,
+
+    /// Parse plain text lines of this format into structured fields
+    #[arg(long, value_enum)]
+    parse: Option<LineParser>,
+
+    /// Keep the colors and text styles that the canister's lines set with ANSI escape
+    /// sequences instead of stripping them; other escape sequences are stripped regardless
+    #[arg(long)]
+    keep_ansi: bool,
+
+    /// Field of structured records that numbers the records of a canister, e.g. idx; records
+    /// that skip numbers are reported as missed and counted in the statistics
+    #[arg(long)]
+    sequence_field: Option<String>,
+
+    /// Reconnect to a node when its records skip sequence numbers, so that the lines it
+    /// missed are resumed or replayed if the node supports it
+    #[arg(long, requires = "sequence_field")]
+    reconnect_on_sequence_gap: bool,
+
+    /// Quarantine a node after this many undecodable or oversized frames within a minute:
+    /// its lines are counted but no longer written, and its bad frames no longer logged
+    #[arg(long, value_name = "N")]
+    quarantine_threshold: Option<NonZeroUsize>,
+
+    /// Write the statistics summary on exit as JSON to this file instead of printing it
+    #[arg(long)]
+    stats_file: Option<PathBuf>,
+
+    /// Periodically redraw a table of per-node and per-canister rates and states on stderr
+    #[arg(long)]
+    stats_view: bool,
+
+    /// Time between redraws of the statistics view
+    #[arg(
+        long,
+        default_value = "2s",
+        value_parser = timespec::parse_positive_duration,
+        requires = "stats_view"
+    )]
+    stats_view_interval: Duration,
+
+    /// Print a summary of the lines among them on stdout at this interval: the line rate per
+    /// node, the line counts by severity, and the most frequent lines or patterns
+    #[arg(long, value_parser = timespec::parse_positive_duration)]
+    summary_interval: Option<Duration>,
+
+    /// Print only the summaries on stdout instead of the lines; other outputs still receive
+    /// the lines
+    #[arg(long, requires = "summary_interval")]
+    summary_only: bool,
+
+    /// Count the lines matching this regular expression in the summaries instead of the most
+    /// frequent lines (repeatable)
+    #[arg(long, requires = "summary_interval")]
+    summary_pattern: Vec<Regex>,
+
+    /// Number of the most frequent lines or patterns listed in a summary
+    #[arg(long, default_value_t = 10, requires = "summary_interval")]
+    summary_top: usize,
+
+    /// Count the lines by the text of the capture groups of this regular expression, e.g.
+    /// 'status=(\d{3})', and print the table on stdout at --count-interval (repeatable)
+    #[arg(long, value_parser = counts::parse_pattern)]
+    count_by: Vec<Regex>,
+
+    /// Interval at which the table of the --count-by counters, and the --count counts, are
+    /// printed [default for --count-by: 60s; --count prints only on exit without it]
+    #[arg(
+        long,
+        value_parser = timespec::parse_positive_duration,
+        requires = "counting"
+    )]
+    count_interval: Option<Duration>,
+
+    /// Number of the most frequent keys listed per --count-by pattern
+    #[arg(long, default_value_t = 10, requires = "count_by")]
+    count_top: usize,
+
+    /// Print no lines on stdout; other outputs still receive the lines
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Count the lines in total, per node, per severity, and per --count-pattern, and print
+    /// the counts on stdout on exit and at --count-interval
+    #[arg(long)]
+    count: bool,
+
+    /// Count the lines matching this regular expression for --count (repeatable)
+    #[arg(long, requires = "count")]
+    count_pattern: Vec<Regex>,
+
+    /// Read commands from stdin to change filters, pause output, or inspect nodes at runtime
+    #[arg(long)]
+    interactive: bool,
+
+    /// Number of lines held back while printing is paused and printed on resuming; later
+    /// lines are not printed
+    #[arg(long, default_value_t = 10_000)]
+    pause_buffer_size: usize,
+
+    /// Detach into the background, write the process ID to --pid-file, and write the
+    /// client's own diagnostics to --log-file
+    #[cfg(unix)]
+    #[arg(
+        long,
+        requires_all = ["pid_file", "log_file"],
+        conflicts_with_all = ["interactive", "stats_view", "dry_run"]
+    )]
+    daemon: bool,
+
+    /// Write the process ID to this file, removed on exit; refuses to start while the file
+    /// names another process that is still running
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Append the client's own diagnostics to this file with --daemon; reopened on SIGHUP
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", requires = "daemon")]
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Stream the logs of a canister from the API boundary nodes (the default)
+    Tail(Box<TailArgs>),
+    /// Feed a capture recorded with --record through the filters, formats, and sinks again
+    Replay(Box<ReplayArgs>),
+    /// Record a support bundle: the raw frames, the diagnostics of the connections, the node
+    /// list, and the configuration, redacted and packed into a single archive
+    Capture(Box<CaptureArgs>),
+    /// List the API boundary nodes
+    Nodes(NodesArgs),
+    /// Load-test the log stream endpoint of the API boundary nodes with parallel connections
+    Bench(BenchArgs),
+    /// Compare the lines that the API boundary nodes deliver for a canister, to find nodes that
+    /// miss lines, reorder them, or deliver extra ones
+    Verify(VerifyArgs),
+    /// Update the client to the latest release, after verifying the signature of the release
+    /// and the checksum of the download
+    SelfUpdate(SelfUpdateArgs),
+    /// Generate shell completions or a man page
+    #[command(subcommand)]
+    Generate(generate::Target),
+    /// Print a completion script for the given shell, like generate completions
+    Completions(generate::CompletionsArgs),
+    /// Show the help topics, or the page of one topic
+    Help {
+        /// The topic to show
+        topic: Option<String>,
+    },
+}
+
+#[derive(clap::Args)]
+#[command(mut_arg("canister_id", |arg| arg.required_unless_present("capture")))]
+struct ReplayArgs {
+    /// The capture file
+    capture: PathBuf,
+
+    /// Speed of the replay relative to the recording: 1 replays in real time, 10 ten times
+    /// faster, and 0 without pauses
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// Replay only the frames received at or after this time: an RFC 3339 timestamp, e.g.
+    /// 2024-06-01T13:00:00Z, or a duration before now
+    #[arg(long, value_parser = timespec::parse_time)]
+    from: Option<SystemTime>,
+
+    /// Replay only the frames received at or before this time, given like --from
+    #[arg(long, value_parser = timespec::parse_time)]
+    to: Option<SystemTime>,
+
+    /// Options as for tail; without canisters, the frames of all canisters in the capture are
+    /// replayed. Options of the connections to the nodes are ignored
+    #[command(flatten)]
+    tail: TailArgs,
+}
+
+#[derive(clap::Args)]
+struct CaptureArgs {
+    /// How long to record, e.g. 10m
+    #[arg(long, value_parser = timespec::parse_positive_duration)]
+    duration: Duration,
+
+    /// The archive to write, a gzip-compressed tar file such as bundle.tar.gz
+    #[arg(long, value_name = "PATH")]
+    out: PathBuf,
+
+    /// Options as for tail; the --redact and --redact-builtin patterns also mask the frames
+    /// and diagnostics in the bundle
+    #[command(flatten)]
+    tail: TailArgs,
+}
+
+#[derive(clap::Args)]
+struct NodesArgs {
+    /// Print the nodes as a JSON array instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Proxy for the connection to the Internet Computer (http://, socks5://, or socks5h://).
+    /// Defaults to the HTTPS_PROXY environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Read the nodes from the state of every subnet instead of only the NNS subnet, and merge
+    /// them
+    #[arg(long)]
+    all_subnets: bool,
+
+    #[command(flatten)]
+    ic: IcArgs,
+}
+
+/// Options of the API endpoints that the boundary nodes are discovered from.
+#[derive(clap::Args)]
+struct IcArgs {
+    /// API endpoint of the Internet Computer that the boundary nodes are read from, e.g. of a
+    /// testnet or a local replica (repeatable or comma-separated; the endpoints are tried in
+    /// order until one answers)
+    #[arg(long, env = "IC_URL", value_delimiter = ',', default_value = nodes::DEFAULT_IC_URL)]
+    ic_url: Vec<Url>,
+
+    /// Fetch the root key from the --ic-url endpoints instead of using the mainnet key, for
+    /// testnets; local replicas always provide their root key. Never use this on mainnet
+    #[arg(long)]
+    fetch_root_key: bool,
+}
+
+impl IcArgs {
+    fn endpoints(&self) -> Endpoints {
+        Endpoints {
+            urls: self.ic_url.clone(),
+            fetch_root_key: self.fetch_root_key,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// The canister whose log stream is requested
+    #[arg(short, long, value_parser = canister::parse_canister_id)]
+    canister_id: String,
+
+    /// Boundary node to benchmark, by domain (repeatable); defaults to all API boundary nodes
+    #[arg(long)]
+    node: Vec<String>,
+
+    /// Parallel connections opened to each node
+    #[arg(long, default_value = "10")]
+    connections: NonZeroUsize,
+
+    /// How long the connections are kept open, e.g. 30s or 5m
+    #[arg(long, default_value = "30s", value_parser = timespec::parse_positive_duration)]
+    duration: Duration,
+
+    /// Offer permessage-deflate compression to the nodes
+    #[arg(long, value_enum, default_value_t = Compression::Off)]
+    compression: Compression,
+
+    /// Print the report as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Proxy for the connections (http://, socks5://, or socks5h://). Defaults to the
+    /// HTTPS_PROXY environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Path of the log stream of a canister on the nodes, as for tail
+    #[arg(
+        long,
+        default_value = endpoint::DEFAULT_PATH_TEMPLATE,
+        value_parser = PathTemplate::parse
+    )]
+    endpoint_path_template: PathTemplate,
+
+    /// Read the nodes from the state of every subnet instead of only the NNS subnet, and merge
+    /// them
+    #[arg(long, conflicts_with = "node")]
+    all_subnets: bool,
+
+    #[command(flatten)]
+    ic: IcArgs,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// The canister whose log streams are compared
+    #[arg(short, long, value_parser = canister::parse_canister_id)]
+    canister_id: String,
+
+    /// Boundary node to compare, by domain (repeatable, at least two); defaults to all API
+    /// boundary nodes
+    #[arg(long)]
+    node: Vec<String>,
+
+    /// How long the lines are recorded, e.g. 60s or 5m
+    #[arg(long, default_value = "60s", value_parser = timespec::parse_positive_duration)]
+    duration: Duration,
+
+    /// Time a node may lag behind the others: lines first delivered within this time after
+    /// the last node connected are not compared, and the connections stay open this long
+    /// after the recording for late lines
+    #[arg(long, default_value = "5s", value_parser = timespec::parse_duration)]
+    grace: Duration,
+
+    /// Print the report as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Proxy for the connections (http://, socks5://, or socks5h://). Defaults to the
+    /// HTTPS_PROXY environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Path of the log stream of a canister on the nodes, as for tail
+    #[arg(
+        long,
+        default_value = endpoint::DEFAULT_PATH_TEMPLATE,
+        value_parser = PathTemplate::parse
+    )]
+    endpoint_path_template: PathTemplate,
+
+    /// PEM file with root certificates to trust in addition to the public roots, as for tail
+    /// (repeatable)
+    #[arg(long)]
+    tls_ca_cert: Vec<PathBuf>,
+
+    /// Connect to a node at this address instead of resolving its domain, as DOMAIN:IP, e.g.
+    /// to verify a node before its DNS record is updated (repeatable)
+    #[arg(long, value_parser = resolve::parse_override)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Read the nodes from the state of every subnet instead of only the NNS subnet, and merge
+    /// them
+    #[arg(long, conflicts_with = "node")]
+    all_subnets: bool,
+
+    #[command(flatten)]
+    ic: IcArgs,
+}
+
+#[derive(clap::Args)]
+struct SelfUpdateArgs {
+    /// Only report whether a newer release is available, exiting with 1 if one is
+    #[arg(long)]
+    check: bool,
+
+    /// Install the release with this tag, e.g. v0.2.0, even if it is not newer than the
+    /// running version; an older one only with --allow-downgrade
+    #[arg(long)]
+    tag: Option<String>,
+
+    /// Install the release given with --tag even if it is older than the running version
+    #[arg(long, requires = "tag")]
+    allow_downgrade: bool,
+
+    /// GitHub repository whose releases are installed, as OWNER/NAME
+    #[arg(long, default_value = update::DEFAULT_REPOSITORY)]
+    repository: String,
+
+    /// Base URL of the GitHub API, e.g. of a GitHub Enterprise server or a mirror
+    #[arg(long, default_value = update::DEFAULT_API_URL)]
+    api_url: String,
+
+    /// Token for the GitHub API, to read private releases or raise the rate limit
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: Option<String>,
+
+    /// Proxy for the requests (http://, socks5://, or socks5h://). Defaults to the
+    /// HTTPS_PROXY environment variable
+    #[arg(long)]
+    proxy: Option<String>,
+}
+
+/// Maximum number of log records reassembled concurrently per connection.
+const MAX_PENDING_RECORDS: usize = 64;
+
+/// Interval at which lines held for confirmation are checked for the end of their window.
+const CONFIRM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest time to wait for remote sinks and alert destinations to deliver what is queued on
+/// shutdown.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exit code when the reader of stdout went away, without --ignore-broken-pipe.
+const BROKEN_PIPE_EXIT_CODE: u8 = 141;
+
+/// The client, with the hooks of the application that embeds it.
+#[derive(Default)]
+pub struct Client {
+    annotator: Option<Annotator>,
+    sinks: Vec<Box<dyn LogSink>>,
+}
+
+impl Client {
+    /// Creates a client without hooks, as the binary runs it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the hooks of the annotator on every event, before those of `--annotate`.
+    pub fn annotator(mut self, annotator: Annotator) -> Self {
+        self.annotator = Some(annotator);
+        self
+    }
+
+    /// Also delivers the events to a sink of the application, next to the sinks enabled on the
+    /// command line; see [`LogSink`] for how it is called. Requires the `extra-sinks` feature.
+    ///
+    /// ```no_run
+    /// use ic_bn_logs_client::{BoxFuture, Client, LogEvent, LogSink};
+    /// use std::process::ExitCode;
+    /// use std::sync::mpsc::{self, SyncSender};
+    ///
+    /// struct Audit(SyncSender<LogEvent>);
+    ///
+    /// impl LogSink for Audit {
+    ///     fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+    ///         // Queue the event for the audit thread, dropping it while the thread is behind.
+    ///         let _ = self.0.try_send(event.clone());
+    ///         Box::pin(async {})
+    ///     }
+    /// }
+    ///
+    /// fn main() -> ExitCode {
+    ///     let (sender, receiver) = mpsc::sync_channel::<LogEvent>(1024);
+    ///     std::thread::spawn(move || {
+    ///         for event in receiver {
+    ///             eprintln!("audit: {} {}", event.canister_id, event.message);
+    ///         }
+    ///     });
+    ///     Client::new().sink(Audit(sender)).run()
+    /// }
+    /// ```
+    #[cfg(feature = "extra-sinks")]
+    pub fn sink(mut self, sink: impl LogSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Parses the command line of the process and runs the command it gives, on an async
+    /// runtime of its own; it must not be called from within a runtime.
+    pub fn run(self) -> ExitCode {
+        // Parse command line arguments, adding the options of the profile if one is selected.
+        let argv: Vec<OsString> = std::env::args_os().collect();
+        let cli = Cli::parse_from(&argv);
+        let profile = cli
+            .tail_args()
+            .and_then(|args| Some((args.config.clone()?, args.profile.clone()?)));
+        let cli = match profile {
+            Some((path, name)) => match profile::apply(argv, &path, &name) {
+                Ok(argv) => Cli::parse_from(argv),
+                Err(e) => {
+                    let e = Error::from(e);
+                    selflog::report_error(&e);
+                    return e.exit_code();
+                }
+            },
+            None => cli,
+        };
+        // Detach before the runtime starts its threads, which would not survive the fork.
+        #[cfg(unix)]
+        let _pid_file = match cli.tail_args().map_or(Ok(None), |args| {
+            daemon::start(
+                args.daemon,
+                args.pid_file.as_deref(),
+                args.log_file.as_deref(),
+            )
+        }) {
+            Ok(pid_file) => pid_file,
+            Err(e) => {
+                selflog::report_error(&e);
+                return e.exit_code();
+            }
+        };
+        let result = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime.block_on(self.run_command(cli)),
+            Err(e) => Err(Error::io("failed to start the async runtime")(e)),
+        };
+        match result {
+            Ok(code) => code,
+            Err(e) => {
+                selflog::report_error(&e);
+                e.exit_code()
+            }
+        }
+    }
+
+    /// Runs the subcommand given on the command line.
+    async fn run_command(self, cli: Cli) -> Result<ExitCode, Error> {
+        // Commands that print a report end quietly when its reader goes away; tailing notices the
+        // closed pipe itself, to shut down cleanly.
+        if cli.help_all
+            || !matches!(
+                cli.command,
+                None | Some(Command::Tail(_) | Command::Replay(_) | Command::Capture(_))
+            )
+        {
+            signal::default_sigpipe();
+        }
+        let mut source = Source::Nodes;
+        let args = match cli.command {
+            None if cli.help_all => {
+                print!("{}", help::render_all(&mut Cli::command()));
+                return Ok(ExitCode::SUCCESS);
+            }
+            None => cli.tail,
+            Some(Command::Tail(args)) => *args,
+            Some(Command::Replay(args)) => {
+                source = Source::Capture {
+                    path: args.capture,
+                    speed: args.speed,
+                    range: TimeRange {
+                        from: args.from,
+                        to: args.to,
+                    },
+                };
+                args.tail
+            }
+            Some(Command::Capture(args)) => {
+                return bundle::capture(*args, cli.self_log_format, cli.verbose, self).await;
+            }
+            Some(Command::Nodes(args)) => {
+                init(cli.self_log_format, cli.verbose);
+                nodes::print(&args).await?;
+                return Ok(ExitCode::SUCCESS);
+            }
+            Some(Command::Bench(args)) => {
+                init(cli.self_log_format, cli.verbose);
+                bench::run(&args).await?;
+                return Ok(ExitCode::SUCCESS);
+            }
+            Some(Command::Verify(args)) => {
+                init(cli.self_log_format, cli.verbose);
+                // Like diff, exit with 1 when the streams differ.
+                return Ok(if verify::run(&args).await? {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::from(1)
+                });
+            }
+            Some(Command::SelfUpdate(args)) => {
+                init(cli.self_log_format, cli.verbose);
+                // With --check, exit with 1 when an update is available.
+                return Ok(if update::run(&args).await? {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::from(1)
+                });
+            }
+            Some(Command::Generate(target)) => return generate(&target),
+            Some(Command::Completions(args)) => {
+                return generate(&generate::Target::Completions(args));
+            }
+            Some(Command::Help { topic: None }) => {
+                print!("{}", help::render_index());
+                return Ok(ExitCode::SUCCESS);
+            }
+            Some(Command::Help { topic: Some(name) }) => {
+                let Some(topic) = help::find(&name) else {
+                    eprint!("Unknown help topic '{name}'.\n\n{}", help::render_index());
+                    // As clap does for an unknown subcommand.
+                    return Ok(ExitCode::from(2));
+                };
+                print!("{}", help::render(topic, &Cli::command()));
+                return Ok(ExitCode::SUCCESS);
+            }
+        };
+        init(cli.self_log_format, cli.verbose);
+        tail(args, source, self).await
+    }
+}
+
+impl Cli {
+    /// Returns the options of tailing, replaying, or capturing, if that is the subcommand.
+    fn tail_args(&self) -> Option<&TailArgs> {
+        match &self.command {
+            None if self.help_all => None,
+            None => Some(&self.tail),
+            Some(Command::Tail(args)) => Some(args),
+            Some(Command::Replay(args)) => Some(&args.tail),
+            Some(Command::Capture(args)) => Some(&args.tail),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Initializes logging and TLS, which every subcommand that connects to the network needs.
+///
+/// A logger or crypto provider that an embedding application installed before is kept.
+/// Writes the completions or man pages of the command line to stdout or a directory.
+fn generate(target: &generate::Target) -> Result<ExitCode, Error> {
+    generate::run(target, Cli::command()).map_err(Error::io(match target.out_dir() {
+        Some(dir) => format!("failed to write to {}", dir.display()),
+        None => "failed to write to stdout".to_string(),
+    }))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn init(self_log_format: SelfLogFormat, verbose: u8) {
+    // Initialize env_logger. By default, it logs to stderr.
+    selflog::init(self_log_format, verbose);
+
+    // Install the default crypto provider for rustls, which fails only if one is installed.
+    if rustls::crypto::CryptoProvider::install_default(ring::default_provider()).is_err() {
+        info!("Using the rustls crypto provider installed before.");
+    }
+}
+
+/// Where the tailed frames come from.
+enum Source {
+    /// The API boundary nodes.
+    Nodes,
+    /// A capture file, replayed at a speed relative to the recording.
+    Capture {
+        path: PathBuf,
+        speed: f64,
+        range: TimeRange,
+    },
+}
+
+/// Streams the logs of the canisters until all connections end, Ctrl+C is pressed, or an exit
+/// condition is met.
+async fn tail(mut args: TailArgs, source: Source, client: Client) -> Result<ExitCode, Error> {
+    let Client {
+        mut annotator,
+        sinks,
+    } = client;
+    if let (Some(path), Some(profile)) = (&args.config, &args.profile) {
+        info!("Using profile {profile} of {}.", path.display());
+    }
+    if let Some(preset) = args.preset {
+        preset.apply(&mut args)?;
+    }
+    let selectors: Vec<String> = args
+        .canister_id
+        .iter()
+        .filter(|value| canister::is_selector(value))
+        .cloned()
+        .collect();
+    check_args(&mut args, &selectors, &source)?;
+    let proxy = proxy::resolve(args.proxy.as_deref())?;
+    if let Some(proxy) = &proxy {
+        info!("Connecting through proxy {proxy}");
+    }
+    let http_client = proxy::http_client(proxy.as_ref())?;
+
+    let Canisters {
+        ids: canister_ids,
+        names: canister_names,
+        checked: checked_canister_ids,
+    } = resolve_canisters(&args, &selectors, &source, &http_client).await?;
+    let base_settings = Settings {
+        canisters: canister_ids,
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        condition: Query::all(args.condition.clone()),
+        alerts: AlertSettings {
+            patterns: args.alert_pattern.clone(),
+            webhook: args.alert_webhook.clone(),
+            slack_webhook: args.slack_webhook.clone(),
+            discord_webhook: args.discord_webhook.clone(),
+            template: args.alert_template.clone().unwrap_or_else(|| {
+                Template::parse(alert::DEFAULT_ALERT_TEMPLATE)
+                    .expect("the default alert template is valid")
+            }),
+            min_interval: args.alert_min_interval,
+            dedup_window: args.alert_dedup_window,
+        },
+        // Mirrored clusters must stay the same, so the file cannot retarget them.
+        elasticsearch: args
+            .elasticsearch_url
+            .clone()
+            .filter(|_| args.mirror_elasticsearch_url.is_none())
+            .map(|url| ElasticsearchTarget {
+                url,
+                index_pattern: args.elasticsearch_index.clone(),
+                api_key: args.elasticsearch_api_key.clone(),
+            }),
+    };
+    let settings = match &args.config {
+        Some(path) => base_settings.with_file(path)?,
+        None => base_settings.clone(),
+    };
+    let canister_ids = settings.canisters.clone();
+    if canister_ids.is_empty() {
+        return Err(
+            "no canister to monitor: give --canister-id, --canister-name, or the \
+                    canisters of --config"
+                .into(),
+        );
+    }
+    let canister_ids_sender = tokio::sync::watch::Sender::new(canister_ids.clone());
+    // Weights may be given by canister name as well.
+    let canister_weights: HashMap<String, u32> = args
+        .canister_weight
+        .iter()
+        .map(|(canister, weight)| {
+            let canister_id = canister_names.get(canister).unwrap_or(canister);
+            (canister_id.clone(), *weight)
+        })
+        .collect();
+    if let Some(canister_id) = canister_weights
+        .keys()
+        .find(|canister_id| !canister_ids.contains(canister_id))
+    {
+        return Err(
+            format!("--canister-weight refers to unmonitored canister {canister_id}").into(),
+        );
+    }
+    if args
+        .canister_rate_limit
+        .is_some_and(|rate| rate.is_nan() || rate <= 0.0)
+    {
+        return Err("--canister-rate-limit must be positive".into());
+    }
+    if !args.annotate.is_empty() {
+        annotator
+            .get_or_insert_with(|| Annotator::new(annotate::DEFAULT_MAX_CONCURRENT))
+            .register(annotate::fixed_fields(args.annotate.clone()));
+    }
+    let scripts = if args.script.is_empty() {
+        None
+    } else {
+        Some(Arc::new(ScriptPipeline::load(&args.script)?))
+    };
+
+    // Start the monotonic clock that event receive offsets are measured against, and render
+    // all timestamps in the requested timezone.
+    clock::start_capture_clock();
+    clock::set_timezone(args.timezone);
+    memory::configure(
+        args.max_buffer_mb
+            .map(|mb| (mb.get() * 1024 * 1024).try_into().unwrap_or(usize::MAX)),
+        args.buffer_drop_policy,
+    );
+
+    // Fetch all API boundary nodes from the Internet Computer.
+    let selection = NodeSelection {
+        only: args.only_node.clone(),
+        exclude: args.exclude_node.clone(),
+    };
+    let discovery = Discovery::new(http_client.clone(), args.ic.endpoints(), args.all_subnets)
+        .with_retries(args.discovery_retries, args.node_cache.clone())
+        .with_selection(selection.clone());
+    let api_bn_domains: Vec<String> = match &source {
+        Source::Nodes => discovery.domains().await?,
+        Source::Capture { .. } => Vec::new(),
+    };
+    if let Source::Nodes = source {
+        info!("Fetched {} API boundary nodes.", api_bn_domains.len());
+        info!("{:?}", api_bn_domains);
+
+        if api_bn_domains.is_empty() && !selection.is_empty() {
+            return Err(
+                "none of the API boundary nodes match --only-node and --exclude-node".into(),
+            );
+        }
+        if api_bn_domains.is_empty() {
+            error!("No API boundary nodes found. Exiting.");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        if !args.no_preflight {
+            preflight::check(
+                http_client.clone(),
+                &args.ic.endpoints(),
+                &checked_canister_ids,
+            )
+            .await?;
+        }
+    }
+    if args.dry_run {
+        let capture = match &source {
+            Source::Nodes => None,
+            Source::Capture { path, .. } => Some(path.as_path()),
+        };
+        signal::default_sigpipe();
+        plan::print(&args, &settings, &api_bn_domains, capture)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let Destinations {
+        output,
+        ledger,
+        elasticsearch: retargetable_elasticsearch,
+        web_ui,
+    } = open_output(&args, &settings, &http_client, &canister_ids_sender, sinks).await?;
+
+    let dedup_window = args.dedup_window;
+    let dedup = match &args.dedup_redis {
+        Some(url) => {
+            info!("Sharing dedup state through Redis.");
+            Some(Deduplicator::redis(url, args.dedup_namespace.clone(), dedup_window).await?)
+        }
+        None => args.dedup.then(|| Deduplicator::in_memory(dedup_window)),
+    };
+
+    let propagation = match (args.confirm_nodes, &dedup) {
+        (Some(_), _) => Some(PropagationTracker::new(args.confirm_window)),
+        (None, Some(dedup)) => Some(PropagationTracker::new(dedup.window())),
+        (None, None) => None,
+    };
+
+    let alerter = settings.alerts.build(&http_client).map(Arc::new);
+
+    // Fetch the records the IC retains for the canisters, to write them before the live lines.
+    let (backfill, overlap) = match source {
+        Source::Nodes if args.backfill => Some(
+            Backfill::fetch(
+                http_client.clone(),
+                &args.ic.endpoints(),
+                &canister_ids,
+                args.tail,
+                args.parse,
+                args.keep_ansi,
+            )
+            .await
+            .into_parts(),
+        ),
+        _ => None,
+    }
+    .unzip();
+
+    let (bus, published) = bus::channel();
+    let config = Arc::new(ConnectionConfig {
+        canister_ids: canister_ids_sender,
+        chunk_limits: args.reassemble_chunks.then_some(ChunkLimits {
+            max_record_size: args.max_record_size,
+            max_pending: MAX_PENDING_RECORDS,
+            timeout: args.chunk_timeout,
+        }),
+        line_framing: args.line_framing,
+        continuation: args
+            .continuation_suffix
+            .as_ref()
+            .map(|suffix| Continuation::new(suffix, args.max_record_size, args.chunk_timeout)),
+        group_panics: args.group_panics,
+        node_time_field: args.node_time_field.clone(),
+        proxy,
+        tls: TlsSettings::new(
+            &args.tls_ca_cert,
+            args.tls_client_cert
+                .as_deref()
+                .zip(args.tls_client_key.as_deref()),
+            args.tls_server_name.as_deref(),
+            args.insecure_skip_verify,
+        )?,
+        headers: endpoint::handshake_headers(&args.headers, args.bearer_token.as_deref())?,
+        resolver: Resolver::new(args.resolve.clone(), args.ip_version)?,
+        compression: args.compression,
+        max_read_rate: args.max_bytes_per_sec_per_node,
+        min_ping_interval: args.min_ping_interval,
+        max_ping_interval: args.max_ping_interval,
+        pong_timeout: args.pong_timeout,
+        connect_timeouts: ConnectTimeouts {
+            connect: args.connect_timeout,
+            handshake: args.handshake_timeout,
+        },
+        replay: Mutex::new(ReplayRequest {
+            since: args.since,
+            tail: args.tail,
+        }),
+        health: HealthRegistry::default(),
+        parked: AtomicBool::new(false),
+        pool_size: AtomicUsize::new(0),
+        bus,
+        output,
+        dedup,
+        confirmer: args
+            .confirm_nodes
+            .map(|required| Confirmer::new(required as usize, args.confirm_window)),
+        propagation,
+        alerter: RwLock::new(alerter),
+        filters: FilterSet::new(
+            settings.include.clone(),
+            settings.exclude.clone(),
+            settings.condition.clone(),
+        ),
+        context: ContextWindows::new(args.before, args.after),
+        anomalies: args.anomaly_factor.map(|factor| {
+            AnomalyDetector::new(
+                AnomalySettings {
+                    factor,
+                    window: args.anomaly_window,
+                    baseline: args.anomaly_baseline,
+                    min_lines: args.anomaly_min_lines,
+                    webhook: args.anomaly_webhook.clone(),
+                },
+                http_client.clone(),
+            )
+        }),
+        upgrades: args.annotate_upgrades.then(|| {
+            UpgradeAnnotator::new(
+                args.upgrade_pattern.clone(),
+                args.upgrade_poll_interval,
+                args.json,
+            )
+        }),
+        summarizer: args
+            .summary_interval
+            .map(|_| Summarizer::new(args.summary_pattern.clone(), args.summary_top, args.json)),
+        counter: (!args.count_by.is_empty())
+            .then(|| FieldCounter::new(args.count_by.clone(), args.count_top, args.json)),
+        line_counter: args
+            .count
+            .then(|| LineCounter::new(args.count_pattern.clone(), args.json)),
+        redactor: (!args.redact.is_empty() || !args.redact_builtin.is_empty())
+            .then(|| Arc::new(Redactor::new(args.redact.clone(), &args.redact_builtin))),
+        scripts,
+        exit: (args.exit_on_match.is_some()
+            || args.exit_after_lines.is_some()
+            || args.exit_after.is_some())
+        .then(|| {
+            ExitPolicy::new(
+                args.exit_on_match.clone(),
+                args.exit_after_lines.map(NonZeroU64::get),
+                args.exit_after,
+            )
+        }),
+        binary: args.binary,
+        binary_dir: args.binary_dir.clone(),
+        parser: args.parse,
+        keep_ansi: args.keep_ansi,
+        sequence: args.sequence_field.clone().map(SequenceTracker::new),
+        reconnect_on_sequence_gap: args.reconnect_on_sequence_gap,
+        quarantine: args.quarantine_threshold.map(Quarantine::new),
+        overlap,
+        endpoint: args.endpoint_path_template.clone(),
+        codec: if args.raw {
+            codec::find("text").expect("the text codec exists")
+        } else {
+            args.codec
+        },
+        tee: args.tee_raw.as_deref().map(RawTee::new),
+        capture: args
+            .record
+            .as_deref()
+            .map(|path| {
+                CaptureWriter::create(path)
+                    .map_err(Error::io(format!("failed to create {}", path.display())))
+            })
+            .transpose()?,
+        flight_recorder: args.anomaly_record_dir.clone().map(|dir| {
+            FlightRecorder::new(dir, args.anomaly_record_before, args.anomaly_record_after)
+        }),
+        redact_capture: args.record_redacted,
+        stats: StatsRegistry::default(),
+        sessions: match &args.state_dir {
+            Some(dir) => SessionRegistry::persisted(dir).map_err(Error::io(format!(
+                "failed to open the state directory {}",
+                dir.display()
+            )))?,
+            None => SessionRegistry::default(),
+        },
+        // Canisters may be added through the config file or the control interface.
+        scheduler: (canister_ids.len() > 1
+            || args.canister_rate_limit.is_some()
+            || args.config.is_some()
+            || args.control_addr.is_some())
+        .then(|| FairScheduler::new(&canister_ids, &canister_weights, args.canister_rate_limit)),
+    });
+
+    route_bus(published, &config, &args, annotator);
+    spawn_tasks(&config, &args, &source, &http_client, web_ui.as_ref()).await?;
+
+    let live_config = match args.config.clone() {
+        Some(path) => {
+            let live_config = Arc::new(LiveConfig::new(
+                path.clone(),
+                base_settings,
+                settings,
+                http_client.clone(),
+                retargetable_elasticsearch,
+            ));
+            live_config
+                .clone()
+                .spawn_watch(config.clone())
+                .map_err(Error::io(format!("failed to watch {}", path.display())))?;
+            Some(live_config)
+        }
+        None => None,
+    };
+
+    for event in backfill.into_iter().flatten() {
+        config.bus.publish(event).await;
+    }
+
+    let mut signals = Signals::new().map_err(Error::io("failed to install the signal handlers"))?;
+    let run = stream(&source, api_bn_domains, &discovery, &config, &args);
+    tokio::pin!(run);
+    let exit_condition = async {
+        match &config.exit {
+            Some(exit) => exit.wait().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(exit_condition);
+    let mut result = Ok(());
+    let mut stdout_closed = false;
+    loop {
+        tokio::select! {
+            outcome = &mut run => {
+                result = outcome;
+                break;
+            }
+            _ = config.output.stdout_closed(), if !stdout_closed => {
+                stdout_closed = true;
+                if !args.ignore_broken_pipe {
+                    info!("Stdout was closed, shutting down WebSocket clients.");
+                    break;
+                }
+                warn!("Stdout was closed; delivering the lines to the other destinations only.");
+            }
+            reason = &mut exit_condition => {
+                info!("Exiting because {}.", reason.describe());
+                break;
+            }
+            signal = signals.recv() => match signal {
+                Signal::Shutdown(name) => {
+                    info!("Received {name}, shutting down WebSocket clients.");
+                    break;
+                }
+                Signal::Reload => reload(&config, live_config.as_deref()),
+            },
+        }
+    }
+
+    #[cfg(unix)]
+    systemd::notify_stopping();
+
+    shut_down(&config, &args, ledger.as_deref()).await;
+
+    result?;
+    if stdout_closed && !args.ignore_broken_pipe {
+        // The code of a process killed by SIGPIPE, as shells report it.
+        return Ok(ExitCode::from(BROKEN_PIPE_EXIT_CODE));
+    }
+    Ok(match &config.exit {
+        Some(exit) => exit.finish().code(),
+        None => ExitCode::SUCCESS,
+    })
+}
+
+/// Streams the logs from the nodes, or from the capture, through the pipeline until all
+/// connections end or the capture is replayed.
+async fn stream(
+    source: &Source,
+    api_bn_domains: Vec<String>,
+    discovery: &Discovery,
+    config: &Arc<ConnectionConfig>,
+    args: &TailArgs,
+) -> Result<(), Error> {
+    match source {
+        Source::Nodes => {
+            let api_bn_domains = match args.nearest {
+                Some(nearest) => {
+                    info!(
+                        "Measuring the latency of the nodes to connect to the {nearest} nearest."
+                    );
+                    nearest::rank(api_bn_domains, config.clone())
+                        .await
+                        .into_iter()
+                        .map(|(domain, _)| domain)
+                        .collect()
+                }
+                None => api_bn_domains,
+            };
+            // Spawn a task for each selected domain to handle its WebSocket connection
+            // independently.
+            let mut pool = Pool::new(
+                api_bn_domains,
+                config.clone(),
+                args.nodes_strategy
+                    .max_connections(args.nearest.or(args.max_connections).map(NonZeroUsize::get)),
+                args.rebalance_interval,
+                args.gap_timeout,
+                args.standby,
+                Supervisor::new(
+                    config.clone(),
+                    args.restart_budget.get(),
+                    args.restart_burst.get(),
+                    args.breaker_cooldown,
+                ),
+            );
+            if args.nearest.is_some() {
+                pool = pool.with_nearest(args.nearest_refresh);
+            }
+            if let Some(hours) = args.active_hours {
+                pool = pool.with_active_hours(hours, discovery.clone(), args.active_hours_backfill);
+            }
+            info!("WebSocket clients started. Press Ctrl+C to exit.");
+            pool.run().await;
+            info!("All WebSocket connections have ended.");
+            Ok(())
+        }
+        Source::Capture { path, speed, range } => {
+            info!("Replaying {}. Press Ctrl+C to exit.", path.display());
+            capture::replay(path, *speed, *range, config)
+                .await
+                .map_err(Error::Replay)
+        }
+    }
+}
+
+/// Writes the lines still waiting for confirmation, on the bus, or in the output scheduler,
+/// and the final reports, then gives remote sinks a chance to deliver what is still queued.
+async fn shut_down(config: &ConnectionConfig, args: &TailArgs, ledger: Option<&MirrorLedger>) {
+    if let Some(confirmer) = &config.confirmer {
+        for event in confirmer.drain() {
+            config.bus.publish(event).await;
+        }
+    }
+    config.bus.flush().await;
+    if let Some(scheduler) = &config.scheduler {
+        for event in scheduler.drain() {
+            config.output.write(&event).await;
+        }
+    }
+    if let Some(summarizer) = &config.summarizer
+        && let Some(summary) = summarizer.take()
+    {
+        config.output.write_text(&summary);
+    }
+    if let Some(counter) = &config.counter {
+        config.output.write_text(&counter.render());
+    }
+    if let Some(counter) = &config.line_counter {
+        config.output.write_text(&counter.render());
+    }
+    config.sessions.save();
+    let flush = async {
+        config.output.shutdown().await;
+        let alerter = config.alerter.read().unwrap().clone();
+        if let Some(alerter) = alerter {
+            alerter.flush().await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, flush)
+        .await
+        .is_err()
+    {
+        error!("Timed out delivering queued log events and alerts.");
+    }
+    if let Err(e) = config.output.close() {
+        error!("Failed to finish the output file: {e}");
+    }
+    if let Some(capture) = &config.capture {
+        capture.finish();
+    }
+    if let Some(recorder) = &config.flight_recorder {
+        recorder.finish(true);
+    }
+    match &args.stats_file {
+        Some(path) => {
+            if let Err(e) = config.stats.write_summary(path) {
+                error!("Failed to write statistics to {}: {e}", path.display());
+            }
+        }
+        None => config.stats.print_summary(),
+    }
+    if let Some(ledger) = ledger {
+        match ledger.check(true) {
+            0 => info!("Mirrored Elasticsearch clusters accepted the same documents."),
+            diverging => {
+                error!("Mirrored Elasticsearch clusters diverge in {diverging} time buckets.")
+            }
+        }
+    }
+}
+
+/// The canisters to monitor, as far as they are known before the config file is read.
+struct Canisters {
+    /// IDs of all the canisters.
+    ids: Vec<String>,
+    /// IDs of the canisters given by name, by their names.
+    names: HashMap<String, String>,
+    /// The canisters given explicitly, whose logs the preflight check confirms are readable.
+    checked: Vec<String>,
+}
+
+/// Resolves the canisters given by ID, URL, and name, and looks up those that the selectors
+/// pick in the subnet or the capture.
+async fn resolve_canisters(
+    args: &TailArgs,
+    selectors: &[String],
+    source: &Source,
+    http_client: &reqwest::Client,
+) -> Result<Canisters, Error> {
+    let mut canister_ids: Vec<String> = args
+        .canister_id
+        .iter()
+        .filter(|value| !canister::is_selector(value))
+        .cloned()
+        .collect();
+    for url in &args.url {
+        let canister_id = canister::resolve_url(url, http_client).await?;
+        info!("Resolved {url} to canister {canister_id}.");
+        if !canister_ids.contains(&canister_id) {
+            canister_ids.push(canister_id);
+        }
+    }
+    let mut canister_names = HashMap::new();
+    for name in &args.canister_name {
+        let canister_id = canister::resolve(name, args.canister_map.as_deref(), &args.project)?;
+        info!("Resolved canister {name} to {canister_id}.");
+        if !canister_ids.contains(&canister_id) {
+            canister_ids.push(canister_id.clone());
+        }
+        canister_names.insert(name.clone(), canister_id);
+    }
+    // The canisters of a subnet are known to exist and have public logs.
+    let checked_canister_ids = canister_ids.clone();
+    if let (Some(subnet), Source::Nodes) = (&args.subnet, source) {
+        let subnet_canister_ids = subnet::canisters(
+            http_client.clone(),
+            &args.ic.endpoints(),
+            subnet,
+            subnet::Enumeration {
+                selectors,
+                limit: args.subnet_max_canisters.max(1),
+                max_empty_batches: args.subnet_max_empty_batches.max(1),
+            },
+        )
+        .await?;
+        for canister_id in subnet_canister_ids {
+            if !canister_ids.contains(&canister_id) {
+                canister_ids.push(canister_id);
+            }
+        }
+    }
+    if let Source::Capture { path, .. } = source
+        && (canister_ids.is_empty() || !selectors.is_empty())
+    {
+        for canister_id in capture::canisters(path)? {
+            if (selectors.is_empty()
+                || selectors
+                    .iter()
+                    .any(|selector| canister::selects(selector, &canister_id)))
+                && !canister_ids.contains(&canister_id)
+            {
+                canister_ids.push(canister_id);
+            }
+        }
+    }
+    Ok(Canisters {
+        ids: canister_ids,
+        names: canister_names,
+        checked: checked_canister_ids,
+    })
+}
+
+/// Applies the shorthands among the arguments and rejects the combinations of them that clap
+/// cannot express; `selectors` are the values of `--canister-id` that select canisters.
+fn check_args(args: &mut TailArgs, selectors: &[String], source: &Source) -> Result<(), Error> {
+    if let Some(interval) = args.ping_interval {
+        args.min_ping_interval = interval;
+        args.max_ping_interval = interval;
+    }
+    if args.min_ping_interval > args.max_ping_interval {
+        return Err("--min-ping-interval must be at most --max-ping-interval".into());
+    }
+    // A replay selects among the canisters of the capture instead.
+    if let (Some(selector), None, Source::Nodes) = (selectors.first(), &args.subnet, source) {
+        return Err(format!("--canister-id {selector} selects the canisters of a --subnet").into());
+    }
+    if args.subnet.is_some() && selectors.is_empty() {
+        return Err(
+            "--subnet requires --canister-id all or a prefix pattern such as rdmx6-*".into(),
+        );
+    }
+    // A subnet may host hundreds of canisters, each of which takes a connection per node.
+    if args.subnet.is_some()
+        && args.nodes_strategy == NodesStrategy::All
+        && args.max_connections.is_none()
+        && args.nearest.is_none()
+    {
+        args.nodes_strategy = NodesStrategy::Single;
+    }
+    if args.nodes_strategy == NodesStrategy::Single && args.max_connections.is_some() {
+        return Err("--max-connections cannot be combined with --nodes-strategy single".into());
+    }
+    if args.standby > 0
+        && args.nodes_strategy == NodesStrategy::All
+        && args.max_connections.is_none()
+        && args.nearest.is_none()
+    {
+        return Err(
+            "--standby requires --max-connections, --nearest, or --nodes-strategy quorum|single"
+                .into(),
+        );
+    }
+    if let (Some(required), Some(max_connections)) = (
+        args.confirm_nodes,
+        args.nodes_strategy
+            .max_connections(args.nearest.or(args.max_connections).map(NonZeroUsize::get)),
+    ) && max_connections < required as usize
+    {
+        return Err(format!(
+            "--confirm-nodes {required} cannot be reached with at most {max_connections} \
+             connections"
+        )
+        .into());
+    }
+    if let Source::Capture { speed, range, .. } = source {
+        if !(*speed >= 0.0 && speed.is_finite()) {
+            return Err("--speed must not be negative".into());
+        }
+        if let (Some(from), Some(to)) = (range.from, range.to)
+            && from > to
+        {
+            return Err("--from must not be later than --to".into());
+        }
+    }
+    Ok(())
+}
+
+/// Starts the tasks that run next to the connections: the output scheduler, confirmation,
+/// the periodic reports and views, and the control interface and web dashboard.
+async fn spawn_tasks(
+    config: &Arc<ConnectionConfig>,
+    args: &TailArgs,
+    source: &Source,
+    http_client: &reqwest::Client,
+    web_ui: Option<&WebUiSink>,
+) -> Result<(), Error> {
+    if config.scheduler.is_some() {
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Some(scheduler) = &config.scheduler {
+                scheduler
+                    .run(
+                        || config.output.ready(),
+                        |event| {
+                            let output = &config.output;
+                            async move { output.write(&event).await }
+                        },
+                    )
+                    .await;
+            }
+        });
+    }
+
+    if config.confirmer.is_some() {
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Some(confirmer) = &config.confirmer {
+                let mut ticker = tokio::time::interval(CONFIRM_CHECK_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    for event in confirmer.take_expired() {
+                        config.bus.publish(event).await;
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if let Source::Nodes = source {
+        systemd::spawn(config.clone());
+    }
+    anomaly::spawn(config.clone());
+    flight::spawn(config.clone());
+
+    // The statistics view redraws stderr, which the status line would disturb.
+    if let Source::Nodes = source
+        && !args.stats_view
+    {
+        status::spawn(config.clone());
+    }
+    if args.stats_view {
+        top::spawn(config.clone(), args.stats_view_interval);
+    }
+    if let Some(every) = args.summary_interval {
+        summary::spawn(config.clone(), every);
+    }
+    quarantine::spawn(config.clone());
+    if let Source::Nodes = source {
+        upgrades::spawn(config.clone(), http_client.clone(), args.ic.endpoints());
+    }
+    if config.sessions.is_persisted() {
+        resume::spawn(config.clone());
+    }
+    let field_interval =
+        (!args.count_by.is_empty()).then(|| args.count_interval.unwrap_or(DEFAULT_COUNT_INTERVAL));
+    if let Some(every) = field_interval.or(args.count_interval) {
+        counts::spawn(
+            config.clone(),
+            every,
+            args.count && args.count_interval.is_some(),
+        );
+    }
+    if args.interactive {
+        interactive::spawn(config.clone());
+    }
+    if let Some(addr) = args.control_addr {
+        control::serve(addr, config.clone())
+            .await
+            .map_err(Error::io(format!(
+                "failed to serve the control interface on {addr}"
+            )))?;
+    }
+    if let (Some(addr), Some(sink)) = (args.web_ui, web_ui) {
+        webui::serve(addr, sink, config.clone())
+            .await
+            .map_err(Error::io(format!(
+                "failed to serve the web dashboard on {addr}"
+            )))?;
+    }
+    Ok(())
+}
+
+/// Starts the stages of the bus that the arguments enable, which carry the published events to
+/// the output.
+fn route_bus(
+    published: bus::Published,
+    config: &Arc<ConnectionConfig>,
+    args: &TailArgs,
+    annotator: Option<Annotator>,
+) {
+    let mut pipeline = bus::Pipeline::new(published, config.clone());
+    if config.anomalies.is_some() || config.upgrades.is_some() {
+        pipeline = pipeline.with_stage(bus::Observe(config.clone()));
+    }
+    if let Some(rate) = args.sample {
+        pipeline = pipeline.with_stage(bus::Sample(Sampler::new(rate, args.sample_keyed.clone())));
+    }
+    if args.detect_injection || annotator.is_some() {
+        pipeline = pipeline.with_stage(bus::Annotate {
+            detect_injection: args.detect_injection,
+            annotator,
+        });
+    }
+    if let Some(scripts) = &config.scripts {
+        pipeline = pipeline.with_stage(bus::Script(scripts.clone()));
+    }
+    if let Some(redactor) = &config.redactor {
+        pipeline = pipeline.with_stage(bus::Redact(redactor.clone()));
+    }
+    pipeline.route();
+}
+
+/// The output, with the sinks that parts of the client other than the output reach.
+struct Destinations {
+    output: Output,
+    /// Compares the documents that mirrored Elasticsearch clusters accepted, if mirroring.
+    ledger: Option<Arc<MirrorLedger>>,
+    /// The Elasticsearch sink, if enabled and not mirrored, which the config file may
+    /// retarget.
+    elasticsearch: Option<Arc<ElasticsearchSink>>,
+    /// Feeds the web dashboard, if enabled.
+    web_ui: Option<WebUiSink>,
+}
+
+/// Opens the output and starts the sinks enabled on the command line, next to the `sinks` of
+/// the application; `canister_ids` tells the servers the monitored canisters.
+async fn open_output(
+    args: &TailArgs,
+    settings: &Settings,
+    http_client: &reqwest::Client,
+    canister_ids: &tokio::sync::watch::Sender<Vec<String>>,
+    sinks: Vec<Box<dyn LogSink>>,
+) -> Result<Destinations, Error> {
+    let format = Arc::new(if args.json {
+        LineFormat::Json
+    } else {
+        LineFormat::Template(args.format.clone().unwrap_or_else(|| {
+            let template = match args.subnet {
+                Some(_) => template::SUBNET_TEMPLATE,
+                None => template::DEFAULT_TEMPLATE,
+            };
+            Template::parse(template).expect("the default templates are valid")
+        }))
+    });
+    let mut output = Output::new(format.clone());
+    let color = args.color.unwrap_or(if args.highlight.is_empty() {
+        ColorMode::Never
+    } else {
+        ColorMode::Auto
+    });
+    if color.enabled() {
+        output = output.with_painter(Painter::new(args.highlight.clone()));
+    }
+    if args.summary_only || args.quiet {
+        output = output.without_stdout_lines();
+    }
+    output = output
+        .with_encoding(args.output_encoding)
+        .with_batching(Batching::new(args.batch_lines, args.flush_interval))
+        .with_pause_buffer(args.pause_buffer_size);
+    if let Some(path) = &args.output_file {
+        output = output
+            .with_file(path, args.output_file_atomic, args.output_file_fsync)
+            .map_err(Error::io(format!("failed to open {path}")))?;
+    }
+    if let Some(dir) = &args.split_output {
+        #[cfg(unix)]
+        let fifo = args.split_output_fifo;
+        #[cfg(not(unix))]
+        let fifo = false;
+        let split = SplitOutput::new(dir.clone(), fifo)
+            .map_err(Error::io(format!("failed to create {}", dir.display())))?;
+        output = output.with_split(split);
+    }
+    let ledger = args.mirror_elasticsearch_url.as_ref().map(|mirror_url| {
+        Arc::new(MirrorLedger::new(
+            [
+                args.elasticsearch_url.as_ref().unwrap().to_string(),
+                mirror_url.to_string(),
+            ],
+            args.mirror_bucket,
+            args.mirror_grace,
+        ))
+    });
+    let target = |url: &Url, api_key: &Option<String>| ElasticsearchTarget {
+        url: url.clone(),
+        index_pattern: args.elasticsearch_index.clone(),
+        api_key: api_key.clone(),
+    };
+    let elasticsearch_targets = [
+        (
+            // The config file may have retargeted the sink already.
+            settings.elasticsearch.clone().or_else(|| {
+                args.elasticsearch_url
+                    .as_ref()
+                    .map(|url| target(url, &args.elasticsearch_api_key))
+            }),
+            "elasticsearch",
+            MirrorSide::Primary,
+        ),
+        (
+            args.mirror_elasticsearch_url
+                .as_ref()
+                .map(|url| target(url, &args.mirror_elasticsearch_api_key)),
+            "elasticsearch-mirror",
+            MirrorSide::Mirror,
+        ),
+    ];
+    let mut retargetable_elasticsearch = None;
+    for (target, spool_name, side) in elasticsearch_targets {
+        let Some(target) = target else {
+            continue;
+        };
+        let spool = open_spool(args, spool_name)?;
+        let sink = ElasticsearchSink::spawn(
+            ElasticsearchConfig {
+                url: target.url,
+                index_pattern: target.index_pattern,
+                api_key: target.api_key,
+                batch_size: args.elasticsearch_batch_size.max(1),
+                flush_interval: args.elasticsearch_flush_interval,
+                mirror: ledger.clone().map(|ledger| (ledger, side)),
+            },
+            http_client.clone(),
+            spool,
+        );
+        let sink = Arc::new(sink);
+        if ledger.is_none() {
+            retargetable_elasticsearch = Some(sink.clone());
+        }
+        output = output.with_sink(sink);
+    }
+    if let Some(bucket) = &args.s3_bucket {
+        let credentials = S3Credentials::from_env()?;
+        let mut prefix = args.s3_prefix.trim_start_matches('/').to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let sink = S3Sink::spawn(
+            S3Config {
+                endpoint: args
+                    .s3_endpoint
+                    .clone()
+                    .unwrap_or_else(|| sinks::s3::aws_endpoint(&args.s3_region)),
+                bucket: bucket.clone(),
+                region: args.s3_region.clone(),
+                prefix,
+                credentials,
+                part_size: (args.s3_part_size_mb * 1024 * 1024) as usize,
+                flush_interval: args.s3_flush_interval,
+            },
+            http_client.clone(),
+        );
+        output = output.with_sink(sink);
+    }
+    if let (Some(collector), Some(cert), Some(key)) = (
+        &args.forward,
+        &args.forward_client_cert,
+        &args.forward_client_key,
+    ) {
+        let tls = TlsSettings::new(&args.forward_ca_cert, Some((cert, key)), None, false)?;
+        let spool = open_spool(args, "forward")?;
+        output = output.with_sink(ForwardSink::spawn(collector.clone(), tls, spool));
+    }
+    if let Some(url) = &args.otlp_endpoint {
+        let spool = open_spool(args, "otlp")?;
+        let sink = OtlpSink::spawn(
+            OtlpConfig {
+                url: url.clone(),
+                headers: args.otlp_header.clone(),
+            },
+            http_client.clone(),
+            spool,
+        );
+        output = output.with_sink(sink);
+    }
+    if let Some(path) = &args.csv_out {
+        let writer = CsvWriter::open(path)
+            .map_err(Error::io(format!("failed to open {}", path.display())))?;
+        output = output.with_sink(TableSink::spawn("CSV", path.clone(), writer));
+    }
+    if let Some(path) = &args.parquet_out {
+        let writer = ParquetWriter::create(path)
+            .map_err(Error::io(format!("failed to create {}", path.display())))?;
+        output = output.with_sink(TableSink::spawn("Parquet", path.clone(), writer));
+    }
+    if let Some(addr) = args.grpc_addr {
+        let sink = sinks::grpc::serve(addr, canister_ids.subscribe())
+            .await
+            .map_err(Error::io(format!("failed to serve gRPC on {addr}")))?;
+        output = output.with_sink(sink);
+    }
+    if let Some(addr) = args.serve_ws {
+        let sink = sinks::websocket::serve(
+            addr,
+            canister_ids.subscribe(),
+            args.serve_ws_allow_origin.clone(),
+        )
+        .await
+        .map_err(Error::io(format!("failed to serve WebSocket on {addr}")))?;
+        output = output.with_sink(sink);
+    }
+    let web_ui = args.web_ui.map(|_| WebUiSink::default());
+    if let Some(sink) = &web_ui {
+        output = output.with_sink(sink.clone());
+    }
+    #[cfg(windows)]
+    if let Some(source) = &args.event_log_source {
+        output = output.with_sink(sinks::eventlog::EventLogSink::open(source, format.clone())?);
+    }
+    #[cfg(target_os = "macos")]
+    if args.os_log {
+        let sink = sinks::oslog::OsLogSink::spawn(format.clone())
+            .map_err(Error::io("failed to start the os_log thread"))?;
+        output = output.with_sink(sink);
+    }
+    for sink in sinks {
+        output = output.with_sink(sink);
+    }
+    if let Some(ledger) = ledger.clone() {
+        let period = args.mirror_bucket;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                ledger.check(false);
+            }
+        });
+    }
+
+    Ok(Destinations {
+        output,
+        ledger,
+        elasticsearch: retargetable_elasticsearch,
+        web_ui,
+    })
+}
+
+/// Opens the spool of a sink in its subdirectory of `--spool-dir`, if one is given.
+fn open_spool(args: &TailArgs, name: &str) -> Result<Option<Spool>, Error> {
+    let Some(dir) = &args.spool_dir else {
+        return Ok(None);
+    };
+    let dir = dir.join(name);
+    Spool::open(&dir, args.spool_max_mb * 1024 * 1024)
+        .map(Some)
+        .map_err(Error::io(format!(
+            "failed to open the spool {}",
+            dir.display()
+        )))
+}
+
+/// Reloads the scripts and the config file and reopens the output files, e.g. on SIGHUP after
+/// log rotation.
+fn reload(config: &ConnectionConfig, live_config: Option<&LiveConfig>) {
+    info!("Reloading scripts and reopening output files.");
+    if let Some(live_config) = live_config {
+        live_config.reload(config);
+    }
+    if let Some(scripts) = &config.scripts
+        && let Err(e) = scripts.reload()
+    {
+        error!("Failed to reload scripts, keeping the previous ones: {e}");
+    }
+    if let Err(e) = config.output.reopen() {
+        error!("Failed to reopen output file: {e}");
+    }
+    if let Some(tee) = &config.tee {
+        tee.reopen();
+    }
+    #[cfg(unix)]
+    if let Err(e) = daemon::reopen_log() {
+        error!("Failed to reopen log file: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    const CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+    /// Collects the events written to it, taking a moment for each.
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<LogEvent>>>);
+
+    impl LogSink for Collect {
+        fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                sleep(Duration::from_millis(1)).await;
+                self.0.lock().unwrap().push(event.clone());
+            })
+        }
+    }
+
+    /// Records the frames in a capture and replays it with the options of tail into an
+    /// application sink, returning the events that the sink received.
+    async fn replay(name: &str, frames: &[&[u8]], options: &[&str]) -> Vec<LogEvent> {
+        // Like init, which sets up the logger as well and runs once per process.
+        let _ = rustls::crypto::CryptoProvider::install_default(ring::default_provider());
+        let file = |extension| {
+            std::env::temp_dir().join(format!(
+                "ic-bn-logs-client-test-{}-{name}.{extension}",
+                std::process::id()
+            ))
+        };
+        let (path, stats_path) = (file("icblog"), file("stats.json"));
+        let capture = CaptureWriter::create(&path).unwrap();
+        for frame in frames {
+            capture.write("node-1.example.com", CANISTER_ID, "auto", frame);
+        }
+        capture.finish();
+        drop(capture);
+
+        let command_line = [
+            "ic-bn-logs-client",
+            "replay",
+            path.to_str().unwrap(),
+            "--speed",
+            "0",
+            "--quiet",
+            "--stats-file",
+            stats_path.to_str().unwrap(),
+        ];
+        let Some(Command::Replay(args)) = Cli::try_parse_from(command_line.iter().chain(options))
+            .unwrap()
+            .command
+        else {
+            unreachable!("the command line gives the replay subcommand");
+        };
+        let source = Source::Capture {
+            path: args.capture.clone(),
+            speed: args.speed,
+            range: TimeRange {
+                from: args.from,
+                to: args.to,
+            },
+        };
+        let sink = Collect::default();
+        let client = Client {
+            annotator: None,
+            sinks: vec![Box::new(sink.clone())],
+        };
+        let result = tail(args.tail, source, client).await;
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&stats_path).unwrap();
+        result.unwrap();
+        sink.0.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn replayed_lines_reach_an_application_sink_in_order() {
+        let lines: Vec<String> = (0..200).map(|i| format!("line {i}")).collect();
+        let frames: Vec<&[u8]> = lines.iter().map(|line| line.as_bytes()).collect();
+        let events = replay("order", &frames, &[]).await;
+        let messages: Vec<&str> = events.iter().map(|event| event.message.as_str()).collect();
+        assert_eq!(messages, lines);
+        assert!(events.iter().all(|event| event.canister_id == CANISTER_ID));
+    }
+
+    #[tokio::test]
+    async fn bus_stages_apply_before_the_sinks() {
+        let frames: [&[u8]; 2] = [b"login token=abc123", b"logout"];
+        let options = ["--redact", r"token=\w+", "--annotate", "team=logs"];
+        let events = replay("stages", &frames, &options).await;
+        let messages: Vec<&str> = events.iter().map(|event| event.message.as_str()).collect();
+        assert_eq!(messages, ["login [REDACTED]", "logout"]);
+        for event in &events {
+            let fields = event.fields.as_ref().unwrap();
+            assert_eq!(fields["team"], "logs", "{}", event.message);
+        }
+    }
+
+    #[tokio::test]
+    async fn undecodable_frames_are_recovered_with_the_binary_policy() {
+        let frames: [&[u8]; 3] = [b"before", b"\xff\x00ok", b"after"];
+        let messages = |events: Vec<LogEvent>| -> Vec<String> {
+            events.into_iter().map(|event| event.message).collect()
+        };
+        let skipped = replay("binary-skip", &frames, &[]).await;
+        assert_eq!(messages(skipped), ["before", "after"]);
+        let recovered = replay("binary-hex", &frames, &["--binary", "hex"]).await;
+        assert_eq!(messages(recovered), ["before", "ff006f6b", "after"]);
+    }
+}
@@ -0,0 +1,85 @@
+//! Parsing of plain text lines into structured fields.
+//!
+//! With `--parse logfmt`, lines made of `key=value` pairs, such as
+//! `level=info msg="payment settled" amount=12`, get their pairs as fields, so that JSON
+//! output and remote sinks receive real fields. Values may be quoted with double quotes, in
+//! which `\"` and `\\` are escapes; a key without a value is `true`. The message keeps the
+//! whole line, and lines that are not logfmt are left without fields.
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+/// How plain text lines are parsed into fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LineParser {
+    /// `key=value` pairs separated by spaces
+    Logfmt,
+}
+
+impl LineParser {
+    /// Parses the fields of a line, or returns `None` if the line does not have the format.
+    pub fn parse(self, line: &str) -> Option<Map<String, Value>> {
+        match self {
+            Self::Logfmt => parse_logfmt(line),
+        }
+    }
+}
+
+/// Parses a logfmt line. At least one pair must have a value, so that plain sentences are not
+/// taken for lists of keys.
+fn parse_logfmt(line: &str) -> Option<Map<String, Value>> {
+    let mut fields = Map::new();
+    let mut has_value = false;
+    let mut chars = line.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            if c == '"' || c.is_control() {
+                return None;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            return None;
+        }
+
+        let value = if chars.next_if_eq(&'=').is_some() {
+            has_value = true;
+            if chars.next_if_eq(&'"').is_some() {
+                let mut value = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => value.push('\n'),
+                            't' => value.push('\t'),
+                            c => value.push(c),
+                        },
+                        c => value.push(c),
+                    }
+                }
+                value
+            } else {
+                let mut value = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    value.push(c);
+                }
+                value
+            }
+            .into()
+        } else {
+            Value::Bool(true)
+        };
+        // A value must be followed by whitespace or the end of the line.
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        fields.insert(key, value);
+    }
+    has_value.then_some(fields)
+}
@@ -0,0 +1,392 @@
+//! The memory budget of the log events waiting in queues, set with `--max-buffer-mb`.
+//!
+//! Events wait wherever a destination is slower than the nodes: in the per-canister queues of
+//! the output scheduler, in the queues of the sinks, and in the backlog of a paused stdout.
+//! Each queue is limited in the number of events, but events can be large, so with many
+//! canisters and a slow sink the queues could together take more memory than the host has.
+//! Every queued event therefore holds a [`Reservation`] of its size against a budget shared
+//! by all queues, which is released when the event leaves the queue.
+//!
+//! When a queue is full or the budget is exhausted, the queue drops its oldest events to make
+//! room for the new one, or with `--buffer-drop-policy newest`, the new event. A queue can
+//! only drop its own events, so a new event is dropped as well when the queue is empty but the
+//! other queues hold the budget. Drops are counted per node for the scheduler, and per sink
+//! for the sinks.
+//!
+//! With `--buffer-drop-policy block`, no event is dropped. Instead, the connections wait for
+//! room in the queues before publishing their next line to the event bus, and the last stage
+//! of the bus again before routing it, see [`QueueSender::ready`], so that the processing
+//! stages of the connections fall behind and their read loops stop reading from the nodes
+//! until the destinations catch up.
+
+use crate::event::LogEvent;
+use clap::ValueEnum;
+use log::warn;
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// Most bytes that the queued events may take together.
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Bytes that the queued events take.
+static USED: AtomicUsize = AtomicUsize::new(0);
+/// Most bytes that the queued events took at any time.
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+/// What full queues do, a [`DropPolicy`] as a number.
+static POLICY: AtomicU8 = AtomicU8::new(DropPolicy::Oldest as u8);
+/// Wakes the connections waiting for room with the `block` policy whenever an event leaves a
+/// queue.
+static ROOM: Notify = Notify::const_new();
+/// Events that the queue of each sink dropped.
+static SINK_DROPS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+/// Shortest interval between two warnings about events dropped by the queue of a sink.
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which events a full queue drops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DropPolicy {
+    /// The oldest events in the queue, so that the destination receives the latest ones.
+    Oldest,
+    /// The new events, so that the destination receives a gapless start.
+    Newest,
+    /// None; the connections stop reading from the nodes until the queues have room.
+    Block,
+}
+
+/// Sets the budget in bytes, if any, and the policy of all queues.
+pub fn configure(limit: Option<usize>, policy: DropPolicy) {
+    LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn policy() -> DropPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        policy if policy == DropPolicy::Newest as u8 => DropPolicy::Newest,
+        policy if policy == DropPolicy::Block as u8 => DropPolicy::Block,
+        _ => DropPolicy::Oldest,
+    }
+}
+
+/// Returns the most bytes that the queued events took at any time, to size the budget.
+pub fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Bytes of the budget held by a queued event, released when it is dropped.
+#[derive(Debug)]
+pub struct Reservation(usize);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        USED.fetch_sub(self.0, Ordering::Relaxed);
+        if policy() == DropPolicy::Block {
+            ROOM.notify_waiters();
+        }
+    }
+}
+
+/// Reserves bytes of the budget, or returns None if they would exceed it.
+pub fn reserve(bytes: usize) -> Option<Reservation> {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let used = USED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|total| *total <= limit)
+        })
+        .ok()?;
+    PEAK.fetch_max(used + bytes, Ordering::Relaxed);
+    Some(Reservation(bytes))
+}
+
+/// Reserves bytes of the budget even if they exceed it, for events that the `block` policy
+/// queues after waiting for room.
+pub fn reserve_beyond(bytes: usize) -> Reservation {
+    let used = USED.fetch_add(bytes, Ordering::Relaxed);
+    PEAK.fetch_max(used + bytes, Ordering::Relaxed);
+    Reservation(bytes)
+}
+
+/// Waits with the `block` policy until the budget has room and `has_room` returns true, which
+/// is checked again whenever an event leaves a queue. Returns right away with other policies.
+pub async fn wait_for_room(mut has_room: impl FnMut() -> bool) {
+    while policy() == DropPolicy::Block {
+        // Registered before checking, so that an event leaving meanwhile is noticed.
+        let room = ROOM.notified();
+        if USED.load(Ordering::Relaxed) < LIMIT.load(Ordering::Relaxed) && has_room() {
+            return;
+        }
+        room.await;
+    }
+}
+
+/// Returns an estimate of the bytes an event takes in memory.
+pub fn size_of(event: &LogEvent) -> usize {
+    std::mem::size_of::<LogEvent>()
+        + event.node.len()
+        + event.canister_id.len()
+        + event.message.len()
+        + event.fields.as_ref().map_or(0, |fields| {
+            fields
+                .iter()
+                .map(|(key, value)| key.len() + size_of_value(value))
+                .sum()
+        })
+}
+
+fn size_of_value(value: &Value) -> usize {
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::String(string) => string.len(),
+            Value::Array(values) => values.iter().map(size_of_value).sum(),
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(key, value)| key.len() + size_of_value(value))
+                .sum(),
+            _ => 0,
+        }
+}
+
+/// Returns the number of events dropped by the queue of each sink that dropped any.
+pub fn sink_drops() -> BTreeMap<&'static str, u64> {
+    SINK_DROPS.lock().unwrap().clone()
+}
+
+/// Creates the queue of a sink, holding up to `capacity` events.
+pub fn queue<T>(sink: &'static str, capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        sink,
+        capacity,
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            events: 0,
+            closed: false,
+            abandoned: false,
+            dropped: 0,
+            last_drop_warning: None,
+        }),
+        notify: Notify::new(),
+        ready: Condvar::new(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+struct Shared<T> {
+    /// Name of the sink, for the drop counters and warnings.
+    sink: &'static str,
+    capacity: usize,
+    state: Mutex<State<T>>,
+    /// Wakes an asynchronous receiver.
+    notify: Notify,
+    /// Wakes a blocking receiver.
+    ready: Condvar,
+}
+
+struct State<T> {
+    /// The queued items, with the reservations of the events; other items are never dropped.
+    items: VecDeque<(T, Option<Reservation>)>,
+    /// Number of events among the items.
+    events: usize,
+    /// Set once the sender is gone.
+    closed: bool,
+    /// Set once the receiver is gone, after which items are dropped right away.
+    abandoned: bool,
+    dropped: u64,
+    last_drop_warning: Option<Instant>,
+}
+
+impl<T> State<T> {
+    /// Drops the oldest event, returning false if there is none.
+    fn drop_oldest(&mut self) -> bool {
+        match self
+            .items
+            .iter()
+            .position(|(_, reservation)| reservation.is_some())
+        {
+            Some(index) => {
+                self.items.remove(index);
+                self.events -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Queues items for a sink.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queues an event taking `bytes` of memory, dropping it or older events if the queue is
+    /// full or the budget is exhausted.
+    pub fn send_event(&self, item: T, bytes: usize) {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.abandoned {
+            return;
+        }
+        let mut dropped = 0;
+        let reservation = loop {
+            if state.events < shared.capacity
+                && let Some(reservation) = reserve(bytes)
+            {
+                break Some(reservation);
+            }
+            if policy() == DropPolicy::Block {
+                break Some(reserve_beyond(bytes));
+            }
+            if policy() == DropPolicy::Oldest && state.drop_oldest() {
+                dropped += 1;
+                continue;
+            }
+            break None;
+        };
+        match reservation {
+            Some(reservation) => {
+                state.items.push_back((item, Some(reservation)));
+                state.events += 1;
+            }
+            None => dropped += 1,
+        }
+        if dropped > 0 {
+            state.dropped += dropped;
+            *SINK_DROPS.lock().unwrap().entry(shared.sink).or_default() += dropped;
+            let now = Instant::now();
+            if state
+                .last_drop_warning
+                .is_none_or(|last| now.duration_since(last) >= DROP_WARNING_INTERVAL)
+            {
+                warn!(
+                    "The {} queue is full, dropped {} events so far.",
+                    shared.sink, state.dropped
+                );
+                state.last_drop_warning = Some(now);
+            }
+        }
+        drop(state);
+        shared.wake();
+    }
+
+    /// Waits with the `block` policy until the queue and the budget have room for another
+    /// event, warning at most every few seconds that the sink holds up the connections.
+    pub async fn ready(&self) {
+        let shared = &self.shared;
+        wait_for_room(|| {
+            let mut state = shared.state.lock().unwrap();
+            if state.abandoned || state.events < shared.capacity {
+                return true;
+            }
+            let now = Instant::now();
+            if state
+                .last_drop_warning
+                .is_none_or(|last| now.duration_since(last) >= DROP_WARNING_INTERVAL)
+            {
+                warn!(
+                    "The {} queue is full, holding up the connections.",
+                    shared.sink
+                );
+                state.last_drop_warning = Some(now);
+            }
+            false
+        })
+        .await;
+    }
+
+    /// Queues an item that is never dropped, such as a request to flush, unless the receiver
+    /// is gone.
+    pub fn send(&self, item: T) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.abandoned {
+            state.items.push_back((item, None));
+        }
+        drop(state);
+        self.shared.wake();
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.wake();
+    }
+}
+
+impl<T> Shared<T> {
+    fn wake(&self) {
+        self.notify.notify_one();
+        self.ready.notify_one();
+    }
+}
+
+/// Takes the items from the queue of a sink, in order.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next item, or returns None once the queue is empty and the sender gone.
+    /// Cancelling the wait loses no item.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = take(&mut state) {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Returns the next item if one is queued.
+    pub fn try_recv(&mut self) -> Option<T> {
+        take(&mut self.shared.state.lock().unwrap())
+    }
+
+    /// Blocks the thread until the next item is queued, or returns None once the queue is
+    /// empty and the sender gone.
+    pub fn blocking_recv(&mut self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(item) = take(&mut state) {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.shared.ready.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.abandoned = true;
+        state.events = 0;
+        let items = std::mem::take(&mut state.items);
+        drop(state);
+        drop(items);
+    }
+}
+
+/// Takes the next item, releasing its reservation.
+fn take<T>(state: &mut State<T>) -> Option<T> {
+    let (item, reservation) = state.items.pop_front()?;
+    if reservation.is_some() {
+        state.events -= 1;
+    }
+    Some(item)
+}
@@ -0,0 +1,150 @@
+//! Cross-checking of two sinks that receive the same log events.
+//!
+//! When migrating to a new log backend, both backends are written to at the same time. Every
+//! document a sink accepts is tallied in a time bucket of its event time: a count and an
+//! order-independent hash of the documents. Once a bucket is old enough for in-flight and
+//! retried batches to have been delivered, the tallies of both sinks are compared and any
+//! divergence is reported. Buckets that diverge are checked again until they converge or
+//! become too old to be delivered.
+
+use crate::clock;
+use chrono::DateTime;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+/// How many grace periods a diverging bucket is kept for re-checks.
+const MAX_RECHECKS: u32 = 12;
+
+/// Documents accepted by one sink within a bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Tally {
+    count: u64,
+    /// XOR of the document hashes, so that the delivery order does not matter.
+    hash: u64,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} documents, hash {:016x}", self.count, self.hash)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    tallies: [Tally; 2],
+    /// Whether a divergence of this bucket has already been reported.
+    diverged: bool,
+}
+
+/// Tallies of the documents accepted by the two mirrored sinks.
+#[derive(Debug)]
+pub struct MirrorLedger {
+    names: [String; 2],
+    bucket: Duration,
+    grace: Duration,
+    buckets: Mutex<BTreeMap<u64, Bucket>>,
+}
+
+/// Identifies one of the two sinks of a ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorSide {
+    Primary = 0,
+    Mirror = 1,
+}
+
+impl MirrorLedger {
+    /// Creates a ledger for two sinks, comparing buckets once they are older than the grace
+    /// period.
+    pub fn new(names: [String; 2], bucket: Duration, grace: Duration) -> Self {
+        Self {
+            names,
+            bucket: bucket.max(Duration::from_secs(1)),
+            grace,
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a document accepted by a sink.
+    pub fn record(&self, side: MirrorSide, time: SystemTime, document: &str) {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let start = secs - secs % self.bucket.as_secs();
+        let hash = u64::from_be_bytes(Sha256::digest(document.as_bytes())[..8].try_into().unwrap());
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let tally = &mut buckets.entry(start).or_default().tallies[side as usize];
+        tally.count += 1;
+        tally.hash ^= hash;
+    }
+
+    /// Records the accepted documents of a bulk request body, given the positions of the
+    /// documents that were rejected.
+    pub fn record_bulk(&self, side: MirrorSide, body: &str, rejected: &[usize]) {
+        // Every document line follows an action line.
+        for (position, document) in body.lines().skip(1).step_by(2).enumerate() {
+            if rejected.contains(&position) {
+                continue;
+            }
+            let time = serde_json::from_str::<serde_json::Value>(document)
+                .ok()
+                .and_then(|doc| {
+                    doc.get("@timestamp")
+                        .and_then(|ts| ts.as_str())
+                        .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                })
+                .map_or(UNIX_EPOCH, SystemTime::from);
+            self.record(side, time, document);
+        }
+    }
+
+    /// Compares the buckets that are old enough, reporting divergences and convergences.
+    ///
+    /// With `all`, every bucket is compared regardless of its age, e.g. on shutdown.
+    /// Returns the number of buckets that diverge.
+    pub fn check(&self, all: bool) -> usize {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let settled = now.saturating_sub(self.bucket.as_secs() + self.grace.as_secs());
+        let expired = now
+            .saturating_sub(self.bucket.as_secs() + self.grace.as_secs() * u64::from(MAX_RECHECKS));
+
+        let mut diverging = 0;
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|start, bucket| {
+            if !all && *start > settled {
+                return true;
+            }
+            let [primary, mirror] = bucket.tallies;
+            let label = format_bucket(*start);
+            if primary == mirror {
+                if bucket.diverged {
+                    info!("Mirrored sinks converged for the bucket at {label}.");
+                }
+                return false;
+            }
+            diverging += 1;
+            if !bucket.diverged || all || *start <= expired {
+                warn!(
+                    "Mirrored sinks diverge for the bucket at {label}: {} has {primary}, {} has {mirror}.",
+                    self.names[0], self.names[1]
+                );
+            }
+            bucket.diverged = true;
+            !all && *start > expired
+        });
+        diverging
+    }
+}
+
+fn format_bucket(start: u64) -> String {
+    clock::local_time(UNIX_EPOCH + Duration::from_secs(start)).to_rfc3339()
+}
@@ -0,0 +1,125 @@
+//! Ranking of the boundary nodes by latency, to connect only to the nearest ones.
+//!
+//! Every node is probed with a short-lived connection to the log stream of the first canister:
+//! once the WebSocket handshake is done, the round trip of a ping is measured, which reflects
+//! the network distance rather than the work of the handshake. Nodes that do not answer within
+//! the probe timeout are ranked last, in their previous order.
+
+use crate::connection::{self, ConnectionConfig, Upgrade};
+use crate::deflate::Compression;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration, Instant};
+use tokio_tungstenite::tungstenite::{Bytes, Message};
+
+/// Longest time a probe of a node may take, including the handshake.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most nodes probed at the same time.
+const MAX_CONCURRENT_PROBES: usize = 32;
+
+/// Probes all nodes and returns them with their latency, fastest first, followed by the nodes
+/// that could not be probed.
+pub async fn rank(
+    domains: Vec<String>,
+    config: Arc<ConnectionConfig>,
+) -> Vec<(String, Option<Duration>)> {
+    let mut latencies = HashMap::new();
+    let mut probes = JoinSet::new();
+    let mut pending = domains.iter().cloned();
+    loop {
+        while probes.len() < MAX_CONCURRENT_PROBES
+            && let Some(domain) = pending.next()
+        {
+            let config = config.clone();
+            probes.spawn(async move {
+                let latency = timeout(PROBE_TIMEOUT, probe(&domain, &config))
+                    .await
+                    .unwrap_or_else(|_| Err("timed out".to_string()));
+                (domain, latency)
+            });
+        }
+        let Some(joined) = probes.join_next().await else {
+            break;
+        };
+        match joined {
+            Ok((domain, Ok(latency))) => {
+                latencies.insert(domain, latency);
+            }
+            Ok((domain, Err(e))) => debug!("[{domain}] Latency probe failed: {e}"),
+            Err(e) => debug!("Latency probe failed: {e}"),
+        }
+    }
+
+    let mut ranked = domains;
+    // The sort is stable, so nodes without a latency keep their order.
+    ranked.sort_by_key(|domain| latencies.get(domain).copied().unwrap_or(Duration::MAX));
+    let listed: Vec<String> = ranked
+        .iter()
+        .filter_map(|domain| {
+            let latency = latencies.get(domain)?;
+            Some(format!("{domain} {}ms", latency.as_millis()))
+        })
+        .collect();
+    info!(
+        "Measured the latency of {} of {} nodes: {}.",
+        latencies.len(),
+        ranked.len(),
+        if listed.is_empty() {
+            "none answered".to_string()
+        } else {
+            listed.join(", ")
+        }
+    );
+    ranked
+        .into_iter()
+        .map(|domain| {
+            let latency = latencies.get(&domain).copied();
+            (domain, latency)
+        })
+        .collect()
+}
+
+/// Connects to a node and returns the round trip of a ping.
+async fn probe(domain: &str, config: &ConnectionConfig) -> Result<Duration, String> {
+    let canister_id = config
+        .canister_ids
+        .borrow()
+        .first()
+        .cloned()
+        .ok_or("no canister is monitored")?;
+    let url = config.endpoint.url(domain, &canister_id)?;
+    let (mut ws, _) = connection::connect_websocket(
+        &url,
+        config.proxy.as_ref(),
+        Upgrade {
+            compression: Compression::Off,
+            headers: &config.headers,
+        },
+        None,
+        &config.tls,
+        &config.resolver,
+        config.connect_timeouts,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let sent = Instant::now();
+    ws.send(Message::Ping(Bytes::from_static(b"latency")))
+        .await
+        .map_err(|e| e.to_string())?;
+    // Log lines may arrive before the pong.
+    let latency = loop {
+        match ws.next().await {
+            Some(Ok(Message::Pong(_))) => break sent.elapsed(),
+            Some(Ok(Message::Close(_))) | None => {
+                return Err("the node closed the connection".to_string());
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.to_string()),
+        }
+    };
+    let _ = ws.close(None).await;
+    Ok(latency)
+}
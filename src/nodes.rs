@@ -0,0 +1,499 @@
+//! Inventory of the API boundary nodes, read from the certified state of the NNS subnet or,
+//! with `--all-subnets`, of every subnet.
+//!
+//! The state is read from the first of the configured API endpoints that answers, verified
+//! against the mainnet root key, or against the root key of the endpoint for testnets and local
+//! replicas, whose keys differ. At startup,
+//! failed fetches are retried with backoff, and if all of them fail, the node list saved by a
+//! previous run can be used instead, so that a hiccup of the endpoints does not keep the
+//! client from starting.
+//!
+//! The fetched nodes can be narrowed down with `--only-node` and `--exclude-node`, e.g. to
+//! skip nodes under maintenance or to stay within a region, whose code is part of the domains.
+
+use crate::error::Error;
+use crate::proxy;
+use crate::NodesArgs;
+use candid::Principal;
+use futures_util::future::join_all;
+use ic_agent::hash_tree::LookupResult;
+use ic_agent::{Agent, AgentError};
+use log::{info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::time::{sleep, Duration};
+use url::{Host, Url};
+
+/// The subnet whose state tree lists the API boundary nodes and all subnets.
+const NNS_SUBNET_ID: &str = "tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe";
+
+/// The API endpoint that the node list is read from by default.
+pub const DEFAULT_IC_URL: &str = "https://icp-api.io";
+
+/// Delay before the first retry of a failed fetch, doubled after every further failure.
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed fetch.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// An API boundary node.
+#[derive(Serialize)]
+pub struct BoundaryNode {
+    pub domain: String,
+    pub ipv4_address: Option<String>,
+    pub ipv6_address: String,
+    pub node_id: String,
+}
+
+/// The API endpoints of the Internet Computer that the node list is read from.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    pub urls: Vec<Url>,
+    /// Whether the root key is fetched from the endpoints instead of using the mainnet key,
+    /// e.g. for testnets. It is always fetched from local replicas.
+    pub fetch_root_key: bool,
+}
+
+impl Endpoints {
+    /// Creates an agent for the first of the endpoints that answers.
+    pub async fn agent(&self, http_client: reqwest::Client) -> Result<Agent, AgentError> {
+        let mut last_error = None;
+        for ic_url in &self.urls {
+            match agent(http_client.clone(), ic_url, self.fetches_root_key(ic_url)).await {
+                Ok(agent) => return Ok(agent),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| AgentError::MessageError("no API endpoint given".into())))
+    }
+
+    fn fetches_root_key(&self, url: &Url) -> bool {
+        self.fetch_root_key
+            || match url.host() {
+                Some(Host::Domain(domain)) => domain == "localhost",
+                Some(Host::Ipv4(ip)) => ip.is_loopback(),
+                Some(Host::Ipv6(ip)) => ip.is_loopback(),
+                None => false,
+            }
+    }
+}
+
+/// Fetches all API boundary nodes, sorted by domain, from the first of the endpoints that
+/// answers.
+///
+/// With `all_subnets`, the nodes are read from the state of every subnet and merged, so that
+/// no node is missed if the subnets disagree, e.g. while a registry change propagates.
+pub async fn fetch(
+    http_client: reqwest::Client,
+    endpoints: &Endpoints,
+    all_subnets: bool,
+) -> Result<Vec<BoundaryNode>, AgentError> {
+    let mut last_error = None;
+    for ic_url in &endpoints.urls {
+        let fetch_root_key = endpoints.fetches_root_key(ic_url);
+        match fetch_from(http_client.clone(), ic_url, fetch_root_key, all_subnets).await {
+            Ok(nodes) => return Ok(nodes),
+            Err(e) => {
+                if endpoints.urls.len() > 1 {
+                    warn!("Failed to fetch the API boundary nodes from {ic_url}: {e}");
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| AgentError::MessageError("no API endpoint given".into())))
+}
+
+/// Fetches all API boundary nodes from one endpoint.
+async fn fetch_from(
+    http_client: reqwest::Client,
+    ic_url: &Url,
+    fetch_root_key: bool,
+    all_subnets: bool,
+) -> Result<Vec<BoundaryNode>, AgentError> {
+    let agent = agent(http_client, ic_url, fetch_root_key).await?;
+    let nns = Principal::from_text(NNS_SUBNET_ID).unwrap();
+    if !all_subnets {
+        return read_boundary_nodes(&agent, nns).await;
+    }
+
+    let subnets = list_subnets(&agent, nns).await?;
+    info!(
+        "Fetching API boundary nodes from {} subnets.",
+        subnets.len()
+    );
+    let results = join_all(
+        subnets
+            .iter()
+            .map(|subnet| read_boundary_nodes(&agent, *subnet)),
+    )
+    .await;
+    let mut nodes = BTreeMap::new();
+    let mut first_error = None;
+    for (subnet, result) in subnets.iter().zip(results) {
+        match result {
+            Ok(subnet_nodes) => {
+                for node in subnet_nodes {
+                    nodes.entry(node.node_id.clone()).or_insert(node);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to fetch API boundary nodes from subnet {subnet}: {e}");
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error
+        && nodes.is_empty()
+    {
+        return Err(e);
+    }
+    let mut nodes: Vec<_> = nodes.into_values().collect();
+    nodes.sort_by(|a, b| a.domain.cmp(&b.domain));
+    Ok(nodes)
+}
+
+/// Creates an agent for an endpoint, with its root key if `fetch_root_key` is set.
+async fn agent(
+    http_client: reqwest::Client,
+    ic_url: &Url,
+    fetch_root_key: bool,
+) -> Result<Agent, AgentError> {
+    let agent = Agent::builder()
+        .with_url(ic_url.as_str())
+        .with_http_client(http_client)
+        .build()?;
+    if fetch_root_key {
+        // A root key fetched over the network proves nothing, so this is for test networks.
+        info!("Fetching the root key from {ic_url}.");
+        agent.fetch_root_key().await?;
+    }
+    Ok(agent)
+}
+
+/// A glob pattern for the domains of nodes, in which `*` matches any characters, including
+/// dots, and `?` a single one. It matches whole domains, ignoring case.
+#[derive(Clone, Debug)]
+pub struct NodePattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl NodePattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("the pattern is empty".to_string());
+        }
+        let mut regex = String::from("(?i)^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex).map_err(|e| e.to_string())?,
+        })
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        self.regex.is_match(domain)
+    }
+}
+
+impl fmt::Display for NodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Which of the discovered nodes are connected to.
+#[derive(Clone, Debug, Default)]
+pub struct NodeSelection {
+    /// If not empty, only nodes matching one of these are selected.
+    pub only: Vec<NodePattern>,
+    /// Nodes matching one of these are not selected, even if they match `only`.
+    pub exclude: Vec<NodePattern>,
+}
+
+impl NodeSelection {
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn selects(&self, domain: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|pattern| pattern.matches(domain)))
+            && !self.exclude.iter().any(|pattern| pattern.matches(domain))
+    }
+
+    /// Returns the selected domains, in order.
+    pub fn apply(&self, domains: Vec<String>) -> Vec<String> {
+        if self.is_empty() {
+            return domains;
+        }
+        let total = domains.len();
+        let selected: Vec<String> = domains
+            .into_iter()
+            .filter(|domain| self.selects(domain))
+            .collect();
+        info!(
+            "Selected {} of {total} API boundary nodes with --only-node and --exclude-node.",
+            selected.len()
+        );
+        selected
+    }
+}
+
+/// Fetches the domains of the API boundary nodes, at startup and whenever the node list is
+/// refreshed.
+#[derive(Clone)]
+pub struct Discovery {
+    http_client: reqwest::Client,
+    endpoints: Endpoints,
+    all_subnets: bool,
+    /// Retries of a failed fetch before giving up.
+    retries: u32,
+    /// File that the last fetched node list is saved to, and read from when fetching fails.
+    cache: Option<PathBuf>,
+    /// Which of the fetched nodes are returned.
+    selection: NodeSelection,
+}
+
+impl Discovery {
+    pub fn new(http_client: reqwest::Client, endpoints: Endpoints, all_subnets: bool) -> Self {
+        Self {
+            http_client,
+            endpoints,
+            all_subnets,
+            retries: 0,
+            cache: None,
+            selection: NodeSelection::default(),
+        }
+    }
+
+    /// Retries failed fetches with backoff, and falls back to the node list saved in the cache
+    /// file, if given, once all retries failed.
+    pub fn with_retries(mut self, retries: u32, cache: Option<PathBuf>) -> Self {
+        self.retries = retries;
+        self.cache = cache;
+        self
+    }
+
+    /// Returns only the fetched nodes that the selection selects. The node cache still holds
+    /// all of them.
+    pub fn with_selection(mut self, selection: NodeSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Fetches the domains of the selected API boundary nodes, sorted.
+    pub async fn domains(&self) -> Result<Vec<String>, AgentError> {
+        self.all_domains()
+            .await
+            .map(|domains| self.selection.apply(domains))
+    }
+
+    /// Fetches the domains of all API boundary nodes, sorted.
+    async fn all_domains(&self) -> Result<Vec<String>, AgentError> {
+        let mut delay = RETRY_MIN_DELAY;
+        let mut attempt = 0;
+        let error = loop {
+            match fetch(self.http_client.clone(), &self.endpoints, self.all_subnets).await {
+                Ok(nodes) => {
+                    let domains: Vec<String> = nodes.into_iter().map(|node| node.domain).collect();
+                    if let Some(cache) = &self.cache
+                        && !domains.is_empty()
+                        && let Err(e) = NodeCache::new(domains.clone()).save(cache)
+                    {
+                        warn!("Failed to save the node list to {}: {e}", cache.display());
+                    }
+                    return Ok(domains);
+                }
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to fetch the API boundary nodes: {e}. Retrying in {}s \
+                         ({attempt}/{}).",
+                        delay.as_secs(),
+                        self.retries
+                    );
+                    sleep(delay).await;
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+                Err(e) => break e,
+            }
+        };
+        let Some(cache) = &self.cache else {
+            return Err(error);
+        };
+        match NodeCache::load(cache) {
+            Ok(cached) => {
+                let age = SystemTime::now()
+                    .duration_since(cached.fetched_at)
+                    .unwrap_or_default();
+                warn!(
+                    "Failed to fetch the API boundary nodes: {error}. Using the {} nodes saved \
+                     in {} {} ago.",
+                    cached.domains.len(),
+                    cache.display(),
+                    humantime::format_duration(Duration::from_secs(age.as_secs()))
+                );
+                Ok(cached.domains)
+            }
+            Err(e) => {
+                warn!("Failed to read the node list from {}: {e}", cache.display());
+                Err(error)
+            }
+        }
+    }
+}
+
+/// The node list saved by a previous run.
+#[derive(Serialize, Deserialize)]
+struct NodeCache {
+    fetched_at: SystemTime,
+    domains: Vec<String>,
+}
+
+impl NodeCache {
+    fn new(domains: Vec<String>) -> Self {
+        Self {
+            fetched_at: SystemTime::now(),
+            domains,
+        }
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        let cache: Self = serde_json::from_slice(&fs::read(path)?)?;
+        if cache.domains.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no nodes saved"));
+        }
+        Ok(cache)
+    }
+
+    /// Saves the node list, replacing the file atomically, so that a crash cannot leave a
+    /// truncated list behind.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+/// Lists the subnets of the Internet Computer, from the certified state of the NNS subnet.
+async fn list_subnets(agent: &Agent, nns: Principal) -> Result<Vec<Principal>, AgentError> {
+    let certificate = agent
+        .read_subnet_state_raw(vec![vec!["subnet".into()]], nns)
+        .await?;
+    let subnets: BTreeSet<Vec<u8>> = certificate
+        .tree
+        .list_paths()
+        .into_iter()
+        .filter(|path| path.len() > 1 && path[0].as_bytes() == b"subnet")
+        .map(|path| path[1].as_bytes().to_vec())
+        .collect();
+    Ok(subnets
+        .iter()
+        .map(|subnet| Principal::from_slice(subnet))
+        .collect())
+}
+
+/// Reads the API boundary nodes from the certified state of a subnet, sorted by domain.
+async fn read_boundary_nodes(
+    agent: &Agent,
+    subnet: Principal,
+) -> Result<Vec<BoundaryNode>, AgentError> {
+    let certificate = agent
+        .read_subnet_state_raw(vec![vec!["api_boundary_nodes".into()]], subnet)
+        .await?;
+    let tree = &certificate.tree;
+    let node_ids: BTreeSet<Vec<u8>> = tree
+        .list_paths()
+        .into_iter()
+        .filter(|path| path.len() > 1 && path[0].as_bytes() == b"api_boundary_nodes")
+        .map(|path| path[1].as_bytes().to_vec())
+        .collect();
+    let lookup = |node_id: &[u8], field: &str| -> Result<Option<String>, AgentError> {
+        match tree.lookup_path([b"api_boundary_nodes".as_slice(), node_id, field.as_bytes()]) {
+            LookupResult::Found(value) => String::from_utf8(value.to_vec())
+                .map(Some)
+                .map_err(|e| AgentError::Utf8ReadError(e.utf8_error())),
+            _ => Ok(None),
+        }
+    };
+
+    let mut nodes = Vec::new();
+    for node_id in node_ids {
+        let Some(domain) = lookup(&node_id, "domain")? else {
+            continue;
+        };
+        nodes.push(BoundaryNode {
+            domain,
+            ipv4_address: lookup(&node_id, "ipv4_address")?,
+            ipv6_address: lookup(&node_id, "ipv6_address")?.unwrap_or_default(),
+            node_id: Principal::from_slice(&node_id).to_text(),
+        });
+    }
+    nodes.sort_by(|a, b| a.domain.cmp(&b.domain));
+    Ok(nodes)
+}
+
+/// Prints the API boundary nodes as a table or as JSON.
+pub async fn print(args: &NodesArgs) -> Result<(), Error> {
+    let proxy = proxy::resolve(args.proxy.as_deref())?;
+    let nodes = fetch(
+        proxy::http_client(proxy.as_ref())?,
+        &args.ic.endpoints(),
+        args.all_subnets,
+    )
+    .await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&nodes)?);
+        return Ok(());
+    }
+
+    let rows: Vec<[&str; 4]> = nodes
+        .iter()
+        .map(|node| {
+            [
+                node.domain.as_str(),
+                node.ipv4_address.as_deref().unwrap_or("-"),
+                node.ipv6_address.as_str(),
+                node.node_id.as_str(),
+            ]
+        })
+        .collect();
+    let header = ["DOMAIN", "IPV4", "IPV6", "NODE ID"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,496 @@
+//! Delivery of log events to stdout, an optional output file, and the sinks.
+//!
+//! Stdout and the output files are written by the output thread of the writer module, which
+//! renders every event once and writes it to all of them in the order the events arrive. Every
+//! sink runs in a task of its own, which takes the events from a bounded queue of the sink, so
+//! that the sinks each consume the events at their own pace, see [`LogSink`].
+
+use crate::clock;
+use crate::color::Painter;
+use crate::encoding::OutputEncoding;
+use crate::event::LogEvent;
+use crate::memory::{self, DropPolicy, QueueReceiver, QueueSender};
+use crate::sinks::LogSink;
+use crate::split::SplitOutput;
+use crate::template::Template;
+use crate::writer::{Batching, OutputWriter};
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::oneshot;
+
+/// How log events are rendered as lines of text.
+pub enum LineFormat {
+    /// Lines rendered from a template.
+    Template(Template),
+    /// One JSON object per line.
+    Json,
+}
+
+impl LineFormat {
+    /// Renders an event as a line without a trailing newline.
+    pub fn render(&self, event: &LogEvent) -> String {
+        match self {
+            Self::Template(template) => template.render(event),
+            Self::Json => event.to_json().to_string(),
+        }
+    }
+}
+
+/// Validates an output file path, which may contain strftime specifiers.
+pub fn parse_file_pattern(pattern: &str) -> Result<String, String> {
+    clock::validate_strftime(pattern)?;
+    Ok(pattern.to_string())
+}
+
+/// When the output file is flushed to disk with fsync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Never; the operating system writes the data back on its own schedule
+    Never,
+    /// When a file is finished, i.e. on rotation and on exit
+    Rotate,
+    /// After every line
+    Always,
+}
+
+/// Most events waiting in the queue of every sink.
+const SINK_QUEUE_CAPACITY: usize = 10_000;
+
+/// Suffix of output files that are still being written in atomic mode.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// An output file whose path may contain strftime specifiers.
+///
+/// The path is evaluated on the wall clock in the configured timezone when every line is
+/// written, and a new file is started whenever it changes, e.g. daily with
+/// `logs/%Y-%m-%d.log`. The time of the events would move back to an earlier file for delayed
+/// or backfilled events, and rotate back and forth between files for events out of order.
+///
+/// In atomic mode, the current file is written under a `.partial` name and renamed to its
+/// final name once it is finished, so that a file under its final name is always complete. If
+/// a file with the final name already exists, e.g. because the path repeats or the client was
+/// restarted, a counter is added to the name instead of overwriting it.
+struct OutputFile {
+    pattern: String,
+    /// The rendered path of the current file, which is written under a partial name in atomic
+    /// mode.
+    path: PathBuf,
+    file: File,
+    atomic: bool,
+    fsync: FsyncPolicy,
+}
+
+impl OutputFile {
+    fn open(pattern: &str, atomic: bool, fsync: FsyncPolicy) -> io::Result<Self> {
+        let path = Self::path_at(pattern, SystemTime::now());
+        let file = open_appending(&Self::writing_path(&path, atomic))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            path,
+            file,
+            atomic,
+            fsync,
+        })
+    }
+
+    fn path_at(pattern: &str, time: SystemTime) -> PathBuf {
+        PathBuf::from(clock::local_time(time).format(pattern).to_string())
+    }
+
+    /// Returns the path that a file is written to until it is finished.
+    fn writing_path(path: &Path, atomic: bool) -> PathBuf {
+        if !atomic {
+            return path.to_path_buf();
+        }
+        let mut name = path.as_os_str().to_os_string();
+        name.push(PARTIAL_SUFFIX);
+        PathBuf::from(name)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_appending(&Self::writing_path(&self.path, self.atomic))?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.pattern.contains('%') {
+            let path = Self::path_at(&self.pattern, SystemTime::now());
+            if path != self.path {
+                info!("Rotating output file to {}", path.display());
+                let file = open_appending(&Self::writing_path(&path, self.atomic))?;
+                self.finish()?;
+                self.file = file;
+                self.path = path;
+            }
+        }
+        // Each line is written at once, so that no other line can end up within it.
+        self.file.write_all(line)?;
+        if self.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the current file: flushes it to disk if configured, and in atomic mode moves
+    /// it to its final name.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.fsync != FsyncPolicy::Never {
+            self.file.sync_all()?;
+        }
+        if !self.atomic {
+            return Ok(());
+        }
+        let partial = Self::writing_path(&self.path, true);
+        // The file opened at startup stays empty if the first event already belongs to the
+        // next one.
+        if self.file.metadata()?.len() == 0 {
+            return fs::remove_file(partial);
+        }
+        let target = unused_path(&self.path);
+        fs::rename(partial, &target)?;
+        if self.fsync != FsyncPolicy::Never {
+            sync_parent(&target)?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens a file for appending, creating it and its directory if needed.
+///
+/// If the file ends with an incomplete line, e.g. after a crash, the line is terminated, so
+/// that the next line does not continue it.
+fn open_appending(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    Ok(file)
+}
+
+/// Returns the path, or if a file already exists there, the first free one with a counter
+/// before the extension, e.g. `app.1.log`.
+fn unused_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+    let mut candidate = path.to_path_buf();
+    let mut counter = 0;
+    while candidate.exists() {
+        counter += 1;
+        let name = match &extension {
+            Some(ext) => format!("{stem}.{counter}.{ext}"),
+            None => format!("{stem}.{counter}"),
+        };
+        candidate = path.with_file_name(name);
+    }
+    candidate
+}
+
+/// Flushes the directory entry of a renamed file to disk.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    // Directories cannot be opened as files on Windows, where renames are durable anyway.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// The destinations on the local machine: stdout and the output files, all written by the
+/// output thread in the same order.
+pub struct LocalOutput {
+    format: Arc<LineFormat>,
+    painter: Option<Painter>,
+    encoding: OutputEncoding,
+    stdout_lines: bool,
+    file: Option<OutputFile>,
+    split: Option<SplitOutput>,
+    /// When the lines for stdout are written out.
+    pub batching: Batching,
+    /// Number of lines held back while paused; later lines are not printed.
+    pub pause_buffer_size: usize,
+}
+
+impl LocalOutput {
+    /// Writes a log event to the output files, and returns the line to print to stdout.
+    pub fn write(&mut self, event: &LogEvent) -> Option<Vec<u8>> {
+        let mut line = self.format.render(event);
+        line.push('\n');
+
+        if let Some(file) = &mut self.file
+            && let Err(e) = file.write(&self.encoding.encode(&line))
+        {
+            error!("Failed to write to output file: {e}");
+        }
+
+        if let Some(split) = &self.split {
+            split.write(&event.canister_id, &self.encoding.encode(&line));
+        }
+
+        self.stdout_lines.then(|| match &self.painter {
+            Some(painter) => self
+                .encoding
+                .encode(&painter.paint(event, &line))
+                .into_owned(),
+            None => self.encoding.encode(&line).into_owned(),
+        })
+    }
+
+    /// Opens the output files again.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Some(split) = &self.split {
+            split.reopen();
+        }
+        match &mut self.file {
+            Some(file) => file.reopen(),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes the output file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where log events are delivered.
+pub struct Output {
+    encoding: OutputEncoding,
+    /// The output thread writing to stdout and the output files.
+    writer: OutputWriter,
+    /// The queues of the tasks of the sinks.
+    sinks: Vec<QueueSender<SinkCommand>>,
+    paused: AtomicBool,
+    written: AtomicU64,
+}
+
+/// What became of the lines received while printing was paused.
+pub struct Resumed {
+    /// Lines printed from the backlog.
+    pub printed: usize,
+    /// Lines not printed because the backlog was full.
+    pub dropped: u64,
+}
+
+impl Output {
+    /// Creates an output writing rendered lines to stdout.
+    pub fn new(format: Arc<LineFormat>) -> Self {
+        let writer = OutputWriter::spawn(LocalOutput {
+            format,
+            painter: None,
+            encoding: OutputEncoding::Utf8,
+            stdout_lines: true,
+            file: None,
+            split: None,
+            batching: Batching::IMMEDIATE,
+            pause_buffer_size: 0,
+        });
+        Self {
+            encoding: OutputEncoding::Utf8,
+            writer,
+            sinks: Vec::new(),
+            paused: AtomicBool::new(false),
+            written: AtomicU64::new(0),
+        }
+    }
+
+    /// Colors the lines printed to stdout.
+    pub fn with_painter(self, painter: Painter) -> Self {
+        self.writer
+            .configure(move |local| local.painter = Some(painter));
+        self
+    }
+
+    /// Encodes the lines written to stdout and the output files; other destinations always
+    /// receive UTF-8.
+    pub fn with_encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.encoding = encoding;
+        self.writer
+            .configure(move |local| local.encoding = encoding);
+        self
+    }
+
+    /// Writes the lines to stdout in batches instead of one by one.
+    pub fn with_batching(self, batching: Batching) -> Self {
+        self.writer
+            .configure(move |local| local.batching = batching);
+        self
+    }
+
+    /// Holds back up to `size` lines while printing is paused, to print them on resuming.
+    pub fn with_pause_buffer(self, size: usize) -> Self {
+        self.writer
+            .configure(move |local| local.pause_buffer_size = size);
+        self
+    }
+
+    /// Stops printing the lines to stdout, e.g. when only summaries are wanted there. Other
+    /// destinations are unaffected.
+    pub fn without_stdout_lines(self) -> Self {
+        self.writer.configure(|local| local.stdout_lines = false);
+        self
+    }
+
+    /// Also appends rendered lines to the file at the given path, which may contain strftime
+    /// specifiers to rotate files by time. In atomic mode, files only appear under their final
+    /// names once they are finished.
+    pub fn with_file(self, pattern: &str, atomic: bool, fsync: FsyncPolicy) -> io::Result<Self> {
+        let file = OutputFile::open(pattern, atomic, fsync)?;
+        self.writer.configure(move |local| local.file = Some(file));
+        Ok(self)
+    }
+
+    /// Also writes the lines of every canister to its own file or named pipe.
+    pub fn with_split(self, split: SplitOutput) -> Self {
+        self.writer
+            .configure(move |local| local.split = Some(split));
+        self
+    }
+
+    /// Also delivers the events to a sink, from a task of its own.
+    pub fn with_sink(mut self, sink: impl LogSink + 'static) -> Self {
+        let (sender, receiver) = memory::queue(sink.name(), SINK_QUEUE_CAPACITY);
+        tokio::spawn(run_sink(sink, receiver));
+        self.sinks.push(sender);
+        self
+    }
+
+    /// Opens the output files again, e.g. after they were moved away by log rotation, once the
+    /// lines written before are in the old files.
+    pub fn reopen(&self) -> io::Result<()> {
+        self.writer.reopen()
+    }
+
+    /// Writes all lines still queued for stdout and the output files and finishes the output
+    /// file, e.g. on exit.
+    pub fn close(&self) -> io::Result<()> {
+        self.writer.close()
+    }
+
+    /// Delivers everything still queued for the sinks and shuts them down, e.g. on exit.
+    pub async fn shutdown(&self) {
+        futures_util::future::join_all(self.sinks.iter().map(|sink| {
+            let (done, shut_down) = oneshot::channel();
+            sink.send(SinkCommand::Shutdown(done));
+            shut_down
+        }))
+        .await;
+    }
+
+    /// Waits until the reader of stdout is gone, e.g. the end of a pipe was closed; the lines
+    /// are no longer printed from then on.
+    pub async fn stdout_closed(&self) {
+        self.writer.stdout_closed().await;
+    }
+
+    /// Returns whether printing to stdout is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pauses printing to stdout, holding back the lines up to the pause buffer size. Other
+    /// destinations are unaffected.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        self.writer.pause();
+    }
+
+    /// Resumes printing to stdout, first printing the lines held back while paused.
+    pub fn resume(&self) -> Resumed {
+        let resumed = self.writer.resume();
+        self.paused.store(false, Ordering::Relaxed);
+        resumed
+    }
+
+    /// Returns the number of lines held back and dropped while paused.
+    pub fn backlog(&self) -> (usize, u64) {
+        self.writer.backlog()
+    }
+
+    /// Returns the number of lines written so far.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Prints a block of text to stdout among the lines, e.g. a summary.
+    pub fn write_text(&self, text: &str) {
+        self.writer
+            .write_text(self.encoding.encode(text).into_owned());
+    }
+
+    /// Waits with `--buffer-drop-policy block` until all sinks can take another event.
+    pub async fn ready(&self) {
+        if memory::policy() != DropPolicy::Block {
+            return;
+        }
+        for sink in &self.sinks {
+            sink.ready().await;
+        }
+    }
+
+    /// Writes a log event to all destinations.
+    pub async fn write(&self, event: &LogEvent) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+        self.writer.write(event.clone());
+
+        if self.sinks.is_empty() {
+            return;
+        }
+        let size = memory::size_of(event);
+        for sink in &self.sinks {
+            sink.send_event(SinkCommand::Write(event.clone()), size);
+        }
+    }
+}
+
+enum SinkCommand {
+    Write(LogEvent),
+    /// Answers once the sink delivered the events queued before it and shut down.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Hands the queued events to a sink until the output is gone.
+async fn run_sink(sink: impl LogSink, mut queue: QueueReceiver<SinkCommand>) {
+    while let Some(command) = queue.recv().await {
+        match command {
+            SinkCommand::Write(event) => {
+                // A sink that falls behind with the block policy fills this queue in turn.
+                if memory::policy() == DropPolicy::Block {
+                    sink.ready().await;
+                }
+                sink.write_event(&event).await;
+            }
+            SinkCommand::Shutdown(done) => {
+                sink.shutdown().await;
+                let _ = done.send(());
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Grouping of the lines of canister traps and panics, with `--group-panics`.
+//!
+//! A canister that traps or panics often logs the message over several lines: the panic
+//! location, the message, a backtrace, and notes. Delivered as separate lines, alerts fire on
+//! fragments and JSON sinks index them as unrelated records. The grouper of a connection holds
+//! back a line that starts a trap or panic, appends the lines that continue it, and passes the
+//! block on as one record flagged as a panic once a line that does not continue it arrives or
+//! the connection stays quiet for a moment.
+
+use crate::decode::DecodedRecord;
+use regex::Regex;
+use std::sync::LazyLock;
+use tokio::time::{Duration, Instant};
+
+/// Time after the last line of a block without further lines after which the block is
+/// complete.
+const QUIET_TIME: Duration = Duration::from_secs(1);
+/// Maximum number of lines in a block; further lines start a new record.
+const MAX_BLOCK_LINES: usize = 256;
+
+/// Lines that start a trap or panic, e.g. `Panicked at 'index out of bounds', src/lib.rs:7:5`,
+/// `[TRAP]: out of cycles`, or `Canister xxx trapped explicitly: ...`.
+static START: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\bpanicked at\b|^thread '[^']*' panicked\b|^\s*\[trap\]|\bcanister\b.*\btrapped\b",
+    )
+    .expect("the pattern is valid")
+});
+/// Lines that continue a block: indented lines such as backtrace frames, and the headings and
+/// notes that Rust and the replica print with traces.
+static CONTINUATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:\s+\S|\d+: |(?:stack|wasm) backtrace:|backtrace:|note: |caused by:|error: )")
+        .expect("the pattern is valid")
+});
+
+/// A block whose end has not been seen yet.
+struct Block {
+    record: DecodedRecord,
+    resumed: bool,
+    lines: usize,
+    /// Whether the last line ends with a colon, as the location line of Rust panics does before
+    /// the message, so that the next line belongs to the block regardless of its form.
+    open: bool,
+    last: Instant,
+}
+
+/// What grouping a record resulted in.
+pub enum Grouped {
+    /// The record is held back in a block.
+    Pending,
+    /// Records to pass on, in order, each with whether it was resumed and whether it is a
+    /// panic: a completed block before the record that ended it, or the record itself.
+    Complete(Vec<(DecodedRecord, bool, bool)>),
+}
+
+/// Groups the trap and panic lines of a single connection into one record per block.
+#[derive(Default)]
+pub struct PanicGrouper {
+    block: Option<Block>,
+}
+
+impl PanicGrouper {
+    /// Processes a record, and whether it was replayed when resuming the stream.
+    pub fn push(&mut self, record: DecodedRecord, resumed: bool) -> Grouped {
+        let mut complete = Vec::new();
+        if let Some(block) = self.block.as_mut() {
+            if block.lines < MAX_BLOCK_LINES
+                && (block.open || CONTINUATION.is_match(&record.message))
+            {
+                block.record.message.push('\n');
+                block.record.message.push_str(&record.message);
+                block.lines += 1;
+                block.open = record.message.trim_end().ends_with(':');
+                block.last = Instant::now();
+                return Grouped::Pending;
+            }
+            complete.extend(self.finish());
+        }
+        if START.is_match(&record.message) {
+            self.block = Some(Block {
+                open: record.message.trim_end().ends_with(':'),
+                record,
+                resumed,
+                lines: 1,
+                last: Instant::now(),
+            });
+            if complete.is_empty() {
+                return Grouped::Pending;
+            }
+        } else {
+            complete.push((record, resumed, false));
+        }
+        Grouped::Complete(complete)
+    }
+
+    /// Returns the held back block if no line continued it for the quiet time.
+    pub fn expire(&mut self) -> Option<(DecodedRecord, bool, bool)> {
+        if self
+            .block
+            .as_ref()
+            .is_some_and(|block| block.last.elapsed() >= QUIET_TIME)
+        {
+            return self.finish();
+        }
+        None
+    }
+
+    /// Returns the held back block, e.g. when the connection ends.
+    pub fn finish(&mut self) -> Option<(DecodedRecord, bool, bool)> {
+        self.block
+            .take()
+            .map(|block| (block.record, block.resumed, true))
+    }
+}
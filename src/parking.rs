@@ -0,0 +1,81 @@
+//! Daily active hours, outside of which the connections to the nodes are parked.
+//!
+//! Teams that only watch logs during business hours can keep the client running continuously
+//! without holding connections open overnight. Outside the active hours, all connections are
+//! closed; when the hours begin again, the node list is fetched anew and the connections are
+//! re-established, optionally replaying the lines logged while they were parked. The hours
+//! are wall-clock times in the configured timezone, so they follow daylight saving time.
+
+use crate::clock;
+use chrono::{NaiveTime, TimeDelta};
+use std::fmt;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// Shortest wait until the active hours are checked again, e.g. around a daylight saving
+/// time change, when the wall clock jumps.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A daily window of wall-clock times, which may wrap around midnight, e.g. `22:00-06:00`.
+#[derive(Clone, Copy, Debug)]
+pub struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    /// Parses a window such as `08:00-20:00`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("expected a time like 08:00, got {time}"))
+        };
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("expected hours like 08:00-20:00, got {value}"))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(format!("the active hours {value} are empty"));
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Returns whether the time lies within the active hours.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let time = clock::local_time(time).time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Returns how long it is from the time until the active hours begin or end.
+    pub fn until_change(&self, time: SystemTime) -> Duration {
+        let time = clock::local_time(time).time();
+        let until = |boundary: NaiveTime| {
+            let delta = boundary - time;
+            if delta > TimeDelta::zero() {
+                delta
+            } else {
+                delta + TimeDelta::days(1)
+            }
+        };
+        until(self.start)
+            .min(until(self.end))
+            .to_std()
+            .unwrap_or_default()
+            .max(MIN_CHECK_INTERVAL)
+    }
+}
+
+impl fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
@@ -0,0 +1,147 @@
+//! Adaptive scheduling of keep-alive pings.
+//!
+//! Log traffic already keeps a connection alive, so the ping interval doubles after every
+//! interval in which messages were received, up to the configured maximum. As soon as an
+//! interval passes without traffic, the interval drops back to the minimum so that a dead
+//! link is detected quickly.
+//!
+//! Each ping carries a sequence number and the wall-clock time it was sent, so that the
+//! matching pong yields the round-trip time. Peers echo the payload as it is, but a peer may
+//! append its own wall-clock time to the pong, as microseconds since the Unix epoch in 8
+//! big-endian bytes, from which the offset of its clock is estimated as in NTP, assuming that
+//! both directions take half the round trip.
+//!
+//! A connection on which a ping has gone unanswered, and nothing else has been received since,
+//! for the pong timeout is considered dead.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+/// Length of the payload of a ping: the sequence number and the send time.
+const PAYLOAD_LEN: usize = 16;
+
+/// A pong that answered the outstanding ping.
+pub struct Pong {
+    pub rtt: Duration,
+    /// How far the clock of the peer is ahead of the local clock, in milliseconds, if the peer
+    /// appended its time to the pong.
+    pub clock_offset_ms: Option<f64>,
+}
+
+/// Tracks when the next keep-alive ping of a connection is due.
+pub struct AdaptivePing {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    deadline: Instant,
+    saw_traffic: bool,
+    sequence: u64,
+    /// The payload of the outstanding ping and when it was sent.
+    outstanding: Option<([u8; PAYLOAD_LEN], Instant)>,
+    /// When the oldest ping that is still unanswered was sent.
+    unanswered_since: Option<Instant>,
+    last_traffic: Instant,
+}
+
+impl AdaptivePing {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+            deadline: Instant::now() + min,
+            saw_traffic: false,
+            sequence: 0,
+            outstanding: None,
+            unanswered_since: None,
+            last_traffic: Instant::now(),
+        }
+    }
+
+    /// Returns the instant at which the next ping is due.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Records that a message was received from the peer.
+    pub fn record_traffic(&mut self) {
+        self.saw_traffic = true;
+        self.last_traffic = Instant::now();
+    }
+
+    /// Returns the instant at which the connection is considered dead if no pong or other
+    /// message arrives until then, while a ping is unanswered.
+    pub fn dead_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.unanswered_since
+            .map(|since| since.max(self.last_traffic) + timeout)
+    }
+
+    /// Adjusts the interval after a ping has been sent and schedules the next one.
+    ///
+    /// Returns the new interval.
+    pub fn schedule_next(&mut self) -> Duration {
+        self.current = if self.saw_traffic {
+            (self.current * 2).min(self.max)
+        } else {
+            self.min
+        };
+        self.saw_traffic = false;
+        self.deadline = Instant::now() + self.current;
+        self.current
+    }
+
+    /// Returns the payload of the next ping and whether the previous ping is still unanswered.
+    pub fn start_ping(&mut self) -> (Vec<u8>, bool) {
+        let unanswered = self.outstanding.is_some();
+        self.sequence += 1;
+        let now = Instant::now();
+        let mut payload = [0; PAYLOAD_LEN];
+        payload[..8].copy_from_slice(&self.sequence.to_be_bytes());
+        payload[8..].copy_from_slice(&unix_micros(SystemTime::now()).to_be_bytes());
+        self.outstanding = Some((payload, now));
+        self.unanswered_since.get_or_insert(now);
+        (payload.to_vec(), unanswered)
+    }
+
+    /// Starts over after reading from the peer was held up, during which its pongs could not
+    /// be read: the outstanding ping is forgotten and the next one is due after the minimum
+    /// interval.
+    pub fn restart(&mut self) {
+        let now = Instant::now();
+        self.current = self.min;
+        self.deadline = now + self.min;
+        self.saw_traffic = false;
+        self.outstanding = None;
+        self.unanswered_since = None;
+        self.last_traffic = now;
+    }
+
+    /// Matches a pong against the outstanding ping, returning the round-trip time and the
+    /// offset of the clock of the peer, if it told its time.
+    pub fn record_pong(&mut self, payload: &[u8]) -> Option<Pong> {
+        let received = SystemTime::now();
+        // Even a late pong to an earlier ping shows that the peer is alive.
+        self.unanswered_since = None;
+        let (sent_payload, sent) = self.outstanding?;
+        let (echo, peer_time) = payload.split_at_checked(PAYLOAD_LEN)?;
+        if echo != sent_payload {
+            return None;
+        }
+        self.outstanding = None;
+        let rtt = sent.elapsed();
+        let clock_offset_ms = <[u8; 8]>::try_from(peer_time).ok().map(|peer_time| {
+            let peer_micros = u64::from_be_bytes(peer_time) as f64;
+            let midpoint = unix_micros(received) as f64 - rtt.as_micros() as f64 / 2.0;
+            (peer_micros - midpoint) / 1000.0
+        });
+        Some(Pong {
+            rtt,
+            clock_offset_ms,
+        })
+    }
+}
+
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
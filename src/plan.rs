@@ -0,0 +1,330 @@
+//! The plan printed by `--dry-run`: the nodes and URLs the client would connect to, and the
+//! filters and destinations of the lines, after the preset and the config file are applied.
+//!
+//! The options that are otherwise only checked when they are used, such as the TLS certificates
+//! and the credentials of the sinks, are validated as well, so that a configuration can be
+//! tested before it is deployed as a service. Nothing is created, bound, or connected to.
+
+use crate::config::Settings;
+use crate::counts::DEFAULT_COUNT_INTERVAL;
+use crate::endpoint;
+use crate::error::Error;
+use crate::resolve::Resolver;
+use crate::sinks::s3::S3Credentials;
+use crate::tls::TlsSettings;
+use crate::TailArgs;
+use clap::ValueEnum;
+use std::fmt::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use url::Url;
+
+/// Validates the options and prints the plan. `capture` is the capture to replay instead of
+/// connecting to `domains`.
+pub fn print(
+    args: &TailArgs,
+    settings: &Settings,
+    domains: &[String],
+    capture: Option<&Path>,
+) -> Result<(), Error> {
+    validate(args)?;
+
+    let mut plan = String::new();
+    if let (Some(path), Some(profile)) = (&args.config, &args.profile) {
+        let _ = writeln!(plan, "Profile:\n  {profile} of {}", path.display());
+    }
+    let _ = writeln!(plan, "Canisters:");
+    for canister_id in &settings.canisters {
+        let _ = writeln!(plan, "  {canister_id}");
+    }
+
+    match capture {
+        Some(path) => {
+            let _ = writeln!(plan, "Source:\n  replay of {}", path.display());
+        }
+        None => {
+            if args.backfill {
+                let _ = writeln!(
+                    plan,
+                    "Backfill:\n  the retained log records, from the management canister"
+                );
+            }
+            nodes(&mut plan, args, settings, domains)?
+        }
+    }
+
+    let _ = writeln!(plan, "Filters:");
+    let filters = plan.len();
+    for include in &settings.include {
+        let _ = writeln!(plan, "  include {include}");
+    }
+    for exclude in &settings.exclude {
+        let _ = writeln!(plan, "  exclude {exclude}");
+    }
+    if let Some(condition) = &settings.condition {
+        let _ = writeln!(plan, "  where {condition}");
+    }
+    if args.before > 0 || args.after > 0 {
+        let _ = writeln!(
+            plan,
+            "  context of {} lines before and {} after a match",
+            args.before, args.after
+        );
+    }
+    if let Some(sample) = &args.sample {
+        let _ = writeln!(plan, "  sample {sample}");
+    }
+    if plan.len() == filters {
+        let _ = writeln!(plan, "  none, every line is written");
+    }
+
+    let alerts = &settings.alerts;
+    let webhooks: Vec<String> = [
+        ("webhook", &alerts.webhook),
+        ("Slack", &alerts.slack_webhook),
+        ("Discord", &alerts.discord_webhook),
+    ]
+    .into_iter()
+    .filter_map(|(kind, url)| Some(format!("{kind} {}", origin(url.as_ref()?))))
+    .collect();
+    if !alerts.patterns.is_empty() && !webhooks.is_empty() {
+        let _ = writeln!(plan, "Alerts to {}:", webhooks.join(", "));
+        for pattern in &alerts.patterns {
+            let _ = writeln!(plan, "  {pattern}");
+        }
+    }
+    if let Some(factor) = args.anomaly_factor {
+        let _ = write!(
+            plan,
+            "Anomalies:\n  rates {factor} times off their {} baseline, counted every {}",
+            humantime::format_duration(args.anomaly_baseline),
+            humantime::format_duration(args.anomaly_window)
+        );
+        match &args.anomaly_webhook {
+            Some(url) => {
+                let _ = writeln!(plan, ", to webhook {}", origin(url));
+            }
+            None => plan.push('\n'),
+        }
+        if let Some(dir) = &args.anomaly_record_dir {
+            let _ = writeln!(
+                plan,
+                "  recorded to {} from {} before until {} after",
+                dir.display(),
+                humantime::format_duration(args.anomaly_record_before),
+                humantime::format_duration(args.anomaly_record_after)
+            );
+        }
+    }
+
+    let _ = writeln!(plan, "Destinations:");
+    for destination in destinations(args) {
+        let _ = writeln!(plan, "  {destination}");
+    }
+    if let Some(addr) = args.control_addr {
+        let _ = writeln!(plan, "Control interface:\n  http://{addr}");
+    }
+    print!("{plan}");
+    Ok(())
+}
+
+/// Checks the options that are only checked when they are used.
+fn validate(args: &TailArgs) -> Result<(), Error> {
+    TlsSettings::new(
+        &args.tls_ca_cert,
+        args.tls_client_cert
+            .as_deref()
+            .zip(args.tls_client_key.as_deref()),
+        args.tls_server_name.as_deref(),
+        args.insecure_skip_verify,
+    )?;
+    Resolver::new(args.resolve.clone(), args.ip_version)?;
+    endpoint::handshake_headers(&args.headers, args.bearer_token.as_deref())?;
+    if let (Some(cert), Some(key)) = (&args.forward_client_cert, &args.forward_client_key) {
+        TlsSettings::new(&args.forward_ca_cert, Some((cert, key)), None, false)?;
+    }
+    if args.s3_bucket.is_some() {
+        S3Credentials::from_env()?;
+    }
+    if let Some(url) = &args.dedup_redis {
+        redis::Client::open(url.as_str()).map_err(|e| format!("invalid --dedup-redis URL: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Appends the nodes that would be connected, with the URLs of the log streams.
+fn nodes(
+    plan: &mut String,
+    args: &TailArgs,
+    settings: &Settings,
+    domains: &[String],
+) -> Result<(), Error> {
+    let strategy = args
+        .nodes_strategy
+        .to_possible_value()
+        .expect("every strategy has a name");
+    let limit = args
+        .nodes_strategy
+        .max_connections(args.nearest.or(args.max_connections).map(NonZeroUsize::get))
+        .unwrap_or(usize::MAX)
+        .min(domains.len());
+    if !args.only_node.is_empty() || !args.exclude_node.is_empty() {
+        let _ = writeln!(plan, "Node selection:");
+        for pattern in &args.only_node {
+            let _ = writeln!(plan, "  only {pattern}");
+        }
+        for pattern in &args.exclude_node {
+            let _ = writeln!(plan, "  exclude {pattern}");
+        }
+    }
+    if let Some(nearest) = args.nearest {
+        let _ = writeln!(
+            plan,
+            "Nodes: the {nearest} nearest of {} API boundary nodes, measured when starting:",
+            domains.len()
+        );
+        for domain in domains {
+            let _ = writeln!(plan, "  {domain}");
+        }
+        return Ok(());
+    }
+    let standby = args.standby.min(domains.len() - limit);
+    let _ = writeln!(
+        plan,
+        "Nodes: {limit} of {} API boundary nodes ({}), {standby} on standby:",
+        domains.len(),
+        strategy.get_name()
+    );
+    for (index, domain) in domains.iter().enumerate().take(limit + standby) {
+        let role = if index < limit { "" } else { " (standby)" };
+        let _ = writeln!(plan, "  {domain}{role}");
+        for canister_id in &settings.canisters {
+            let _ = writeln!(
+                plan,
+                "    {}",
+                args.endpoint_path_template.url(domain, canister_id)?
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Describes where the lines are written.
+fn destinations(args: &TailArgs) -> Vec<String> {
+    let mut destinations = Vec::new();
+    if args.quiet {
+        destinations.push("stdout: no lines".to_string());
+    } else if args.summary_only {
+        destinations.push("stdout: summaries only".to_string());
+    } else if args.json {
+        destinations.push("stdout: JSON lines".to_string());
+    } else {
+        destinations.push("stdout: lines from the template".to_string());
+    }
+    for pattern in &args.count_by {
+        destinations.push(format!(
+            "stdout: counts by {pattern} every {}",
+            humantime::format_duration(args.count_interval.unwrap_or(DEFAULT_COUNT_INTERVAL))
+        ));
+    }
+    if args.count {
+        let every = match args.count_interval {
+            Some(every) => format!("every {} and ", humantime::format_duration(every)),
+            None => String::new(),
+        };
+        destinations.push(format!("stdout: line counts {every}on exit"));
+    }
+    if args.annotate_upgrades {
+        destinations.push(format!(
+            "stdout: upgrade and restart annotations, module hashes read every {}",
+            humantime::format_duration(args.upgrade_poll_interval)
+        ));
+    }
+    if let Some(path) = &args.output_file {
+        let atomic = if args.output_file_atomic {
+            ", atomic"
+        } else {
+            ""
+        };
+        destinations.push(format!("file {path}{atomic}"));
+    }
+    if let Some(dir) = &args.split_output {
+        #[cfg(unix)]
+        let kind = if args.split_output_fifo {
+            "named pipe"
+        } else {
+            "file"
+        };
+        #[cfg(not(unix))]
+        let kind = "file";
+        destinations.push(format!("a {kind} per canister in {}", dir.display()));
+    }
+    for url in [&args.elasticsearch_url, &args.mirror_elasticsearch_url]
+        .into_iter()
+        .flatten()
+    {
+        destinations.push(format!(
+            "Elasticsearch {}, index {}",
+            without_credentials(url),
+            args.elasticsearch_index
+        ));
+    }
+    if let Some(bucket) = &args.s3_bucket {
+        destinations.push(format!("S3 bucket {bucket}/{}", args.s3_prefix));
+    }
+    if let Some(collector) = &args.forward {
+        destinations.push(format!(
+            "collector tls://{}:{}",
+            collector.host, collector.port
+        ));
+    }
+    if let Some(url) = &args.otlp_endpoint {
+        destinations.push(format!("OTLP collector {}", without_credentials(url)));
+    }
+    if let Some(path) = &args.csv_out {
+        destinations.push(format!("CSV file {}", path.display()));
+    }
+    if let Some(path) = &args.parquet_out {
+        destinations.push(format!("Parquet file {}", path.display()));
+    }
+    if let Some(addr) = args.grpc_addr {
+        destinations.push(format!("gRPC subscribers on {addr}"));
+    }
+    if let Some(addr) = args.serve_ws {
+        destinations.push(format!("WebSocket clients on ws://{addr}"));
+    }
+    if let Some(addr) = args.web_ui {
+        destinations.push(format!("Web dashboard on http://{addr}"));
+    }
+    #[cfg(windows)]
+    if let Some(source) = &args.event_log_source {
+        destinations.push(format!("Windows Event Log as {source}"));
+    }
+    #[cfg(target_os = "macos")]
+    if args.os_log {
+        destinations.push("macOS unified logging".to_string());
+    }
+    if let Some(path) = &args.record {
+        destinations.push(format!("capture {}", path.display()));
+    }
+    if let Some(path) = &args.tee_raw {
+        destinations.push(format!("raw messages to {path}"));
+    }
+    if let Some(path) = &args.stats_file {
+        destinations.push(format!("statistics to {} on exit", path.display()));
+    }
+    destinations
+}
+
+/// Returns the scheme and host of a URL, as the rest of a webhook URL is a secret.
+fn origin(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+/// Returns a URL without the user name and password.
+fn without_credentials(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
@@ -0,0 +1,623 @@
+//! Management of the set of active boundary node connections.
+//!
+//! By default every known node is connected, and a connection that ends is re-established
+//! with jittered exponential backoff, within the restart budget of the supervisor and not
+//! before the circuit of a failing node closes again. With a connection limit, only a subset is
+//! active and the remaining nodes are kept as candidates: a connection that ends is replaced
+//! by the next candidate, and the worst-scoring connection is periodically swapped out when
+//! it falls clearly behind the others. A connection is also swapped out when it stops
+//! delivering lines: either its pings go unanswered, or it has been silent for the gap
+//! timeout while another connection keeps delivering. Candidates whose circuit is open are
+//! passed over while others are available. Nodes that reject the client, e.g. with a
+//! policy-violation Close code, are not connected again.
+//!
+//! Optionally, a few candidates are kept connected as muted standbys, whose frames are
+//! discarded. A connection that ends or is swapped out is then replaced by promoting a standby,
+//! without a gap for connecting.
+//!
+//! With active hours, all connections are parked outside of them, and the pool is filled again
+//! from a freshly fetched node list when they begin.
+//!
+//! With --nearest, the candidates are ordered by latency, so that the nearest nodes are
+//! connected, and optionally ranked again periodically: connections to nodes that are no longer
+//! among the nearest are then swapped for nearer candidates.
+
+use crate::connection::{ConnectionConfig, Disconnect};
+use crate::nearest;
+use crate::nodes::Discovery;
+use crate::parking::ActiveHours;
+use crate::replay::ReplayRequest;
+use crate::supervisor::{self, Supervisor};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{interval, sleep, Duration, Instant, MissedTickBehavior};
+
+/// A connection is swapped out if its score is this many times worse than the median.
+const REBALANCE_FACTOR: f64 = 2.0;
+/// Minimum score difference to the median, in milliseconds, before a connection is swapped.
+const REBALANCE_MIN_MARGIN_MS: f64 = 250.0;
+/// Delay before reconnecting to a node whose connection ended.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay before reconnecting to a node that keeps failing.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// A connection is swapped for a nearer node only if the node's latency is below this share
+/// of the connection's, so that nodes of similar latency are not swapped back and forth.
+const NEARER_FACTOR: f64 = 0.8;
+/// Interval at which the active connections are checked for delivery gaps.
+const GAP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which boundary nodes to connect to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum NodesStrategy {
+    /// Every known node.
+    All,
+    /// A subset of --max-connections nodes (default: 3), replaced when they fall behind or
+    /// stop delivering.
+    Quorum,
+    /// A single node, replaced when it stops delivering.
+    Single,
+}
+
+impl NodesStrategy {
+    /// Number of nodes used by a quorum unless --max-connections is given.
+    const DEFAULT_QUORUM: usize = 3;
+
+    /// Returns the connection limit of the strategy, given the --max-connections option.
+    pub fn max_connections(self, max_connections: Option<usize>) -> Option<usize> {
+        match self {
+            Self::All => max_connections,
+            Self::Quorum => Some(max_connections.unwrap_or(Self::DEFAULT_QUORUM)),
+            Self::Single => Some(1),
+        }
+    }
+}
+
+/// The active connections and the candidate nodes that can replace them.
+pub struct Pool {
+    config: Arc<ConnectionConfig>,
+    max_connections: Option<usize>,
+    rebalance_interval: Duration,
+    gap_timeout: Duration,
+    supervisor: Supervisor,
+    /// Current reconnect delay of each node that failed to connect.
+    reconnect_delays: HashMap<String, Duration>,
+    active: HashMap<String, AbortHandle>,
+    /// Number of standby connections to keep.
+    standby_count: usize,
+    standby: Vec<Standby>,
+    candidates: VecDeque<String>,
+    /// Nodes that rejected the client, which are not connected again.
+    rejected: HashSet<String>,
+    parking: Option<Parking>,
+    nearest: Option<Nearest>,
+}
+
+/// How the nodes are ranked by latency.
+struct Nearest {
+    /// Time between two rankings, if the nodes are ranked again.
+    refresh: Option<Duration>,
+    /// Rankings of the nodes measured in the background, fastest first.
+    rankings: mpsc::Receiver<Vec<(String, Option<Duration>)>>,
+    sender: mpsc::Sender<Vec<(String, Option<Duration>)>>,
+}
+
+/// When the connections are parked, and how they are re-established.
+struct Parking {
+    hours: ActiveHours,
+    discovery: Discovery,
+    /// Whether the lines logged while parked are replayed when the connections resume.
+    backfill: bool,
+    /// When the connections were parked, while they are.
+    since: Option<SystemTime>,
+}
+
+/// A connection that is established but muted until it is promoted.
+struct Standby {
+    domain: String,
+    handle: AbortHandle,
+    muted: Arc<AtomicBool>,
+}
+
+impl Pool {
+    pub fn new(
+        domains: Vec<String>,
+        config: Arc<ConnectionConfig>,
+        max_connections: Option<usize>,
+        rebalance_interval: Duration,
+        gap_timeout: Duration,
+        standby_count: usize,
+        supervisor: Supervisor,
+    ) -> Self {
+        // Sessions can only be resumed on the node that started them, e.g. before a restart.
+        let mut candidates: VecDeque<String> = domains.into();
+        candidates
+            .make_contiguous()
+            .sort_by_key(|domain| !config.sessions.has_session(domain));
+        Self {
+            config,
+            max_connections,
+            rebalance_interval,
+            gap_timeout,
+            supervisor,
+            reconnect_delays: HashMap::new(),
+            active: HashMap::new(),
+            standby_count,
+            standby: Vec::new(),
+            candidates,
+            rejected: HashSet::new(),
+            parking: None,
+            nearest: None,
+        }
+    }
+
+    /// Keeps the candidates ordered by latency, as ranked before the pool starts, and ranks the
+    /// nodes again every `refresh` and whenever the node list is fetched again.
+    pub fn with_nearest(mut self, refresh: Option<Duration>) -> Self {
+        let (sender, rankings) = mpsc::channel(1);
+        self.nearest = Some(Nearest {
+            refresh,
+            rankings,
+            sender,
+        });
+        self
+    }
+
+    /// Parks the connections outside the active hours, fetching the node list again with
+    /// `discovery` when they begin. With `backfill`, the lines logged while parked are
+    /// replayed.
+    pub fn with_active_hours(
+        mut self,
+        hours: ActiveHours,
+        discovery: Discovery,
+        backfill: bool,
+    ) -> Self {
+        self.parking = Some(Parking {
+            hours,
+            discovery,
+            backfill,
+            since: None,
+        });
+        self
+    }
+
+    /// Supervises the connections until all of them have ended.
+    pub async fn run(mut self) {
+        let mut rebalance = interval(self.rebalance_interval);
+        rebalance.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        rebalance.tick().await; // Consume the first tick
+        let mut gap_check = interval(GAP_CHECK_INTERVAL);
+        gap_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let schedule_check = sleep(Duration::ZERO);
+        tokio::pin!(schedule_check);
+        let refresh = self.nearest.as_ref().and_then(|nearest| nearest.refresh);
+        let mut ranking = interval(refresh.unwrap_or(self.rebalance_interval));
+        ranking.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ranking.tick().await; // The nodes were ranked before the pool started.
+
+        match &self.parking {
+            Some(parking) if !parking.hours.contains(SystemTime::now()) => self.park(),
+            _ => self.fill(),
+        }
+        while !self.active.is_empty() || self.is_parked() {
+            self.config
+                .pool_size
+                .store(self.active.len(), Ordering::Relaxed);
+            tokio::select! {
+                Some((domain, disconnect)) = self.supervisor.join_next() => {
+                    // Aborted connections were already removed when they were swapped out or
+                    // parked, and connections that ended just before parking are candidates.
+                    if !self.is_parked() {
+                        if let Some(index) = self.standby.iter().position(|s| s.domain == domain) {
+                            self.standby.remove(index);
+                            if matches!(disconnect, Disconnect::Rejected(_)) {
+                                self.rejected.insert(domain);
+                            } else {
+                                self.candidates.push_back(domain);
+                            }
+                            self.fill();
+                        } else {
+                            self.active.remove(&domain);
+                            self.handle_disconnect(domain, disconnect);
+                        }
+                    }
+                }
+                _ = rebalance.tick() => {
+                    self.rebalance();
+                    self.config.health.decay();
+                }
+                _ = gap_check.tick(), if self.max_connections.is_some() => {
+                    self.replace_silent();
+                }
+                _ = ranking.tick(), if refresh.is_some() && !self.is_parked() => self.rank(),
+                Some(ranking) = async {
+                    match &mut self.nearest {
+                        Some(nearest) => nearest.rankings.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => self.apply_ranking(ranking),
+                _ = &mut schedule_check, if self.parking.is_some() => {
+                    let hours = self.check_schedule().await;
+                    let wait = hours.until_change(SystemTime::now());
+                    schedule_check.as_mut().reset(Instant::now() + wait);
+                }
+            }
+        }
+    }
+
+    fn is_parked(&self) -> bool {
+        self.parking
+            .as_ref()
+            .is_some_and(|parking| parking.since.is_some())
+    }
+
+    /// Parks or resumes the connections if the active hours began or ended, and returns the
+    /// active hours.
+    async fn check_schedule(&mut self) -> ActiveHours {
+        let parking = self.parking.as_ref().expect("active hours are configured");
+        let hours = parking.hours;
+        let active = hours.contains(SystemTime::now());
+        if active && self.is_parked() {
+            self.resume().await;
+        } else if !active && !self.is_parked() {
+            self.park();
+        }
+        hours
+    }
+
+    /// Closes all connections until the active hours begin.
+    fn park(&mut self) {
+        let parking = self.parking.as_mut().expect("active hours are configured");
+        info!(
+            "Parking connections outside the active hours {}.",
+            parking.hours
+        );
+        parking.since = Some(SystemTime::now());
+        self.config.parked.store(true, Ordering::Relaxed);
+        let standby = self
+            .standby
+            .drain(..)
+            .map(|standby| (standby.domain, standby.handle));
+        let connections: Vec<_> = self.active.drain().chain(standby).collect();
+        for (domain, handle) in connections {
+            handle.abort();
+            // Nodes waiting to reconnect are not connected.
+            if self
+                .config
+                .health
+                .get(&domain)
+                .is_some_and(|health| health.connected)
+            {
+                self.config.health.set_connected(&domain, false);
+                self.config.stats.record_disconnected(&domain);
+            }
+            self.candidates.push_back(domain);
+        }
+        self.reconnect_delays.clear();
+        self.supervisor.reset();
+    }
+
+    /// Fetches the node list again and re-establishes the connections.
+    async fn resume(&mut self) {
+        let parking = self.parking.as_mut().expect("active hours are configured");
+        info!(
+            "Resuming connections within the active hours {}.",
+            parking.hours
+        );
+        let parked_since = parking.since.take();
+        match parking.discovery.domains().await {
+            Ok(domains) => {
+                info!("Fetched {} API boundary nodes.", domains.len());
+                let domains: Vec<String> = domains
+                    .into_iter()
+                    .filter(|domain| !self.rejected.contains(domain))
+                    .collect();
+                self.candidates = match &self.nearest {
+                    Some(_) => nearest::rank(domains, self.config.clone())
+                        .await
+                        .into_iter()
+                        .map(|(domain, _)| domain)
+                        .collect(),
+                    None => domains,
+                }
+                .into();
+            }
+            Err(e) => error!("Failed to fetch API boundary nodes, keeping the previous ones: {e}"),
+        }
+        // Lines logged while parked are replayed, and previously requested ones are not
+        // requested again.
+        *self.config.replay.lock().unwrap() = ReplayRequest {
+            since: parked_since.filter(|_| parking.backfill),
+            tail: None,
+        };
+        self.config.parked.store(false, Ordering::Relaxed);
+        self.fill();
+    }
+
+    /// Connects to candidates, or promotes standbys, until the connection limit is reached,
+    /// then connects to candidates as standbys.
+    fn fill(&mut self) {
+        let limit = self.max_connections.unwrap_or(usize::MAX);
+        while self.active.len() < limit {
+            if let Some(domain) = self.promote_standby() {
+                info!("[{domain}] Promoted standby connection.");
+                continue;
+            }
+            let Some((domain, delay)) = self.next_candidate(true) else {
+                break;
+            };
+            self.spawn_after(domain, delay);
+        }
+        while self.standby.len() < self.standby_count {
+            let Some((domain, delay)) = self.next_candidate(true) else {
+                break;
+            };
+            debug!("[{domain}] Connecting as standby.");
+            let muted = Arc::new(AtomicBool::new(true));
+            let handle = self.start(domain.clone(), delay, muted.clone());
+            self.standby.push(Standby {
+                domain,
+                handle,
+                muted,
+            });
+        }
+    }
+
+    /// Unmutes a standby connection, preferring one that is already connected, and makes it
+    /// active.
+    fn promote_standby(&mut self) -> Option<String> {
+        let index = self
+            .standby
+            .iter()
+            .position(|standby| {
+                self.config
+                    .health
+                    .get(&standby.domain)
+                    .is_some_and(|health| health.connected)
+            })
+            .or((!self.standby.is_empty()).then_some(0))?;
+        let standby = self.standby.remove(index);
+        standby.muted.store(false, Ordering::Relaxed);
+        self.active.insert(standby.domain.clone(), standby.handle);
+        Some(standby.domain)
+    }
+
+    /// Takes the first candidate whose circuit is closed. If there is none, and `cooling` is
+    /// set, takes the candidate whose circuit closes first, with the time until then.
+    fn next_candidate(&mut self, cooling: bool) -> Option<(String, Duration)> {
+        if let Some(index) = self
+            .candidates
+            .iter()
+            .position(|domain| self.supervisor.cooldown(domain).is_none())
+        {
+            let domain = self.candidates.remove(index)?;
+            return Some((domain, Duration::ZERO));
+        }
+        if !cooling {
+            return None;
+        }
+        let (index, cooldown) = self
+            .candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(index, domain)| Some((index, self.supervisor.cooldown(domain)?)))
+            .min_by_key(|(_, cooldown)| *cooldown)?;
+        let domain = self.candidates.remove(index)?;
+        info!(
+            "[{domain}] All candidates have an open circuit, connecting in {}s.",
+            cooldown.as_secs()
+        );
+        Some((domain, cooldown))
+    }
+
+    /// Connects to a node after a delay, counting it as active while waiting.
+    fn spawn_after(&mut self, domain: String, delay: Duration) {
+        let handle = self.start(domain.clone(), delay, Arc::new(AtomicBool::new(false)));
+        self.active.insert(domain, handle);
+    }
+
+    fn start(&mut self, domain: String, delay: Duration, muted: Arc<AtomicBool>) -> AbortHandle {
+        self.supervisor.start(domain, delay, muted)
+    }
+
+    /// Reconnects to a node whose connection ended, or replaces it with a candidate.
+    fn handle_disconnect(&mut self, domain: String, disconnect: Disconnect) {
+        if let Disconnect::Rejected(reason) = disconnect {
+            warn!("[{domain}] Not connecting to this node again: {reason}.");
+            self.rejected.insert(domain);
+            self.fill();
+            return;
+        }
+        if self.max_connections.is_some() {
+            self.candidates.push_back(domain);
+            self.fill();
+            return;
+        }
+        // A connection that was established resets the backoff, and a node whose circuit is
+        // open is connected again once it closes.
+        let backoff = match disconnect {
+            Disconnect::Closed => RECONNECT_MIN_DELAY,
+            _ => self
+                .reconnect_delays
+                .get(&domain)
+                .map_or(RECONNECT_MIN_DELAY, |delay| {
+                    (*delay * 2).min(RECONNECT_MAX_DELAY)
+                }),
+        };
+        self.reconnect_delays.insert(domain.clone(), backoff);
+        let delay =
+            supervisor::jitter(backoff).max(self.supervisor.cooldown(&domain).unwrap_or_default());
+        info!("[{domain}] Reconnecting in {:.1}s.", delay.as_secs_f64());
+        self.spawn_after(domain, delay);
+    }
+
+    /// Swaps the worst-scoring active connection for a candidate if it lags clearly behind.
+    fn rebalance(&mut self) {
+        if self.max_connections.is_none() || (self.candidates.is_empty() && self.standby.is_empty())
+        {
+            return;
+        }
+
+        let mut scores: Vec<(String, f64)> = self
+            .active
+            .keys()
+            .filter_map(|domain| {
+                let health = self.config.health.get(domain)?;
+                Some((domain.clone(), health.score()))
+            })
+            .collect();
+        if scores.len() < 2 {
+            return;
+        }
+        scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let median = scores[scores.len() / 2].1;
+        let (worst, worst_score) = scores.pop().expect("at least two scores");
+        debug!("Worst connection {worst} scores {worst_score:.0}, median is {median:.0}.");
+        if worst_score < median * REBALANCE_FACTOR || worst_score - median < REBALANCE_MIN_MARGIN_MS
+        {
+            return;
+        }
+
+        self.swap(
+            &worst,
+            &format!("score {worst_score:.0}, median {median:.0}"),
+        );
+    }
+
+    /// Swaps out active connections that stopped delivering lines for candidates.
+    fn replace_silent(&mut self) {
+        let now = Instant::now();
+        let health: Vec<_> = self
+            .active
+            .keys()
+            .filter_map(|domain| Some((domain.clone(), self.config.health.get(domain)?)))
+            .collect();
+        // Whether any connection delivered lines recently, i.e. the canisters are not quiet.
+        let flowing = health.iter().any(|(_, node)| {
+            node.last_line
+                .is_some_and(|last| now.duration_since(last) < self.gap_timeout)
+        });
+
+        for (domain, node) in health {
+            let Some(connected_since) = node.connected_since else {
+                continue;
+            };
+            let silent_since = node
+                .last_line
+                .map_or(connected_since, |last| last.max(connected_since));
+            let reason = if node.stalls >= 1.0 {
+                "pings are unanswered".to_string()
+            } else if flowing && now.duration_since(silent_since) >= self.gap_timeout {
+                format!(
+                    "no lines for {}s while other nodes deliver",
+                    now.duration_since(silent_since).as_secs()
+                )
+            } else {
+                continue;
+            };
+            if !self.swap(&domain, &reason) {
+                return;
+            }
+        }
+    }
+
+    /// Ranks all known nodes by latency in the background.
+    fn rank(&self) {
+        let Some(nearest) = &self.nearest else {
+            return;
+        };
+        let domains: Vec<String> = self
+            .active
+            .keys()
+            .chain(self.standby.iter().map(|standby| &standby.domain))
+            .chain(&self.candidates)
+            .cloned()
+            .collect();
+        let sender = nearest.sender.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            let _ = sender.send(nearest::rank(domains, config).await).await;
+        });
+    }
+
+    /// Orders the candidates by a new ranking, and swaps connections to nodes that are no
+    /// longer among the nearest for nearer candidates.
+    fn apply_ranking(&mut self, ranking: Vec<(String, Option<Duration>)>) {
+        if self.is_parked() {
+            return;
+        }
+        let position: HashMap<&String, usize> = ranking
+            .iter()
+            .enumerate()
+            .map(|(position, (domain, _))| (domain, position))
+            .collect();
+        let latency: HashMap<&String, Duration> = ranking
+            .iter()
+            .filter_map(|(domain, latency)| Some((domain, (*latency)?)))
+            .collect();
+        let mut candidates = Vec::from(std::mem::take(&mut self.candidates));
+        candidates.sort_by_key(|domain| position.get(domain).copied().unwrap_or(usize::MAX));
+        self.candidates = candidates.into();
+
+        let limit = self.max_connections.unwrap_or(usize::MAX);
+        let nearest: HashSet<&String> = ranking
+            .iter()
+            .map(|(domain, _)| domain)
+            .filter(|domain| !self.rejected.contains(*domain))
+            .take(limit)
+            .collect();
+        let mut distant: Vec<String> = self
+            .active
+            .keys()
+            .filter(|domain| !nearest.contains(domain))
+            .cloned()
+            .collect();
+        // Swap the most distant connections first.
+        distant.sort_by_key(|domain| std::cmp::Reverse(position.get(domain).copied()));
+        for domain in distant {
+            let Some(candidate) = self.candidates.front() else {
+                break;
+            };
+            let nearer = match (latency.get(candidate), latency.get(&domain)) {
+                (Some(candidate), Some(current)) => {
+                    candidate.as_secs_f64() < current.as_secs_f64() * NEARER_FACTOR
+                }
+                (candidate, _) => candidate.is_some(),
+            };
+            if !nearest.contains(candidate) || !nearer {
+                break;
+            }
+            self.swap(&domain, "no longer among the nearest nodes");
+        }
+    }
+
+    /// Aborts an active connection and replaces it with a standby or a candidate, keeping the
+    /// aborted node as a candidate. Returns false if there is no replacement.
+    fn swap(&mut self, domain: &str, reason: &str) -> bool {
+        let replacement = match self.promote_standby() {
+            Some(standby) => format!("standby {standby}"),
+            None => {
+                let Some((candidate, _)) = self.next_candidate(false) else {
+                    return false;
+                };
+                info!("[{candidate}] Connecting as replacement.");
+                self.spawn_after(candidate.clone(), Duration::ZERO);
+                candidate
+            }
+        };
+        warn!("[{domain}] Swapping out connection ({reason}) for {replacement}.");
+        if let Some(handle) = self.active.remove(domain) {
+            handle.abort();
+            self.config.health.set_connected(domain, false);
+            self.config.stats.record_disconnected(domain);
+        }
+        self.candidates.push_back(domain.to_string());
+        self.fill();
+        true
+    }
+}
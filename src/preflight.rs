@@ -0,0 +1,121 @@
+//! Checks of the canisters before connecting to the nodes.
+//!
+//! A canister that does not exist, or whose logs only its controllers and allowed viewers may
+//! read, would otherwise show up as a handshake failure on every node. Before connecting, the
+//! certified state of every canister is read to check that it exists and has code installed,
+//! and its logs are fetched from the management canister as an anonymous caller, which
+//! succeeds only if its log visibility is public. A check that cannot be carried out, e.g.
+//! because the API endpoints are unreachable, is reported and skipped.
+
+use crate::canister_log;
+use crate::error::Error;
+use crate::nodes::Endpoints;
+use candid::Principal;
+use futures_util::future::join_all;
+use ic_agent::agent::RejectCode;
+use ic_agent::{Agent, AgentError};
+use log::{info, warn};
+use tokio::time::{timeout, Duration};
+
+/// Longest time the checks of a canister may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the checks found out about a canister.
+enum Finding {
+    /// The logs can be streamed.
+    Visible,
+    /// The canister has no code installed, so it logs nothing until it is installed.
+    Empty,
+    /// A check could not be carried out.
+    Unknown(String),
+}
+
+/// Checks that the canisters exist and that their logs are public.
+pub async fn check(
+    http_client: reqwest::Client,
+    endpoints: &Endpoints,
+    canister_ids: &[String],
+) -> Result<(), Error> {
+    let agent = match endpoints.agent(http_client).await {
+        Ok(agent) => agent,
+        Err(e) => {
+            warn!("Skipped checking the canisters: {e}");
+            return Ok(());
+        }
+    };
+    let agent = &agent;
+    let findings = join_all(canister_ids.iter().map(|canister_id| async move {
+        let finding = timeout(CHECK_TIMEOUT, check_canister(agent, canister_id))
+            .await
+            .unwrap_or_else(|_| Ok(Finding::Unknown("timed out".to_string())));
+        (canister_id, finding)
+    }))
+    .await;
+    for (canister_id, finding) in findings {
+        match finding? {
+            Finding::Visible => info!("Canister {canister_id} exists and its logs are public."),
+            Finding::Empty => warn!(
+                "Canister {canister_id} has no code installed, so it logs nothing until code \
+                 is installed."
+            ),
+            Finding::Unknown(e) => warn!("Could not check canister {canister_id}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Checks a single canister, failing if it does not exist or its logs are not public.
+async fn check_canister(agent: &Agent, canister_id: &str) -> Result<Finding, Error> {
+    let principal =
+        Principal::from_text(canister_id).map_err(|e| format!("invalid canister ID: {e}"))?;
+    match agent
+        .read_state_canister_info(principal, "module_hash")
+        .await
+    {
+        Ok(_) => {}
+        Err(AgentError::LookupPathAbsent(_)) => return Ok(Finding::Empty),
+        Err(e) if is_not_found(&e) => {
+            return Err(format!("canister {canister_id} does not exist").into());
+        }
+        Err(e) => return Ok(Finding::Unknown(e.to_string())),
+    }
+
+    match canister_log::fetch(agent, principal).await {
+        Ok(_) => Ok(Finding::Visible),
+        Err(e) if is_not_allowed(&e) => Err(format!(
+            "the logs of canister {canister_id} are controller-only: its log_visibility does \
+             not make them public, so the boundary nodes do not stream them"
+        )
+        .into()),
+        Err(e) if is_not_found(&e) => Err(format!("canister {canister_id} does not exist").into()),
+        Err(e) => Ok(Finding::Unknown(e.to_string())),
+    }
+}
+
+/// Returns whether the error says that the caller may not read the logs of the canister.
+pub fn is_not_allowed(error: &AgentError) -> bool {
+    matches!(
+        error,
+        AgentError::CertifiedReject { reject, .. } | AgentError::UncertifiedReject { reject, .. }
+            if reject.reject_message.contains("not allowed")
+    )
+}
+
+/// Returns whether the error says that the canister does not exist.
+pub fn is_not_found(error: &AgentError) -> bool {
+    let message = match error {
+        AgentError::HttpError(payload) if matches!(payload.status, 400 | 404) => {
+            String::from_utf8_lossy(&payload.content).to_lowercase()
+        }
+        AgentError::CertifiedReject { reject, .. }
+        | AgentError::UncertifiedReject { reject, .. }
+            if reject.reject_code == RejectCode::DestinationInvalid =>
+        {
+            reject.reject_message.to_lowercase()
+        }
+        _ => return false,
+    };
+    ["not found", "not_found", "does not exist"]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+}
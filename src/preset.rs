@@ -0,0 +1,110 @@
+//! Presets that bundle the options for common ways of watching logs.
+//!
+//! A preset only fills in what the command line leaves open, so explicit options still take
+//! precedence over it, except where they would break the guarantees of the pipe preset.
+
+use crate::color::ColorMode;
+use crate::template::Template;
+use crate::TailArgs;
+use clap::ValueEnum;
+use log::info;
+
+/// Line template of the incident preset.
+const INCIDENT_TEMPLATE: &str = "{ts} [{node}] {backfill}{suspect}{msg}";
+
+/// A bundle of options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// For on-call engineers during an outage: dedup, timestamps and node prefixes, colors by
+    /// severity with highlighted errors, and no alerts
+    Incident,
+    /// For scripts: one JSON object per line on stdout, flushed line by line, without colors
+    /// or prefixes, and everything else on stderr, regardless of future default changes
+    Pipe,
+}
+
+impl Preset {
+    /// Applies the preset to the arguments of the tail command.
+    pub fn apply(self, args: &mut TailArgs) -> Result<(), String> {
+        match self {
+            Self::Incident => {
+                args.dedup = true;
+                if args.format.is_none() && !args.json {
+                    args.format = Some(Template::parse(INCIDENT_TEMPLATE)?);
+                }
+                if !args.json {
+                    args.color.get_or_insert(ColorMode::Auto);
+                }
+                // The incident is already being handled, so alerts would only add noise.
+                let webhooks = [
+                    args.alert_webhook.take(),
+                    args.slack_webhook.take(),
+                    args.discord_webhook.take(),
+                ];
+                if webhooks.iter().any(Option::is_some) {
+                    info!("Alerts are silenced by the incident preset.");
+                }
+            }
+            Self::Pipe => {
+                if args.format.is_some() {
+                    return Err("--preset pipe prints JSON and cannot be used with --format".into());
+                }
+                if args.color.is_some_and(|color| color != ColorMode::Never)
+                    || !args.highlight.is_empty()
+                {
+                    return Err("--preset pipe never prints colors".into());
+                }
+                if args.batch_lines.is_some() || args.flush_interval.is_some() {
+                    return Err("--preset pipe flushes every line and cannot batch stdout".into());
+                }
+                args.json = true;
+                args.color = Some(ColorMode::Never);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::Parser;
+
+    fn tail_args(options: &[&str]) -> TailArgs {
+        let command_line = ["ic-bn-logs-client", "-c", "ryjl3-tyaaa-aaaaa-aaaba-cai"];
+        Cli::try_parse_from(command_line.iter().chain(options))
+            .unwrap()
+            .tail
+    }
+
+    #[test]
+    fn pipe_prints_json_without_colors() {
+        let mut args = tail_args(&[]);
+        Preset::Pipe.apply(&mut args).unwrap();
+        assert!(args.json);
+        assert_eq!(args.color, Some(ColorMode::Never));
+
+        let mut args = tail_args(&["--color", "never"]);
+        Preset::Pipe.apply(&mut args).unwrap();
+        assert_eq!(args.color, Some(ColorMode::Never));
+    }
+
+    #[test]
+    fn pipe_rejects_options_that_change_stdout() {
+        for options in [
+            &["--format", "{msg}"][..],
+            &["--color", "always"],
+            &["--color", "auto"],
+            &["--highlight", "error"],
+            &["--batch-lines", "100"],
+            &["--flush-interval", "1s"],
+        ] {
+            let mut args = tail_args(options);
+            assert!(
+                Preset::Pipe.apply(&mut args).is_err(),
+                "--preset pipe accepted {options:?}"
+            );
+        }
+    }
+}
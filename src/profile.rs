@@ -0,0 +1,127 @@
+//! Named profiles in the `--config` file, selected with `--profile`, that bundle the options of
+//! an environment, such as the `--ic-url` of a testnet, its subnet, node overrides, and sinks,
+//! so that switching environments takes one option instead of a list of them.
+//!
+//! The `profiles` key of the file maps the names of the profiles to objects whose keys are the
+//! long names of options, e.g. `ic-url` or `otlp-endpoint`, and whose values are strings,
+//! numbers, `true` for flags, or arrays for repeatable options. The options of the selected
+//! profile fill in those that the command line and the environment leave open. Unlike the
+//! other keys of the file, the profiles are only read at startup.
+
+use crate::Cli;
+use clap::parser::ValueSource;
+use clap::CommandFactory;
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+/// Options that select the profile, and so cannot be set by one.
+const RESERVED: &[&str] = &["config", "profile"];
+
+/// Returns the command line with the options of the profile added, or an error if the profile
+/// is not defined or sets options that do not exist.
+pub fn apply(argv: Vec<OsString>, path: &Path, name: &str) -> Result<Vec<OsString>, String> {
+    let profile = read(path, name)?;
+    let command = Cli::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(&argv)
+        .map_err(|e| e.to_string())?;
+    let (command, matches) = match matches.subcommand() {
+        Some((subcommand, matches)) => match command.find_subcommand(subcommand) {
+            Some(command) => (command, matches),
+            None => return Ok(argv),
+        },
+        None => (&command, &matches),
+    };
+
+    let mut options: Vec<OsString> = Vec::new();
+    for (key, value) in profile {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .filter(|arg| !RESERVED.contains(&arg.get_id().as_str()))
+            .ok_or_else(|| format!("profile {name} sets the unknown option {key}"))?;
+        // The options given on the command line or in the environment take precedence.
+        if matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let takes_values = arg.get_action().takes_values();
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::Bool(true) if !takes_values => {
+                    options.push(format!("--{long}").into());
+                    continue;
+                }
+                Value::Bool(false) if !takes_values => continue,
+                _ if !takes_values => {
+                    return Err(format!(
+                        "profile {name} sets the flag {key} to {value}, expected true or false"
+                    ));
+                }
+                Value::String(value) => value,
+                Value::Number(number) => number.to_string(),
+                Value::Bool(flag) => flag.to_string(),
+                value => {
+                    return Err(format!(
+                        "profile {name} sets {key} to {value}, expected a string, a number, a \
+                         boolean, or an array of them"
+                    ));
+                }
+            };
+            options.push(format!("--{long}={value}").into());
+        }
+    }
+
+    // Positional values may follow a `--`, so the options go before it.
+    let at = argv
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(argv.len());
+    let mut argv = argv;
+    argv.splice(at..at, options);
+    Ok(argv)
+}
+
+/// Reads the options of the profile from the config file.
+fn read(path: &Path, name: &str) -> Result<Map<String, Value>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let contents: Value = serde_json::from_str(&text)
+        .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+    let profiles = match contents.get("profiles") {
+        Some(Value::Object(profiles)) => profiles.clone(),
+        Some(_) => {
+            return Err(format!(
+                "the profiles of the config file {} must be an object",
+                path.display()
+            ));
+        }
+        None => Map::new(),
+    };
+    match profiles.get(name) {
+        Some(Value::Object(profile)) => Ok(profile.clone()),
+        Some(_) => Err(format!(
+            "profile {name} of the config file {} must be an object",
+            path.display()
+        )),
+        None if profiles.is_empty() => Err(format!(
+            "profile {name} is not defined; the config file {} defines no profiles",
+            path.display()
+        )),
+        None => Err(format!(
+            "profile {name} is not defined; the config file {} defines {}",
+            path.display(),
+            profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
@@ -0,0 +1,151 @@
+//! Tunneling of outgoing connections through SOCKS5 and HTTP proxies.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio_socks::tcp::Socks5Stream;
+use url::Url;
+
+/// Maximum size of the response header returned by an HTTP proxy for a CONNECT request.
+const MAX_CONNECT_RESPONSE_SIZE: usize = 8 * 1024;
+
+/// Environment variables consulted when no proxy is given on the command line.
+const PROXY_ENV_VARS: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+
+/// Returns the proxy to use, preferring the command line over the environment.
+pub fn resolve(cli_proxy: Option<&str>) -> Result<Option<Url>, String> {
+    let proxy = match cli_proxy {
+        Some(proxy) => proxy.to_string(),
+        None => match PROXY_ENV_VARS
+            .iter()
+            .find_map(|var| std::env::var(var).ok())
+        {
+            Some(proxy) if !proxy.is_empty() => proxy,
+            _ => return Ok(None),
+        },
+    };
+
+    let url = Url::parse(&proxy).map_err(|e| format!("invalid proxy URL {proxy}: {e}"))?;
+    match url.scheme() {
+        "http" | "socks5" | "socks5h" => {}
+        scheme => return Err(format!("unsupported proxy scheme: {scheme}")),
+    }
+    if url.host_str().is_none() {
+        return Err(format!("proxy URL {proxy} has no host"));
+    }
+    Ok(Some(url))
+}
+
+/// Builds an HTTP client that sends all requests through the proxy, if one is given.
+pub fn http_client(proxy: Option<&Url>) -> reqwest::Result<reqwest::Client> {
+    // Same overall request timeout as the default client of `ic_agent`.
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .timeout(Duration::from_secs(360));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+    }
+    builder.build()
+}
+
+/// Opens a TCP connection to `host:port` tunneled through the given proxy.
+pub async fn connect(proxy: &Url, host: &str, port: u16) -> io::Result<TcpStream> {
+    let proxy_host = proxy.host_str().unwrap_or_default();
+    let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+    let proxy_addr = (proxy_host, proxy_port);
+
+    match proxy.scheme() {
+        "http" => connect_http(proxy, proxy_addr, host, port).await,
+        scheme @ ("socks5" | "socks5h") => {
+            // Like curl, `socks5` resolves the target locally and `socks5h` lets the proxy
+            // resolve it.
+            let stream = match (scheme, credentials(proxy)) {
+                ("socks5", credentials) => {
+                    let target = lookup_host((host, port)).await?.next().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {host}"))
+                    })?;
+                    match credentials {
+                        Some((user, pass)) => {
+                            Socks5Stream::connect_with_password(proxy_addr, target, &user, &pass)
+                                .await
+                        }
+                        None => Socks5Stream::connect(proxy_addr, target).await,
+                    }
+                }
+                (_, Some((user, pass))) => {
+                    Socks5Stream::connect_with_password(proxy_addr, (host, port), &user, &pass)
+                        .await
+                }
+                (_, None) => Socks5Stream::connect(proxy_addr, (host, port)).await,
+            };
+            stream
+                .map(Socks5Stream::into_inner)
+                .map_err(|e| io::Error::other(format!("SOCKS5 proxy error: {e}")))
+        }
+        scheme => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported proxy scheme: {scheme}"),
+        )),
+    }
+}
+
+/// Establishes a tunnel with an HTTP `CONNECT` request.
+async fn connect_http(
+    proxy: &Url,
+    proxy_addr: (&str, u16),
+    host: &str,
+    port: u16,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy_addr).await?;
+
+    // IPv6 addresses are enclosed in brackets, to tell them apart from the port.
+    let authority = match host.parse() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, pass)) = credentials(proxy) {
+        let token = STANDARD.encode(format!("{user}:{pass}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response header byte by byte so that no tunneled data is consumed.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP proxy response header too large",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(stream),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP proxy refused tunnel: {status_line}"),
+        )),
+    }
+}
+
+/// Extracts the percent-decoded username and password from the proxy URL.
+fn credentials(proxy: &Url) -> Option<(String, String)> {
+    if proxy.username().is_empty() {
+        return None;
+    }
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+    Some((
+        decode(proxy.username()),
+        decode(proxy.password().unwrap_or_default()),
+    ))
+}
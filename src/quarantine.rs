@@ -0,0 +1,159 @@
+//! Quarantine of nodes that keep sending bad frames, with `--quarantine-threshold`.
+//!
+//! A node whose frames cannot be decoded, reassembled, or received within the size limit would
+//! otherwise fill stderr with warnings and the output with hex or lossy garbage. Once a node has
+//! sent the threshold number of bad frames within a minute, it is quarantined: its lines are
+//! still counted, but no longer delivered, and its bad frames are no longer logged. The
+//! quarantined nodes are reported periodically, and a node is released once it has sent no bad
+//! frame for five minutes.
+
+use crate::connection::ConnectionConfig;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval_at, Duration, Instant};
+
+/// Time within which the threshold number of bad frames quarantines a node.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Time without bad frames after which a quarantined node is released.
+const RELEASE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Interval at which the quarantined nodes are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The bad frames of a single node.
+#[derive(Default)]
+struct NodeState {
+    /// When the recent bad frames arrived, within the window.
+    strikes: VecDeque<Instant>,
+    /// When the node was quarantined, if it is.
+    since: Option<Instant>,
+    /// When the last bad frame arrived.
+    last_bad: Option<Instant>,
+    /// Bad frames and lines held back since the node was quarantined.
+    bad_frames: u64,
+    held_back: u64,
+}
+
+impl NodeState {
+    /// Releases the node if it has sent no bad frame for the release time.
+    fn release_expired(&mut self, domain: &str) {
+        if self.since.is_some()
+            && self
+                .last_bad
+                .is_none_or(|last| last.elapsed() >= RELEASE_AFTER)
+        {
+            info!(
+                "[{domain}] Released from quarantine after {} without bad frames; {} lines were \
+                 held back.",
+                humantime::format_duration(RELEASE_AFTER),
+                self.held_back
+            );
+            *self = Self::default();
+        }
+    }
+}
+
+/// Tracks the bad frames of the nodes and quarantines the nodes that keep sending them.
+pub struct Quarantine {
+    threshold: NonZeroUsize,
+    nodes: Mutex<HashMap<String, NodeState>>,
+}
+
+impl Quarantine {
+    /// Creates a quarantine for nodes sending `threshold` bad frames within a minute.
+    pub fn new(threshold: NonZeroUsize) -> Self {
+        Self {
+            threshold,
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an undecodable or oversized frame of a node, returning whether it is worth
+    /// logging, which it is not once the node is quarantined.
+    pub fn record_bad_frame(&self, domain: &str) -> bool {
+        let now = Instant::now();
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.entry(domain.to_string()).or_default();
+        node.last_bad = Some(now);
+        if node.since.is_some() {
+            node.bad_frames += 1;
+            return false;
+        }
+        node.strikes.push_back(now);
+        while node
+            .strikes
+            .front()
+            .is_some_and(|strike| now.duration_since(*strike) > WINDOW)
+        {
+            node.strikes.pop_front();
+        }
+        if node.strikes.len() >= self.threshold.get() {
+            warn!(
+                "[{domain}] Quarantined after {} bad frames within {}: its lines are counted but \
+                 no longer written, and its bad frames no longer logged.",
+                node.strikes.len(),
+                humantime::format_duration(WINDOW)
+            );
+            node.strikes.clear();
+            node.since = Some(now);
+        }
+        true
+    }
+
+    /// Returns whether the lines of a node are held back, counting the line if they are.
+    pub fn holds_back(&self, domain: &str) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(node) = nodes.get_mut(domain) else {
+            return false;
+        };
+        node.release_expired(domain);
+        if node.since.is_none() {
+            return false;
+        }
+        node.held_back += 1;
+        true
+    }
+
+    /// Describes the quarantined nodes, releasing those that have recovered, or returns
+    /// `None` if no node is quarantined.
+    fn report(&self) -> Option<String> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut quarantined: Vec<String> = nodes
+            .iter_mut()
+            .filter_map(|(domain, node)| {
+                node.release_expired(domain);
+                let since = node.since?;
+                Some(format!(
+                    "{domain} for {} ({} bad frames, {} lines held back)",
+                    humantime::format_duration(Duration::from_secs(since.elapsed().as_secs())),
+                    node.bad_frames,
+                    node.held_back
+                ))
+            })
+            .collect();
+        if quarantined.is_empty() {
+            return None;
+        }
+        quarantined.sort();
+        Some(quarantined.join(", "))
+    }
+}
+
+/// Starts a task that reports the quarantined nodes periodically.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    if config.quarantine.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + REPORT_INTERVAL, REPORT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Some(quarantine) = &config.quarantine
+                && let Some(report) = quarantine.report()
+            {
+                warn!("Quarantined nodes: {report}.");
+            }
+        }
+    });
+}
@@ -0,0 +1,449 @@
+//! Filter expressions given with `--where`, in the config file, at the interactive prompt, or
+//! through the control interface, such as
+//! `node ~ "fr1" && msg contains "timeout" && level >= warn`.
+//!
+//! An expression combines conditions with `&&`, `||`, `!`, and parentheses; `&&` binds more
+//! tightly than `||`. A condition compares a field with a value:
+//!
+//! - `==` and `!=` compare the text of the field with the value.
+//! - `~` and `!~` match the field against a regular expression.
+//! - `contains` looks for the value in the field.
+//! - `<`, `<=`, `>`, and `>=` compare levels, ordered `info < warn < error`, or numbers.
+//!
+//! The fields are `node`, `canister`, `msg`, `level` (`error`, `warn`, or `info`, as for
+//! colored output), `backfill`, `resumed`, `suspect`, `tainted`, `panic`, and `fields.<NAME>`
+//! for the entries of structured records, where nested entries are reached with further dots.
+//! A field on its own, such as `backfill` or `fields.user`, holds if it is true or present.
+//! Values are double-quoted strings with `\"` and `\\` escapes, or bare words and numbers. A
+//! condition on an entry that a line does not have fails.
+
+use crate::event::LogEvent;
+use crate::severity::Severity;
+use regex::Regex;
+use serde_json::Value;
+use std::fmt;
+
+/// A parsed filter expression.
+#[derive(Clone, Debug)]
+pub struct Query {
+    expr: Expr,
+    /// The expression as written, to show it back.
+    source: String,
+}
+
+impl Query {
+    /// Parses an expression.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source).map_err(|e| format!("invalid expression {source:?}: {e}"))?;
+        let mut parser = Parser { tokens, next: 0 };
+        let expr = parser
+            .expression()
+            .and_then(|expr| match parser.tokens.get(parser.next) {
+                None => Ok(expr),
+                Some((at, token)) => Err(format!("unexpected {token} at position {at}")),
+            })
+            .map_err(|e| format!("invalid expression {source:?}: {e}"))?;
+        Ok(Self {
+            expr,
+            source: source.trim().to_string(),
+        })
+    }
+
+    /// Combines expressions into one that holds if all of them hold, or None if there are
+    /// none.
+    pub fn all(queries: Vec<Self>) -> Option<Self> {
+        if queries.len() < 2 {
+            return queries.into_iter().next();
+        }
+        let source = queries
+            .iter()
+            .map(|query| format!("({})", query.source))
+            .collect::<Vec<_>>()
+            .join(" && ");
+        let expr = queries
+            .into_iter()
+            .map(|query| query.expr)
+            .reduce(|left, right| Expr::And(Box::new(left), Box::new(right)))
+            .expect("there are at least two expressions");
+        Some(Self { expr, source })
+    }
+
+    /// Returns whether a line satisfies the expression.
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        self.expr.eval(event)
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PartialEq for Query {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// A field on its own, which holds if it is true or present.
+    Present(Field),
+    Condition(Field, Test),
+}
+
+impl Expr {
+    fn eval(&self, event: &LogEvent) -> bool {
+        match self {
+            Self::And(left, right) => left.eval(event) && right.eval(event),
+            Self::Or(left, right) => left.eval(event) || right.eval(event),
+            Self::Not(expr) => !expr.eval(event),
+            Self::Present(field) => field
+                .text(event)
+                .is_some_and(|text| !text.is_empty() && text != "false"),
+            Self::Condition(field, test) => field.text(event).is_some_and(|text| test.holds(&text)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Field {
+    Node,
+    Canister,
+    Message,
+    Level,
+    Backfill,
+    Resumed,
+    Suspect,
+    Tainted,
+    Panic,
+    /// An entry of a structured record, by the path of its name and those of its parents.
+    Record(Vec<String>),
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "node" => Some(Self::Node),
+            "canister" => Some(Self::Canister),
+            "msg" | "message" => Some(Self::Message),
+            "level" => Some(Self::Level),
+            "backfill" => Some(Self::Backfill),
+            "resumed" => Some(Self::Resumed),
+            "suspect" => Some(Self::Suspect),
+            "tainted" => Some(Self::Tainted),
+            "panic" => Some(Self::Panic),
+            _ => {
+                let path = name.strip_prefix("fields.")?;
+                let path: Vec<String> = path.split('.').map(str::to_string).collect();
+                path.iter()
+                    .all(|name| !name.is_empty())
+                    .then_some(Self::Record(path))
+            }
+        }
+    }
+
+    /// Returns the text of the field, or None if the line has no such entry.
+    fn text(&self, event: &LogEvent) -> Option<String> {
+        let text = match self {
+            Self::Node => event.node.clone(),
+            Self::Canister => event.canister_id.clone(),
+            Self::Message => event.message.clone(),
+            Self::Level => Severity::of(event).name().to_string(),
+            Self::Backfill => event.backfill.to_string(),
+            Self::Resumed => event.resumed.to_string(),
+            Self::Suspect => event.suspect.to_string(),
+            Self::Tainted => (!event.taint.is_empty()).to_string(),
+            Self::Panic => event.panic.to_string(),
+            Self::Record(path) => {
+                let (first, rest) = path.split_first()?;
+                let mut value = event.fields.as_ref()?.get(first)?;
+                for name in rest {
+                    value = value.get(name)?;
+                }
+                match value {
+                    Value::Null => return None,
+                    Value::String(text) => text.clone(),
+                    value => value.to_string(),
+                }
+            }
+        };
+        Some(text)
+    }
+}
+
+/// Returns the rank of a level in the order `info < warn < error`.
+fn level_rank(name: &str) -> Option<f64> {
+    match name.to_ascii_lowercase().as_str() {
+        "info" => Some(0.0),
+        "warn" | "warning" => Some(1.0),
+        "error" => Some(2.0),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Test {
+    Equals(String),
+    Matches(Regex),
+    Contains(String),
+    /// An ordering of the field relative to a number.
+    Order(Operator, f64),
+    /// An ordering of the level relative to the rank of a level.
+    LevelOrder(Operator, f64),
+}
+
+impl Test {
+    fn holds(&self, text: &str) -> bool {
+        match self {
+            Self::Equals(value) => text == value,
+            Self::Matches(regex) => regex.is_match(text),
+            Self::Contains(value) => text.contains(value.as_str()),
+            Self::Order(operator, value) => text
+                .parse::<f64>()
+                .is_ok_and(|number| operator.orders(number, *value)),
+            Self::LevelOrder(operator, value) => {
+                level_rank(text).is_some_and(|rank| operator.orders(rank, *value))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Match,
+    NotMatch,
+    Contains,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    fn orders(self, left: f64, right: f64) -> bool {
+        match self {
+            Self::Equal => left == right,
+            Self::Less => left < right,
+            Self::LessOrEqual => left <= right,
+            Self::Greater => left > right,
+            Self::GreaterOrEqual => left >= right,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Operator(Operator),
+    /// A double-quoted string.
+    Quoted(String),
+    /// A field name, bare word, or number.
+    Word(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open => write!(f, "'('"),
+            Self::Close => write!(f, "')'"),
+            Self::And => write!(f, "'&&'"),
+            Self::Or => write!(f, "'||'"),
+            Self::Not => write!(f, "'!'"),
+            Self::Operator(_) => write!(f, "operator"),
+            Self::Quoted(text) => write!(f, "string {text:?}"),
+            Self::Word(word) => write!(f, "'{word}'"),
+        }
+    }
+}
+
+/// Splits an expression into tokens, with their positions counted from 1.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let at = i + 1;
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (chars[i], next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Operator(Operator::Equal), 2),
+            ('!', Some('=')) => (Token::Operator(Operator::NotEqual), 2),
+            ('!', Some('~')) => (Token::Operator(Operator::NotMatch), 2),
+            ('!', _) => (Token::Not, 1),
+            ('~', _) => (Token::Operator(Operator::Match), 1),
+            ('<', Some('=')) => (Token::Operator(Operator::LessOrEqual), 2),
+            ('<', _) => (Token::Operator(Operator::Less), 1),
+            ('>', Some('=')) => (Token::Operator(Operator::GreaterOrEqual), 2),
+            ('>', _) => (Token::Operator(Operator::Greater), 1),
+            ('"', _) => {
+                let mut text = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => return Err(format!("unterminated string at position {at}")),
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.get(j + 1), Some('"' | '\\')) => {
+                            text.push(chars[j + 1]);
+                            j += 2;
+                        }
+                        Some(c) => {
+                            text.push(*c);
+                            j += 1;
+                        }
+                    }
+                }
+                (Token::Quoted(text), j + 1 - i)
+            }
+            (c, _) if is_word_char(c) => {
+                let len = chars[i..].iter().take_while(|c| is_word_char(**c)).count();
+                let word: String = chars[i..i + len].iter().collect();
+                let token = match word.as_str() {
+                    "contains" => Token::Operator(Operator::Contains),
+                    _ => Token::Word(word),
+                };
+                (token, len)
+            }
+            (c, _) => return Err(format!("unexpected character {c:?} at position {at}")),
+        };
+        tokens.push((at, token));
+        i += len;
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | ':')
+}
+
+/// A recursive descent parser over the tokens.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    /// Returns the next token, or an error naming what was expected at the end.
+    fn take(&mut self, expected: &str) -> Result<(usize, Token), String> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| format!("{expected} expected at the end"))?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut expr = self.conjunction()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.conjunction()?));
+        }
+        Ok(expr)
+    }
+
+    fn conjunction(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.take("a condition")? {
+            (_, Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            (_, Token::Open) => {
+                let expr = self.expression()?;
+                match self.take("')'")? {
+                    (_, Token::Close) => Ok(expr),
+                    (at, token) => Err(format!("')' expected at position {at}, found {token}")),
+                }
+            }
+            (at, Token::Word(name)) => {
+                let field = Field::parse(&name).ok_or_else(|| {
+                    format!(
+                        "unknown field '{name}' at position {at}; expected node, canister, msg, \
+                         level, backfill, resumed, suspect, tainted, panic, or fields.<NAME>"
+                    )
+                })?;
+                match self.peek() {
+                    Some(Token::Operator(_)) => self.condition(field),
+                    _ => Ok(Expr::Present(field)),
+                }
+            }
+            (at, token) => Err(format!(
+                "a condition expected at position {at}, found {token}"
+            )),
+        }
+    }
+
+    fn condition(&mut self, field: Field) -> Result<Expr, String> {
+        let Token::Operator(operator) = self.take("an operator")?.1 else {
+            unreachable!("the caller saw an operator");
+        };
+        let (at, value) = match self.take("a value")? {
+            (at, Token::Quoted(value) | Token::Word(value)) => (at, value),
+            (at, token) => return Err(format!("a value expected at position {at}, found {token}")),
+        };
+        let test = match operator {
+            Operator::Equal | Operator::NotEqual if field == Field::Level => {
+                let rank = level_rank(&value).ok_or_else(|| unknown_level(&value, at))?;
+                Test::LevelOrder(Operator::Equal, rank)
+            }
+            Operator::Equal | Operator::NotEqual => Test::Equals(value),
+            Operator::Match | Operator::NotMatch => Test::Matches(
+                Regex::new(&value).map_err(|e| format!("invalid regex at position {at}: {e}"))?,
+            ),
+            Operator::Contains => Test::Contains(value),
+            _ if field == Field::Level => Test::LevelOrder(
+                operator,
+                level_rank(&value).ok_or_else(|| unknown_level(&value, at))?,
+            ),
+            _ if matches!(field, Field::Record(_)) => Test::Order(
+                operator,
+                value
+                    .parse()
+                    .map_err(|_| format!("a number expected at position {at}, found {value:?}"))?,
+            ),
+            _ => {
+                return Err(format!(
+                    "only level and fields.<NAME> can be ordered, at position {at}"
+                ));
+            }
+        };
+        let condition = Expr::Condition(field, test);
+        Ok(match operator {
+            Operator::NotEqual | Operator::NotMatch => Expr::Not(Box::new(condition)),
+            _ => condition,
+        })
+    }
+}
+
+fn unknown_level(value: &str, at: usize) -> String {
+    format!("unknown level {value:?} at position {at}; expected error, warn, or info")
+}
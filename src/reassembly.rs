@@ -0,0 +1,544 @@
+//! Reassembly of log records that a boundary node split across several WebSocket messages.
+//!
+//! Each message is limited to 5KB, so a boundary node that supports chunking sends long
+//! records as a sequence of chunk messages. A chunk starts with the ASCII record separator
+//! (`0x1E`), followed by a `<record-id>:<index>:<count>` header terminated by the ASCII unit
+//! separator (`0x1F`), followed by the chunk payload. Messages without this prefix are
+//! complete records and are passed through unchanged.
+//!
+//! Nodes and canisters that split long entries without chunk headers can instead mark the
+//! parts that continue in the next record with a suffix, such as a trailing backslash. With
+//! `--continuation-suffix`, the decoded records ending with it are joined with the records that
+//! follow on the same connection, and the suffix is removed.
+
+use crate::decode::DecodedRecord;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use tokio::time::{Duration, Instant};
+
+/// Marks the start of a chunk message.
+const CHUNK_START: u8 = 0x1E;
+/// Separates the chunk header from the chunk payload.
+const HEADER_END: u8 = 0x1F;
+/// Largest chunk count accepted in a header. The chunks of a record are tracked in a vector of
+/// this length, so the count a node sends must be bounded before it is allocated.
+const MAX_CHUNK_COUNT: usize = 4096;
+
+/// Limits applied while reassembling chunked records.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkLimits {
+    /// Maximum size in bytes of a reassembled record.
+    pub max_record_size: usize,
+    /// Maximum number of records reassembled concurrently.
+    pub max_pending: usize,
+    /// Time to wait for the remaining chunks of a record before discarding it.
+    pub timeout: Duration,
+}
+
+/// Reasons a chunk or a partially reassembled record is discarded.
+#[derive(Debug)]
+pub enum ReassemblyError {
+    /// The chunk header could not be parsed.
+    MalformedHeader,
+    /// The chunk index is outside of the announced chunk count.
+    InvalidIndex {
+        id: String,
+        index: usize,
+        count: usize,
+    },
+    /// A chunk disagrees with the chunk count announced by earlier chunks.
+    CountMismatch { id: String },
+    /// The chunk count exceeds the number of chunks a record may have.
+    TooManyChunks { id: String, count: usize },
+    /// The reassembled record would exceed the configured size limit.
+    TooLarge { id: String, size: usize },
+    /// Too many records are being reassembled at the same time.
+    TooManyPending { id: String },
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedHeader => write!(f, "malformed chunk header"),
+            Self::InvalidIndex { id, index, count } => {
+                write!(
+                    f,
+                    "chunk {index} of record {id} exceeds chunk count {count}"
+                )
+            }
+            Self::CountMismatch { id } => write!(f, "inconsistent chunk count for record {id}"),
+            Self::TooManyChunks { id, count } => write!(
+                f,
+                "record {id} announces {count} chunks, more than the limit of {MAX_CHUNK_COUNT}"
+            ),
+            Self::TooLarge { id, size } => {
+                write!(f, "record {id} exceeds the size limit ({size} bytes)")
+            }
+            Self::TooManyPending { id } => {
+                write!(f, "too many records pending reassembly, dropping {id}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReassemblyError {}
+
+/// A record whose chunks are still being collected.
+struct PendingRecord {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects chunks of a single connection and yields records once they are complete.
+pub struct Reassembler {
+    limits: ChunkLimits,
+    pending: HashMap<String, PendingRecord>,
+}
+
+impl Reassembler {
+    pub fn new(limits: ChunkLimits) -> Self {
+        Self {
+            limits,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Processes a received message.
+    ///
+    /// Returns the complete record if the message was not chunked or if it was the last
+    /// missing chunk of a record, and `None` while chunks of the record are still missing.
+    pub fn push(&mut self, message: &[u8]) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        if message.first() != Some(&CHUNK_START) {
+            return Ok(Some(message.to_vec()));
+        }
+
+        let (id, index, count, payload) = parse_chunk(message)?;
+
+        if index >= count {
+            return Err(ReassemblyError::InvalidIndex { id, index, count });
+        }
+
+        if !self.pending.contains_key(&id) && self.pending.len() >= self.limits.max_pending {
+            return Err(ReassemblyError::TooManyPending { id });
+        }
+
+        let record = self
+            .pending
+            .entry(id.clone())
+            .or_insert_with(|| PendingRecord {
+                chunks: vec![None; count],
+                received: 0,
+                size: 0,
+                started: Instant::now(),
+            });
+
+        if record.chunks.len() != count {
+            self.pending.remove(&id);
+            return Err(ReassemblyError::CountMismatch { id });
+        }
+
+        // A repeated chunk replaces the previous copy.
+        if let Some(previous) = record.chunks[index].replace(payload.to_vec()) {
+            record.size -= previous.len();
+        } else {
+            record.received += 1;
+        }
+        record.size += payload.len();
+
+        if record.size > self.limits.max_record_size {
+            let size = record.size;
+            self.pending.remove(&id);
+            return Err(ReassemblyError::TooLarge { id, size });
+        }
+
+        if record.received < count {
+            return Ok(None);
+        }
+
+        let record = self.pending.remove(&id).expect("record is pending");
+        Ok(Some(
+            record.chunks.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Discards records whose chunks did not all arrive within the timeout.
+    ///
+    /// Returns the identifiers of the discarded records.
+    pub fn expire(&mut self) -> Vec<String> {
+        let timeout = self.limits.timeout;
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, record)| record.started.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.pending.remove(id);
+        }
+        expired
+    }
+}
+
+/// Splits a chunk message into its record identifier, chunk index, chunk count, and payload.
+fn parse_chunk(message: &[u8]) -> Result<(String, usize, usize, &[u8]), ReassemblyError> {
+    let header_end = message
+        .iter()
+        .position(|&b| b == HEADER_END)
+        .ok_or(ReassemblyError::MalformedHeader)?;
+    let header = std::str::from_utf8(&message[1..header_end])
+        .map_err(|_| ReassemblyError::MalformedHeader)?;
+
+    let mut fields = header.split(':');
+    let (Some(id), Some(index), Some(count), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(ReassemblyError::MalformedHeader);
+    };
+    let index = index
+        .parse()
+        .map_err(|_| ReassemblyError::MalformedHeader)?;
+    let count = count
+        .parse()
+        .map_err(|_| ReassemblyError::MalformedHeader)?;
+    if id.is_empty() || count == 0 {
+        return Err(ReassemblyError::MalformedHeader);
+    }
+    if count > MAX_CHUNK_COUNT {
+        return Err(ReassemblyError::TooManyChunks {
+            id: id.to_string(),
+            count,
+        });
+    }
+
+    Ok((id.to_string(), index, count, &message[header_end + 1..]))
+}
+
+/// How records that continue in the next record are recognized, with `--continuation-suffix`.
+#[derive(Clone, Debug)]
+pub struct Continuation {
+    /// Matches at the end of a record that the next record continues.
+    pub suffix: Regex,
+    /// Maximum size in bytes of a joined record.
+    pub max_record_size: usize,
+    /// Time to wait for the continuation of a record before passing it on as it is.
+    pub timeout: Duration,
+}
+
+impl Continuation {
+    /// Anchors the pattern at the end of the record.
+    pub fn new(pattern: &Regex, max_record_size: usize, timeout: Duration) -> Self {
+        let suffix = Regex::new(&format!("(?:{})$", pattern.as_str()))
+            .expect("an anchored valid pattern is valid");
+        Self {
+            suffix,
+            max_record_size,
+            timeout,
+        }
+    }
+}
+
+/// A record whose continuation has not arrived yet.
+struct PendingText {
+    record: DecodedRecord,
+    resumed: bool,
+    started: Instant,
+}
+
+/// What joining a record resulted in.
+pub enum Joined {
+    /// The record continues in the next record, so it is held back.
+    Pending,
+    /// The record is complete.
+    Complete(DecodedRecord, bool),
+    /// The joined record exceeded the size limit, so it is passed on cut at the limit.
+    TooLarge(DecodedRecord, bool),
+}
+
+/// Joins the records of a single connection that end with the continuation suffix with the
+/// records following them, so that log entries split by a node are written as one line.
+pub struct Joiner {
+    continuation: Continuation,
+    pending: Option<PendingText>,
+}
+
+impl Joiner {
+    pub fn new(continuation: Continuation) -> Self {
+        Self {
+            continuation,
+            pending: None,
+        }
+    }
+
+    /// Processes a decoded record, and whether it was replayed when resuming the stream.
+    pub fn push(&mut self, record: DecodedRecord, resumed: bool) -> Joined {
+        let Some(mut pending) = self.pending.take() else {
+            return self.hold_or_pass(record, resumed, Instant::now());
+        };
+        pending.record.message.push_str(&record.message);
+        // A joined record counts as resumed if its start was.
+        match self.hold_or_pass(pending.record, pending.resumed, pending.started) {
+            Joined::Complete(record, resumed)
+                if record.message.len() > self.continuation.max_record_size =>
+            {
+                self.truncate(record, resumed)
+            }
+            joined => joined,
+        }
+    }
+
+    fn hold_or_pass(
+        &mut self,
+        mut record: DecodedRecord,
+        resumed: bool,
+        started: Instant,
+    ) -> Joined {
+        let Some(suffix) = self.continuation.suffix.find(&record.message) else {
+            return Joined::Complete(record, resumed);
+        };
+        record.message.truncate(suffix.start());
+        if record.message.len() > self.continuation.max_record_size {
+            return self.truncate(record, resumed);
+        }
+        self.pending = Some(PendingText {
+            record,
+            resumed,
+            started,
+        });
+        Joined::Pending
+    }
+
+    /// Cuts a joined record that exceeds the size limit down to the limit.
+    fn truncate(&self, mut record: DecodedRecord, resumed: bool) -> Joined {
+        let end = record
+            .message
+            .floor_char_boundary(self.continuation.max_record_size);
+        record.message.truncate(end);
+        Joined::TooLarge(record, resumed)
+    }
+
+    /// Returns the held back record if its continuation did not arrive within the timeout.
+    pub fn expire(&mut self) -> Option<(DecodedRecord, bool)> {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.started.elapsed() >= self.continuation.timeout)
+        {
+            return self.finish();
+        }
+        None
+    }
+
+    /// Returns the held back record, e.g. when the connection ends.
+    pub fn finish(&mut self) -> Option<(DecodedRecord, bool)> {
+        self.pending
+            .take()
+            .map(|pending| (pending.record, pending.resumed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ChunkLimits = ChunkLimits {
+        max_record_size: 64,
+        max_pending: 2,
+        timeout: Duration::from_secs(60),
+    };
+
+    fn chunk(header: &str, payload: &str) -> Vec<u8> {
+        let mut message = vec![CHUNK_START];
+        message.extend_from_slice(header.as_bytes());
+        message.push(HEADER_END);
+        message.extend_from_slice(payload.as_bytes());
+        message
+    }
+
+    fn record(message: &str) -> DecodedRecord {
+        DecodedRecord {
+            message: message.to_string(),
+            fields: None,
+        }
+    }
+
+    fn joiner(max_record_size: usize) -> Joiner {
+        let suffix = Regex::new(r"\\").unwrap();
+        Joiner::new(Continuation::new(
+            &suffix,
+            max_record_size,
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[test]
+    fn parse_chunk_splits_header_and_payload() {
+        let message = chunk("abc:1:3", "payload");
+        let (id, index, count, payload) = parse_chunk(&message).unwrap();
+        assert_eq!((id.as_str(), index, count, payload), ("abc", 1, 3, &b"payload"[..]));
+    }
+
+    #[test]
+    fn parse_chunk_rejects_malformed_headers() {
+        for header in ["abc:1", "abc:1:3:4", ":0:1", "abc:x:1", "abc:0:-1", "abc:0:0"] {
+            assert!(
+                matches!(
+                    parse_chunk(&chunk(header, "")),
+                    Err(ReassemblyError::MalformedHeader)
+                ),
+                "{header}"
+            );
+        }
+        let unterminated = [&[CHUNK_START][..], b"abc:0:1"].concat();
+        assert!(matches!(
+            parse_chunk(&unterminated),
+            Err(ReassemblyError::MalformedHeader)
+        ));
+    }
+
+    #[test]
+    fn parse_chunk_bounds_the_chunk_count() {
+        assert!(parse_chunk(&chunk(&format!("abc:0:{MAX_CHUNK_COUNT}"), "")).is_ok());
+        assert!(matches!(
+            parse_chunk(&chunk(&format!("abc:0:{}", MAX_CHUNK_COUNT + 1), "")),
+            Err(ReassemblyError::TooManyChunks { count, .. }) if count == MAX_CHUNK_COUNT + 1
+        ));
+    }
+
+    #[test]
+    fn push_passes_unchunked_messages_through() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(b"plain").unwrap(), Some(b"plain".to_vec()));
+    }
+
+    #[test]
+    fn push_reassembles_chunks_out_of_order() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(&chunk("r:2:3", "c")).unwrap(), None);
+        assert_eq!(reassembler.push(&chunk("r:0:3", "a")).unwrap(), None);
+        assert_eq!(
+            reassembler.push(&chunk("r:1:3", "b")).unwrap(),
+            Some(b"abc".to_vec())
+        );
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn push_replaces_duplicate_chunks() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(&chunk("r:0:2", "old")).unwrap(), None);
+        assert_eq!(reassembler.push(&chunk("r:0:2", "a")).unwrap(), None);
+        assert_eq!(
+            reassembler.push(&chunk("r:1:2", "b")).unwrap(),
+            Some(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn push_rejects_an_index_beyond_the_count() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert!(matches!(
+            reassembler.push(&chunk("r:2:2", "a")),
+            Err(ReassemblyError::InvalidIndex { index: 2, count: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn push_drops_a_record_whose_count_changes() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(&chunk("r:0:2", "a")).unwrap(), None);
+        assert!(matches!(
+            reassembler.push(&chunk("r:1:3", "b")),
+            Err(ReassemblyError::CountMismatch { .. })
+        ));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn push_limits_the_pending_records() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(&chunk("a:0:2", "a")).unwrap(), None);
+        assert_eq!(reassembler.push(&chunk("b:0:2", "b")).unwrap(), None);
+        assert!(matches!(
+            reassembler.push(&chunk("c:0:2", "c")),
+            Err(ReassemblyError::TooManyPending { .. })
+        ));
+        // Chunks of records already pending are still accepted.
+        assert_eq!(
+            reassembler.push(&chunk("a:1:2", "a")).unwrap(),
+            Some(b"aa".to_vec())
+        );
+    }
+
+    #[test]
+    fn push_limits_the_record_size() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        let half = "x".repeat(LIMITS.max_record_size / 2);
+        assert_eq!(reassembler.push(&chunk("r:0:3", &half)).unwrap(), None);
+        assert_eq!(reassembler.push(&chunk("r:1:3", &half)).unwrap(), None);
+        assert!(matches!(
+            reassembler.push(&chunk("r:2:3", "x")),
+            Err(ReassemblyError::TooLarge { size, .. }) if size == LIMITS.max_record_size + 1
+        ));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn expire_discards_incomplete_records() {
+        let mut reassembler = Reassembler::new(LIMITS);
+        assert_eq!(reassembler.push(&chunk("r:0:2", "a")).unwrap(), None);
+        assert!(reassembler.expire().is_empty());
+
+        let mut reassembler = Reassembler::new(ChunkLimits {
+            timeout: Duration::ZERO,
+            ..LIMITS
+        });
+        assert_eq!(reassembler.push(&chunk("r:0:2", "a")).unwrap(), None);
+        assert_eq!(reassembler.expire(), vec!["r".to_string()]);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn joiner_joins_continued_records() {
+        let mut joiner = joiner(64);
+        assert!(matches!(joiner.push(record("first \\"), true), Joined::Pending));
+        assert!(matches!(joiner.push(record("second \\"), false), Joined::Pending));
+        match joiner.push(record("third"), false) {
+            Joined::Complete(record, resumed) => {
+                assert_eq!(record.message, "first second third");
+                assert!(resumed);
+            }
+            _ => panic!("the record is complete"),
+        }
+        assert!(joiner.finish().is_none());
+    }
+
+    #[test]
+    fn joiner_cuts_joined_records_at_the_size_limit() {
+        let mut joiner = joiner(8);
+        assert!(matches!(joiner.push(record("12345\\"), false), Joined::Pending));
+        match joiner.push(record("67890"), false) {
+            Joined::TooLarge(record, _) => assert_eq!(record.message, "12345678"),
+            _ => panic!("the joined record exceeds the limit"),
+        }
+
+        assert!(matches!(joiner.push(record("12345\\"), false), Joined::Pending));
+        // The record is cut before a character that would cross the limit.
+        match joiner.push(record("67é\\"), false) {
+            Joined::TooLarge(record, _) => assert_eq!(record.message, "1234567"),
+            _ => panic!("the joined record exceeds the limit"),
+        }
+        assert!(joiner.finish().is_none());
+    }
+
+    #[test]
+    fn joiner_passes_unjoined_records_on() {
+        let mut joiner = joiner(4);
+        assert!(matches!(
+            joiner.push(record("longer than the limit"), false),
+            Joined::Complete(..)
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+//! Masking of sensitive data in log lines, such as principals, email addresses, and secrets,
+//! before the filters, alerts, and outputs see them.
+//!
+//! Matches of the patterns are replaced in the message and in the string values of the fields,
+//! so that lines can be forwarded to third parties without leaking user identifiers. The
+//! canister and node of an event are kept, as they identify the stream rather than a user.
+//!
+//! With `--record-redacted`, the patterns are also applied to the raw frames written to the
+//! capture files of `--record` and `--anomaly-record-dir`. Frames may be binary encodings with
+//! length prefixes, such as CBOR, so every matched byte is replaced by an asterisk instead,
+//! which keeps the frames decodable.
+
+use crate::event::LogEvent;
+use clap::ValueEnum;
+use regex::{bytes, Regex};
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// Text that replaces every match.
+pub const MASK: &str = "[REDACTED]";
+
+/// Patterns for common kinds of sensitive data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BuiltinPattern {
+    /// Textual principals, e.g. of users and canisters
+    Principals,
+    /// Email addresses
+    Emails,
+    /// Hex strings of 32 digits or more, e.g. keys, tokens, and hashes, with or without 0x
+    HexSecrets,
+}
+
+impl BuiltinPattern {
+    fn regex(self) -> Regex {
+        let pattern = match self {
+            // Groups of five base32 characters, the last one possibly shorter. Canister IDs
+            // have five groups and user principals eleven; fewer groups match too many words.
+            Self::Principals => r"\b[a-z2-7]{5}(?:-[a-z2-7]{5}){3,9}-[a-z2-7]{1,5}\b",
+            Self::Emails => {
+                r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}\b"
+            }
+            Self::HexSecrets => r"\b(?:0x)?[0-9a-fA-F]{32,}\b",
+        };
+        Regex::new(pattern).expect("the built-in patterns are valid")
+    }
+}
+
+/// The patterns that are masked in every line.
+pub struct Redactor {
+    patterns: Vec<Regex>,
+    /// The same patterns, for raw frames.
+    frame_patterns: Vec<bytes::Regex>,
+}
+
+impl Redactor {
+    /// Creates a redactor for the given patterns and built-in patterns.
+    pub fn new(patterns: Vec<Regex>, builtins: &[BuiltinPattern]) -> Self {
+        let mut patterns = patterns;
+        patterns.extend(builtins.iter().map(|builtin| builtin.regex()));
+        let frame_patterns = patterns
+            .iter()
+            .map(|pattern| {
+                bytes::Regex::new(pattern.as_str()).expect("a valid pattern is valid for bytes")
+            })
+            .collect();
+        Self {
+            patterns,
+            frame_patterns,
+        }
+    }
+
+    /// Masks all matches in the message and the fields of the event.
+    pub fn redact(&self, event: &mut LogEvent) {
+        if let Cow::Owned(message) = self.redact_text(&event.message) {
+            event.message = message;
+        }
+        if let Some(fields) = event.fields.as_mut() {
+            for value in fields.values_mut() {
+                self.redact_value(value);
+            }
+        }
+    }
+
+    /// Masks all matches in a raw frame, byte for byte.
+    pub fn redact_frame<'a>(&self, frame: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut frame = Cow::Borrowed(frame);
+        for pattern in &self.frame_patterns {
+            let ranges: Vec<_> = pattern.find_iter(&frame).map(|m| m.range()).collect();
+            for range in ranges {
+                frame.to_mut()[range].fill(b'*');
+            }
+        }
+        frame
+    }
+
+    /// Masks all matches in the string values of a JSON value.
+    pub fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                if let Cow::Owned(redacted) = self.redact_text(text) {
+                    *text = redacted;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_value(item)),
+            Value::Object(fields) => fields.values_mut().for_each(|item| self.redact_value(item)),
+            _ => {}
+        }
+    }
+
+    pub fn redact_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, MASK) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
+}
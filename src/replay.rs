@@ -0,0 +1,93 @@
+//! Requesting historical log lines from boundary nodes and telling them apart from live ones.
+//!
+//! When a replay is requested, the boundary node first sends a burst of historical lines and
+//! then continues with live tailing. The end of the burst is not signaled explicitly, so a
+//! connection considers itself backfilling until either the requested number of lines has
+//! been received or the stream has been quiet for a short while.
+
+use crate::endpoint;
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+/// Gap between messages after which the initial backfill burst is considered complete.
+const BACKFILL_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Historical lines requested from the boundary nodes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplayRequest {
+    /// Replay lines logged at or after this time.
+    pub since: Option<SystemTime>,
+    /// Replay at most this many of the most recent lines.
+    pub tail: Option<u64>,
+}
+
+impl ReplayRequest {
+    /// Returns true if any historical lines are requested.
+    pub fn is_requested(&self) -> bool {
+        self.since.is_some() || self.tail.is_some()
+    }
+
+    /// Adds the replay parameters to the query of a log stream URL.
+    pub fn append_to(&self, url: &mut Url) {
+        if let Some(since) = self.since {
+            endpoint::set_query_param(
+                url,
+                "since",
+                &humantime::format_rfc3339_seconds(since).to_string(),
+            );
+        }
+        if let Some(tail) = self.tail {
+            endpoint::set_query_param(url, "tail", &tail.to_string());
+        }
+    }
+}
+
+/// Tracks whether the lines of a connection still belong to the initial backfill burst.
+pub struct Backfill {
+    remaining: Option<u64>,
+    last_message: Instant,
+    received: u64,
+    active: bool,
+}
+
+impl Backfill {
+    pub fn new(request: &ReplayRequest) -> Self {
+        Self {
+            remaining: request.tail,
+            last_message: Instant::now(),
+            received: 0,
+            active: request.is_requested() && request.tail != Some(0),
+        }
+    }
+
+    /// Classifies the next received line, returning true if it is a backfilled line.
+    pub fn classify(&mut self) -> bool {
+        if self.active && self.last_message.elapsed() >= BACKFILL_QUIET_PERIOD {
+            self.active = false;
+        }
+        self.last_message = Instant::now();
+        if !self.active {
+            return false;
+        }
+
+        self.received += 1;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.active = false;
+            }
+        }
+        true
+    }
+
+    /// Returns true while the initial backfill burst is in progress.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns the number of backfilled lines received so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
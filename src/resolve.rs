@@ -0,0 +1,137 @@
+//! Address selection for the connections to the nodes: pinning a node domain to an address,
+//! like curl's `--resolve`, and restricting the connections to IPv4 or IPv6.
+//!
+//! Only the TCP connection uses the selected address; the URL, the `Host` header, and the TLS
+//! server name keep the node domain, so the node serves and authenticates as usual. Through a
+//! proxy, the selected address is passed to the proxy instead of the domain.
+
+use crate::proxy;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{lookup_host, TcpStream};
+use url::Url;
+
+/// IP version of the connections to the nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IpVersion {
+    /// IPv4 only
+    #[value(name = "4")]
+    V4,
+    /// IPv6 only
+    #[value(name = "6")]
+    V6,
+}
+
+impl IpVersion {
+    fn matches(self, ip: IpAddr) -> bool {
+        match self {
+            Self::V4 => ip.is_ipv4(),
+            Self::V6 => ip.is_ipv6(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::V4 => "IPv4",
+            Self::V6 => "IPv6",
+        }
+    }
+}
+
+/// Selects the addresses that the connections to the nodes are opened to.
+pub struct Resolver {
+    overrides: HashMap<String, IpAddr>,
+    ip_version: Option<IpVersion>,
+}
+
+impl Resolver {
+    /// Creates a resolver that connects to the given addresses instead of resolving their
+    /// domains, and only to addresses of the IP version, if given.
+    pub fn new(
+        overrides: Vec<(String, IpAddr)>,
+        ip_version: Option<IpVersion>,
+    ) -> Result<Self, String> {
+        if let Some(ip_version) = ip_version
+            && let Some((domain, ip)) = overrides.iter().find(|(_, ip)| !ip_version.matches(*ip))
+        {
+            return Err(format!(
+                "the address {ip} of {domain} is not an {} address",
+                ip_version.name()
+            ));
+        }
+        Ok(Self {
+            overrides: overrides.into_iter().collect(),
+            ip_version,
+        })
+    }
+
+    /// Returns the addresses to connect to, in order, or `None` if the host is resolved by the
+    /// proxy.
+    pub async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        proxy: Option<&Url>,
+    ) -> io::Result<Option<Vec<SocketAddr>>> {
+        if let Some(ip) = self.overrides.get(host) {
+            return Ok(Some(vec![SocketAddr::new(*ip, port)]));
+        }
+        let Some(ip_version) = self.ip_version else {
+            if proxy.is_some() {
+                return Ok(None);
+            }
+            return Ok(Some(lookup_host((host, port)).await?.collect()));
+        };
+        let addresses: Vec<_> = lookup_host((host, port))
+            .await?
+            .filter(|address| ip_version.matches(address.ip()))
+            .collect();
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} has no {} address", ip_version.name()),
+            ));
+        }
+        Ok(Some(addresses))
+    }
+
+    /// Opens a TCP connection to the host, directly or through the proxy, trying the
+    /// addresses returned by [`Resolver::resolve`] in order.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        addresses: Option<Vec<SocketAddr>>,
+        proxy: Option<&Url>,
+    ) -> io::Result<TcpStream> {
+        match (addresses, proxy) {
+            (None, None) => TcpStream::connect((host, port)).await,
+            (None, Some(proxy)) => proxy::connect(proxy, host, port).await,
+            (Some(addresses), None) => TcpStream::connect(addresses.as_slice()).await,
+            (Some(addresses), Some(proxy)) => {
+                proxy::connect(proxy, &addresses[0].ip().to_string(), port).await
+            }
+        }
+    }
+}
+
+/// Parses a `--resolve` value: a domain and the IP address to connect to instead, e.g.
+/// `node.example.com:192.0.2.1` or `node.example.com:[2001:db8::1]`.
+pub fn parse_override(value: &str) -> Result<(String, IpAddr), String> {
+    let (domain, ip) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected DOMAIN:IP, got {value}"))?;
+    let ip = ip
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(ip);
+    let ip = ip
+        .parse()
+        .map_err(|_| format!("invalid IP address in {value}: {ip}"))?;
+    if domain.is_empty() {
+        return Err(format!("expected DOMAIN:IP, got {value}"));
+    }
+    Ok((domain.to_ascii_lowercase(), ip))
+}
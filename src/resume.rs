@@ -0,0 +1,240 @@
+//! Resumption of log streams after a reconnect, for boundary nodes that support it.
+//!
+//! A node that can resume streams names a session in the `x-log-session` response header.
+//! The client counts the records it receives in the session, and when it reconnects to the
+//! node it asks for the stream to continue after them with the `resume` and `from` query
+//! parameters. The node answers with the number of missed records it replays first in the
+//! `x-log-resumed` header; these are marked as replayed. Nodes without the capability never
+//! name a session, so the client connects to them as usual.
+//!
+//! With `--state-dir`, the sessions are also saved to a file every few seconds and on exit,
+//! and loaded at startup, so that a restarted client resumes the streams where it stopped.
+//! Nodes with a saved session are connected first, since a session can only be resumed on the
+//! node that started it.
+
+use crate::connection::ConnectionConfig;
+use crate::endpoint;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval_at, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use url::Url;
+
+/// Response header naming the resumable session of a stream.
+pub const SESSION_HEADER: &str = "x-log-session";
+/// Response header with the number of replayed records of a resumed stream.
+pub const RESUMED_HEADER: &str = "x-log-resumed";
+/// Name of the file in the state directory that holds the sessions.
+const SESSIONS_FILE: &str = "sessions.json";
+/// Interval at which the connections note their progress and the sessions are saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A resumable stream and the number of records received from it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub token: String,
+    pub received: u64,
+}
+
+impl Session {
+    /// Adds the resumption parameters to the query of a log stream URL.
+    pub fn append_to(&self, url: &mut Url) {
+        endpoint::set_query_param(url, "resume", &self.token);
+        endpoint::set_query_param(url, "from", &self.received.to_string());
+    }
+}
+
+/// Determines the session of a new connection from the handshake response, returning it
+/// together with the number of replayed records the stream starts with.
+pub fn negotiate(
+    domain: &str,
+    response: &Response,
+    previous: Option<Session>,
+) -> (Option<Session>, u64) {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    let replayed = header(RESUMED_HEADER).and_then(|value| value.parse::<u64>().ok());
+    let token = header(SESSION_HEADER).map(str::to_string);
+
+    match (previous, replayed) {
+        (Some(previous), Some(replayed)) => {
+            info!(
+                "[{domain}] Resumed the stream after {} records, replaying {replayed} missed \
+                 records.",
+                previous.received
+            );
+            // The session continues unless the node starts a new one.
+            if token.as_deref().is_none_or(|token| token == previous.token) {
+                return (Some(previous), replayed);
+            }
+        }
+        (Some(_), None) => warn!(
+            "[{domain}] Node did not resume the stream; lines logged while disconnected may be \
+             missing."
+        ),
+        (None, _) => {}
+    }
+    let session = token.map(|token| Session { token, received: 0 });
+    (session, replayed.unwrap_or(0))
+}
+
+/// A session as saved in the state directory.
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    node: String,
+    canister_id: String,
+    token: String,
+    received: u64,
+}
+
+/// The sessions of the connections that ended, to be resumed when reconnecting, and with a
+/// state directory also those of the established connections, as of their last checkpoint.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<(String, String), Session>>,
+    /// The file the sessions are saved to, if they persist across restarts.
+    path: Option<PathBuf>,
+    /// Whether the sessions changed since they were last saved.
+    changed: AtomicBool,
+}
+
+impl SessionRegistry {
+    /// Creates a registry that saves the sessions in the state directory, starting with those
+    /// saved by a previous run.
+    pub fn persisted(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(SESSIONS_FILE);
+        let sessions = match fs::read(&path) {
+            Ok(contents) => match serde_json::from_slice::<Vec<SavedSession>>(&contents) {
+                Ok(saved) => saved
+                    .into_iter()
+                    .map(|saved| {
+                        let session = Session {
+                            token: saved.token,
+                            received: saved.received,
+                        };
+                        ((saved.node, saved.canister_id), session)
+                    })
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        "Ignoring the saved sessions in {}, which are invalid: {e}",
+                        path.display()
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        if !sessions.is_empty() {
+            info!(
+                "Loaded {} sessions to resume from {}.",
+                sessions.len(),
+                path.display()
+            );
+        }
+        Ok(Self {
+            sessions: Mutex::new(sessions),
+            path: Some(path),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the sessions are saved, so that connections should note their progress.
+    pub fn is_persisted(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Whether a session of the node is saved, e.g. from before a restart.
+    pub fn has_session(&self, domain: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|(node, _)| node == domain)
+    }
+
+    /// Takes the session of a node and canister, if there is one to resume.
+    pub fn take(&self, domain: &str, canister_id: &str) -> Option<Session> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&(domain.to_string(), canister_id.to_string()));
+        if session.is_some() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        session
+    }
+
+    /// Keeps the session of a connection that ended.
+    ///
+    /// With a state directory, established connections also note their progress with it, so
+    /// that the session is saved as far as the stream got.
+    pub fn store(&self, domain: &str, canister_id: &str, session: Session) {
+        let previous = self.sessions.lock().unwrap().insert(
+            (domain.to_string(), canister_id.to_string()),
+            session.clone(),
+        );
+        if previous.as_ref() != Some(&session) {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Saves the sessions to the state directory if they changed since they were last saved,
+    /// replacing the file atomically.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let saved: Vec<SavedSession> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((node, canister_id), session)| SavedSession {
+                node: node.clone(),
+                canister_id: canister_id.clone(),
+                token: session.token.clone(),
+                received: session.received,
+            })
+            .collect();
+        let mut temporary = path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        let result = serde_json::to_vec_pretty(&saved)
+            .map_err(io::Error::from)
+            .and_then(|contents| fs::write(&temporary, contents))
+            .and_then(|()| fs::rename(&temporary, path));
+        if let Err(e) = result {
+            warn!("Failed to save the sessions to {}: {e}", path.display());
+            // Try again next time.
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Starts a task that saves the sessions to the state directory at the save interval.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + SAVE_INTERVAL, SAVE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            config.sessions.save();
+        }
+    });
+}
@@ -0,0 +1,77 @@
+//! Sampling of the lines of very chatty canisters, to monitor them at a fraction of the cost.
+//!
+//! Lines are kept at random with the configured rate. With a key pattern, the decision is made
+//! on the key extracted from the line instead, e.g. a request ID, so that either all lines of
+//! a request are kept or none of them. Keys are hashed with SHA-256, so instances sampling the
+//! same canister keep the same requests. Lines without a key are sampled at random.
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// The share of lines that is kept, e.g. 1/100.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleRate {
+    keep: u64,
+    of: u64,
+}
+
+impl SampleRate {
+    /// Parses a rate written as `N/M`, keeping N of every M lines.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (keep, of) = value
+            .split_once('/')
+            .ok_or_else(|| format!("expected a rate like 1/100, got {value}"))?;
+        let keep: u64 = keep
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of kept lines in {value}"))?;
+        let of: u64 = of
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of lines in {value}"))?;
+        if keep == 0 || keep > of {
+            return Err(format!(
+                "the rate {value} must keep at least 1 and at most all lines"
+            ));
+        }
+        Ok(Self { keep, of })
+    }
+}
+
+impl fmt::Display for SampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.keep, self.of)
+    }
+}
+
+/// Decides which lines are kept.
+pub struct Sampler {
+    rate: SampleRate,
+    key: Option<Regex>,
+}
+
+impl Sampler {
+    /// Creates a sampler; the key is the first capture group of the pattern, or else its
+    /// whole match.
+    pub fn new(rate: SampleRate, key: Option<Regex>) -> Self {
+        Self { rate, key }
+    }
+
+    /// Returns whether the line is kept.
+    pub fn keeps(&self, message: &str) -> bool {
+        let key = self.key.as_ref().and_then(|pattern| {
+            let captures = pattern.captures(message)?;
+            captures.get(1).or_else(|| captures.get(0))
+        });
+        let draw = match key {
+            Some(key) => u64::from_be_bytes(
+                Sha256::digest(key.as_str().as_bytes())[..8]
+                    .try_into()
+                    .unwrap(),
+            ),
+            None => fastrand::u64(..),
+        };
+        draw % self.rate.of < self.rate.keep
+    }
+}
@@ -0,0 +1,250 @@
+//! Fair scheduling of the merged output of several canisters.
+//!
+//! Every canister has its own queue, and a single writer takes lines from the queues in
+//! weighted round-robin order: in each round, a canister may deliver as many lines as its
+//! weight. A canister that floods therefore only delays its own lines, not those of the
+//! others. Optionally, every canister is also held to a rate cap; lines above the cap wait in
+//! the queue, and when the queue is full its oldest lines are dropped. The queued lines count
+//! against the memory budget, see [`crate::memory`]; with the `block` policy, a connection
+//! waits for room in the queue of its canister instead, and the writer for room in the sinks.
+
+use crate::event::LogEvent;
+use crate::memory::{self, DropPolicy, Reservation};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Maximum number of lines waiting per canister.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Shortest interval between two warnings about dropped lines of a canister.
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parses a `CANISTER=WEIGHT` pair.
+pub fn parse_weight(value: &str) -> Result<(String, u32), String> {
+    let (canister, weight) = value.split_once('=').ok_or("expected CANISTER=WEIGHT")?;
+    let weight: u32 = weight
+        .parse()
+        .map_err(|e| format!("invalid weight {weight}: {e}"))?;
+    if weight == 0 {
+        return Err("the weight must be positive".to_string());
+    }
+    Ok((canister.to_string(), weight))
+}
+
+struct CanisterQueue {
+    events: VecDeque<(LogEvent, Reservation)>,
+    weight: u32,
+    /// Tokens of the rate cap; a line can be delivered while at least one is available.
+    tokens: f64,
+    last_refill: Instant,
+    dropped: u64,
+    last_drop_warning: Option<Instant>,
+}
+
+impl CanisterQueue {
+    fn new(weight: u32, rate_limit: Option<f64>, now: Instant) -> Self {
+        Self {
+            events: VecDeque::new(),
+            weight,
+            tokens: rate_limit.unwrap_or(0.0).max(1.0),
+            last_refill: now,
+            dropped: 0,
+            last_drop_warning: None,
+        }
+    }
+
+    fn refill(&mut self, rate_limit: Option<f64>, now: Instant) {
+        if let Some(rate) = rate_limit {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            // Allow bursts of up to one second worth of lines.
+            self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        }
+        self.last_refill = now;
+    }
+}
+
+/// Per-canister queues drained by a single writer in weighted round-robin order.
+pub struct FairScheduler {
+    /// Queues in round-robin order.
+    queues: Mutex<Vec<(String, CanisterQueue)>>,
+    weights: HashMap<String, u32>,
+    rate_limit: Option<f64>,
+    notify: Notify,
+}
+
+impl FairScheduler {
+    /// Creates queues for the canisters, which have a weight of 1 unless given.
+    pub fn new(
+        canister_ids: &[String],
+        weights: &HashMap<String, u32>,
+        rate_limit: Option<f64>,
+    ) -> Self {
+        let now = Instant::now();
+        let queues = canister_ids
+            .iter()
+            .map(|canister_id| {
+                let weight = weights.get(canister_id).copied().unwrap_or(1);
+                (
+                    canister_id.clone(),
+                    CanisterQueue::new(weight, rate_limit, now),
+                )
+            })
+            .collect();
+        Self {
+            queues: Mutex::new(queues),
+            weights: weights.clone(),
+            rate_limit,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Adds a queue for a canister that is monitored from now on, unless it has one.
+    pub fn add_canister(&self, canister_id: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        if !queues.iter().any(|(queued, _)| queued == canister_id) {
+            let weight = self.weights.get(canister_id).copied().unwrap_or(1);
+            let queue = CanisterQueue::new(weight, self.rate_limit, Instant::now());
+            queues.push((canister_id.to_string(), queue));
+        }
+    }
+
+    /// Queues a line for delivery. If the queue of the canister is full or the memory budget
+    /// is exhausted, the oldest lines of the canister are dropped, or the line itself with
+    /// the `newest` policy. Returns the dropped lines.
+    pub fn submit(&self, event: LogEvent) -> Vec<LogEvent> {
+        let mut queues = self.queues.lock().unwrap();
+        let Some((canister_id, queue)) = queues
+            .iter_mut()
+            .find(|(canister_id, _)| *canister_id == event.canister_id)
+        else {
+            return vec![event];
+        };
+        let bytes = memory::size_of(&event);
+        let mut dropped = Vec::new();
+        let reservation = loop {
+            if queue.events.len() < QUEUE_CAPACITY
+                && let Some(reservation) = memory::reserve(bytes)
+            {
+                break Some(reservation);
+            }
+            if memory::policy() == DropPolicy::Block {
+                break Some(memory::reserve_beyond(bytes));
+            }
+            if memory::policy() == DropPolicy::Oldest
+                && let Some((oldest, _)) = queue.events.pop_front()
+            {
+                dropped.push(oldest);
+                continue;
+            }
+            break None;
+        };
+        match reservation {
+            Some(reservation) => queue.events.push_back((event, reservation)),
+            None => dropped.push(event),
+        }
+        if !dropped.is_empty() {
+            queue.dropped += dropped.len() as u64;
+            let now = Instant::now();
+            if queue
+                .last_drop_warning
+                .is_none_or(|last| now.duration_since(last) >= DROP_WARNING_INTERVAL)
+            {
+                warn!(
+                    "Output queue of canister {canister_id} is full, dropped {} lines so far.",
+                    queue.dropped
+                );
+                queue.last_drop_warning = Some(now);
+            }
+        }
+        drop(queues);
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Waits with the `block` policy until the queue of the canister has room for a line.
+    pub async fn ready(&self, canister_id: &str) {
+        memory::wait_for_room(|| {
+            self.queues
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(queued, _)| queued == canister_id)
+                .is_none_or(|(_, queue)| queue.events.len() < QUEUE_CAPACITY)
+        })
+        .await;
+    }
+
+    /// Takes the lines of the next round: up to its weight from every canister, within its
+    /// rate cap. Returns how long to wait if lines are only held back by the rate cap.
+    fn next_round(&self) -> (Vec<LogEvent>, Option<Duration>) {
+        let now = Instant::now();
+        let mut queues = self.queues.lock().unwrap();
+        let mut round = Vec::new();
+        let mut throttled = false;
+        for (_, queue) in queues.iter_mut() {
+            queue.refill(self.rate_limit, now);
+            for _ in 0..queue.weight {
+                if queue.events.is_empty() {
+                    break;
+                }
+                if self.rate_limit.is_some() {
+                    if queue.tokens < 1.0 {
+                        throttled = true;
+                        break;
+                    }
+                    queue.tokens -= 1.0;
+                }
+                round.extend(queue.events.pop_front().map(|(event, _)| event));
+            }
+        }
+        let wait = (round.is_empty() && throttled).then(|| {
+            let rate = self.rate_limit.unwrap_or(1.0);
+            Duration::from_secs_f64(1.0 / rate).min(Duration::from_secs(1))
+        });
+        (round, wait)
+    }
+
+    /// Delivers queued lines in weighted round-robin order until the task is cancelled,
+    /// waiting for `ready` before every round.
+    pub async fn run<F: Future<Output = ()>, G: Future<Output = ()>>(
+        &self,
+        ready: impl Fn() -> F,
+        deliver: impl Fn(LogEvent) -> G,
+    ) {
+        loop {
+            ready().await;
+            let notified = self.notify.notified();
+            let (round, wait) = self.next_round();
+            if !round.is_empty() {
+                for event in round {
+                    deliver(event).await;
+                }
+                continue;
+            }
+            match wait {
+                Some(wait) => sleep(wait).await,
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Takes all queued lines in weighted round-robin order regardless of the rate cap, e.g. on
+    /// shutdown.
+    pub fn drain(&self) -> Vec<LogEvent> {
+        let mut queues = self.queues.lock().unwrap();
+        let mut drained = Vec::new();
+        loop {
+            let taken = drained.len();
+            for (_, queue) in queues.iter_mut() {
+                let round = queue.events.len().min(queue.weight as usize);
+                drained.extend(queue.events.drain(..round).map(|(event, _)| event));
+            }
+            if drained.len() == taken {
+                return drained;
+            }
+        }
+    }
+}
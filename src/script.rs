@@ -0,0 +1,153 @@
+//! Rhai scripts that drop, modify, or annotate log events before they reach the outputs.
+//!
+//! A script runs once per event with the variable `event` in scope, a map with the keys
+//! `message`, `node`, `canister_id`, `timestamp`, `backfill`, `resumed`, `suspect`, `panic`,
+//! `taint`, and `fields`. Changes to `event.message` and `event.fields` are kept; if the script evaluates
+//! to `false`, the event is dropped. Scripts form a pipeline in the order they are given.
+
+use crate::event::LogEvent;
+use log::warn;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Maximum number of operations per script run, so that a runaway script cannot stall the
+/// connections.
+const MAX_OPERATIONS: u64 = 100_000;
+/// A failing script is reported on its first failure and then every this many failures.
+const ERROR_REPORT_INTERVAL: u64 = 1000;
+
+struct Script {
+    path: PathBuf,
+    ast: AST,
+    errors: AtomicU64,
+}
+
+/// The scripts applied to every event, in order.
+pub struct ScriptPipeline {
+    engine: Engine,
+    paths: Vec<PathBuf>,
+    scripts: RwLock<Vec<Script>>,
+}
+
+impl ScriptPipeline {
+    /// Compiles the scripts, failing on the first syntax error.
+    pub fn load(paths: &[PathBuf]) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let scripts = compile(&engine, paths)?;
+        Ok(Self {
+            engine,
+            paths: paths.to_vec(),
+            scripts: RwLock::new(scripts),
+        })
+    }
+
+    /// Compiles the scripts again from their files.
+    ///
+    /// If a script fails to compile, the previous scripts stay in place.
+    pub fn reload(&self) -> Result<(), String> {
+        let scripts = compile(&self.engine, &self.paths)?;
+        *self.scripts.write().unwrap() = scripts;
+        Ok(())
+    }
+
+    /// Runs the event through the scripts, returning `None` if a script drops it.
+    ///
+    /// A script that fails leaves the event unchanged.
+    pub fn apply(&self, mut event: LogEvent) -> Option<LogEvent> {
+        for script in self.scripts.read().unwrap().iter() {
+            let mut scope = Scope::new();
+            scope.push("event", to_map(&event));
+            match self
+                .engine
+                .eval_ast_with_scope::<Dynamic>(&mut scope, &script.ast)
+            {
+                Ok(result) => {
+                    if result.as_bool() == Ok(false) {
+                        return None;
+                    }
+                    if let Some(map) = scope.get_value::<Map>("event") {
+                        update_from_map(&mut event, map);
+                    }
+                }
+                Err(e) => script.report_error(&e.to_string()),
+            }
+        }
+        Some(event)
+    }
+}
+
+impl Script {
+    fn report_error(&self, error: &str) {
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed);
+        if errors.is_multiple_of(ERROR_REPORT_INTERVAL) {
+            warn!(
+                "Script {} failed ({} failures so far): {error}",
+                self.path.display(),
+                errors + 1
+            );
+        }
+    }
+}
+
+fn compile(engine: &Engine, paths: &[PathBuf]) -> Result<Vec<Script>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            let source = fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            let ast = engine
+                .compile(source)
+                .map_err(|e| format!("failed to compile {}: {e}", path.display()))?;
+            Ok(Script {
+                path: path.clone(),
+                ast,
+                errors: AtomicU64::new(0),
+            })
+        })
+        .collect()
+}
+
+/// Converts an event into the map seen by scripts.
+fn to_map(event: &LogEvent) -> Map {
+    let mut map = Map::new();
+    map.insert("message".into(), event.message.clone().into());
+    map.insert("node".into(), event.node.clone().into());
+    map.insert("canister_id".into(), event.canister_id.clone().into());
+    map.insert("timestamp".into(), event.timestamp_rfc3339().into());
+    map.insert("backfill".into(), event.backfill.into());
+    map.insert("resumed".into(), event.resumed.into());
+    map.insert("suspect".into(), event.suspect.into());
+    map.insert("panic".into(), event.panic.into());
+    let taint: rhai::Array = event
+        .taint_names()
+        .into_iter()
+        .map(|name| name.into())
+        .collect();
+    map.insert("taint".into(), taint.into());
+    let fields = event
+        .fields
+        .as_ref()
+        .and_then(|fields| rhai::serde::to_dynamic(fields).ok())
+        .unwrap_or_else(|| Map::new().into());
+    map.insert("fields".into(), fields);
+    map
+}
+
+/// Takes the message and fields a script may have changed back into the event.
+fn update_from_map(event: &mut LogEvent, map: Map) {
+    if let Some(message) = map.get("message") {
+        event.message = message.to_string();
+    }
+    if let Some(fields) = map.get("fields") {
+        match rhai::serde::from_dynamic::<Value>(fields) {
+            Ok(Value::Object(fields)) if fields.is_empty() && event.fields.is_none() => {}
+            Ok(Value::Object(fields)) => event.fields = Some(fields),
+            _ => {}
+        }
+    }
+}
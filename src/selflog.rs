@@ -0,0 +1,176 @@
+//! The client's own diagnostics on stderr, as text or as one JSON object per line.
+//!
+//! Container log collectors read stdout and stderr as one stream of lines; with the JSON format,
+//! the diagnostics can be told apart from the canister log lines and filtered by level or node.
+//! The messages logged by a connection carry the node and canister of the connection, as a
+//! span; messages about a node that are logged elsewhere name it in a `[node]` prefix, which
+//! is moved into the `node` field.
+
+use log::{error, Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::fmt::Display;
+use std::fs::File;
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Whether the installed logger writes JSON.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How the client's own diagnostics are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SelfLogFormat {
+    /// Human-readable lines, as configured by RUST_LOG_STYLE.
+    #[default]
+    Text,
+    /// One JSON object per line with the fields `timestamp`, `level`, `target`, `message`, and
+    /// `node` and `canister_id` where known.
+    Json,
+}
+
+/// The connection a task belongs to.
+struct ConnectionSpan {
+    node: String,
+    canister_id: String,
+}
+
+tokio::task_local! {
+    static SPAN: ConnectionSpan;
+}
+
+/// Runs the future of a connection, attributing the messages it logs to the node and canister.
+pub async fn in_connection<F: Future>(node: String, canister_id: String, future: F) -> F::Output {
+    SPAN.scope(ConnectionSpan { node, canister_id }, future)
+        .await
+}
+
+/// Installs the logger, with the level filter of RUST_LOG raised for the client's own messages
+/// by the number of `-v` flags, unless the application embedding the client installed one
+/// before.
+pub fn init(format: SelfLogFormat, verbose: u8) {
+    if builder(format, verbose).try_init().is_ok() {
+        JSON.store(format == SelfLogFormat::Json, Ordering::Relaxed);
+    }
+}
+
+/// Installs the logger like [`init`], and also writes every message at the info level or
+/// above to the file as a JSON object, whatever the level filter, e.g. for a support bundle.
+pub fn init_with_copy(format: SelfLogFormat, verbose: u8, copy: File) {
+    let stderr = builder(format, verbose).build();
+    let max_level = stderr.filter().max(LevelFilter::Info);
+    let logger = CopyingLogger {
+        stderr,
+        copy: Mutex::new(copy),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+        JSON.store(format == SelfLogFormat::Json, Ordering::Relaxed);
+    }
+}
+
+fn builder(format: SelfLogFormat, verbose: u8) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+    // -v shows the info messages, such as connection attempts, -vv debug and -vvv trace
+    // messages, of the client only; RUST_LOG still filters the libraries.
+    let level = match verbose {
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
+    if let Some(level) = level {
+        builder.filter_module(env!("CARGO_CRATE_NAME"), level);
+    }
+    if format == SelfLogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", to_json(record)));
+    }
+    builder
+}
+
+/// Writes the messages that pass the level filter to stderr, and all messages at the info
+/// level or above to a file.
+struct CopyingLogger {
+    stderr: env_logger::Logger,
+    copy: Mutex<File>,
+}
+
+impl Log for CopyingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
+        }
+        if record.level() <= Level::Info {
+            let _ = writeln!(self.copy.lock().unwrap(), "{}", to_json(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        let _ = self.copy.lock().unwrap().flush();
+    }
+}
+
+/// Reports the error that ends the client, as a JSON object when the diagnostics are JSON.
+pub fn report_error(e: &impl Display) {
+    if JSON.load(Ordering::Relaxed) {
+        error!("{e}");
+    } else {
+        eprintln!("Error: {e}");
+    }
+}
+
+/// Reports a message the user asked for, such as an anomaly, on stderr whatever the level
+/// filter, as a JSON object with the level when the diagnostics are JSON.
+pub fn report(level: Level, target: &str, message: &str) {
+    if JSON.load(Ordering::Relaxed) {
+        eprintln!(
+            "{}",
+            to_json(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{message}"))
+                    .build()
+            )
+        );
+    } else {
+        eprintln!("{message}");
+    }
+}
+
+fn to_json(record: &Record) -> serde_json::Value {
+    let message = record.args().to_string();
+    let (node, canister_id) = SPAN
+        .try_with(|span| (Some(span.node.clone()), Some(span.canister_id.clone())))
+        .unwrap_or_default();
+    // Prefer the node of the prefix, which names the node a message is about.
+    let (node, message) = match split_node(&message) {
+        Some((prefix, rest)) => (Some(prefix.to_string()), rest.to_string()),
+        None => (node, message),
+    };
+    let mut object = json!({
+        "timestamp": humantime::format_rfc3339_millis(std::time::SystemTime::now()).to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message,
+    });
+    if let Some(node) = node {
+        object["node"] = node.into();
+    }
+    if let Some(canister_id) = canister_id {
+        object["canister_id"] = canister_id.into();
+    }
+    object
+}
+
+/// Splits a message of the form `[node] text` into the node and the text.
+fn split_node(message: &str) -> Option<(&str, &str)> {
+    let rest = message.strip_prefix('[')?;
+    let (node, text) = rest.split_once("] ")?;
+    (!node.is_empty() && !node.contains(char::is_whitespace)).then_some((node, text))
+}
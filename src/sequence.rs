@@ -0,0 +1,66 @@
+//! Detection of missed log records from a sequence number that the records carry.
+//!
+//! Structured records, and plain text lines parsed into fields, may number the records of a
+//! canister, like the `idx` of the records of the canister log API. The last number received
+//! from each node is kept per canister, across reconnects, so a number that skips ahead shows
+//! how many records the node did not deliver, whether they were lost on the connection or
+//! while it was down. Numbers that do not advance, e.g. of lines replayed after a reconnect,
+//! or after the canister was reinstalled, are accepted without reporting a gap.
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Records missing between two consecutive records of a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gap {
+    /// Sequence number of the last record before the gap.
+    pub after: u64,
+    /// Sequence number of the first record after the gap.
+    pub before: u64,
+}
+
+impl Gap {
+    /// Returns the number of missed records.
+    pub fn missed(&self) -> u64 {
+        self.before - self.after - 1
+    }
+}
+
+/// Tracks the sequence numbers of the records of all nodes and canisters.
+pub struct SequenceTracker {
+    /// Field holding the sequence number.
+    field: String,
+    /// Last sequence number received per node and canister.
+    last: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl SequenceTracker {
+    pub fn new(field: String) -> Self {
+        Self {
+            field,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the sequence number in the fields of a record, returning the gap before it, if
+    /// records were missed. Records without a sequence number are ignored.
+    pub fn observe(
+        &self,
+        domain: &str,
+        canister_id: &str,
+        fields: Option<&Map<String, Value>>,
+    ) -> Option<Gap> {
+        let sequence = match fields?.get(&self.field)? {
+            Value::Number(n) => n.as_u64()?,
+            Value::String(s) => s.trim().parse().ok()?,
+            _ => return None,
+        };
+        let mut last = self.last.lock().unwrap();
+        let previous = last.insert((domain.to_string(), canister_id.to_string()), sequence)?;
+        (sequence > previous + 1).then_some(Gap {
+            after: previous,
+            before: sequence,
+        })
+    }
+}
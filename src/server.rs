@@ -0,0 +1,34 @@
+//! Accept loop shared by the local servers: the control interface, the web dashboard, and the
+//! WebSocket relay.
+
+use log::error;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Duration};
+
+/// Delay before accepting connections again after accepting failed.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Accepts the connections of the listener in a background task, handling each in a task of
+/// its own; `name` names the server in the errors of accepting.
+pub fn spawn_accept_loop<F, Fut>(listener: TcpListener, name: &'static str, handle: F)
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(handle(stream, peer));
+                }
+                Err(e) => {
+                    error!("{name} failed to accept a connection: {e}");
+                    // Errors such as running out of file descriptors persist for a while.
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,60 @@
+//! Severity of log lines, for colored output and system log sinks that classify their entries.
+
+use crate::event::LogEvent;
+
+/// Fields of structured records that carry the severity.
+const SEVERITY_FIELDS: &[&str] = &["level", "severity"];
+const ERROR_LEVELS: &[&str] = &[
+    "error", "err", "critical", "crit", "fatal", "alert", "emerg", "panic",
+];
+const WARNING_LEVELS: &[&str] = &["warning", "warn"];
+
+/// How severe a log line is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl Severity {
+    /// Determines the severity of a line.
+    ///
+    /// The severity is taken from the `level` or `severity` field of structured records, and
+    /// otherwise from the first word of the line, e.g. `ERROR` or `[warn]`. Traps and panics
+    /// grouped with `--group-panics` are errors.
+    pub fn of(event: &LogEvent) -> Self {
+        if event.panic {
+            return Self::Error;
+        }
+        let field = event.fields.as_ref().and_then(|fields| {
+            SEVERITY_FIELDS
+                .iter()
+                .find_map(|name| fields.get(*name).and_then(|value| value.as_str()))
+        });
+        let word = field.unwrap_or_else(|| {
+            event
+                .message
+                .split(|c: char| !c.is_ascii_alphabetic())
+                .find(|word| !word.is_empty())
+                .unwrap_or("")
+        });
+        let level = word.to_ascii_lowercase();
+        if ERROR_LEVELS.contains(&level.as_str()) {
+            Self::Error
+        } else if WARNING_LEVELS.contains(&level.as_str()) {
+            Self::Warning
+        } else {
+            Self::Info
+        }
+    }
+
+    /// Returns the name of the severity: `error`, `warn`, or `info`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warn",
+            Self::Info => "info",
+        }
+    }
+}
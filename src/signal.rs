@@ -0,0 +1,102 @@
+//! Signals and console events that stop the client or make it reload.
+//!
+//! On Unix, SIGINT and SIGTERM shut the client down gracefully and SIGHUP reloads the scripts
+//! and the config file and reopens the output files, so that it works with log rotation tools
+//! such as logrotate.
+//! On Windows, all console events (Ctrl+C, Ctrl+Break, closing the console, logoff, and system
+//! shutdown) shut the client down.
+
+use std::io;
+
+/// What a received signal asks the client to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// Stop, flushing the outputs; carries the name of the signal.
+    Shutdown(&'static str),
+    /// Reload the scripts and the config file and reopen the output files; Windows has no such
+    /// event.
+    #[cfg_attr(windows, allow(dead_code))]
+    Reload,
+}
+
+/// Listens for the signals handled by the client.
+#[cfg(unix)]
+pub struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    /// Installs the signal handlers.
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Waits for the next signal.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.interrupt.recv() => Signal::Shutdown("SIGINT"),
+            _ = self.terminate.recv() => Signal::Shutdown("SIGTERM"),
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+/// Listens for the signals handled by the client.
+#[cfg(windows)]
+pub struct Signals {
+    ctrl_c: tokio::signal::windows::CtrlC,
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    ctrl_close: tokio::signal::windows::CtrlClose,
+    ctrl_logoff: tokio::signal::windows::CtrlLogoff,
+    ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+}
+
+#[cfg(windows)]
+impl Signals {
+    /// Installs the console event handlers.
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::windows;
+        Ok(Self {
+            ctrl_c: windows::ctrl_c()?,
+            ctrl_break: windows::ctrl_break()?,
+            ctrl_close: windows::ctrl_close()?,
+            ctrl_logoff: windows::ctrl_logoff()?,
+            ctrl_shutdown: windows::ctrl_shutdown()?,
+        })
+    }
+
+    /// Waits for the next console event.
+    ///
+    /// Windows ends the process a few seconds after closing the console, logoff, and system
+    /// shutdown events, which may cut the delivery to remote sinks short.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.ctrl_c.recv() => Signal::Shutdown("Ctrl+C"),
+            _ = self.ctrl_break.recv() => Signal::Shutdown("Ctrl+Break"),
+            _ = self.ctrl_close.recv() => Signal::Shutdown("console close"),
+            _ = self.ctrl_logoff.recv() => Signal::Shutdown("logoff"),
+            _ = self.ctrl_shutdown.recv() => Signal::Shutdown("system shutdown"),
+        }
+    }
+}
+
+/// Restores the default action of SIGPIPE, so that writing to a pipe whose reader is gone ends
+/// the process quietly, as for other command line tools, instead of failing the write.
+#[cfg(unix)]
+pub fn default_sigpipe() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+/// Restores the default action of SIGPIPE; Windows has no such signal.
+#[cfg(windows)]
+pub fn default_sigpipe() {}
@@ -0,0 +1,60 @@
+//! Export of the log events as CSV with `--csv-out`, see [`crate::sinks::table`].
+//!
+//! The file is appended to, and starts with a header line if it is empty. Values are quoted as
+//! in RFC 4180 where they contain commas, quotes, or line breaks, and the timestamps are
+//! written in RFC 3339.
+
+use crate::sinks::table::{Row, TableWriter, COLUMNS};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct CsvWriter {
+    file: BufWriter<File>,
+}
+
+impl CsvWriter {
+    /// Opens the file for appending, writing the header if it is empty.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut file = BufWriter::new(file);
+        if empty {
+            write!(file, "{}\r\n", COLUMNS.join(","))?;
+        }
+        Ok(Self { file })
+    }
+}
+
+impl TableWriter for CsvWriter {
+    fn write(&mut self, row: Row) -> io::Result<()> {
+        let fields = [
+            row.timestamp.as_str(),
+            &row.node,
+            &row.canister,
+            row.level,
+            &row.message,
+            if row.backfill { "true" } else { "false" },
+            if row.suspect { "true" } else { "false" },
+            row.fields.as_deref().unwrap_or(""),
+        ];
+        for (index, field) in fields.iter().enumerate() {
+            if index > 0 {
+                self.file.write_all(b",")?;
+            }
+            write_field(&mut self.file, field)?;
+        }
+        self.file.write_all(b"\r\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn write_field(out: &mut impl Write, field: &str) -> io::Result<()> {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return out.write_all(field.as_bytes());
+    }
+    write!(out, "\"{}\"", field.replace('"', "\"\""))
+}
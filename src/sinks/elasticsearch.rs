@@ -0,0 +1,426 @@
+//! Elasticsearch / OpenSearch sink using the `_bulk` API.
+//!
+//! Events are batched and indexed into an index whose name is derived from the event time
+//! with a strftime pattern (`ic-bn-logs-%Y.%m.%d` by default), evaluated in the configured
+//! timezone. Documents carry an
+//! `@timestamp` field so the indices work with ILM policies and index templates keyed on
+//! time. When the cluster is unreachable, the batch is retried with exponential backoff while
+//! new events queue up to a fixed limit. With a spool, failed batches are written to disk
+//! instead and delivered in order once the cluster recovers.
+//!
+//! The cluster, index pattern, and API key can be changed at runtime through the `--config`
+//! file. The batch collected until then is sent to the previous cluster, unless it fails to
+//! accept the batch; spooled batches and retries go to the new cluster.
+
+use crate::clock;
+use crate::event::LogEvent;
+use crate::memory::{self, QueueReceiver, QueueSender};
+use crate::mirror::{MirrorLedger, MirrorSide};
+use crate::sinks::LogSink;
+use crate::spool::{Retry, Spool};
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{oneshot, watch};
+use tokio::time::{interval, sleep, sleep_until, Duration, MissedTickBehavior};
+use url::Url;
+
+/// Maximum number of events waiting to be indexed.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Timeout of a single bulk request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// First delay before retrying a failed bulk request.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed bulk request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Settings of the Elasticsearch sink.
+#[derive(Clone, Debug)]
+pub struct ElasticsearchConfig {
+    /// Base URL of the cluster, optionally with basic auth credentials.
+    pub url: Url,
+    /// strftime pattern of the index name, evaluated on the event time.
+    pub index_pattern: String,
+    /// API key sent in the `Authorization` header.
+    pub api_key: Option<String>,
+    /// Number of events per bulk request.
+    pub batch_size: usize,
+    /// Longest time an event waits before its batch is sent.
+    pub flush_interval: Duration,
+    /// Ledger that tallies the accepted documents when mirroring to a second cluster.
+    pub mirror: Option<(Arc<MirrorLedger>, MirrorSide)>,
+}
+
+/// The cluster and index that the events go to, which the config file can change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElasticsearchTarget {
+    pub url: Url,
+    pub index_pattern: String,
+    pub api_key: Option<String>,
+}
+
+/// Validates a strftime index pattern.
+pub fn parse_index_pattern(pattern: &str) -> Result<String, String> {
+    clock::validate_strftime(pattern)?;
+    let sample = clock::local_time(SystemTime::now())
+        .format(pattern)
+        .to_string();
+    if sample.is_empty() || sample.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(format!(
+            "index names must be non-empty and lowercase, got {sample}"
+        ));
+    }
+    Ok(pattern.to_string())
+}
+
+enum Command {
+    Index(LogEvent),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the background task that indexes events.
+pub struct ElasticsearchSink {
+    name: &'static str,
+    sender: QueueSender<Command>,
+    target: watch::Sender<ElasticsearchTarget>,
+}
+
+impl ElasticsearchSink {
+    /// Starts the background task indexing events into the cluster.
+    ///
+    /// With a spool, batches that cannot be delivered are buffered on disk.
+    pub fn spawn(
+        config: ElasticsearchConfig,
+        client: reqwest::Client,
+        spool: Option<Spool>,
+    ) -> Self {
+        let name = match config.mirror {
+            Some((_, MirrorSide::Mirror)) => "Elasticsearch mirror",
+            _ => "Elasticsearch",
+        };
+        let (sender, receiver) = memory::queue(name, QUEUE_CAPACITY);
+        info!(
+            "Indexing log events into Elasticsearch at {} ({}).",
+            config.url, config.index_pattern
+        );
+        if let Some(spool) = spool.as_ref().filter(|spool| !spool.is_empty()) {
+            info!(
+                "Delivering {} spooled Elasticsearch batches from a previous run.",
+                spool.len()
+            );
+        }
+        let (target, targets) = watch::channel(ElasticsearchTarget {
+            url: config.url.clone(),
+            index_pattern: config.index_pattern.clone(),
+            api_key: config.api_key.clone(),
+        });
+        tokio::spawn(Indexer::new(config, client, spool, targets).run(receiver));
+        Self {
+            name,
+            sender,
+            target,
+        }
+    }
+
+    /// Sends further events to another cluster or index.
+    pub fn retarget(&self, target: ElasticsearchTarget) {
+        self.target.send_replace(target);
+    }
+}
+
+impl LogSink for ElasticsearchSink {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queues an event for indexing, see [`memory`] for when events are dropped.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send_event(Command::Index(event.clone()), memory::size_of(event));
+        })
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sender.ready())
+    }
+
+    /// Indexes all queued events.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Flush(done));
+            let _ = wait.await;
+        })
+    }
+}
+
+/// Subset of the `_bulk` response needed to detect rejected documents.
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    #[serde(default)]
+    items: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+struct Indexer {
+    config: ElasticsearchConfig,
+    client: reqwest::Client,
+    bulk_url: Url,
+    batch: Vec<LogEvent>,
+    spool: Option<Spool>,
+    /// When to next try to deliver the spooled batches.
+    retry: Retry,
+    /// Targets given through the config file.
+    targets: watch::Receiver<ElasticsearchTarget>,
+}
+
+impl Indexer {
+    fn new(
+        config: ElasticsearchConfig,
+        client: reqwest::Client,
+        spool: Option<Spool>,
+        targets: watch::Receiver<ElasticsearchTarget>,
+    ) -> Self {
+        Self {
+            bulk_url: bulk_url(&config.url),
+            config,
+            client,
+            batch: Vec::new(),
+            spool,
+            retry: Retry::new(),
+            targets,
+        }
+    }
+
+    /// Sends further requests to the latest target given through the config file.
+    fn retarget(&mut self) {
+        let target = self.targets.borrow_and_update().clone();
+        info!(
+            "Indexing log events into Elasticsearch at {} ({}).",
+            target.url, target.index_pattern
+        );
+        self.bulk_url = bulk_url(&target.url);
+        self.config.url = target.url;
+        self.config.index_pattern = target.index_pattern;
+        self.config.api_key = target.api_key;
+    }
+
+    fn has_spooled(&self) -> bool {
+        self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+    }
+
+    async fn run(mut self, mut receiver: QueueReceiver<Command>) {
+        let mut flush_timer = interval(self.config.flush_interval);
+        flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(Command::Index(event)) => {
+                        self.batch.push(event);
+                        if self.batch.len() >= self.config.batch_size {
+                            self.flush().await;
+                        }
+                    }
+                    Some(Command::Flush(done)) => {
+                        self.flush().await;
+                        let _ = done.send(());
+                    }
+
+                    None => {
+                        self.flush().await;
+                        return;
+                    }
+                },
+                Ok(()) = self.targets.changed() => {
+                    self.flush().await;
+                    self.retarget();
+                }
+                _ = flush_timer.tick() => self.flush().await,
+                _ = sleep_until(self.retry.at), if self.has_spooled() => self.drain_spool().await,
+            }
+        }
+    }
+
+    /// Sends the current batch, retrying with exponential backoff until it is accepted.
+    ///
+    /// With a spool, the batch is spooled instead of retried, and also while older batches
+    /// are still spooled so that batches are delivered in order.
+    async fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let mut body = self.bulk_body();
+        if self.spool.is_some() {
+            if !self.has_spooled() {
+                match self.send_bulk(body.clone()).await {
+                    Ok(rejected) => {
+                        self.report_rejected(rejected);
+                        self.batch.clear();
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Elasticsearch bulk request failed, spooling the batch: {e}");
+                        self.retry.schedule();
+                    }
+                }
+            }
+            if let Some(spool) = &mut self.spool
+                && let Err(e) = spool.push(body.as_bytes())
+            {
+                error!(
+                    "Failed to spool {} log events, dropping them: {e}",
+                    self.batch.len()
+                );
+            }
+            self.batch.clear();
+            return;
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match self.send_bulk(body.clone()).await {
+                Ok(rejected) => {
+                    self.report_rejected(rejected);
+                    self.batch.clear();
+                    return;
+                }
+                Err(e) => {
+                    error!(
+                        "Elasticsearch bulk request failed, retrying in {}s: {e}",
+                        backoff.as_secs()
+                    );
+                    tokio::select! {
+                        _ = sleep(backoff) => backoff = (backoff * 2).min(MAX_BACKOFF),
+                        // A new target gets the batch right away.
+                        Ok(()) = self.targets.changed() => {
+                            self.retarget();
+                            body = self.bulk_body();
+                            backoff = INITIAL_BACKOFF;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Delivers spooled batches in order until the spool is empty or a delivery fails.
+    async fn drain_spool(&mut self) {
+        let Some(mut spool) = self.spool.take() else {
+            return;
+        };
+        let mut retry = self.retry;
+        let this = &*self;
+        spool
+            .drain("Elasticsearch", &mut retry, |body| async move {
+                let rejected = this
+                    .send_bulk(String::from_utf8_lossy(&body).into_owned())
+                    .await?;
+                if rejected > 0 {
+                    warn!("Elasticsearch rejected {rejected} spooled log events.");
+                }
+                Ok(())
+            })
+            .await;
+        self.spool = Some(spool);
+        self.retry = retry;
+    }
+
+    fn report_rejected(&self, rejected: usize) {
+        if rejected > 0 {
+            warn!(
+                "Elasticsearch rejected {rejected} of {} log events.",
+                self.batch.len()
+            );
+        }
+    }
+
+    /// Builds the newline-delimited body of a bulk request for the current batch.
+    fn bulk_body(&self) -> String {
+        let mut body = String::new();
+        for event in &self.batch {
+            let index = event
+                .local_time()
+                .format(&self.config.index_pattern)
+                .to_string();
+            let action = json!({ "create": { "_index": index } });
+            let mut document = json!({
+                "@timestamp": event.timestamp_rfc3339(),
+                "monotonic_offset_us": event.monotonic_offset.as_micros() as u64,
+                "message": event.message,
+                "canister_id": event.canister_id,
+                "boundary_node": event.node,
+                "backfill": event.backfill,
+                "resumed": event.resumed,
+                "suspect": event.suspect,
+            });
+            if !event.taint.is_empty() {
+                document["taint"] = event.taint_names().into();
+            }
+            if event.panic {
+                document["panic"] = true.into();
+            }
+            if let Some(fields) = &event.fields {
+                document["fields"] = serde_json::Value::Object(fields.clone());
+            }
+            body.push_str(&action.to_string());
+            body.push('\n');
+            body.push_str(&document.to_string());
+            body.push('\n');
+        }
+        body
+    }
+
+    /// Sends a bulk request, returning the number of rejected documents.
+    async fn send_bulk(&self, body: String) -> Result<usize, String> {
+        let mirror_body = self.config.mirror.as_ref().map(|_| body.clone());
+        let mut request = self
+            .client
+            .post(self.bulk_url.clone())
+            .timeout(REQUEST_TIMEOUT)
+            .header("Content-Type", "application/x-ndjson")
+            .body(body);
+        if let Some(api_key) = &self.config.api_key {
+            request = request.header("Authorization", format!("ApiKey {api_key}"));
+        } else if !self.config.url.username().is_empty() {
+            request = request.basic_auth(self.config.url.username(), self.config.url.password());
+        }
+
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("HTTP {status}: {text}"));
+        }
+        let response: BulkResponse = response.json().await.map_err(|e| e.to_string())?;
+        let rejected: Vec<usize> = if response.errors {
+            response
+                .items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| item.values().any(|result| result.get("error").is_some()))
+                .map(|(position, _)| position)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if let (Some((ledger, side)), Some(body)) = (&self.config.mirror, mirror_body) {
+            ledger.record_bulk(*side, &body, &rejected);
+        }
+        Ok(rejected.len())
+    }
+}
+
+/// Returns the URL of the `_bulk` API of a cluster, without the credentials.
+fn bulk_url(url: &Url) -> Url {
+    let mut bulk_url = url.clone();
+    bulk_url.set_username("").ok();
+    bulk_url.set_password(None).ok();
+    let path = format!("{}/_bulk", bulk_url.path().trim_end_matches('/'));
+    bulk_url.set_path(&path);
+    bulk_url
+}
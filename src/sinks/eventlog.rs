@@ -0,0 +1,214 @@
+//! Windows Event Log sink, so that Windows hosts can collect canister logs through their
+//! standard channel.
+//!
+//! Events are reported to the Application log under an event source. The source is registered
+//! on first use, which needs administrator rights once; it uses the generic message file of
+//! the .NET Framework, so Event Viewer shows the log lines as they are. Each event gets the
+//! type matching the severity of the line: error, warning, or information. `ReportEventW`
+//! blocks while the Event Log service is busy, so the events are reported from a thread of the
+//! sink, and dropped while its queue is full.
+
+use crate::event::LogEvent;
+use crate::output::LineFormat;
+use crate::severity::Severity;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use log::{debug, info, warn};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use windows_sys::Win32::Foundation::{ERROR_SUCCESS, HANDLE};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+use windows_sys::Win32::System::Registry::{
+    RegCloseKey, RegCreateKeyExW, RegOpenKeyExW, RegSetValueExW, HKEY, HKEY_LOCAL_MACHINE,
+    KEY_READ, KEY_WRITE, REG_DWORD, REG_EXPAND_SZ, REG_OPTION_NON_VOLATILE,
+};
+
+/// Registry key under which the event sources of the Application log are registered.
+const SOURCES_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application";
+/// Message file whose messages all consist of the first inserted string.
+const MESSAGE_FILE: &str =
+    r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+/// Event ID of reported log lines.
+const EVENT_ID: u32 = 1000;
+/// Longest inserted string the Event Log accepts, in UTF-16 code units.
+const MAX_MESSAGE_LEN: usize = 31_839;
+/// Maximum number of events waiting to be reported.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// Handle to the thread that reports log events to the Windows Event Log.
+pub struct EventLogSink {
+    sender: SyncSender<LogEvent>,
+}
+
+impl EventLogSink {
+    /// Opens the event source, registering it first if needed, and starts the thread
+    /// reporting the events to it as rendered by the format.
+    pub fn open(source: &str, format: Arc<LineFormat>) -> Result<Self, String> {
+        register_source(source);
+        let name = wide(source);
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(format!(
+                "failed to open event source {source}: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let source = EventSource(handle);
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("event-log".to_string())
+            .spawn(move || source.run(receiver, &format))
+            .map_err(|e| format!("failed to start the Event Log thread: {e}"))?;
+        Ok(Self { sender })
+    }
+}
+
+impl LogSink for EventLogSink {
+    fn name(&self) -> &'static str {
+        "Event Log"
+    }
+
+    /// Queues an event for reporting, dropping it if the queue is full.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.sender.try_send(event.clone()).is_err() {
+                debug!("Event Log queue is full, dropping event.");
+            }
+        })
+    }
+}
+
+/// An open event source, owned by the thread of the sink.
+struct EventSource(HANDLE);
+
+// SAFETY: an event source handle is not tied to the thread that opened it; the Event Log
+// functions accept it on any thread. The handle moves to the thread of the sink, which is the
+// only one that uses and closes it, so it is never used concurrently.
+unsafe impl Send for EventSource {}
+
+impl EventSource {
+    /// Reports the queued events until the sink is dropped.
+    fn run(self, receiver: Receiver<LogEvent>, format: &LineFormat) {
+        for event in receiver {
+            self.report(&event, &format.render(&event));
+        }
+    }
+
+    fn report(&self, event: &LogEvent, line: &str) {
+        let mut message: Vec<u16> = line.encode_utf16().take(MAX_MESSAGE_LEN).collect();
+        message.push(0);
+        let strings = [message.as_ptr()];
+        let reported = unsafe {
+            ReportEventW(
+                self.0,
+                event_type(event),
+                0,
+                EVENT_ID,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            )
+        };
+        if reported == 0 {
+            warn!(
+                "Failed to report event to the Event Log: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.0) };
+    }
+}
+
+/// Maps the severity of a line to an event type.
+fn event_type(event: &LogEvent) -> REPORT_EVENT_TYPE {
+    match Severity::of(event) {
+        Severity::Error => EVENTLOG_ERROR_TYPE,
+        Severity::Warning => EVENTLOG_WARNING_TYPE,
+        Severity::Info => EVENTLOG_INFORMATION_TYPE,
+    }
+}
+
+/// Registers the event source in the Application log unless it already is.
+///
+/// Without registration the events are still reported, but Event Viewer does not show their
+/// text as the description.
+fn register_source(source: &str) {
+    let subkey = wide(&format!(r"{SOURCES_KEY}\{source}"));
+    let mut key: HKEY = ptr::null_mut();
+    if unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, subkey.as_ptr(), 0, KEY_READ, &mut key) }
+        == ERROR_SUCCESS
+    {
+        unsafe { RegCloseKey(key) };
+        return;
+    }
+
+    let status = unsafe {
+        RegCreateKeyExW(
+            HKEY_LOCAL_MACHINE,
+            subkey.as_ptr(),
+            0,
+            ptr::null(),
+            REG_OPTION_NON_VOLATILE,
+            KEY_WRITE,
+            ptr::null(),
+            &mut key,
+            ptr::null_mut(),
+        )
+    };
+    if status != ERROR_SUCCESS {
+        warn!(
+            "Failed to register event source {source} ({}); run once as administrator to \
+             register it.",
+            std::io::Error::from_raw_os_error(status as i32)
+        );
+        return;
+    }
+    let message_file = wide(MESSAGE_FILE);
+    let types_supported =
+        (EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE) as u32;
+    let statuses = unsafe {
+        [
+            RegSetValueExW(
+                key,
+                wide("EventMessageFile").as_ptr(),
+                0,
+                REG_EXPAND_SZ,
+                message_file.as_ptr().cast(),
+                (message_file.len() * 2) as u32,
+            ),
+            RegSetValueExW(
+                key,
+                wide("TypesSupported").as_ptr(),
+                0,
+                REG_DWORD,
+                (&types_supported as *const u32).cast(),
+                4,
+            ),
+        ]
+    };
+    unsafe { RegCloseKey(key) };
+    match statuses.into_iter().find(|status| *status != ERROR_SUCCESS) {
+        Some(status) => warn!(
+            "Failed to register event source {source}: {}",
+            std::io::Error::from_raw_os_error(status as i32)
+        ),
+        None => info!("Registered event source {source} in the Application log."),
+    }
+}
+
+/// Encodes a string as a NUL-terminated UTF-16 string.
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
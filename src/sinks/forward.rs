@@ -0,0 +1,423 @@
+//! Forwarding of log events to a central collector over a persistent mutual TLS connection.
+//!
+//! Every event is sent as its JSON representation, prefixed with its length as a 32-bit
+//! big-endian integer, so that the collector can split the stream without parsing it. The
+//! client authenticates with a certificate, and verifies the collector's certificate against
+//! the public roots and the given CA certificates. When the connection breaks, it is
+//! re-established with exponential backoff; the events meanwhile are held in memory up to a
+//! fixed limit, or with a spool, written to disk and sent in order once the collector is
+//! reachable again, also after a restart. The protocol has no acknowledgements, so events
+//! written just before a connection broke unnoticed may be lost.
+
+use crate::event::LogEvent;
+use crate::memory::{self, QueueReceiver, QueueSender};
+use crate::sinks::LogSink;
+use crate::spool::Spool;
+use crate::tls::TlsSettings;
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+/// Port of the collector if the URL names none, as for syslog over TLS.
+const DEFAULT_PORT: u16 = 6514;
+/// Maximum number of events waiting to be forwarded.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Size above which the frames of queued events are sent without waiting for more.
+const BATCH_BYTES: usize = 64 * 1024;
+/// Largest number of bytes held in memory while the collector is unreachable, without a
+/// spool.
+const MAX_PENDING_BYTES: usize = 64 * 1024 * 1024;
+/// Timeout of establishing the connection, including the TLS handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of sending a batch.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// First delay before reconnecting.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The collector that events are forwarded to.
+#[derive(Clone, Debug)]
+pub struct Collector {
+    pub host: String,
+    pub port: u16,
+    /// Name that the certificate of the collector is verified against, unless overridden.
+    pub server_name: ServerName<'static>,
+}
+
+/// Parses the address of the collector, given as `tls://HOST[:PORT]`.
+pub fn parse_collector(value: &str) -> Result<Collector, String> {
+    let url = Url::parse(value).map_err(|e| format!("invalid collector URL {value}: {e}"))?;
+    if url.scheme() != "tls" {
+        return Err(format!(
+            "the collector URL {value} must start with tls://, as events are only forwarded \
+             over TLS"
+        ));
+    }
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.trim_start_matches('[').trim_end_matches(']'),
+        _ => return Err(format!("the collector URL {value} names no host")),
+    };
+    if !matches!(url.path(), "" | "/") || url.query().is_some() || url.fragment().is_some() {
+        return Err(format!(
+            "the collector URL {value} must consist of a host and an optional port"
+        ));
+    }
+    let server_name = ServerName::try_from(host.to_string()).map_err(|_| {
+        format!("the host {host} of the collector URL {value} is not a valid server name")
+    })?;
+    Ok(Collector {
+        host: host.to_string(),
+        port: url.port().unwrap_or(DEFAULT_PORT),
+        server_name,
+    })
+}
+
+enum Command {
+    Forward(LogEvent),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the background task that forwards events.
+pub struct ForwardSink {
+    sender: QueueSender<Command>,
+}
+
+impl ForwardSink {
+    /// Starts the background task forwarding events to the collector.
+    ///
+    /// With a spool, the events that cannot be sent are buffered on disk.
+    pub fn spawn(collector: Collector, tls: TlsSettings, spool: Option<Spool>) -> Self {
+        let (sender, receiver) = memory::queue("forward", QUEUE_CAPACITY);
+        info!(
+            "Forwarding log events to the collector at {}:{}.",
+            collector.host, collector.port
+        );
+        let pending = match spool {
+            Some(spool) => {
+                if !spool.is_empty() {
+                    info!(
+                        "Forwarding {} spooled batches from a previous run.",
+                        spool.len()
+                    );
+                }
+                Pending::Spool(spool)
+            }
+            None => Pending::Memory {
+                batches: VecDeque::new(),
+                bytes: 0,
+            },
+        };
+        tokio::spawn(Forwarder::new(collector, tls, pending).run(receiver));
+        Self { sender }
+    }
+}
+
+impl LogSink for ForwardSink {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    /// Queues an event for forwarding, see [`memory`] for when events are dropped.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send_event(Command::Forward(event.clone()), memory::size_of(event));
+        })
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sender.ready())
+    }
+
+    /// Sends all queued events, unless the collector is unreachable.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Flush(done));
+            let _ = wait.await;
+        })
+    }
+}
+
+/// Batches not sent yet because the collector is unreachable, oldest first.
+enum Pending {
+    Memory {
+        batches: VecDeque<Vec<u8>>,
+        bytes: usize,
+    },
+    Spool(Spool),
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Memory { batches, .. } => batches.is_empty(),
+            Self::Spool(spool) => spool.is_empty(),
+        }
+    }
+
+    /// Stores a batch, dropping the oldest batches beyond the size limit.
+    fn push(&mut self, batch: Vec<u8>) {
+        match self {
+            Self::Memory { batches, bytes } => {
+                *bytes += batch.len();
+                batches.push_back(batch);
+                while *bytes > MAX_PENDING_BYTES
+                    && let Some(dropped) = batches.pop_front()
+                {
+                    warn!("Collector unreachable for too long, dropping the oldest events.");
+                    *bytes -= dropped.len();
+                }
+            }
+            Self::Spool(spool) => {
+                if let Err(e) = spool.push(&batch) {
+                    error!("Failed to spool events for the collector, dropping them: {e}");
+                }
+            }
+        }
+    }
+
+    /// Returns the oldest batch, dropping batches that cannot be read from the spool.
+    fn front(&mut self) -> Option<Vec<u8>> {
+        match self {
+            Self::Memory { batches, .. } => batches.front().cloned(),
+            Self::Spool(spool) => {
+                loop {
+                    match spool.peek() {
+                        Ok(segment) => return segment.map(|(_, batch)| batch),
+                        Err(e) => {
+                            error!("Failed to read spooled events for the collector, dropping them: {e}");
+                            if let Err(e) = spool.discard_oldest() {
+                                error!("Failed to remove spooled events for the collector: {e}");
+                                return None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the oldest batch once it is sent.
+    fn pop(&mut self) {
+        match self {
+            Self::Memory { batches, bytes } => {
+                if let Some(batch) = batches.pop_front() {
+                    *bytes -= batch.len();
+                }
+            }
+            Self::Spool(spool) => {
+                if let Err(e) = spool.discard_oldest() {
+                    error!("Failed to remove sent events from the spool: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// An established connection to the collector.
+struct Connection {
+    writer: WriteHalf<TlsStream<TcpStream>>,
+    /// Signals that the collector closed the connection.
+    closed: oneshot::Receiver<()>,
+}
+
+struct Forwarder {
+    collector: Collector,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    connection: Option<Connection>,
+    /// Frames of the events received since the last send.
+    batch: Vec<u8>,
+    pending: Pending,
+    /// When to next try to connect.
+    retry_at: Instant,
+    /// Delay before the next attempt after a failed connection.
+    backoff: Duration,
+}
+
+impl Forwarder {
+    fn new(collector: Collector, tls: TlsSettings, pending: Pending) -> Self {
+        let server_name = tls
+            .server_name
+            .clone()
+            .unwrap_or_else(|| collector.server_name.clone());
+        Self {
+            collector,
+            connector: TlsConnector::from(tls.client_config),
+            server_name,
+            connection: None,
+            batch: Vec::new(),
+            pending,
+            retry_at: Instant::now(),
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    async fn run(mut self, mut receiver: QueueReceiver<Command>) {
+        loop {
+            let reconnect = self.connection.is_none() && !self.pending.is_empty();
+            let closed = async {
+                match &mut self.connection {
+                    Some(connection) => {
+                        let _ = (&mut connection.closed).await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(command) => {
+                        let mut flushed = Vec::new();
+                        self.handle(command, &mut flushed);
+                        // Send the events queued meanwhile together.
+                        while self.batch.len() < BATCH_BYTES
+                            && let Some(command) = receiver.try_recv()
+                        {
+                            self.handle(command, &mut flushed);
+                        }
+                        self.send().await;
+                        for done in flushed {
+                            let _ = done.send(());
+                        }
+                    }
+                    None => {
+                        self.send().await;
+                        return;
+                    }
+                },
+                _ = closed => {
+                    warn!("The collector closed the connection.");
+                    self.connection = None;
+                }
+                _ = sleep_until(self.retry_at), if reconnect => self.send().await,
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command, flushed: &mut Vec<oneshot::Sender<()>>) {
+        match command {
+            Command::Forward(event) => {
+                let json = event.to_json().to_string();
+                self.batch
+                    .extend_from_slice(&(json.len() as u32).to_be_bytes());
+                self.batch.extend_from_slice(json.as_bytes());
+            }
+            Command::Flush(done) => flushed.push(done),
+        }
+    }
+
+    /// Sends the pending batches and the current batch in order, connecting first if needed.
+    /// Whatever cannot be sent is kept pending.
+    async fn send(&mut self) {
+        let held_back = !self.pending.is_empty();
+        let batch = std::mem::take(&mut self.batch);
+        if !batch.is_empty() {
+            // While nothing is pending, the batch is sent without storing it first.
+            if self.connection.is_some() && self.pending.is_empty() && self.write(&batch).await {
+                return;
+            }
+            self.pending.push(batch);
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+        if self.connection.is_none() {
+            if Instant::now() < self.retry_at {
+                return;
+            }
+            if let Err(e) = self.connect().await {
+                error!(
+                    "Failed to connect to the collector at {}:{}, retrying in {}s: {e}",
+                    self.collector.host,
+                    self.collector.port,
+                    self.backoff.as_secs()
+                );
+                self.retry_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                return;
+            }
+        }
+        while let Some(batch) = self.pending.front() {
+            if !self.write(&batch).await {
+                return;
+            }
+            self.pending.pop();
+        }
+        if held_back {
+            info!("Forwarded all events held back while the collector was unreachable.");
+        }
+    }
+
+    /// Writes a batch to the connection, returning false and dropping the connection if
+    /// writing fails.
+    async fn write(&mut self, batch: &[u8]) -> bool {
+        let Some(connection) = &mut self.connection else {
+            return false;
+        };
+        let written = match timeout(WRITE_TIMEOUT, connection.writer.write_all(batch)).await {
+            Ok(Ok(())) => match timeout(WRITE_TIMEOUT, connection.writer.flush()).await {
+                Ok(result) => result,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            },
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        };
+        if let Err(e) = written {
+            error!(
+                "Forwarding to the collector failed, reconnecting in {}s: {e}",
+                self.backoff.as_secs()
+            );
+            self.connection = None;
+            self.retry_at = Instant::now() + self.backoff;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            return false;
+        }
+        self.backoff = INITIAL_BACKOFF;
+        true
+    }
+
+    async fn connect(&mut self) -> io::Result<()> {
+        let connect = async {
+            let stream =
+                TcpStream::connect((self.collector.host.as_str(), self.collector.port)).await?;
+            stream.set_nodelay(true)?;
+            self.connector
+                .connect(self.server_name.clone(), stream)
+                .await
+        };
+        let stream = timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        let (reader, writer) = tokio::io::split(stream);
+        let (close, closed) = oneshot::channel();
+        tokio::spawn(watch_close(reader, close));
+        info!(
+            "Connected to the collector at {}:{}.",
+            self.collector.host, self.collector.port
+        );
+        self.connection = Some(Connection { writer, closed });
+        Ok(())
+    }
+}
+
+/// Reads from the collector, which sends nothing, until it closes the connection.
+async fn watch_close(mut reader: ReadHalf<TlsStream<TcpStream>>, close: oneshot::Sender<()>) {
+    let mut buf = [0; 1024];
+    while let Ok(read) = reader.read(&mut buf).await {
+        if read == 0 {
+            break;
+        }
+    }
+    let _ = close.send(());
+}
@@ -0,0 +1,165 @@
+//! gRPC server through which other services subscribe to the aggregated log stream.
+//!
+//! Every subscriber receives the events written to the output from the moment it subscribes,
+//! optionally restricted to one canister and to lines matching a regular expression. A
+//! subscriber that falls too far behind skips the events it missed.
+
+use crate::event::LogEvent;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use futures_util::Stream;
+use log::{error, info, warn};
+use regex::Regex;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("ic_bn_logs.v1");
+}
+
+use proto::log_stream_server::{LogStream, LogStreamServer};
+use proto::StreamLogsRequest;
+
+/// Number of events buffered for each subscriber.
+const SUBSCRIBER_BUFFER: usize = 4096;
+
+/// Publishes the written events to the subscribers of the gRPC server.
+#[derive(Clone)]
+pub struct GrpcSink {
+    events: broadcast::Sender<LogEvent>,
+}
+
+impl LogSink for GrpcSink {
+    fn name(&self) -> &'static str {
+        "gRPC"
+    }
+
+    /// Publishes an event to all current subscribers.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            // Sending only fails if there are no subscribers.
+            let _ = self.events.send(event.clone());
+        })
+    }
+}
+
+struct LogStreamService {
+    events: broadcast::Sender<LogEvent>,
+    canister_ids: watch::Receiver<Vec<String>>,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::LogEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl LogStream for LogStreamService {
+    type StreamLogsStream = EventStream;
+
+    async fn stream_logs(
+        &self,
+        request: Request<StreamLogsRequest>,
+    ) -> Result<Response<Self::StreamLogsStream>, Status> {
+        let peer = request
+            .remote_addr()
+            .map_or("unknown peer".to_string(), |addr| addr.to_string());
+        let request = request.into_inner();
+        let canister_id = Some(request.canister_id).filter(|id| !id.is_empty());
+        if let Some(canister_id) = &canister_id {
+            let canister_ids = self.canister_ids.borrow();
+            if !canister_ids.contains(canister_id) {
+                return Err(Status::not_found(format!(
+                    "canister {canister_id} is not monitored; monitored canisters: {}",
+                    canister_ids.join(", ")
+                )));
+            }
+        }
+        let filter =
+            match request.filter.as_str() {
+                "" => None,
+                pattern => Some(Regex::new(pattern).map_err(|e| {
+                    Status::invalid_argument(format!("invalid filter {pattern}: {e}"))
+                })?),
+            };
+        info!("gRPC subscriber {peer} connected.");
+
+        let mut events = self.events.subscribe();
+        let (sender, mut receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if canister_id
+                            .as_ref()
+                            .is_some_and(|id| *id != event.canister_id)
+                            || filter.as_ref().is_some_and(|f| !f.is_match(&event.message))
+                        {
+                            continue;
+                        }
+                        // Sending fails once the subscriber has gone away.
+                        if sender.send(Ok(to_proto(&event))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("gRPC subscriber {peer} fell behind, skipped {skipped} events.");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            info!("gRPC subscriber {peer} disconnected.");
+        });
+        let stream = futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn to_proto(event: &LogEvent) -> proto::LogEvent {
+    proto::LogEvent {
+        timestamp: event.timestamp_rfc3339(),
+        monotonic_offset_us: event.monotonic_offset.as_micros() as u64,
+        node: event.node.clone(),
+        canister_id: event.canister_id.clone(),
+        message: event.message.clone(),
+        backfill: event.backfill,
+        resumed: event.resumed,
+        suspect: event.suspect,
+        taint: event.taint_names().into_iter().map(String::from).collect(),
+        fields_json: event
+            .fields
+            .as_ref()
+            .map(|fields| serde_json::Value::Object(fields.clone()).to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Starts the gRPC server on the address, returning the sink that feeds its subscribers.
+pub async fn serve(
+    addr: SocketAddr,
+    canister_ids: watch::Receiver<Vec<String>>,
+) -> io::Result<GrpcSink> {
+    let (events, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+    let service = LogStreamService {
+        events: events.clone(),
+        canister_ids,
+    };
+    // Bind before returning, so that an unavailable address is reported as a startup error.
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("gRPC server listening on {}.", listener.local_addr()?);
+    tokio::spawn(async move {
+        let incoming = futures_util::stream::unfold(listener, |listener| async move {
+            let connection = listener.accept().await.map(|(stream, _)| stream);
+            Some((connection, listener))
+        });
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(LogStreamServer::new(service))
+            .serve_with_incoming(incoming)
+            .await
+        {
+            error!("gRPC server failed: {e}");
+        }
+    });
+    Ok(GrpcSink { events })
+}
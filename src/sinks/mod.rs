@@ -0,0 +1,102 @@
+//! Sinks that ship log events to remote systems and to the logging system of the host.
+
+pub mod csv;
+pub mod elasticsearch;
+#[cfg(windows)]
+pub mod eventlog;
+pub mod forward;
+pub mod grpc;
+#[cfg(target_os = "macos")]
+pub mod oslog;
+pub mod otlp;
+pub mod parquet;
+pub mod s3;
+pub mod table;
+pub mod websocket;
+
+use crate::event::LogEvent;
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+
+/// A destination of the log events besides stdout and the output files.
+///
+/// The output runs every sink in a task of its own, which takes the written events from a
+/// bounded queue of the sink and awaits [`write_event`] for each of them in turn. A sink that
+/// falls behind thus fills its queue, which then drops events like the other queues, see
+/// [`memory`](crate::memory), rather than holding up the bus and the other destinations; with
+/// `--buffer-drop-policy block`, its [`ready`] holds up the bus instead. The built-in sinks
+/// still batch the events in a task of their own, or pass them to their subscribers.
+///
+/// [`write_event`]: LogSink::write_event
+/// [`ready`]: LogSink::ready
+pub trait LogSink: Send + Sync {
+    /// Names the sink in warnings and in the statistics of dropped events.
+    fn name(&self) -> &'static str {
+        "application sink"
+    }
+
+    /// Hands an event to the sink.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()>;
+
+    /// Waits until the sink can take another event without dropping one, with
+    /// `--buffer-drop-policy block`.
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Delivers the events still queued.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Delivers the events still queued and completes the work of the sink before exiting,
+    /// e.g. writes the footer of a file. No events are written afterwards.
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        self.flush()
+    }
+}
+
+impl<S: LogSink + ?Sized> LogSink for Box<S> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        (**self).write_event(event)
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        (**self).ready()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        (**self).flush()
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        (**self).shutdown()
+    }
+}
+
+/// A sink shared with the code that controls it at runtime, e.g. to retarget it.
+impl<S: LogSink + ?Sized> LogSink for Arc<S> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        (**self).write_event(event)
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        (**self).ready()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        (**self).flush()
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        (**self).shutdown()
+    }
+}
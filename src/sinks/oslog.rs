@@ -0,0 +1,74 @@
+//! Apple unified logging (os_log) sink, so that macOS users can view and filter canister logs
+//! in Console.app and with `log stream` alongside the other system logs.
+//!
+//! Every canister is a subsystem and every node a category within it, e.g.
+//! `log stream --predicate 'subsystem == "<CANISTER_ID>"'`. The severity of a line selects
+//! the log type the same way as for Rust log levels: errors are faults, warnings are errors,
+//! and everything else is logged with the default type, which is persisted. The lines are
+//! logged from a thread of the sink, so that a busy logging daemon does not hold up the
+//! connections, and dropped while its queue is full.
+
+use crate::event::LogEvent;
+use crate::output::LineFormat;
+use crate::severity::Severity;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use log::debug;
+use oslog::{Level, OsLog};
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+/// Maximum number of events waiting to be logged.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// Handle to the thread that logs events to the unified logging system.
+pub struct OsLogSink {
+    sender: SyncSender<LogEvent>,
+}
+
+impl OsLogSink {
+    /// Starts the thread logging the events as rendered by the format.
+    pub fn spawn(format: Arc<LineFormat>) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("os-log".to_string())
+            .spawn(move || run(receiver, &format))?;
+        Ok(Self { sender })
+    }
+}
+
+impl LogSink for OsLogSink {
+    fn name(&self) -> &'static str {
+        "os_log"
+    }
+
+    /// Queues an event for logging, dropping it if the queue is full.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.sender.try_send(event.clone()).is_err() {
+                debug!("os_log queue is full, dropping event.");
+            }
+        })
+    }
+}
+
+/// Logs the queued events until the sink is dropped.
+fn run(receiver: Receiver<LogEvent>, format: &LineFormat) {
+    // Log handles by canister and node; os_log handles are meant to live for the whole
+    // process.
+    let mut logs: HashMap<(String, String), OsLog> = HashMap::new();
+    for event in receiver {
+        let level = match Severity::of(&event) {
+            Severity::Error => Level::Fault,
+            Severity::Warning => Level::Error,
+            Severity::Info => Level::Default,
+        };
+        let line = format.render(&event);
+        logs.entry((event.canister_id, event.node))
+            .or_insert_with_key(|(canister_id, node)| OsLog::new(canister_id, node))
+            .with_level(level, &line);
+    }
+}
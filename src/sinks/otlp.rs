@@ -0,0 +1,435 @@
+//! OpenTelemetry sink exporting log events as OTLP log records over HTTP.
+//!
+//! Events are batched and posted as JSON-encoded `ExportLogsServiceRequest`s to the `/v1/logs`
+//! path of the collector, compressed with gzip, so that they enter OpenTelemetry pipelines
+//! without a translating agent. The records are grouped by canister and boundary node, which
+//! become the resource attributes `ic.canister_id` and `ic.boundary_node`, with the canister
+//! also as `service.name`. The line is the body of the record, its severity is derived as for
+//! colored output, and the fields of structured records become attributes. Batches that fail
+//! because the collector is unreachable or overloaded are retried with exponential backoff, or
+//! with a spool, written to disk and delivered in order once the collector recovers; batches
+//! that the collector rejects are dropped.
+
+use crate::event::LogEvent;
+use crate::memory::{self, QueueReceiver, QueueSender};
+use crate::severity::Severity;
+use crate::sinks::LogSink;
+use crate::spool::{Retry, Spool};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::UNIX_EPOCH;
+use tokio::sync::oneshot;
+use tokio::time::{interval, sleep, sleep_until, Duration, MissedTickBehavior};
+use url::Url;
+
+/// Path of the logs signal, appended to the endpoint of the collector.
+const LOGS_PATH: &str = "v1/logs";
+/// Maximum number of events waiting to be exported.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Number of events per export request.
+const BATCH_SIZE: usize = 512;
+/// Longest time an event waits before its batch is exported.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Timeout of a single export request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// First delay before retrying a failed export request.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed export request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Parses the OTLP/HTTP endpoint of a collector, e.g. `http://localhost:4318`, and returns the
+/// URL that log records are posted to.
+pub fn parse_endpoint(value: &str) -> Result<Url, String> {
+    let mut url = Url::parse(value).map_err(|e| format!("invalid OTLP endpoint {value}: {e}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "the OTLP endpoint {value} must start with http:// or https://"
+        ));
+    }
+    if !url.path().trim_end_matches('/').ends_with(LOGS_PATH) {
+        let path = format!("{}/{LOGS_PATH}", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Parses a header sent with every export request, given as KEY=VALUE.
+pub fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value.split_once('=').ok_or("expected KEY=VALUE")?;
+    let name = name.trim();
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name {name}"))?;
+    reqwest::header::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value of header {name}"))?;
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Settings of the OTLP sink.
+#[derive(Clone, Debug)]
+pub struct OtlpConfig {
+    /// URL that the log records are posted to, ending in `/v1/logs`.
+    pub url: Url,
+    /// Headers sent with every request, e.g. for authentication.
+    pub headers: Vec<(String, String)>,
+}
+
+enum Command {
+    Export(LogEvent),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the background task that exports events.
+pub struct OtlpSink {
+    sender: QueueSender<Command>,
+}
+
+impl OtlpSink {
+    /// Starts the background task exporting events to the collector.
+    ///
+    /// With a spool, batches that cannot be delivered are buffered on disk.
+    pub fn spawn(config: OtlpConfig, client: reqwest::Client, spool: Option<Spool>) -> Self {
+        let (sender, receiver) = memory::queue("OTLP", QUEUE_CAPACITY);
+        info!("Exporting log events over OTLP to {}.", config.url);
+        if let Some(spool) = spool.as_ref().filter(|spool| !spool.is_empty()) {
+            info!(
+                "Delivering {} spooled OTLP batches from a previous run.",
+                spool.len()
+            );
+        }
+        tokio::spawn(Exporter::new(config, client, spool).run(receiver));
+        Self { sender }
+    }
+}
+
+impl LogSink for OtlpSink {
+    fn name(&self) -> &'static str {
+        "OTLP"
+    }
+
+    /// Queues an event for export, see [`memory`] for when events are dropped.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send_event(Command::Export(event.clone()), memory::size_of(event));
+        })
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sender.ready())
+    }
+
+    /// Exports all queued events.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Flush(done));
+            let _ = wait.await;
+        })
+    }
+}
+
+/// Why an export request failed.
+enum ExportError {
+    /// The collector is unreachable or overloaded, so the request can be retried.
+    Transient(String),
+    /// The collector rejected the request, which would fail again.
+    Rejected(String),
+}
+
+struct Exporter {
+    config: OtlpConfig,
+    client: reqwest::Client,
+    batch: Vec<LogEvent>,
+    spool: Option<Spool>,
+    /// When to next try to deliver the spooled batches.
+    retry: Retry,
+}
+
+impl Exporter {
+    fn new(config: OtlpConfig, client: reqwest::Client, spool: Option<Spool>) -> Self {
+        Self {
+            config,
+            client,
+            batch: Vec::new(),
+            spool,
+            retry: Retry::new(),
+        }
+    }
+
+    fn has_spooled(&self) -> bool {
+        self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+    }
+
+    async fn run(mut self, mut receiver: QueueReceiver<Command>) {
+        let mut flush_timer = interval(FLUSH_INTERVAL);
+        flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(Command::Export(event)) => {
+                        self.batch.push(event);
+                        if self.batch.len() >= BATCH_SIZE {
+                            self.flush().await;
+                        }
+                    }
+                    Some(Command::Flush(done)) => {
+                        self.flush().await;
+                        let _ = done.send(());
+                    }
+                    None => {
+                        self.flush().await;
+                        return;
+                    }
+                },
+                _ = flush_timer.tick() => self.flush().await,
+                _ = sleep_until(self.retry.at), if self.has_spooled() => self.drain_spool().await,
+            }
+        }
+    }
+
+    /// Exports the current batch, retrying with exponential backoff while the collector is
+    /// unavailable.
+    ///
+    /// With a spool, the batch is spooled instead of retried, and also while older batches
+    /// are still spooled so that batches are delivered in order.
+    async fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let body = export_request(&self.batch).to_string();
+        let events = self.batch.len();
+        self.batch.clear();
+        if self.spool.is_some() {
+            if !self.has_spooled() {
+                match self.export(body.as_bytes()).await {
+                    Ok(()) => return,
+                    Err(ExportError::Rejected(e)) => {
+                        error!("The OTLP collector rejected {events} log events: {e}");
+                        return;
+                    }
+                    Err(ExportError::Transient(e)) => {
+                        error!("OTLP export failed, spooling the batch: {e}");
+                        self.retry.schedule();
+                    }
+                }
+            }
+            if let Some(spool) = &mut self.spool
+                && let Err(e) = spool.push(body.as_bytes())
+            {
+                error!("Failed to spool {events} log events, dropping them: {e}");
+            }
+            return;
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match self.export(body.as_bytes()).await {
+                Ok(()) => return,
+                Err(ExportError::Rejected(e)) => {
+                    error!("The OTLP collector rejected {events} log events: {e}");
+                    return;
+                }
+                Err(ExportError::Transient(e)) => {
+                    error!(
+                        "OTLP export failed, retrying in {}s: {e}",
+                        backoff.as_secs()
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Delivers spooled batches in order until the spool is empty or a delivery fails.
+    async fn drain_spool(&mut self) {
+        let Some(mut spool) = self.spool.take() else {
+            return;
+        };
+        let mut retry = self.retry;
+        let this = &*self;
+        spool
+            .drain("OTLP", &mut retry, |body| async move {
+                match this.export(&body).await {
+                    Ok(()) => Ok(()),
+                    Err(ExportError::Rejected(e)) => {
+                        error!("The OTLP collector rejected a spooled batch, dropping it: {e}");
+                        Ok(())
+                    }
+                    Err(ExportError::Transient(e)) => Err(e),
+                }
+            })
+            .await;
+        self.spool = Some(spool);
+        self.retry = retry;
+    }
+
+    /// Posts a JSON-encoded export request, compressed with gzip.
+    async fn export(&self, body: &[u8]) -> Result<(), ExportError> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(body)
+            .map_err(|e| ExportError::Transient(e.to_string()))?;
+        let body = encoder
+            .finish()
+            .map_err(|e| ExportError::Transient(e.to_string()))?;
+        let mut request = self
+            .client
+            .post(self.config.url.clone())
+            .timeout(REQUEST_TIMEOUT)
+            .header("Content-Type", "application/json")
+            .header("Content-Encoding", "gzip")
+            .body(body);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| ExportError::Transient(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            let message = format!("HTTP {status}: {text}");
+            // The statuses after which the OTLP specification allows retrying.
+            return Err(match status.as_u16() {
+                429 | 502 | 503 | 504 => ExportError::Transient(message),
+                _ => ExportError::Rejected(message),
+            });
+        }
+        // A collector that accepts only some of the records says so in the response.
+        let response: Value = response.json().await.unwrap_or_default();
+        let partial = &response["partialSuccess"];
+        let rejected = match &partial["rejectedLogRecords"] {
+            Value::String(count) => count.parse().unwrap_or(0),
+            count => count.as_u64().unwrap_or(0),
+        };
+        if rejected > 0 {
+            warn!(
+                "The OTLP collector rejected {rejected} log records: {}",
+                partial["errorMessage"]
+                    .as_str()
+                    .unwrap_or("no reason given")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Builds the `ExportLogsServiceRequest` of a batch, with a resource per canister and node.
+fn export_request(batch: &[LogEvent]) -> Value {
+    let mut streams: Vec<(&LogEvent, Vec<Value>)> = Vec::new();
+    let mut positions: HashMap<(&str, &str), usize> = HashMap::new();
+    for event in batch {
+        let position = *positions
+            .entry((&event.canister_id, &event.node))
+            .or_insert_with(|| {
+                streams.push((event, Vec::new()));
+                streams.len() - 1
+            });
+        streams[position].1.push(log_record(event));
+    }
+    let resource_logs: Vec<Value> = streams
+        .into_iter()
+        .map(|(event, records)| {
+            json!({
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", &event.canister_id.as_str().into()),
+                        attribute("ic.canister_id", &event.canister_id.as_str().into()),
+                        attribute("ic.boundary_node", &event.node.as_str().into()),
+                    ],
+                },
+                "scopeLogs": [{
+                    "scope": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "logRecords": records,
+                }],
+            })
+        })
+        .collect();
+    json!({ "resourceLogs": resource_logs })
+}
+
+/// Builds the log record of an event.
+fn log_record(event: &LogEvent) -> Value {
+    let time = event
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+    let (severity_number, severity_text) = match Severity::of(event) {
+        Severity::Error => (17, "ERROR"),
+        Severity::Warning => (13, "WARN"),
+        Severity::Info => (9, "INFO"),
+    };
+    let mut attributes = Vec::new();
+    for (name, flag) in [
+        ("ic.backfill", event.backfill),
+        ("ic.resumed", event.resumed),
+        ("ic.suspect", event.suspect),
+        ("ic.panic", event.panic),
+    ] {
+        if flag {
+            attributes.push(attribute(name, &true.into()));
+        }
+    }
+    if !event.taint.is_empty() {
+        attributes.push(attribute("ic.taint", &event.taint_names().into()));
+    }
+    if let Some(fields) = &event.fields {
+        for (name, value) in fields {
+            attributes.push(attribute(name, value));
+        }
+    }
+    json!({
+        "timeUnixNano": time,
+        "observedTimeUnixNano": time,
+        "severityNumber": severity_number,
+        "severityText": severity_text,
+        "body": { "stringValue": event.message },
+        "attributes": attributes,
+    })
+}
+
+/// Builds a `KeyValue` of the OTLP JSON encoding.
+fn attribute(key: &str, value: &Value) -> Value {
+    json!({ "key": key, "value": any_value(value) })
+}
+
+/// Converts a JSON value into an `AnyValue` of the OTLP JSON encoding.
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({}),
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) => match number.as_i64() {
+            // 64-bit integers are encoded as strings in the OTLP JSON encoding.
+            Some(number) => json!({ "intValue": number.to_string() }),
+            None => json!({ "doubleValue": number.as_f64() }),
+        },
+        Value::String(value) => json!({ "stringValue": value }),
+        Value::Array(values) => {
+            let values: Vec<Value> = values.iter().map(any_value).collect();
+            json!({ "arrayValue": { "values": values } })
+        }
+        Value::Object(fields) => json!({ "kvlistValue": { "values": key_values(fields) } }),
+    }
+}
+
+/// Converts the members of a JSON object into `KeyValue`s.
+fn key_values(fields: &Map<String, Value>) -> Vec<Value> {
+    fields
+        .iter()
+        .map(|(key, value)| attribute(key, value))
+        .collect()
+}
@@ -0,0 +1,272 @@
+//! Export of the log events as a Parquet file with `--parquet-out`, see
+//! [`crate::sinks::table`].
+//!
+//! The rows are buffered in columns and written as a gzip-compressed row group once 65,536
+//! rows or 64 MiB of text are buffered, and on exit. The file metadata, which readers need to
+//! find the row groups, is written as the footer on exit, so the file can only be read once the
+//! client has exited; a client that is killed leaves an unreadable file.
+//!
+//! The `timestamp` column holds microseconds since the Unix epoch, annotated as a UTC
+//! timestamp; the text columns are annotated as UTF-8, and `fields` is null for plain lines.
+
+use crate::sinks::table::{Row, TableWriter};
+use parquet::basic::{Compression, GzipLevel};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Number of rows after which a row group is written.
+const ROW_GROUP_ROWS: usize = 65_536;
+/// Bytes of text after which a row group is written, which bounds the memory the buffered rows
+/// take.
+const ROW_GROUP_BYTES: usize = 64 * 1024 * 1024;
+
+/// Schema of the file, with the columns of [`crate::sinks::table::COLUMNS`].
+const SCHEMA: &str = "
+    message schema {
+        REQUIRED INT64 timestamp (TIMESTAMP(MICROS, true));
+        REQUIRED BYTE_ARRAY node (UTF8);
+        REQUIRED BYTE_ARRAY canister (UTF8);
+        REQUIRED BYTE_ARRAY level (UTF8);
+        REQUIRED BYTE_ARRAY message (UTF8);
+        REQUIRED BOOLEAN backfill;
+        REQUIRED BOOLEAN suspect;
+        OPTIONAL BYTE_ARRAY fields (UTF8);
+    }
+";
+
+/// The rows of the row group being collected, by column.
+#[derive(Default)]
+struct Columns {
+    timestamp: Vec<i64>,
+    node: Vec<ByteArray>,
+    canister: Vec<ByteArray>,
+    level: Vec<ByteArray>,
+    message: Vec<ByteArray>,
+    backfill: Vec<bool>,
+    suspect: Vec<bool>,
+    fields: Vec<ByteArray>,
+    /// Definition levels of `fields`: 1 for a value, 0 for null.
+    fields_defined: Vec<i16>,
+    /// Bytes of text in the columns.
+    bytes: usize,
+}
+
+impl Columns {
+    fn push(&mut self, row: Row) {
+        self.bytes += row.node.len()
+            + row.canister.len()
+            + row.message.len()
+            + row.fields.as_ref().map_or(0, String::len);
+        self.timestamp.push(row.timestamp_micros);
+        self.node.push(row.node.into_bytes().into());
+        self.canister.push(row.canister.into_bytes().into());
+        self.level.push(row.level.into());
+        self.message.push(row.message.into_bytes().into());
+        self.backfill.push(row.backfill);
+        self.suspect.push(row.suspect);
+        match row.fields {
+            Some(fields) => {
+                self.fields.push(fields.into_bytes().into());
+                self.fields_defined.push(1);
+            }
+            None => self.fields_defined.push(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.timestamp.len()
+    }
+}
+
+pub struct ParquetWriter {
+    /// The writer of the file, until the footer is written.
+    file: Option<SerializedFileWriter<File>>,
+    columns: Columns,
+}
+
+impl ParquetWriter {
+    /// Creates the file, replacing any file at the path.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let schema = Arc::new(parse_message_type(SCHEMA).expect("the schema is valid"));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::GZIP(GzipLevel::default()))
+            .set_max_row_group_size(ROW_GROUP_ROWS)
+            .set_created_by(concat!("ic-bn-logs-client version ", env!("CARGO_PKG_VERSION")).into())
+            .build();
+        let file = SerializedFileWriter::new(File::create(path)?, schema, Arc::new(properties))
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            file: Some(file),
+            columns: Columns::default(),
+        })
+    }
+
+    /// Writes the buffered rows as a row group.
+    fn write_row_group(&mut self) -> Result<(), ParquetError> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        if self.columns.len() == 0 {
+            return Ok(());
+        }
+        let columns = std::mem::take(&mut self.columns);
+        let mut group = file.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut writer) = group.next_column()? {
+            match column {
+                0 => writer
+                    .typed::<Int64Type>()
+                    .write_batch(&columns.timestamp, None, None)?,
+                1 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.node, None, None)?,
+                2 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.canister, None, None)?,
+                3 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.level, None, None)?,
+                4 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.message, None, None)?,
+                5 => writer
+                    .typed::<BoolType>()
+                    .write_batch(&columns.backfill, None, None)?,
+                6 => writer
+                    .typed::<BoolType>()
+                    .write_batch(&columns.suspect, None, None)?,
+                _ => writer.typed::<ByteArrayType>().write_batch(
+                    &columns.fields,
+                    Some(&columns.fields_defined),
+                    None,
+                )?,
+            };
+            writer.close()?;
+            column += 1;
+        }
+        group.close()?;
+        Ok(())
+    }
+}
+
+impl TableWriter for ParquetWriter {
+    fn write(&mut self, row: Row) -> io::Result<()> {
+        self.columns.push(row);
+        if self.columns.len() >= ROW_GROUP_ROWS || self.columns.bytes >= ROW_GROUP_BYTES {
+            self.write_row_group().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_row_group().map_err(io::Error::other)?;
+        if let Some(file) = self.file.take() {
+            file.close().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::table::COLUMNS;
+    use parquet::basic::{ConvertedType, Type};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use std::fs;
+
+    fn row(message: &str, backfill: bool, suspect: bool, fields: Option<&str>) -> Row {
+        Row {
+            timestamp_micros: 1_717_246_800_123_456,
+            timestamp: "2024-06-01T13:00:00.123Z".to_string(),
+            node: "node.example".to_string(),
+            canister: "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
+            level: "info",
+            message: message.to_string(),
+            backfill,
+            suspect,
+            fields: fields.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn written_file_is_read_by_the_parquet_crate() {
+        let path = std::env::temp_dir().join(format!(
+            "ic-bn-logs-client-test-{}.parquet",
+            std::process::id()
+        ));
+        let mut writer = ParquetWriter::create(&path).unwrap();
+        writer.write(row("started", false, false, None)).unwrap();
+        writer
+            .write(row("replayed", true, true, Some(r#"{"user":"alice"}"#)))
+            .unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 2);
+        assert_eq!(metadata.num_row_groups(), 1);
+        let schema = metadata.file_metadata().schema_descr();
+        let names: Vec<&str> = schema
+            .columns()
+            .iter()
+            .map(|column| column.name())
+            .collect();
+        assert_eq!(names, COLUMNS);
+        assert_eq!(schema.column(0).physical_type(), Type::INT64);
+        assert_eq!(
+            schema.column(0).converted_type(),
+            ConvertedType::TIMESTAMP_MICROS
+        );
+        assert_eq!(schema.column(7).max_def_level(), 1);
+        assert_eq!(schema.column(4).converted_type(), ConvertedType::UTF8);
+        assert_eq!(schema.column(5).physical_type(), Type::BOOLEAN);
+
+        let rows: Vec<Vec<Field>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(_, field)| field.clone())
+                    .collect()
+            })
+            .collect();
+        let text = |value: &str| Field::Str(value.to_string());
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    Field::TimestampMicros(1_717_246_800_123_456),
+                    text("node.example"),
+                    text("ryjl3-tyaaa-aaaaa-aaaba-cai"),
+                    text("info"),
+                    text("started"),
+                    Field::Bool(false),
+                    Field::Bool(false),
+                    Field::Null,
+                ],
+                vec![
+                    Field::TimestampMicros(1_717_246_800_123_456),
+                    text("node.example"),
+                    text("ryjl3-tyaaa-aaaaa-aaaba-cai"),
+                    text("info"),
+                    text("replayed"),
+                    Field::Bool(true),
+                    Field::Bool(true),
+                    text(r#"{"user":"alice"}"#),
+                ],
+            ]
+        );
+    }
+}
@@ -0,0 +1,710 @@
+//! Archive sink that uploads log events to an S3-compatible object store.
+//!
+//! Events are written as gzip-compressed NDJSON, one object per canister and hour of the event
+//! time in the configured timezone, under keys such as
+//! `canister=<id>/dt=2024-06-01-13/part-0001.ndjson.gz`, a layout that query engines like
+//! Athena or Spark read as partitions. An object is uploaded when its hour is over or at the
+//! latest after the flush interval, after which the next events of the hour go to the next
+//! part. Objects larger than the part size are uploaded in parts with a multipart upload while
+//! they are written. Requests are signed with AWS Signature Version 4 and use path-style URLs,
+//! so that MinIO, Ceph, and other S3-compatible stores work as well as AWS.
+//!
+//! Requests that fail because the store is unreachable or overloaded are retried with
+//! exponential backoff while new events queue up to a fixed limit; objects that the store
+//! rejects, or that still fail after the last attempt, are dropped.
+
+use crate::clock;
+use crate::event::LogEvent;
+use crate::memory::{self, QueueReceiver, QueueSender};
+use crate::sinks::LogSink;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::SystemTime;
+use tokio::sync::oneshot;
+use tokio::time::{interval, sleep, Duration, Instant, MissedTickBehavior};
+use url::Url;
+
+/// Maximum number of events waiting to be archived.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Timeout of a single request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// First delay before retrying a failed request.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Most attempts of a request that keeps failing transiently, about four minutes with the
+/// backoff, before its object is dropped.
+const MAX_ATTEMPTS: u32 = 10;
+/// How often open objects are checked for whether they are due for upload.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// strftime pattern of the hour in object keys.
+const HOUR_FORMAT: &str = "%Y-%m-%d-%H";
+/// Smallest part size that S3 accepts for all but the last part of a multipart upload.
+pub const MIN_PART_SIZE_MB: u64 = 5;
+
+/// Characters that are percent-encoded in signed URLs: all except the unreserved ones.
+const ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+/// Characters that are percent-encoded in the path of signed URLs.
+const ENCODE_PATH: &AsciiSet = &ENCODE.remove(b'/');
+
+/// Credentials of the object store.
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Token of temporary credentials, sent in the `x-amz-security-token` header.
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    /// Reads the credentials from the standard AWS environment variables.
+    pub fn from_env() -> Result<Self, String> {
+        let (Ok(access_key_id), Ok(secret_access_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) else {
+            return Err(
+                "--s3-bucket requires the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY \
+                 environment variables"
+                    .to_string(),
+            );
+        };
+        Ok(Self {
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Settings of the S3 sink.
+#[derive(Clone)]
+pub struct S3Config {
+    /// Base URL of the object store, e.g. `https://s3.eu-central-1.amazonaws.com`.
+    pub endpoint: Url,
+    pub bucket: String,
+    /// Region that requests are signed for.
+    pub region: String,
+    /// Prefix of all object keys.
+    pub prefix: String,
+    pub credentials: S3Credentials,
+    /// Size of the parts of multipart uploads, and above which objects are uploaded in parts.
+    pub part_size: usize,
+    /// Longest time an event waits before its object is uploaded.
+    pub flush_interval: Duration,
+}
+
+/// Returns the endpoint of AWS S3 in the region.
+pub fn aws_endpoint(region: &str) -> Url {
+    Url::parse(&format!("https://s3.{region}.amazonaws.com"))
+        .expect("the S3 endpoint of a region is a valid URL")
+}
+
+/// Validates a bucket name.
+pub fn parse_bucket(bucket: &str) -> Result<String, String> {
+    let valid = (3..=63).contains(&bucket.len())
+        && bucket
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'.');
+    if !valid {
+        return Err(format!(
+            "bucket names have 3 to 63 lowercase letters, digits, hyphens, and dots, got \
+             {bucket}"
+        ));
+    }
+    Ok(bucket.to_string())
+}
+
+/// Parses a part size in MiB of at least the minimum that S3 accepts.
+pub fn parse_part_size(value: &str) -> Result<u64, String> {
+    let size: u64 = value
+        .parse()
+        .map_err(|e| format!("invalid size {value}: {e}"))?;
+    if size < MIN_PART_SIZE_MB {
+        return Err(format!(
+            "the part size must be at least {MIN_PART_SIZE_MB} MiB"
+        ));
+    }
+    Ok(size)
+}
+
+enum Command {
+    Archive(LogEvent),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the background task that archives events.
+pub struct S3Sink {
+    sender: QueueSender<Command>,
+}
+
+impl S3Sink {
+    /// Starts the background task archiving events into the bucket.
+    pub fn spawn(config: S3Config, client: reqwest::Client) -> Self {
+        let (sender, receiver) = memory::queue("S3", QUEUE_CAPACITY);
+        info!(
+            "Archiving log events into the bucket {} at {}.",
+            config.bucket, config.endpoint
+        );
+        tokio::spawn(Archiver::new(config, client).run(receiver));
+        Self { sender }
+    }
+}
+
+impl LogSink for S3Sink {
+    fn name(&self) -> &'static str {
+        "S3"
+    }
+
+    /// Queues an event for archiving, see [`memory`] for when events are dropped.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send_event(Command::Archive(event.clone()), memory::size_of(event));
+        })
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sender.ready())
+    }
+
+    /// Uploads all open objects.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Flush(done));
+            let _ = wait.await;
+        })
+    }
+}
+
+/// Canister and hour of the events of an object.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Partition {
+    canister_id: String,
+    hour: String,
+}
+
+/// An object being written.
+struct Object {
+    key: String,
+    encoder: GzEncoder<Vec<u8>>,
+    events: usize,
+    opened: Instant,
+    /// Whether the hour of the object was the current hour when it was opened, so that the
+    /// object is uploaded when the hour is over. Objects of earlier hours, e.g. of a replayed
+    /// capture, wait for the flush interval instead.
+    current: bool,
+    /// The multipart upload, once the object outgrew a single part.
+    upload: Option<MultipartUpload>,
+}
+
+struct MultipartUpload {
+    id: String,
+    /// ETags of the uploaded parts, in order.
+    etags: Vec<String>,
+}
+
+/// Why a request failed.
+enum RequestError {
+    /// The store could not be reached or is overloaded; the request may succeed later.
+    Transient(String),
+    /// The object or the bucket does not exist.
+    NotFound(String),
+    /// The store rejected the request.
+    Rejected(String),
+}
+
+impl From<RequestError> for String {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Transient(e) | RequestError::NotFound(e) | RequestError::Rejected(e) => e,
+        }
+    }
+}
+
+struct Archiver {
+    config: S3Config,
+    client: reqwest::Client,
+    objects: HashMap<Partition, Object>,
+    /// Number of the next part of each partition seen.
+    next_part: HashMap<Partition, u32>,
+}
+
+impl Archiver {
+    fn new(config: S3Config, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            client,
+            objects: HashMap::new(),
+            next_part: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, mut receiver: QueueReceiver<Command>) {
+        let mut check_timer = interval(CHECK_INTERVAL);
+        check_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(Command::Archive(event)) => self.archive(&event).await,
+                    Some(Command::Flush(done)) => {
+                        self.upload_due(true).await;
+                        let _ = done.send(());
+                    }
+                    None => {
+                        self.upload_due(true).await;
+                        return;
+                    }
+                },
+                _ = check_timer.tick() => self.upload_due(false).await,
+            }
+        }
+    }
+
+    /// Writes an event to the object of its partition, uploading a part once enough
+    /// compressed data accumulated.
+    async fn archive(&mut self, event: &LogEvent) {
+        let partition = Partition {
+            canister_id: event.canister_id.clone(),
+            hour: event.local_time().format(HOUR_FORMAT).to_string(),
+        };
+        if !self.objects.contains_key(&partition) {
+            let key = self.next_key(&partition).await;
+            let current = partition.hour == current_hour();
+            self.objects.insert(
+                partition.clone(),
+                Object {
+                    key,
+                    encoder: GzEncoder::new(Vec::new(), Compression::default()),
+                    events: 0,
+                    opened: Instant::now(),
+                    current,
+                    upload: None,
+                },
+            );
+        }
+        let object = self.objects.get_mut(&partition).unwrap();
+        let mut line = event.to_json().to_string();
+        line.push('\n');
+        if let Err(e) = object.encoder.write_all(line.as_bytes()) {
+            error!("Failed to compress a log event for S3, dropping it: {e}");
+            return;
+        }
+        object.events += 1;
+        if object.encoder.get_ref().len() >= self.config.part_size {
+            let part = std::mem::take(object.encoder.get_mut());
+            let mut object = self.objects.remove(&partition).unwrap();
+            match self.upload_part(&mut object, part).await {
+                Ok(()) => {
+                    self.objects.insert(partition, object);
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to upload a part of {}, dropping {} log events: {e}",
+                        object.key, object.events
+                    );
+                    self.abort(&object).await;
+                }
+            }
+        }
+    }
+
+    /// Uploads the objects whose hour is over or that were opened at least the flush interval
+    /// ago, or all objects.
+    async fn upload_due(&mut self, all: bool) {
+        let current_hour = current_hour();
+        let due: Vec<Partition> = self
+            .objects
+            .iter()
+            .filter(|(partition, object)| {
+                all || (object.current && partition.hour != current_hour)
+                    || object.opened.elapsed() >= self.config.flush_interval
+            })
+            .map(|(partition, _)| partition.clone())
+            .collect();
+        for partition in due {
+            let object = self.objects.remove(&partition).unwrap();
+            self.complete(object).await;
+        }
+    }
+
+    /// Returns the key of the next object of a partition. The first time a partition is seen,
+    /// existing objects are skipped, so that a restart does not overwrite them.
+    async fn next_key(&mut self, partition: &Partition) -> String {
+        let mut part = self.next_part.get(partition).copied().unwrap_or(0);
+        let probe = part == 0;
+        part = part.max(1);
+        let mut key = self.key(partition, part);
+        if probe {
+            while self.exists(&key).await {
+                part += 1;
+                key = self.key(partition, part);
+            }
+        }
+        self.next_part.insert(partition.clone(), part + 1);
+        key
+    }
+
+    fn key(&self, partition: &Partition, part: u32) -> String {
+        format!(
+            "{}canister={}/dt={}/part-{part:04}.ndjson.gz",
+            self.config.prefix, partition.canister_id, partition.hour
+        )
+    }
+
+    /// Returns whether an object exists. If that cannot be told, it is assumed to exist, so
+    /// that it is not overwritten.
+    async fn exists(&self, key: &str) -> bool {
+        match self
+            .request_with_retry(Method::HEAD, key, &[], Vec::new())
+            .await
+        {
+            Ok(_) => true,
+            Err(RequestError::NotFound(_)) => false,
+            Err(RequestError::Transient(e) | RequestError::Rejected(e)) => {
+                warn!("Failed to check whether {key} exists, skipping it: {e}");
+                true
+            }
+        }
+    }
+
+    /// Finishes the compression of an object and uploads the rest of it.
+    async fn complete(&self, object: Object) {
+        let Object {
+            key,
+            encoder,
+            events,
+            upload,
+            ..
+        } = object;
+        let data = match encoder.finish() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to compress {key}, dropping {events} log events: {e}");
+                return;
+            }
+        };
+        let result = match &upload {
+            None => self
+                .request_with_retry(Method::PUT, &key, &[], data)
+                .await
+                .map(drop)
+                .map_err(String::from),
+            Some(upload) => self.complete_upload(&key, upload, data).await,
+        };
+        match result {
+            Ok(()) => debug!("Uploaded {events} log events to {key}."),
+            Err(e) => {
+                error!("Failed to upload {key}, dropping {events} log events: {e}");
+                if let Some(upload) = &upload {
+                    self.abort_upload(&key, upload).await;
+                }
+            }
+        }
+    }
+
+    /// Uploads a part of an object, starting its multipart upload first if needed.
+    async fn upload_part(&self, object: &mut Object, data: Vec<u8>) -> Result<(), String> {
+        if object.upload.is_none() {
+            let response = self
+                .request_with_retry(Method::POST, &object.key, &[("uploads", "")], Vec::new())
+                .await?;
+            let id = xml_element(&response.body, "UploadId")
+                .ok_or("the response to creating a multipart upload has no UploadId")?;
+            object.upload = Some(MultipartUpload {
+                id: id.to_string(),
+                etags: Vec::new(),
+            });
+        }
+        let upload = object.upload.as_mut().unwrap();
+        let part_number = (upload.etags.len() + 1).to_string();
+        let response = self
+            .request_with_retry(
+                Method::PUT,
+                &object.key,
+                &[("partNumber", &part_number), ("uploadId", &upload.id)],
+                data,
+            )
+            .await?;
+        let etag = response
+            .etag
+            .ok_or("the response to uploading a part has no ETag")?;
+        upload.etags.push(etag);
+        Ok(())
+    }
+
+    /// Uploads the last part of an object and completes its multipart upload.
+    async fn complete_upload(
+        &self,
+        key: &str,
+        upload: &MultipartUpload,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut etags = upload.etags.clone();
+        if !data.is_empty() {
+            let part_number = (etags.len() + 1).to_string();
+            let response = self
+                .request_with_retry(
+                    Method::PUT,
+                    key,
+                    &[("partNumber", &part_number), ("uploadId", &upload.id)],
+                    data,
+                )
+                .await?;
+            etags.push(
+                response
+                    .etag
+                    .ok_or("the response to uploading a part has no ETag")?,
+            );
+        }
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                index + 1
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        self.request_with_retry(
+            Method::POST,
+            key,
+            &[("uploadId", &upload.id)],
+            body.into_bytes(),
+        )
+        .await
+        .map(drop)
+        .map_err(String::from)
+    }
+
+    /// Aborts the multipart upload of an object, if it has one.
+    async fn abort(&self, object: &Object) {
+        if let Some(upload) = &object.upload {
+            self.abort_upload(&object.key, upload).await;
+        }
+    }
+
+    /// Aborts a multipart upload, so that the store discards its parts.
+    async fn abort_upload(&self, key: &str, upload: &MultipartUpload) {
+        let query = [("uploadId", upload.id.as_str())];
+        if let Err(e) = self.request(Method::DELETE, key, &query, Vec::new()).await {
+            warn!(
+                "Failed to abort the multipart upload of {key}: {}",
+                String::from(e)
+            );
+        }
+    }
+
+    /// Sends a request, retrying with exponential backoff while the failure is transient, up
+    /// to the most attempts.
+    async fn request_with_retry(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response, RequestError> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.request(method.clone(), key, query, body.clone()).await {
+                Err(RequestError::Transient(e)) if attempts >= MAX_ATTEMPTS => {
+                    return Err(RequestError::Transient(format!(
+                        "giving up after {attempts} attempts: {e}"
+                    )));
+                }
+                Err(RequestError::Transient(e)) => {
+                    error!(
+                        "S3 {method} request for {key} failed, retrying in {}s: {e}",
+                        backoff.as_secs()
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends a signed request for an object.
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response, RequestError> {
+        let mut url = self.config.endpoint.clone();
+        let path = format!(
+            "{}/{}/{}",
+            url.path().trim_end_matches('/'),
+            self.config.bucket,
+            key
+        );
+        url.set_path(&utf8_percent_encode(&path, ENCODE_PATH).to_string());
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (encode(name), encode(value)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query((!query.is_empty()).then_some(query.as_str()));
+
+        let headers = self.sign(&method, &url, &body);
+        let mut request = self
+            .client
+            .request(method, url)
+            .timeout(REQUEST_TIMEOUT)
+            .body(body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| RequestError::Transient(e.to_string()))?;
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let body = response
+            .text()
+            .await
+            .map_err(|e| RequestError::Transient(e.to_string()))?;
+        // CompleteMultipartUpload reports some failures in the body of a successful response.
+        if status.is_success() && !body.contains("<Error>") {
+            return Ok(Response { etag, body });
+        }
+        let message = match xml_element(&body, "Message") {
+            Some(message) => format!("HTTP {status}: {message}"),
+            None => format!("HTTP {status}"),
+        };
+        if status == StatusCode::NOT_FOUND {
+            Err(RequestError::NotFound(message))
+        } else if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+            || (status.is_success() && xml_element(&body, "Code") == Some("InternalError"))
+        {
+            Err(RequestError::Transient(message))
+        } else {
+            Err(RequestError::Rejected(message))
+        }
+    }
+
+    /// Returns the headers that authenticate a request with AWS Signature Version 4.
+    fn sign(&self, method: &Method, url: &Url, body: &[u8]) -> Vec<(&'static str, String)> {
+        let credentials = &self.config.credentials;
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        // Sorted by name, as the canonical request requires.
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{method}\n{}\n{}\n{canonical_headers}\n{signed_headers}\n{payload_hash}",
+            url.path(),
+            url.query().unwrap_or_default()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [
+            date.as_str(),
+            self.config.region.as_str(),
+            "s3",
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        headers.remove(0);
+        headers.push((
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+                 Signature={signature}",
+                credentials.access_key_id
+            ),
+        ));
+        headers
+    }
+}
+
+/// The parts of a successful response that the sink uses.
+struct Response {
+    etag: Option<String>,
+    body: String,
+}
+
+/// Returns the partition hour of the current time.
+fn current_hour() -> String {
+    clock::local_time(SystemTime::now())
+        .format(HOUR_FORMAT)
+        .to_string()
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, ENCODE).to_string()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the text of the first element with the name in an XML document.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(&xml[start..end])
+}
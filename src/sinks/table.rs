@@ -0,0 +1,187 @@
+//! The rows of the `--csv-out` and `--parquet-out` exports, for loading canister log sessions
+//! into pandas, DuckDB, or a spreadsheet.
+//!
+//! Every event becomes a row with the columns in [`COLUMNS`]. The fields of structured records
+//! vary from line to line, so they are kept together as a JSON object in the `fields` column,
+//! which DuckDB queries with `fields->>'name'` and pandas expands with `json_normalize`.
+//!
+//! The rows are written by a thread of their own, so that slow disks do not hold up the
+//! connections; rows are dropped when too many are waiting, see [`crate::memory`].
+
+use crate::event::LogEvent;
+use crate::memory::{self, QueueReceiver, QueueSender};
+use crate::severity::Severity;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use log::error;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use tokio::sync::oneshot;
+
+/// Names of the columns, in order.
+pub const COLUMNS: &[&str] = &[
+    "timestamp",
+    "node",
+    "canister",
+    "level",
+    "message",
+    "backfill",
+    "suspect",
+    "fields",
+];
+
+/// Maximum number of rows waiting to be written.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// An event as a row of the export.
+pub struct Row {
+    /// Receive time, or for backfilled records the time the canister logged them, in
+    /// microseconds since the Unix epoch.
+    pub timestamp_micros: i64,
+    /// The same time as an RFC 3339 timestamp in the configured timezone.
+    pub timestamp: String,
+    pub node: String,
+    pub canister: String,
+    /// `error`, `warn`, or `info`.
+    pub level: &'static str,
+    pub message: String,
+    pub backfill: bool,
+    pub suspect: bool,
+    /// The fields of a structured record as a JSON object, or None for plain lines.
+    pub fields: Option<String>,
+}
+
+impl Row {
+    pub fn of(event: &LogEvent) -> Self {
+        let timestamp_micros = event
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as i64);
+        Self {
+            timestamp_micros,
+            timestamp: event.timestamp_rfc3339(),
+            node: event.node.clone(),
+            canister: event.canister_id.clone(),
+            level: Severity::of(event).name(),
+            message: event.message.clone(),
+            backfill: event.backfill,
+            suspect: event.suspect,
+            fields: event
+                .fields
+                .as_ref()
+                .filter(|fields| !fields.is_empty())
+                .map(|fields| serde_json::Value::Object(fields.clone()).to_string()),
+        }
+    }
+}
+
+/// Writes rows to a file in a table format.
+pub trait TableWriter: Send + 'static {
+    fn write(&mut self, row: Row) -> io::Result<()>;
+
+    /// Writes what is buffered, and completes the file if the format requires it. No rows are
+    /// written afterwards.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+enum Command {
+    Write(Row),
+    /// Answers once the rows queued before it are written.
+    Sync(oneshot::Sender<()>),
+    Finish(oneshot::Sender<()>),
+}
+
+/// Handle to the thread that writes the rows of an export.
+pub struct TableSink {
+    format: &'static str,
+    sender: QueueSender<Command>,
+}
+
+impl TableSink {
+    /// Starts the thread writing the rows to the file at the path.
+    pub fn spawn(format: &'static str, path: PathBuf, writer: impl TableWriter) -> Self {
+        let (sender, receiver) = memory::queue(format, QUEUE_CAPACITY);
+        thread::spawn(move || run(format, path, writer, receiver));
+        Self { format, sender }
+    }
+}
+
+impl LogSink for TableSink {
+    fn name(&self) -> &'static str {
+        self.format
+    }
+
+    /// Queues an event for writing, see [`memory`] for when events are dropped.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send_event(Command::Write(Row::of(event)), memory::size_of(event));
+        })
+    }
+
+    fn ready(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.sender.ready())
+    }
+
+    /// Writes all queued rows.
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Sync(done));
+            let _ = wait.await;
+        })
+    }
+
+    /// Writes all queued rows and completes the file.
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let (done, wait) = oneshot::channel();
+            self.sender.send(Command::Finish(done));
+            let _ = wait.await;
+        })
+    }
+}
+
+fn run(
+    format: &'static str,
+    path: PathBuf,
+    mut writer: impl TableWriter,
+    mut receiver: QueueReceiver<Command>,
+) {
+    let mut failed = false;
+    let mut finished = false;
+    while let Some(command) = receiver.blocking_recv() {
+        match command {
+            Command::Write(_) if failed || finished => {}
+            Command::Write(row) => {
+                if let Err(e) = writer.write(row) {
+                    error!(
+                        "Failed to write to {}, stopping the {format} export: {e}",
+                        path.display()
+                    );
+                    failed = true;
+                }
+            }
+            Command::Sync(done) => {
+                let _ = done.send(());
+            }
+            Command::Finish(done) => {
+                if !failed && !finished {
+                    if let Err(e) = writer.finish() {
+                        error!("Failed to finish {}: {e}", path.display());
+                    }
+                    finished = true;
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+    if !failed
+        && !finished
+        && let Err(e) = writer.finish()
+    {
+        error!("Failed to finish {}: {e}", path.display());
+    }
+}
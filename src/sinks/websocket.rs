@@ -0,0 +1,236 @@
+//! Local WebSocket server that relays the aggregated log stream, so that browser dashboards
+//! and other consumers can attach to one upstream session instead of each connecting to the
+//! boundary nodes.
+//!
+//! Every client receives the events written to the output from the moment it connects, as
+//! JSON text messages, optionally restricted to one canister and to lines matching a regular
+//! expression given in the query string. A client that falls too far behind skips the events
+//! it missed. Browsers send the origin of the page that opens a connection, and only the
+//! allowed origins are accepted, so that arbitrary websites cannot read the logs through the
+//! browsers of their visitors.
+
+use crate::event::LogEvent;
+use crate::server;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use regex::Regex;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use url::form_urlencoded;
+
+/// Number of events buffered for each client.
+const CLIENT_BUFFER: usize = 4096;
+
+/// An event as relayed to the clients, serialized once for all of them.
+struct RelayedEvent {
+    canister_id: String,
+    message: String,
+    json: String,
+}
+
+/// Publishes the written events to the clients of the WebSocket server.
+#[derive(Clone)]
+pub struct WebSocketSink {
+    events: broadcast::Sender<Arc<RelayedEvent>>,
+}
+
+impl LogSink for WebSocketSink {
+    fn name(&self) -> &'static str {
+        "WebSocket"
+    }
+
+    /// Publishes an event to all connected clients.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.events.receiver_count() == 0 {
+                return;
+            }
+            // Sending only fails if the last client disconnected meanwhile.
+            let _ = self.events.send(Arc::new(RelayedEvent {
+                canister_id: event.canister_id.clone(),
+                message: event.message.clone(),
+                json: event.to_json().to_string(),
+            }));
+        })
+    }
+}
+
+/// Which events a client receives, from the query string of its request.
+struct Subscription {
+    canister_id: Option<String>,
+    filter: Option<Regex>,
+}
+
+impl Subscription {
+    fn accepts(&self, event: &RelayedEvent) -> bool {
+        self.canister_id
+            .as_ref()
+            .is_none_or(|id| *id == event.canister_id)
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.is_match(&event.message))
+    }
+}
+
+/// Settings shared by all connections to the server.
+struct Server {
+    events: broadcast::Sender<Arc<RelayedEvent>>,
+    canister_ids: watch::Receiver<Vec<String>>,
+    allowed_origins: Vec<String>,
+}
+
+impl Server {
+    /// Checks the handshake request of a client and reads its subscription.
+    fn subscribe(&self, request: &Request) -> Result<Subscription, (StatusCode, String)> {
+        if let Some(origin) = request.headers().get(header::ORIGIN) {
+            let origin = origin.to_str().unwrap_or_default();
+            if !self.allowed_origins.iter().any(|allowed| allowed == origin) {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    format!("origin {origin} is not allowed"),
+                ));
+            }
+        }
+        let mut subscription = Subscription {
+            canister_id: None,
+            filter: None,
+        };
+        let query = request.uri().query().unwrap_or_default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "canister_id" if !value.is_empty() => {
+                    let canister_ids = self.canister_ids.borrow();
+                    if !canister_ids.iter().any(|id| *id == value) {
+                        return Err((
+                            StatusCode::NOT_FOUND,
+                            format!(
+                                "canister {value} is not monitored; monitored canisters: {}",
+                                canister_ids.join(", ")
+                            ),
+                        ));
+                    }
+                    subscription.canister_id = Some(value.into_owned());
+                }
+                "filter" if !value.is_empty() => {
+                    let filter = Regex::new(&value).map_err(|e| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!("invalid filter {value}: {e}"),
+                        )
+                    })?;
+                    subscription.filter = Some(filter);
+                }
+                _ => {}
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Relays the events to a client until it disconnects.
+    async fn relay(&self, stream: TcpStream, peer: SocketAddr) {
+        let mut subscription = None;
+        let handshake = Handshake {
+            server: self,
+            subscription: &mut subscription,
+        };
+        let handshake = tokio_tungstenite::accept_hdr_async(stream, handshake).await;
+        let websocket = match handshake {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                warn!("WebSocket client {peer} failed to connect: {e}");
+                return;
+            }
+        };
+        let Some(subscription) = subscription else {
+            return;
+        };
+        info!("WebSocket client {peer} connected.");
+
+        let mut events = self.events.subscribe();
+        let (mut write, mut read) = websocket.split();
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if !subscription.accepts(&event) {
+                            continue;
+                        }
+                        if write.send(Message::text(event.json.as_str())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("WebSocket client {peer} fell behind, skipped {skipped} events.");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                // Reading answers pings; messages from the client are ignored.
+                message = read.next() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        let _ = write.close().await;
+        info!("WebSocket client {peer} disconnected.");
+    }
+}
+
+/// Accepts or rejects the handshake request of a client, keeping its subscription.
+struct Handshake<'a> {
+    server: &'a Server,
+    subscription: &'a mut Option<Subscription>,
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        match self.server.subscribe(request) {
+            Ok(subscription) => {
+                *self.subscription = Some(subscription);
+                Ok(response)
+            }
+            Err((status, reason)) => {
+                let mut response = ErrorResponse::new(Some(reason));
+                *response.status_mut() = status;
+                Err(response)
+            }
+        }
+    }
+}
+
+/// Starts the WebSocket server on the address, returning the sink that feeds its clients.
+///
+/// Requests from browsers are only accepted from the `allowed_origins`, e.g.
+/// `http://localhost:3000`; requests without an `Origin` header are always accepted.
+pub async fn serve(
+    addr: SocketAddr,
+    canister_ids: watch::Receiver<Vec<String>>,
+    allowed_origins: Vec<String>,
+) -> io::Result<WebSocketSink> {
+    let (events, _) = broadcast::channel(CLIENT_BUFFER);
+    let server = Arc::new(Server {
+        events: events.clone(),
+        canister_ids,
+        allowed_origins,
+    });
+    // Bind before returning, so that an unavailable address is reported as a startup error.
+    let listener = TcpListener::bind(addr).await?;
+    info!("WebSocket server listening on {}.", listener.local_addr()?);
+    server::spawn_accept_loop(listener, "WebSocket server", move |stream, peer| {
+        let server = server.clone();
+        async move { server.relay(stream, peer).await }
+    });
+    Ok(WebSocketSink { events })
+}
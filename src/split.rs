@@ -0,0 +1,240 @@
+//! Separate output streams per canister in a directory, so that downstream tools can consume
+//! the lines of each canister on their own instead of parsing the merged stream.
+//!
+//! Each canister gets a file `<canister_id>.log`, created when its first line arrives. On Unix,
+//! canisters can get named pipes `<canister_id>.pipe` instead, and a `.log` path that is a named
+//! pipe created beforehand is used as one. Pipes are written without blocking: while no reader
+//! has a pipe open, or while its reader falls behind, the lines for it are dropped, so that a
+//! stalled consumer cannot hold up the other canisters and outputs. A line that a pipe could
+//! only take in part is completed before the next one, so lines are never interleaved.
+
+use log::error;
+#[cfg(unix)]
+use log::{info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Writes the lines of every canister to its own file or named pipe in a directory.
+pub struct SplitOutput {
+    dir: PathBuf,
+    /// Whether named pipes are created instead of files.
+    #[cfg(unix)]
+    fifo: bool,
+    streams: Mutex<HashMap<String, Stream>>,
+}
+
+enum Stream {
+    File(File),
+    #[cfg(unix)]
+    Pipe(Pipe),
+}
+
+impl SplitOutput {
+    /// Creates the output, creating the directory if needed. With `fifo`, which is only
+    /// supported on Unix, every canister gets a named pipe instead of a file.
+    pub fn new(dir: PathBuf, fifo: bool) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        #[cfg(not(unix))]
+        let _ = fifo;
+        Ok(Self {
+            dir,
+            #[cfg(unix)]
+            fifo,
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Writes a rendered line to the stream of the canister, opening it on its first line.
+    pub fn write(&self, canister_id: &str, line: &[u8]) {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = match streams.entry(canister_id.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match self.open(canister_id) {
+                Ok(stream) => entry.insert(stream),
+                Err(e) => {
+                    error!("Failed to open the output of {canister_id}: {e}");
+                    return;
+                }
+            },
+        };
+        let result = match stream {
+            Stream::File(file) => file.write_all(line),
+            #[cfg(unix)]
+            Stream::Pipe(pipe) => pipe.write(line),
+        };
+        if let Err(e) = result {
+            error!("Failed to write to the output of {canister_id}: {e}");
+        }
+    }
+
+    /// Closes the files, so that they are opened again on the next line, e.g. after they were
+    /// moved away by log rotation. Pipes stay open, as their readers would see them end.
+    pub fn reopen(&self) {
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|_, stream| !matches!(stream, Stream::File(_)));
+    }
+
+    fn open(&self, canister_id: &str) -> io::Result<Stream> {
+        #[cfg(unix)]
+        if self.fifo {
+            let path = self.dir.join(format!("{canister_id}.pipe"));
+            if !path.exists() {
+                pipe::create(&path)?;
+            }
+            return Ok(Stream::Pipe(Pipe::new(path)));
+        }
+        let path = self.dir.join(format!("{canister_id}.log"));
+        #[cfg(unix)]
+        if pipe::is_pipe(&path) {
+            return Ok(Stream::Pipe(Pipe::new(path)));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Stream::File(file))
+    }
+}
+
+/// A named pipe that lines are written to without blocking.
+#[cfg(unix)]
+struct Pipe {
+    path: PathBuf,
+    /// The write end, while a reader has the pipe open.
+    writer: Option<File>,
+    /// The rest of a line that the pipe could only take in part.
+    pending: Vec<u8>,
+    /// Lines dropped since the last line was written.
+    dropped: u64,
+}
+
+#[cfg(unix)]
+impl Pipe {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            writer: None,
+            pending: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.writer.is_none() {
+            match pipe::open_writer(&self.path)? {
+                Some(writer) => {
+                    info!("A reader opened {}.", self.path.display());
+                    self.writer = Some(writer);
+                }
+                None => {
+                    self.drop_line();
+                    return Ok(());
+                }
+            }
+        }
+        // Complete the line the reader got in part first, so that lines are not interleaved.
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            let written = self.send(&pending)?;
+            if written < pending.len() {
+                if self.writer.is_some() {
+                    self.pending = pending[written..].to_vec();
+                }
+                self.drop_line();
+                return Ok(());
+            }
+        }
+        let written = self.send(line)?;
+        if written == 0 {
+            self.drop_line();
+            return Ok(());
+        }
+        if written < line.len() && self.writer.is_some() {
+            self.pending = line[written..].to_vec();
+        }
+        if self.dropped > 0 {
+            warn!(
+                "Dropped {} lines for {} while it had no reader or its reader fell behind.",
+                self.dropped,
+                self.path.display()
+            );
+            self.dropped = 0;
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the data as the pipe takes without blocking, returning how much it
+    /// took. Closes the write end if the reader closed the pipe.
+    fn send(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(0);
+        };
+        let mut written = 0;
+        while written < data.len() {
+            match writer.write(&data[written..]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    info!("The reader closed {}.", self.path.display());
+                    self.writer = None;
+                    self.pending.clear();
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+
+    fn drop_line(&mut self) {
+        if self.dropped == 0 && self.writer.is_some() {
+            warn!(
+                "The reader of {} falls behind, dropping lines.",
+                self.path.display()
+            );
+        }
+        self.dropped += 1;
+    }
+}
+
+#[cfg(unix)]
+mod pipe {
+    use std::ffi::CString;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+    use std::path::Path;
+
+    /// Returns whether the path is a named pipe.
+    pub fn is_pipe(path: &Path) -> bool {
+        fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+
+    /// Creates a named pipe.
+    pub fn create(path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(path.as_ptr(), 0o644) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Opens the write end of a named pipe without blocking, or returns `None` if no reader
+    /// has it open.
+    pub fn open_writer(path: &Path) -> io::Result<Option<File>> {
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+        {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
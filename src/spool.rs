@@ -0,0 +1,323 @@
+//! Disk-backed queue that buffers sink payloads while the sink is unreachable.
+//!
+//! Each payload (e.g. the body of a bulk request) is stored in its own segment file named
+//! after a sequence number, so payloads are delivered in order and survive restarts. When the
+//! total size exceeds the cap, the oldest segments are dropped to make room.
+
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::time::{Duration, Instant};
+
+/// File extension of complete segments.
+const SEGMENT_EXTENSION: &str = "seg";
+/// File extension of segments that are still being written.
+const PARTIAL_EXTENSION: &str = "tmp";
+
+/// First delay before retrying a failed delivery of a spooled payload.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between retries of a failed delivery of a spooled payload.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// When to retry delivering the spooled payloads of a sink, with exponential backoff.
+#[derive(Clone, Copy)]
+pub struct Retry {
+    /// When to next try to deliver the spooled payloads.
+    pub at: Instant,
+    /// Delay before the next retry after a failed delivery.
+    backoff: Duration,
+}
+
+impl Retry {
+    /// Schedules the first delivery right away.
+    pub fn new() -> Self {
+        Self {
+            at: Instant::now(),
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Schedules the next delivery after the current delay.
+    pub fn schedule(&mut self) {
+        self.at = Instant::now() + self.backoff;
+    }
+
+    /// Schedules the next delivery after a failed one, doubling the delay.
+    fn back_off(&mut self) {
+        self.schedule();
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// A directory of segment files, oldest first.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Sequence numbers and sizes of the stored segments.
+    segments: VecDeque<(u64, u64)>,
+    total_bytes: u64,
+    next_sequence: u64,
+}
+
+impl Spool {
+    /// Opens the spool in the directory, picking up the segments left by a previous run.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(sequence) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(SEGMENT_EXTENSION) => segments.push((sequence, fs::metadata(&path)?.len())),
+                // Left over from a crash while writing; the payload is incomplete.
+                Some(PARTIAL_EXTENSION) => fs::remove_file(&path)?,
+                _ => {}
+            }
+        }
+        segments.sort_unstable();
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            total_bytes: segments.iter().map(|(_, size)| size).sum(),
+            next_sequence: segments.last().map_or(0, |(sequence, _)| sequence + 1),
+            segments: segments.into(),
+        })
+    }
+
+    /// Returns true if no payloads are stored.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Returns the number of stored payloads.
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Delivers the payloads in order until the spool is empty or a delivery fails.
+    ///
+    /// `deliver` returns an error if the payload should be retried, and `Ok` once it is
+    /// delivered or rejected for good; `name` names the sink in log messages.
+    pub async fn drain<F>(
+        &mut self,
+        name: &str,
+        retry: &mut Retry,
+        mut deliver: impl FnMut(Vec<u8>) -> F,
+    ) where
+        F: Future<Output = Result<(), String>>,
+    {
+        loop {
+            let (sequence, payload) = match self.peek() {
+                Ok(Some(segment)) => segment,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read spooled {name} batch, dropping it: {e}");
+                    if let Err(e) = self.discard_oldest() {
+                        error!("Failed to remove spooled {name} batch: {e}");
+                        return;
+                    }
+                    continue;
+                }
+            };
+            if let Err(e) = deliver(payload).await {
+                error!(
+                    "Delivering spooled {name} batch failed, retrying in {}s: {e}",
+                    retry.backoff.as_secs()
+                );
+                retry.back_off();
+                return;
+            }
+            retry.backoff = INITIAL_BACKOFF;
+            if let Err(e) = self.remove(sequence) {
+                error!("Failed to remove delivered {name} batch from spool: {e}");
+                retry.schedule();
+                return;
+            }
+        }
+        info!("Delivered all spooled {name} batches.");
+    }
+
+    /// Stores a payload, dropping the oldest payloads if the size cap is exceeded.
+    pub fn push(&mut self, payload: &[u8]) -> io::Result<()> {
+        let size = payload.len() as u64;
+        if size > self.max_bytes {
+            return Err(io::Error::other(format!(
+                "payload of {size} bytes exceeds the spool size cap"
+            )));
+        }
+        while self.total_bytes + size > self.max_bytes {
+            if self.segments.is_empty() {
+                break;
+            }
+            warn!(
+                "Spool in {} is full, dropping its oldest payload.",
+                self.dir.display()
+            );
+            self.discard_oldest()?;
+        }
+
+        let sequence = self.next_sequence;
+        let partial = self.path(sequence, PARTIAL_EXTENSION);
+        fs::write(&partial, payload)?;
+        fs::rename(&partial, self.path(sequence, SEGMENT_EXTENSION))?;
+        self.next_sequence += 1;
+        self.segments.push_back((sequence, size));
+        self.total_bytes += size;
+        Ok(())
+    }
+
+    /// Returns the oldest payload and its sequence number, without removing it.
+    pub fn peek(&self) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let Some((sequence, _)) = self.segments.front() else {
+            return Ok(None);
+        };
+        let payload = fs::read(self.path(*sequence, SEGMENT_EXTENSION))?;
+        Ok(Some((*sequence, payload)))
+    }
+
+    /// Removes the oldest payload, e.g. because it cannot be read.
+    pub fn discard_oldest(&mut self) -> io::Result<()> {
+        match self.segments.front() {
+            Some((sequence, _)) => self.remove(*sequence),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes a delivered payload.
+    pub fn remove(&mut self, sequence: u64) -> io::Result<()> {
+        if let Some(position) = self.segments.iter().position(|(s, _)| *s == sequence) {
+            let (_, size) = self.segments.remove(position).unwrap();
+            self.total_bytes -= size;
+            match fs::remove_file(self.path(sequence, SEGMENT_EXTENSION)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, sequence: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{sequence:020}.{extension}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for the test of the name.
+    fn empty_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ic-bn-logs-client-test-{}-spool-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn payloads(spool: &mut Spool) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        while let Some((sequence, payload)) = spool.peek().unwrap() {
+            spool.remove(sequence).unwrap();
+            payloads.push(payload);
+        }
+        payloads
+    }
+
+    #[test]
+    fn payloads_keep_their_order_across_restarts() {
+        let dir = empty_dir("restarts");
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.len(), 2);
+        spool.push(b"third").unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        let payloads = payloads(&mut spool);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(payloads, [&b"first"[..], b"second", b"third"]);
+    }
+
+    #[test]
+    fn partial_segments_are_removed_on_open() {
+        let dir = empty_dir("partial");
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        spool.push(b"complete").unwrap();
+        let partial = spool.path(1, PARTIAL_EXTENSION);
+        fs::write(&partial, b"incompl").unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        let partial_left = partial.exists();
+        let payloads = payloads(&mut spool);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!partial_left);
+        assert_eq!(payloads, [b"complete"]);
+    }
+
+    #[test]
+    fn oldest_payloads_are_dropped_at_the_size_cap() {
+        let dir = empty_dir("cap");
+        let mut spool = Spool::open(&dir, 10).unwrap();
+        spool.push(b"aaaa").unwrap();
+        spool.push(b"bbbb").unwrap();
+        spool.push(b"cccc").unwrap();
+        assert_eq!(spool.len(), 2);
+        let oversized = spool.push(b"ddddddddddd");
+        let payloads = payloads(&mut spool);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(oversized.is_err());
+        assert_eq!(payloads, [b"bbbb", b"cccc"]);
+    }
+
+    #[tokio::test]
+    async fn drain_backs_off_on_failure_and_resets_after_delivery() {
+        let dir = empty_dir("drain");
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        let mut retry = Retry::new();
+
+        spool
+            .drain("test", &mut retry, |_| async {
+                Err("unreachable".to_string())
+            })
+            .await;
+        spool
+            .drain("test", &mut retry, |_| async {
+                Err("unreachable".to_string())
+            })
+            .await;
+        let backoff_after_failures = retry.backoff;
+        let len_after_failures = spool.len();
+
+        let mut delivered = Vec::new();
+        spool
+            .drain("test", &mut retry, |payload| {
+                delivered.push(payload);
+                async { Ok(()) }
+            })
+            .await;
+        let empty = spool.is_empty();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(backoff_after_failures, INITIAL_BACKOFF * 4);
+        assert_eq!(len_after_failures, 2);
+        assert_eq!(delivered, [&b"first"[..], b"second"]);
+        assert!(empty);
+        assert_eq!(retry.backoff, INITIAL_BACKOFF);
+    }
+}
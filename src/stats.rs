@@ -0,0 +1,542 @@
+//! Per-node counters, summarized when the client shuts down.
+
+use crate::clock;
+use crate::memory;
+use chrono::SecondsFormat;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+/// Upper bounds in milliseconds of the buckets of the propagation delay histogram; a last
+/// bucket holds longer delays.
+const DELAY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000];
+
+/// How long after the first node a node delivers the lines that several nodes deliver.
+#[derive(Clone, Debug, Default)]
+struct DelayHistogram {
+    /// Lines the node delivered first.
+    first: u64,
+    /// Lines delivered later, by bucket of their delay.
+    buckets: [u64; DELAY_BUCKETS_MS.len() + 1],
+    max: Duration,
+}
+
+impl DelayHistogram {
+    fn record(&mut self, delay: Option<Duration>) {
+        let Some(delay) = delay else {
+            self.first += 1;
+            return;
+        };
+        let millis = delay.as_millis() as u64;
+        let bucket = DELAY_BUCKETS_MS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(DELAY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.max = self.max.max(delay);
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.first += other.first;
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    fn samples(&self) -> u64 {
+        self.first + self.buckets.iter().sum::<u64>()
+    }
+
+    /// Returns an upper bound in milliseconds of the quantile of the delays, counting lines
+    /// delivered first as no delay.
+    fn quantile_ms(&self, quantile: f64) -> f64 {
+        let rank = (quantile * self.samples() as f64).ceil().max(1.0) as u64;
+        if rank <= self.first {
+            return 0.0;
+        }
+        let mut seen = self.first;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = DELAY_BUCKETS_MS
+                    .get(index)
+                    .map_or(f64::INFINITY, |ms| *ms as f64);
+                return bound.min(millis(self.max));
+            }
+        }
+        millis(self.max)
+    }
+
+    fn to_json(&self) -> Value {
+        let mut buckets: serde_json::Map<String, Value> = DELAY_BUCKETS_MS
+            .iter()
+            .zip(self.buckets)
+            .map(|(bound, count)| (format!("le_{bound}ms"), count.into()))
+            .collect();
+        buckets.insert(
+            "gt_10000ms".to_string(),
+            self.buckets[DELAY_BUCKETS_MS.len()].into(),
+        );
+        json!({
+            "samples": self.samples(),
+            "first": self.first,
+            "p50_ms": self.quantile_ms(0.5),
+            "p90_ms": self.quantile_ms(0.9),
+            "p99_ms": self.quantile_ms(0.99),
+            "max_ms": millis(self.max),
+            "buckets": buckets,
+        })
+    }
+}
+
+/// Counters of a single node.
+#[derive(Clone, Debug, Default)]
+struct NodeStats {
+    /// Log lines received.
+    messages: u64,
+    /// Bytes of all received frames.
+    bytes: u64,
+    /// Successful connections.
+    connections: u64,
+    /// Start of the current connection, if connected.
+    connected_since: Option<Instant>,
+    /// Total duration of the previous connections.
+    connected_for: Duration,
+    first_message: Option<SystemTime>,
+    last_message: Option<SystemTime>,
+    /// Frames that could not be reassembled or decoded.
+    dropped: u64,
+    /// Lines suppressed as duplicates of lines from other nodes.
+    duplicates: u64,
+    /// Lines rejected by the filters.
+    filtered: u64,
+    /// Gaps in the sequence numbers of the records.
+    gaps: u64,
+    /// Records missing in the gaps.
+    missed: u64,
+    /// Lines held back while the node was quarantined.
+    quarantined: u64,
+    /// Delays behind the first node delivering the same lines.
+    delays: DelayHistogram,
+}
+
+impl NodeStats {
+    fn connected_duration(&self) -> Duration {
+        self.connected_for
+            + self
+                .connected_since
+                .map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "messages": self.messages,
+            "bytes": self.bytes,
+            "connections": self.connections,
+            "reconnects": self.connections.saturating_sub(1),
+            "connected_secs": self.connected_duration().as_secs_f64(),
+            "first_message": self.first_message.map(format_time),
+            "last_message": self.last_message.map(format_time),
+            "dropped": self.dropped,
+            "duplicates": self.duplicates,
+            "filtered": self.filtered,
+            "gaps": self.gaps,
+            "missed": self.missed,
+            "quarantined": self.quarantined,
+            "propagation_delay": self.delays.to_json(),
+        })
+    }
+}
+
+/// Current counters of a node, as shown by the live statistics view.
+pub struct NodeCounters {
+    pub domain: String,
+    pub connected: bool,
+    pub messages: u64,
+    pub bytes: u64,
+    pub dropped: u64,
+}
+
+/// Current counters of all nodes and canisters, sorted by name.
+pub struct LiveCounters {
+    pub nodes: Vec<NodeCounters>,
+    /// Log lines received per canister.
+    pub canisters: Vec<(String, u64)>,
+}
+
+/// Reconnects deferred by the restart budget.
+#[derive(Clone, Debug, Default)]
+struct ReconnectQueue {
+    /// Reconnects deferred so far.
+    deferred: u64,
+    /// Deferred reconnects still waiting for their turn.
+    queued: u64,
+    /// Most deferred reconnects waiting at once.
+    peak_queued: u64,
+    /// Total time by which the reconnects were deferred.
+    waited: Duration,
+}
+
+impl ReconnectQueue {
+    fn to_json(&self) -> Value {
+        json!({
+            "deferred": self.deferred,
+            "queued": self.queued,
+            "peak_queued": self.peak_queued,
+            "wait_secs": self.waited.as_secs_f64(),
+        })
+    }
+}
+
+/// Counters of all nodes, shared by the connection tasks.
+#[derive(Default)]
+pub struct StatsRegistry {
+    nodes: Mutex<HashMap<String, NodeStats>>,
+    /// Log lines received per canister, from all nodes.
+    canisters: Mutex<HashMap<String, u64>>,
+    /// Spikes and drops of the line rates per canister.
+    anomalies: Mutex<HashMap<String, u64>>,
+    reconnects: Mutex<ReconnectQueue>,
+}
+
+impl StatsRegistry {
+    fn update(&self, domain: &str, f: impl FnOnce(&mut NodeStats)) {
+        f(self
+            .nodes
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_default());
+    }
+
+    /// Records a successful connection to the node.
+    pub fn record_connected(&self, domain: &str) {
+        self.update(domain, |node| {
+            node.connections += 1;
+            node.connected_since = Some(Instant::now());
+        });
+    }
+
+    /// Records the end of the connection to the node.
+    pub fn record_disconnected(&self, domain: &str) {
+        self.update(domain, |node| {
+            if let Some(since) = node.connected_since.take() {
+                node.connected_for += since.elapsed();
+            }
+        });
+    }
+
+    /// Records a received frame.
+    pub fn record_frame(&self, domain: &str, bytes: usize) {
+        self.update(domain, |node| node.bytes += bytes as u64);
+    }
+
+    /// Records a received log line.
+    pub fn record_message(&self, domain: &str, canister_id: &str, time: SystemTime) {
+        self.update(domain, |node| {
+            node.messages += 1;
+            node.first_message.get_or_insert(time);
+            node.last_message = Some(time);
+        });
+        *self
+            .canisters
+            .lock()
+            .unwrap()
+            .entry(canister_id.to_string())
+            .or_default() += 1;
+    }
+
+    /// Records a frame that could not be reassembled or decoded.
+    pub fn record_dropped(&self, domain: &str) {
+        self.update(domain, |node| node.dropped += 1);
+    }
+
+    /// Records a line suppressed as a duplicate.
+    pub fn record_duplicate(&self, domain: &str) {
+        self.update(domain, |node| node.duplicates += 1);
+    }
+
+    /// Records a line rejected by the filters.
+    pub fn record_filtered(&self, domain: &str) {
+        self.update(domain, |node| node.filtered += 1);
+    }
+
+    /// Records a line held back from a quarantined node.
+    pub fn record_quarantined(&self, domain: &str) {
+        self.update(domain, |node| node.quarantined += 1);
+    }
+
+    /// Records a gap of missed records in the sequence numbers.
+    pub fn record_gap(&self, domain: &str, missed: u64) {
+        self.update(domain, |node| {
+            node.gaps += 1;
+            node.missed += missed;
+        });
+    }
+
+    /// Records how long after the first node the node delivered a line, or None if it was
+    /// the first.
+    pub fn record_delay(&self, domain: &str, delay: Option<Duration>) {
+        self.update(domain, |node| node.delays.record(delay));
+    }
+
+    /// Records a spike or drop of a line rate of the canister.
+    pub fn record_anomaly(&self, canister_id: &str) {
+        *self
+            .anomalies
+            .lock()
+            .unwrap()
+            .entry(canister_id.to_string())
+            .or_default() += 1;
+    }
+
+    /// Records a reconnect deferred by the restart budget for the given time, which waits in
+    /// the queue until [`record_reconnect_dequeued`](Self::record_reconnect_dequeued).
+    pub fn record_reconnect_deferred(&self, wait: Duration) {
+        let mut reconnects = self.reconnects.lock().unwrap();
+        reconnects.deferred += 1;
+        reconnects.queued += 1;
+        reconnects.peak_queued = reconnects.peak_queued.max(reconnects.queued);
+        reconnects.waited += wait;
+    }
+
+    /// Records that a deferred reconnect left the queue, because its turn came or it was
+    /// cancelled.
+    pub fn record_reconnect_dequeued(&self) {
+        let mut reconnects = self.reconnects.lock().unwrap();
+        reconnects.queued = reconnects.queued.saturating_sub(1);
+    }
+
+    /// Returns the counters of the reconnects deferred by the restart budget.
+    pub fn reconnects_json(&self) -> Value {
+        self.reconnects.lock().unwrap().to_json()
+    }
+
+    /// Returns the current counters of all nodes and canisters.
+    pub fn live(&self) -> LiveCounters {
+        let mut nodes: Vec<NodeCounters> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, node)| NodeCounters {
+                domain: domain.clone(),
+                connected: node.connected_since.is_some(),
+                messages: node.messages,
+                bytes: node.bytes,
+                dropped: node.dropped,
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.domain.cmp(&b.domain));
+        let mut canisters: Vec<(String, u64)> = self
+            .canisters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(canister_id, messages)| (canister_id.clone(), *messages))
+            .collect();
+        canisters.sort();
+        LiveCounters { nodes, canisters }
+    }
+
+    /// Returns the summary of all nodes and their totals as JSON.
+    pub fn summary_json(&self) -> Value {
+        let nodes = self.nodes.lock().unwrap();
+        let mut total = NodeStats::default();
+        for node in nodes.values() {
+            total.messages += node.messages;
+            total.bytes += node.bytes;
+            total.connections += node.connections;
+            total.dropped += node.dropped;
+            total.duplicates += node.duplicates;
+            total.filtered += node.filtered;
+            total.gaps += node.gaps;
+            total.missed += node.missed;
+            total.quarantined += node.quarantined;
+            total.delays.add(&node.delays);
+            total.first_message = match (total.first_message, node.first_message) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            total.last_message = total.last_message.max(node.last_message);
+        }
+        let per_node: serde_json::Map<String, Value> = nodes
+            .iter()
+            .map(|(domain, node)| (domain.clone(), node.to_json()))
+            .collect();
+        let mut total = total.to_json();
+        // Connection times of different nodes overlap, so their sum is meaningless.
+        total.as_object_mut().unwrap().remove("connected_secs");
+        total.as_object_mut().unwrap().remove("reconnects");
+        let sinks: serde_json::Map<String, Value> = memory::sink_drops()
+            .into_iter()
+            .map(|(sink, dropped)| (sink.to_string(), json!({ "dropped": dropped })))
+            .collect();
+        let anomalies: serde_json::Map<String, Value> = self
+            .anomalies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(canister_id, anomalies)| (canister_id.clone(), json!(anomalies)))
+            .collect();
+        json!({
+            "nodes": per_node,
+            "total": total,
+            "sinks": sinks,
+            "buffer": { "peak_bytes": memory::peak() },
+            "anomalies": anomalies,
+            "reconnects": self.reconnects_json(),
+        })
+    }
+
+    /// Prints a human-readable summary to stderr.
+    pub fn print_summary(&self) {
+        let mut nodes: Vec<(String, NodeStats)> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, node)| (domain.clone(), node.clone()))
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let width = nodes
+            .iter()
+            .map(|(domain, _)| domain.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        eprintln!(
+            "{:<width$}  {:>9}  {:>11}  {:>10}  {:>10}  {:>7}  {:>10}  {:>8}  {:>6}  {:<24}  LAST MESSAGE",
+            "NODE",
+            "MESSAGES",
+            "BYTES",
+            "RECONNECTS",
+            "CONNECTED",
+            "DROPPED",
+            "DUPLICATES",
+            "FILTERED",
+            "MISSED",
+            "FIRST MESSAGE",
+        );
+        for (domain, node) in &nodes {
+            eprintln!(
+                "{domain:<width$}  {:>9}  {:>11}  {:>10}  {:>9}s  {:>7}  {:>10}  {:>8}  {:>6}  {:<24}  {}",
+                node.messages,
+                node.bytes,
+                node.connections.saturating_sub(1),
+                node.connected_duration().as_secs(),
+                node.dropped,
+                node.duplicates,
+                node.filtered,
+                node.missed,
+                node.first_message.map_or("-".to_string(), format_time),
+                node.last_message.map_or("-".to_string(), format_time),
+            );
+        }
+        let total = self.summary_json()["total"].clone();
+        eprintln!(
+            "Total: {} messages, {} bytes, {} dropped, {} duplicates, {} filtered, {} missed in \
+             {} gaps.",
+            total["messages"],
+            total["bytes"],
+            total["dropped"],
+            total["duplicates"],
+            total["filtered"],
+            total["missed"],
+            total["gaps"]
+        );
+        let quarantined: Vec<String> = nodes
+            .iter()
+            .filter(|(_, node)| node.quarantined > 0)
+            .map(|(domain, node)| format!("{domain} {}", node.quarantined))
+            .collect();
+        if !quarantined.is_empty() {
+            eprintln!(
+                "Held back from quarantined nodes: {}.",
+                quarantined.join(", ")
+            );
+        }
+        let sink_drops = memory::sink_drops();
+        if !sink_drops.is_empty() {
+            let drops: Vec<String> = sink_drops
+                .iter()
+                .map(|(sink, dropped)| format!("{sink} {dropped}"))
+                .collect();
+            eprintln!("Dropped by the sink queues: {}.", drops.join(", "));
+        }
+        let reconnects = self.reconnects.lock().unwrap().clone();
+        if reconnects.deferred > 0 {
+            eprintln!(
+                "Reconnects deferred by the restart budget: {}, up to {} waiting at once, {:.1}s \
+                 of waiting in total.",
+                reconnects.deferred,
+                reconnects.peak_queued,
+                reconnects.waited.as_secs_f64()
+            );
+        }
+        let mut anomalies: Vec<(String, u64)> = self
+            .anomalies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(canister_id, anomalies)| (canister_id.clone(), *anomalies))
+            .collect();
+        if !anomalies.is_empty() {
+            anomalies.sort();
+            let anomalies: Vec<String> = anomalies
+                .iter()
+                .map(|(canister_id, anomalies)| format!("{canister_id} {anomalies}"))
+                .collect();
+            eprintln!("Anomalies: {}.", anomalies.join(", "));
+        }
+        if nodes.iter().any(|(_, node)| node.delays.samples() > 0) {
+            eprintln!();
+            eprintln!(
+                "{:<width$}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}",
+                "PROPAGATION DELAY",
+                "LINES",
+                "FIRST",
+                "P50",
+                "P90",
+                "P99",
+                "MAX",
+                width = width.max(17)
+            );
+            for (domain, node) in &nodes {
+                let delays = &node.delays;
+                let ms = |ms: f64| format!("{ms:.0}ms");
+                eprintln!(
+                    "{domain:<width$}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}",
+                    delays.samples(),
+                    delays.first,
+                    ms(delays.quantile_ms(0.5)),
+                    ms(delays.quantile_ms(0.9)),
+                    ms(delays.quantile_ms(0.99)),
+                    ms(millis(delays.max)),
+                    width = width.max(17)
+                );
+            }
+        }
+    }
+
+    /// Writes the summary as JSON to a file.
+    pub fn write_summary(&self, path: &Path) -> io::Result<()> {
+        let summary = serde_json::to_string_pretty(&self.summary_json())?;
+        fs::write(path, summary + "\n")
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+fn format_time(time: SystemTime) -> String {
+    clock::local_time(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
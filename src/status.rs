@@ -0,0 +1,70 @@
+//! A concise status line on stderr with the number of connected nodes.
+//!
+//! Tailing in a terminal prints no diagnostics below the error level unless `-v` is given, so
+//! that the log lines are not buried in connection chatter. Instead, a single line such as
+//! `Connected to 12/13 boundary nodes.` tells that the stream is live, and is printed again
+//! only when the number of connected nodes changes and stays changed for a moment.
+
+use crate::connection::ConnectionConfig;
+use crate::selflog;
+use log::Level;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant};
+
+/// Interval at which the connections are counted.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// Time a new count must hold before it is printed, unless all nodes are connected, so that
+/// connections made one after the other at startup or a quick reconnect print one line.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Starts a task that prints the status line whenever the number of connected nodes settles
+/// on a new value, if stderr is a terminal.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    if !io::stderr().is_terminal() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = interval(CHECK_INTERVAL);
+        let mut printed = None;
+        let mut changed: Option<((usize, usize), Instant)> = None;
+        loop {
+            ticker.tick().await;
+            let total = config.pool_size.load(Ordering::Relaxed);
+            // Before the pool starts and while it is parked, there is nothing to report.
+            if total == 0 || config.parked.load(Ordering::Relaxed) {
+                changed = None;
+                continue;
+            }
+            let connected = config
+                .health
+                .snapshot()
+                .iter()
+                .filter(|(_, health)| health.connected)
+                .count()
+                .min(total);
+            let count = (connected, total);
+            if printed == Some(count) {
+                changed = None;
+                continue;
+            }
+            let since = match changed {
+                Some((pending, since)) if pending == count => since,
+                _ => {
+                    changed = Some((count, Instant::now()));
+                    continue;
+                }
+            };
+            if connected == total || since.elapsed() >= SETTLE_TIME {
+                selflog::report(
+                    Level::Info,
+                    module_path!(),
+                    &format!("Connected to {connected}/{total} boundary nodes."),
+                );
+                printed = Some(count);
+                changed = None;
+            }
+        }
+    });
+}
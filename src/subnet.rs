@@ -0,0 +1,190 @@
+//! Enumeration of the canisters on a subnet, to tail those selected with `--canister-id all`
+//! or a prefix pattern and `--subnet`.
+//!
+//! The certified state of a subnet lists the ranges of canister IDs assigned to it, but not the
+//! canisters created in them. Canister IDs are allocated in order from the start of every
+//! range, so the IDs of each range are probed in order, a batch at a time, by fetching their
+//! logs as an anonymous caller, until `--subnet-max-empty-batches` batches in a row hold no
+//! canister; canisters allocated after such a gap are missed. A probe succeeds only for
+//! canisters whose logs are public, so those are tailed; canisters whose logs are private are
+//! counted and left out, as the boundary nodes would reject their streams. Probes that fail,
+//! e.g. because of a timeout, are retried, and then skipped with a warning.
+
+use crate::canister;
+use crate::canister_log;
+use crate::error::Error;
+use crate::nodes::Endpoints;
+use crate::preflight;
+use candid::Principal;
+use futures_util::future::join_all;
+use ic_agent::Agent;
+use log::{info, warn};
+use tokio::time::{sleep, timeout, Duration};
+
+/// Number of canister IDs probed at once.
+const PROBE_BATCH: u64 = 64;
+/// Longest time probing a canister ID may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of times a canister ID is probed before it is skipped.
+const PROBE_ATTEMPTS: u32 = 3;
+/// Delay before probing a canister ID again after a failed probe.
+const PROBE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What probing a canister ID found.
+enum Probe {
+    /// A canister whose logs are public.
+    Public,
+    /// A canister whose logs only its controllers and allowed viewers may read.
+    Private,
+    /// No canister, or one that was deleted.
+    Absent,
+    /// The probe failed, e.g. because the endpoint is unreachable.
+    Failed(String),
+}
+
+/// How the canisters of a subnet are enumerated.
+pub struct Enumeration<'a> {
+    /// The `--canister-id` values selecting the canisters, `all` or prefix patterns.
+    pub selectors: &'a [String],
+    /// Most canisters to return.
+    pub limit: usize,
+    /// Number of batches in a row without a canister that end a canister ID range.
+    pub max_empty_batches: usize,
+}
+
+/// Returns the selected canisters on a subnet whose logs are public, in the order of their
+/// IDs.
+pub async fn canisters(
+    http_client: reqwest::Client,
+    endpoints: &Endpoints,
+    subnet: &str,
+    enumeration: Enumeration<'_>,
+) -> Result<Vec<String>, Error> {
+    let subnet_id =
+        Principal::from_text(subnet).map_err(|e| format!("invalid subnet ID {subnet}: {e}"))?;
+    let agent = endpoints
+        .agent(http_client)
+        .await
+        .map_err(|e| Error::Subnet(e.to_string()))?;
+    let ranges = agent
+        .read_state_subnet_canister_ranges(subnet_id)
+        .await
+        .map_err(|e| Error::Subnet(format!("subnet {subnet}: {e}")))?;
+    info!(
+        "Enumerating the canisters in the {} canister ID ranges of subnet {subnet}.",
+        ranges.len()
+    );
+    let limit = enumeration.limit;
+    let selected = |id: &str| {
+        enumeration
+            .selectors
+            .iter()
+            .any(|selector| canister::selects(selector, id))
+    };
+    let mut public = Vec::new();
+    let mut private = 0;
+    let mut failed = 0;
+    'ranges: for (start, end) in ranges {
+        let (Some(start), Some(end)) = (index(&start), index(&end)) else {
+            warn!("Skipped the canister ID range {start} to {end} of subnet {subnet}.");
+            continue;
+        };
+        let mut next = start;
+        let mut empty_batches = 0;
+        while next <= end {
+            let batch: Vec<Principal> = (next..=end.min(next + PROBE_BATCH - 1))
+                .map(canister_id)
+                .collect();
+            next += batch.len() as u64;
+            let probes = join_all(batch.iter().map(|id| probe(&agent, *id))).await;
+            let mut found = false;
+            for (id, probe) in batch.iter().zip(probes) {
+                let id = id.to_text();
+                match probe {
+                    Probe::Public => {
+                        found = true;
+                        if !selected(&id) {
+                            continue;
+                        }
+                        public.push(id);
+                        if public.len() == limit {
+                            warn!(
+                                "Tailing only the first {limit} canisters of subnet {subnet}; \
+                                 raise --subnet-max-canisters to tail more."
+                            );
+                            break 'ranges;
+                        }
+                    }
+                    Probe::Private => {
+                        found = true;
+                        if selected(&id) {
+                            private += 1;
+                        }
+                    }
+                    Probe::Absent => {}
+                    Probe::Failed(e) => {
+                        warn!(
+                            "Skipped canister {id} of subnet {subnet}, as probing it failed: {e}"
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+            empty_batches = if found { 0 } else { empty_batches + 1 };
+            if empty_batches >= enumeration.max_empty_batches {
+                break;
+            }
+        }
+    }
+    info!(
+        "Found {} canisters with public logs on subnet {subnet}, and {private} whose logs are \
+         private.",
+        public.len()
+    );
+    if failed > 0 {
+        warn!("Skipped {failed} canister IDs of subnet {subnet} that could not be probed.");
+    }
+    if public.is_empty() {
+        return Err(Error::Subnet(format!(
+            "subnet {subnet} has no selected canisters whose logs are public"
+        )));
+    }
+    Ok(public)
+}
+
+/// Fetches the logs of a canister ID to find out whether a canister has it, retrying failed
+/// probes.
+async fn probe(agent: &Agent, canister_id: Principal) -> Probe {
+    let mut attempt = 1;
+    loop {
+        let probe = match timeout(PROBE_TIMEOUT, canister_log::fetch(agent, canister_id)).await {
+            Ok(Ok(_)) => Probe::Public,
+            Ok(Err(e)) if preflight::is_not_allowed(&e) => Probe::Private,
+            Ok(Err(e)) if preflight::is_not_found(&e) => Probe::Absent,
+            Ok(Err(e)) => Probe::Failed(e.to_string()),
+            Err(_) => Probe::Failed("timed out".to_string()),
+        };
+        if !matches!(probe, Probe::Failed(_)) || attempt == PROBE_ATTEMPTS {
+            return probe;
+        }
+        attempt += 1;
+        sleep(PROBE_RETRY_DELAY).await;
+    }
+}
+
+/// Returns the index of a canister ID, which makes up its first 8 bytes.
+fn index(canister_id: &Principal) -> Option<u64> {
+    match canister_id.as_slice() {
+        [index @ .., 0x01, 0x01] if index.len() == 8 => {
+            Some(u64::from_be_bytes(index.try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the canister ID with the given index.
+fn canister_id(index: u64) -> Principal {
+    let mut bytes = index.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&[0x01, 0x01]);
+    Principal::from_slice(&bytes)
+}
@@ -0,0 +1,229 @@
+//! Periodic summaries of the log lines, for capacity reviews where the raw lines are too many
+//! to read.
+//!
+//! Every interval, the line rate per node, the line counts by severity, and the most frequent
+//! patterns are printed among the lines on stdout. The patterns are the `--summary-pattern`
+//! regular expressions if given, and otherwise the lines themselves with their numbers masked,
+//! so that lines differing only in IDs or amounts are counted together.
+
+use crate::connection::ConnectionConfig;
+use crate::event::LogEvent;
+use crate::severity::Severity;
+use regex::Regex;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{interval_at, Duration, Instant};
+
+/// Most distinct lines counted per interval; further lines only count in the totals.
+const MAX_SHAPES: usize = 10_000;
+
+/// Longest line shape, in characters, counted and printed.
+const MAX_SHAPE_CHARS: usize = 100;
+
+/// The counts of one interval.
+struct Window {
+    started: SystemTime,
+    lines: u64,
+    nodes: BTreeMap<String, u64>,
+    errors: u64,
+    warnings: u64,
+    infos: u64,
+    /// Matches per `--summary-pattern`, in the order given.
+    matches: Vec<u64>,
+    /// Lines per shape when no patterns are given.
+    shapes: HashMap<String, u64>,
+}
+
+impl Window {
+    fn new(patterns: usize) -> Self {
+        Self {
+            started: SystemTime::now(),
+            lines: 0,
+            nodes: BTreeMap::new(),
+            errors: 0,
+            warnings: 0,
+            infos: 0,
+            matches: vec![0; patterns],
+            shapes: HashMap::new(),
+        }
+    }
+}
+
+/// Counts the delivered lines for the periodic summaries.
+pub struct Summarizer {
+    patterns: Vec<Regex>,
+    top: usize,
+    json: bool,
+    window: Mutex<Window>,
+}
+
+impl Summarizer {
+    /// Creates a summarizer listing the `top` most frequent patterns, as JSON objects or text.
+    pub fn new(patterns: Vec<Regex>, top: usize, json: bool) -> Self {
+        let window = Mutex::new(Window::new(patterns.len()));
+        Self {
+            patterns,
+            top,
+            json,
+            window,
+        }
+    }
+
+    /// Counts a line delivered to the outputs.
+    pub fn record(&self, event: &LogEvent) {
+        let mut window = self.window.lock().unwrap();
+        window.lines += 1;
+        *window.nodes.entry(event.node.clone()).or_default() += 1;
+        match Severity::of(event) {
+            Severity::Error => window.errors += 1,
+            Severity::Warning => window.warnings += 1,
+            Severity::Info => window.infos += 1,
+        }
+        if self.patterns.is_empty() {
+            let shape = shape(&event.message);
+            if let Some(count) = window.shapes.get_mut(&shape) {
+                *count += 1;
+            } else if window.shapes.len() < MAX_SHAPES {
+                window.shapes.insert(shape, 1);
+            }
+        } else {
+            for (pattern, matches) in self.patterns.iter().zip(&mut window.matches) {
+                if pattern.is_match(&event.message) {
+                    *matches += 1;
+                }
+            }
+        }
+    }
+
+    /// Renders the summary of the lines counted since the previous one and starts a new
+    /// interval. Returns None if no lines were counted.
+    pub fn take(&self) -> Option<String> {
+        let window = std::mem::replace(
+            &mut *self.window.lock().unwrap(),
+            Window::new(self.patterns.len()),
+        );
+        if window.lines == 0 {
+            return None;
+        }
+        let ended = SystemTime::now();
+        let seconds = ended
+            .duration_since(window.started)
+            .unwrap_or_default()
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let mut top: Vec<(&str, u64)> = if self.patterns.is_empty() {
+            window
+                .shapes
+                .iter()
+                .map(|(shape, count)| (shape.as_str(), *count))
+                .collect()
+        } else {
+            self.patterns
+                .iter()
+                .zip(&window.matches)
+                .filter(|(_, matches)| **matches > 0)
+                .map(|(pattern, matches)| (pattern.as_str(), *matches))
+                .collect()
+        };
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        top.truncate(self.top);
+
+        if self.json {
+            let nodes: serde_json::Map<String, serde_json::Value> = window
+                .nodes
+                .iter()
+                .map(|(node, lines)| {
+                    let rate = *lines as f64 / seconds;
+                    (node.clone(), json!({"lines": lines, "lines_per_sec": rate}))
+                })
+                .collect();
+            let top: Vec<serde_json::Value> = top
+                .iter()
+                .map(|(pattern, count)| json!({"pattern": pattern, "lines": count}))
+                .collect();
+            let summary = json!({"summary": {
+                "start": humantime::format_rfc3339_seconds(window.started).to_string(),
+                "end": humantime::format_rfc3339_seconds(ended).to_string(),
+                "lines": window.lines,
+                "lines_per_sec": window.lines as f64 / seconds,
+                "severity": {
+                    "error": window.errors,
+                    "warning": window.warnings,
+                    "info": window.infos,
+                },
+                "nodes": nodes,
+                "top": top,
+            }});
+            return Some(format!("{summary}\n"));
+        }
+
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "--- Summary until {}, last {}: {} lines ({:.1}/s), {} errors, {} warnings, {} info",
+            humantime::format_rfc3339_seconds(ended),
+            humantime::format_duration(Duration::from_secs(seconds.round() as u64)),
+            window.lines,
+            window.lines as f64 / seconds,
+            window.errors,
+            window.warnings,
+            window.infos,
+        );
+        let width = window.nodes.keys().map(String::len).max().unwrap_or(0);
+        text.push_str("  Nodes:\n");
+        for (node, lines) in &window.nodes {
+            let _ = writeln!(
+                text,
+                "    {node:<width$}  {lines:>9} lines  {:>9.1}/s",
+                *lines as f64 / seconds
+            );
+        }
+        if !top.is_empty() {
+            text.push_str(if self.patterns.is_empty() {
+                "  Most frequent lines:\n"
+            } else {
+                "  Most matched patterns:\n"
+            });
+        }
+        for (pattern, count) in &top {
+            let _ = writeln!(text, "    {count:>9}  {pattern}");
+        }
+        Some(text)
+    }
+}
+
+/// Masks the numbers of a line and shortens it, so that similar lines are counted together.
+fn shape(message: &str) -> String {
+    let mut shape = String::new();
+    let mut in_number = false;
+    for c in message.chars().take(MAX_SHAPE_CHARS) {
+        if c.is_ascii_digit() {
+            if !in_number {
+                shape.push('#');
+            }
+            in_number = true;
+        } else {
+            shape.push(c);
+            in_number = false;
+        }
+    }
+    shape
+}
+
+/// Starts a task that prints a summary among the lines on stdout at the given interval.
+pub fn spawn(config: Arc<ConnectionConfig>, every: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + every, every);
+        loop {
+            ticker.tick().await;
+            if let Some(summarizer) = &config.summarizer
+                && let Some(summary) = summarizer.take()
+            {
+                config.output.write_text(&summary);
+            }
+        }
+    });
+}
@@ -0,0 +1,212 @@
+//! Ownership of the connection tasks, with a global restart budget and a circuit breaker per
+//! node.
+//!
+//! Every connection runs as a task of the supervisor, which reports how it ended; a task that
+//! panics counts as a failed connection instead of silently disappearing. Reconnecting to nodes
+//! that were connected before draws from a budget of restarts per minute shared by all nodes,
+//! so that a network outage does not turn into a reconnect storm: at most a burst of restarts
+//! happens at once, and restarts beyond it are deferred, with jitter so that they do not fire
+//! together. The deferred restarts are counted in the statistics. A node whose connections fail
+//! several times in a row has its circuit opened and is not connected again until a cooldown
+//! has passed; a single attempt then decides whether the circuit closes or opens again.
+
+use crate::connection::{handle_node, ConnectionConfig, Disconnect};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tokio::task::{AbortHandle, Id, JoinSet};
+use tokio::time::{sleep, Duration, Instant};
+
+/// Failed connections in a row after which the circuit of a node opens.
+const BREAKER_THRESHOLD: u32 = 5;
+
+/// Fraction by which delays are randomly lengthened or shortened.
+const JITTER: f64 = 0.25;
+
+/// Returns the delay lengthened or shortened by up to a quarter, so that connections delayed
+/// together spread out.
+pub fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 + JITTER * (2.0 * fastrand::f64() - 1.0))
+}
+
+/// Token bucket of restarts, refilled continuously up to the burst.
+struct RestartBudget {
+    per_minute: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RestartBudget {
+    fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            per_minute: per_minute as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a restart from the budget, returning how long it has to wait for its turn.
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.per_minute / 60.0;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens * 60.0 / self.per_minute)
+        }
+    }
+}
+
+/// A reconnect waiting for its turn, which leaves the queue when dropped, also when its task
+/// is aborted.
+struct Deferred(Arc<ConnectionConfig>);
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        self.0.stats.record_reconnect_dequeued();
+    }
+}
+
+/// The failures of a node since its last established connection.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Runs the connection tasks and keeps track of how they end.
+pub struct Supervisor {
+    config: Arc<ConnectionConfig>,
+    tasks: JoinSet<Disconnect>,
+    /// The node of every running task.
+    domains: HashMap<Id, String>,
+    budget: RestartBudget,
+    breakers: HashMap<String, Breaker>,
+    cooldown: Duration,
+    /// Nodes connected before, whose next connection counts as a restart.
+    started: HashSet<String>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor allowing `restarts_per_minute` restarts, at most `burst` of them at
+    /// once, and keeping nodes whose circuit opened disconnected for `cooldown`.
+    pub fn new(
+        config: Arc<ConnectionConfig>,
+        restarts_per_minute: u32,
+        burst: u32,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            config,
+            tasks: JoinSet::new(),
+            domains: HashMap::new(),
+            budget: RestartBudget::new(restarts_per_minute.max(1), burst.max(1)),
+            breakers: HashMap::new(),
+            cooldown,
+            started: HashSet::new(),
+        }
+    }
+
+    /// Connects to a node after a delay, which a restart beyond the budget extends.
+    pub fn start(
+        &mut self,
+        domain: String,
+        delay: Duration,
+        muted: Arc<AtomicBool>,
+    ) -> AbortHandle {
+        let mut delay = delay;
+        let mut deferred = None;
+        if !self.started.insert(domain.clone()) {
+            let wait = self.budget.reserve();
+            if wait > delay {
+                delay = jitter(wait).max(delay);
+                info!(
+                    "[{domain}] Restart budget exhausted, connecting in {:.1}s instead.",
+                    delay.as_secs_f64()
+                );
+                self.config.stats.record_reconnect_deferred(delay);
+                deferred = Some(Deferred(self.config.clone()));
+            }
+        }
+        let config = self.config.clone();
+        let handle = self.tasks.spawn({
+            let domain = domain.clone();
+            async move {
+                sleep(delay).await;
+                drop(deferred);
+                handle_node(domain, config, muted).await
+            }
+        });
+        self.domains.insert(handle.id(), domain);
+        handle
+    }
+
+    /// Waits for the next connection task to end and returns its node and how it ended.
+    /// Aborted tasks are skipped, and a task that panicked counts as a failed connection.
+    /// Returns None once no task is running.
+    pub async fn join_next(&mut self) -> Option<(String, Disconnect)> {
+        loop {
+            let (id, disconnect) = match self.tasks.join_next_with_id().await? {
+                Ok(joined) => joined,
+                Err(e) if e.is_cancelled() => {
+                    self.domains.remove(&e.id());
+                    continue;
+                }
+                Err(e) => {
+                    let domain = self
+                        .domains
+                        .get(&e.id())
+                        .map_or("unknown node", String::as_str);
+                    error!("[{domain}] Connection task failed: {e}");
+                    (e.id(), Disconnect::Failed)
+                }
+            };
+            let Some(domain) = self.domains.remove(&id) else {
+                continue;
+            };
+            self.record(&domain, &disconnect);
+            return Some((domain, disconnect));
+        }
+    }
+
+    /// Returns how long the circuit of a node stays open, or None if it is closed.
+    pub fn cooldown(&self, domain: &str) -> Option<Duration> {
+        let open_until = self.breakers.get(domain)?.open_until?;
+        let remaining = open_until.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Forgets which nodes were connected, so that connecting them again, e.g. when the
+    /// active hours begin, does not count as restarts.
+    pub fn reset(&mut self) {
+        self.started.clear();
+    }
+
+    fn record(&mut self, domain: &str, disconnect: &Disconnect) {
+        match disconnect {
+            Disconnect::Closed => {
+                self.breakers.remove(domain);
+            }
+            Disconnect::Failed => {
+                let breaker = self.breakers.entry(domain.to_string()).or_default();
+                breaker.failures += 1;
+                if breaker.failures >= BREAKER_THRESHOLD {
+                    breaker.open_until = Some(Instant::now() + self.cooldown);
+                    warn!(
+                        "[{domain}] Opening the circuit after {} failed connections, not \
+                         connecting again for {}.",
+                        breaker.failures,
+                        humantime::format_duration(self.cooldown)
+                    );
+                }
+            }
+            Disconnect::Rejected(_) => {}
+        }
+    }
+}
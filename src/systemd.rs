@@ -0,0 +1,85 @@
+//! Integration with systemd, so that the client can run as a `Type=notify` service.
+//!
+//! When started by systemd with a notification socket, the client reports `READY=1` once the
+//! first connection to a node is established, and keeps the status line of `systemctl status`
+//! up to date with the number of connected nodes. If the unit sets `WatchdogSec=`, the watchdog
+//! is only fed while at least one connection is healthy, i.e. connected and answering pings, so
+//! that systemd restarts a client that lost all its nodes. While the connections are parked
+//! outside the active hours, the client counts as ready and the watchdog is fed. Without a
+//! notification socket, nothing is sent.
+
+use crate::connection::ConnectionConfig;
+use log::warn;
+use sd_notify::NotifyState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+
+/// Interval at which the status is updated when the watchdog is disabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts a task that reports readiness, status, and watchdog keep-alives to systemd.
+pub fn spawn(config: Arc<ConnectionConfig>) {
+    // The watchdog must be fed at least once per interval; feed it twice as often, as
+    // recommended by systemd.
+    let watchdog = sd_notify::watchdog_enabled();
+    let period = watchdog.map_or(STATUS_INTERVAL, |timeout| {
+        (timeout / 2).clamp(Duration::from_millis(10), STATUS_INTERVAL)
+    });
+    tokio::spawn(async move {
+        let mut ticker = interval(period);
+        let mut ready = false;
+        let mut last_status = String::new();
+        // Set while notifications fail, to avoid logging every failure.
+        let mut failing = false;
+        loop {
+            ticker.tick().await;
+            let nodes = config.health.snapshot();
+            let connected = nodes.iter().filter(|(_, health)| health.connected).count();
+            // A stall is a ping left unanswered; stall counts decay over time.
+            let healthy = nodes
+                .iter()
+                .filter(|(_, health)| health.connected && health.stalls < 1.0)
+                .count();
+
+            let parked = config.parked.load(Ordering::Relaxed);
+
+            let status = if parked {
+                "Parked outside the active hours".to_string()
+            } else {
+                format!("Connected to {connected} nodes, {healthy} healthy")
+            };
+            let mut states = Vec::new();
+            if (connected > 0 || parked) && !ready {
+                ready = true;
+                states.push(NotifyState::Ready);
+            }
+            if status != last_status {
+                states.push(NotifyState::Status(&status));
+            }
+            if watchdog.is_some() && (healthy > 0 || parked) {
+                states.push(NotifyState::Watchdog);
+            }
+            if states.is_empty() {
+                continue;
+            }
+            match sd_notify::notify(&states) {
+                Ok(()) => failing = false,
+                Err(e) => {
+                    if !failing {
+                        warn!("Failed to notify systemd: {e}");
+                    }
+                    failing = true;
+                }
+            }
+            last_status = status;
+        }
+    });
+}
+
+/// Tells systemd that the client is shutting down.
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(&[NotifyState::Stopping]) {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
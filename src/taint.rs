@@ -0,0 +1,83 @@
+//! Heuristics that flag canister-controlled text that may try to deceive its readers.
+//!
+//! Log lines are written by the canister, so they can contain anything its code chooses to
+//! log, including text crafted to forge log lines, hide content on a terminal, or exploit the
+//! systems that consume the logs. With `--detect-injection`, every line is checked, and the
+//! reasons a line looks suspicious are listed in the `taint` field of structured outputs, so
+//! that downstream systems can treat the text with care. Lines are never changed or dropped.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Length above which a token without whitespace counts as extremely long.
+const MAX_TOKEN_LENGTH: usize = 512;
+
+/// Remnants of ANSI escape sequences whose escape character was lost or stripped, e.g. `[31m`.
+static ANSI_REMNANT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[[0-9]{1,3}(;[0-9]{1,3})*[A-HJKSTfmsu]").unwrap());
+/// Prefixes of the lines the client itself prints: the markers of the line templates, the
+/// timestamp and node of the incident preset, and the records of its own log.
+static OWN_PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"^(\[(backfill( #\d+)?|suspect)\] ",
+        r"|\d{4}-\d\d-\d\dT[\d:.]+Z \[",
+        r"|\[\d{4}-\d\d-\d\dT[\d:.]+Z (ERROR|WARN|INFO|DEBUG|TRACE) )",
+    ))
+    .unwrap()
+});
+
+/// A reason for a line to look suspicious.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Taint {
+    /// Escape sequences, or their remnants, survived ANSI stripping.
+    Ansi,
+    /// A token is extremely long, e.g. an encoded payload.
+    LongToken,
+    /// The line contains control characters other than tabs and line breaks, or invisible
+    /// formatting characters such as bidirectional overrides, which make text display
+    /// differently from what it is.
+    ControlCharacters,
+    /// The line, or a line within it, starts like a line printed by the client itself.
+    MimicsClient,
+}
+
+impl Taint {
+    /// Returns the name used in structured outputs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ansi => "ansi",
+            Self::LongToken => "long_token",
+            Self::ControlCharacters => "control_characters",
+            Self::MimicsClient => "mimics_client",
+        }
+    }
+}
+
+/// Returns the reasons the message looks suspicious, or an empty list.
+pub fn detect(message: &str) -> Vec<Taint> {
+    let mut taint = Vec::new();
+    if message.contains(['\x1b', '\u{9b}']) || ANSI_REMNANT.is_match(message) {
+        taint.push(Taint::Ansi);
+    }
+    if message
+        .split_whitespace()
+        .any(|token| token.chars().count() > MAX_TOKEN_LENGTH)
+    {
+        taint.push(Taint::LongToken);
+    }
+    if message.chars().any(|c| {
+        (c.is_control() && !matches!(c, '\t' | '\n' | '\x1b' | '\u{9b}')) || is_invisible_format(c)
+    }) {
+        taint.push(Taint::ControlCharacters);
+    }
+    if message.lines().any(|line| OWN_PREFIX.is_match(line)) {
+        taint.push(Taint::MimicsClient);
+    }
+    taint
+}
+
+/// Returns whether the character is an invisible formatting character: zero-width characters,
+/// bidirectional embeddings, overrides, and isolates, and the byte order mark.
+fn is_invisible_format(c: char) -> bool {
+    matches!(c, '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{feff}')
+}
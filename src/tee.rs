@@ -0,0 +1,81 @@
+//! Copies of the raw frames received from each node, before any processing.
+//!
+//! The frames are written exactly as received, before chunk reassembly, decoding, ANSI
+//! stripping, dedup, and filtering, so the original stream is preserved however aggressively
+//! the output is processed. Each node gets its own file; frames are separated by newlines.
+
+use log::error;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Placeholder in the tee path that is replaced by the node domain.
+const NODE_PLACEHOLDER: &str = "{node}";
+
+/// Writes the raw frames of every node to a file per node.
+pub struct RawTee {
+    pattern: String,
+    files: Mutex<HashMap<String, File>>,
+}
+
+impl RawTee {
+    /// Creates a tee writing to the path, in which `{node}` is replaced by the node domain.
+    ///
+    /// Without the placeholder, the node domain is added before the file extension.
+    pub fn new(pattern: &str) -> Self {
+        let pattern = if pattern.contains(NODE_PLACEHOLDER) {
+            pattern.to_string()
+        } else {
+            let path = Path::new(pattern);
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match path.extension() {
+                Some(ext) => format!("{stem}.{NODE_PLACEHOLDER}.{}", ext.to_string_lossy()),
+                None => format!("{stem}.{NODE_PLACEHOLDER}"),
+            };
+            path.with_file_name(name).to_string_lossy().into_owned()
+        };
+        Self {
+            pattern,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Appends a frame received from the node.
+    pub fn write(&self, node: &str, frame: &[u8]) {
+        let mut files = self.files.lock().unwrap();
+        let result = match files.get_mut(node) {
+            Some(file) => write_frame(file, frame),
+            None => self.open(node).and_then(|mut file| {
+                write_frame(&mut file, frame)?;
+                files.insert(node.to_string(), file);
+                Ok(())
+            }),
+        };
+        if let Err(e) = result {
+            error!("[{node}] Failed to write raw frame: {e}");
+        }
+    }
+
+    /// Closes the files, so that they are opened again on the next frame, e.g. after they were
+    /// moved away by log rotation.
+    pub fn reopen(&self) {
+        self.files.lock().unwrap().clear();
+    }
+
+    fn open(&self, node: &str) -> io::Result<File> {
+        let path = PathBuf::from(self.pattern.replace(NODE_PLACEHOLDER, node));
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+fn write_frame(file: &mut File, frame: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(frame.len() + 1);
+    record.extend_from_slice(frame);
+    record.push(b'\n');
+    file.write_all(&record)
+}
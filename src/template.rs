@@ -0,0 +1,144 @@
+//! A small template engine for rendering log events as lines of text.
+//!
+//! Templates contain literal text and `{field}` placeholders. Literal braces are written as
+//! `{{` and `}}`. The supported fields are:
+//!
+//! - `{ts}`: the receive time as an RFC 3339 timestamp with millisecond precision
+//! - `{mono}`: the monotonic receive time in seconds since the start of the capture
+//! - `{node}`: the domain of the boundary node that delivered the line
+//! - `{canister}`: the canister ID
+//! - `{msg}`: the log line
+//! - `{backfill}`: `[backfill] ` for replayed lines, `[backfill #<idx>] ` for replayed records
+//!   with an `idx` field, empty for live lines
+//! - `{suspect}`: `[suspect] ` for lines not confirmed by enough nodes, empty otherwise
+
+use crate::event::LogEvent;
+use std::fmt::Write;
+
+/// The template used when no `--format` is given.
+pub const DEFAULT_TEMPLATE: &str = "{backfill}{suspect}{msg}";
+
+/// Template of the output lines by default with `--subnet`, which labels every line with its
+/// canister.
+pub const SUBNET_TEMPLATE: &str = "[{canister}] {backfill}{suspect}{msg}";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Timestamp,
+    Monotonic,
+    Node,
+    Canister,
+    Message,
+    Backfill,
+    Suspect,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "ts" => Some(Self::Timestamp),
+            "mono" => Some(Self::Monotonic),
+            "node" => Some(Self::Node),
+            "canister" => Some(Self::Canister),
+            "msg" => Some(Self::Message),
+            "backfill" => Some(Self::Backfill),
+            "suspect" => Some(Self::Suspect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parses a template string.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unterminated template field {{{name}")),
+                        }
+                    }
+                    let field = Field::parse(&name)
+                        .ok_or_else(|| format!("unknown template field {{{name}}}"))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(field));
+                }
+                '}' => return Err("unmatched } in template, use }} for a literal brace".into()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Renders an event into a line of text without a trailing newline.
+    pub fn render(&self, event: &LogEvent) -> String {
+        let mut line = String::with_capacity(event.message.len() + 64);
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => line.push_str(text),
+                Segment::Field(Field::Timestamp) => line.push_str(&event.timestamp_rfc3339()),
+                Segment::Field(Field::Monotonic) => {
+                    let _ = write!(line, "{:.6}", event.monotonic_offset.as_secs_f64());
+                }
+                Segment::Field(Field::Node) => line.push_str(&event.node),
+                Segment::Field(Field::Canister) => line.push_str(&event.canister_id),
+                Segment::Field(Field::Message) => line.push_str(&event.message),
+                Segment::Field(Field::Backfill) => {
+                    if event.backfill {
+                        match event
+                            .fields
+                            .as_ref()
+                            .and_then(|fields| fields.get("idx"))
+                            .and_then(|idx| idx.as_u64())
+                        {
+                            Some(idx) => {
+                                let _ = write!(line, "[backfill #{idx}] ");
+                            }
+                            None => line.push_str("[backfill] "),
+                        }
+                    }
+                }
+                Segment::Field(Field::Suspect) => {
+                    if event.suspect {
+                        line.push_str("[suspect] ");
+                    }
+                }
+            }
+        }
+        line
+    }
+}
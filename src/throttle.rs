@@ -0,0 +1,122 @@
+//! Read throttling of the connections to a node, so that a node flooding the client cannot
+//! starve the connections to the other nodes or saturate a slow uplink.
+//!
+//! The connections of a node share a token bucket that is refilled at the configured rate and
+//! holds at most one second worth of bytes. A read may overdraw the bucket by the size of the
+//! read; the next read then waits until the debt is paid off, so the long-term rate never
+//! exceeds the limit. While a connection waits, the bytes stay in the socket buffer, and TCP
+//! flow control slows down the node.
+
+use std::future::Future;
+use std::io;
+use std::num::NonZeroU64;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep_until, Duration, Instant, Sleep};
+
+/// A token bucket limiting the bytes read per second, shared by the connections of a node.
+#[derive(Clone)]
+pub struct ReadLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Bytes that may be read right away; negative while a read overdrew the bucket.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl ReadLimiter {
+    /// Creates a limiter allowing `bytes_per_sec` bytes per second, starting with a full
+    /// bucket.
+    pub fn new(bytes_per_sec: NonZeroU64) -> Self {
+        let rate = bytes_per_sec.get() as f64;
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns when reading may continue, or `None` if it may continue right away.
+    fn resume_at(&self) -> Option<Instant> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate);
+        bucket.last_refill = now;
+        (bucket.tokens < 0.0).then(|| now + Duration::from_secs_f64(-bucket.tokens / bucket.rate))
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// A stream whose reads are limited by a [`ReadLimiter`]; writes are not limited.
+pub struct Throttled<S> {
+    inner: S,
+    limiter: Option<ReadLimiter>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wraps a stream, limiting its reads if a limiter is given.
+    pub fn new(inner: S, limiter: Option<ReadLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(limiter) = &this.limiter else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        // Other connections of the node may overdraw the bucket while this one sleeps, so the
+        // bucket is checked again after every sleep.
+        while let Some(resume_at) = limiter.resume_at() {
+            match &mut this.sleep {
+                Some(sleep) => sleep.as_mut().reset(resume_at),
+                None => this.sleep = Some(Box::pin(sleep_until(resume_at))),
+            }
+            ready!(this.sleep.as_mut().unwrap().as_mut().poll(cx));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        limiter.consume(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,66 @@
+//! Parsing of the durations and points in time given on the command line.
+//!
+//! Durations are written like `500ms`, `30s`, `15m`, or `1h30m`. A plain number counts as
+//! seconds, so flags that used to take seconds keep accepting them. Points in time are either
+//! a duration before now or an RFC 3339 timestamp such as `2024-06-01T13:00:00Z`.
+
+use humantime::DurationError;
+use std::time::{Duration, SystemTime};
+
+/// Examples appended to the errors, to show what is accepted.
+const DURATION_EXAMPLES: &str = "expected a duration like 90, 30s, 15m, or 1h30m";
+
+/// Parses a duration such as `1h30m`, or a plain number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    humantime::parse_duration(value).map_err(|e| {
+        let reason = match e {
+            DurationError::Empty => "it is empty".to_string(),
+            DurationError::InvalidCharacter(at) => {
+                format!("invalid character at position {}", at + 1)
+            }
+            DurationError::NumberExpected(at) => {
+                format!("a number is missing at position {}", at + 1)
+            }
+            DurationError::UnknownUnit { unit, .. } if unit.is_empty() => {
+                "a unit is missing after a number".to_string()
+            }
+            DurationError::UnknownUnit { unit, .. } => format!("unknown unit {unit:?}"),
+            DurationError::NumberOverflow => "it is too long".to_string(),
+        };
+        format!("invalid duration {value:?}: {reason}; {DURATION_EXAMPLES}")
+    })
+}
+
+/// Parses a duration like [`parse_duration`], rejecting zero.
+pub fn parse_positive_duration(value: &str) -> Result<Duration, String> {
+    let duration = parse_duration(value)?;
+    if duration.is_zero() {
+        return Err(format!(
+            "the duration must be positive; {DURATION_EXAMPLES}"
+        ));
+    }
+    Ok(duration)
+}
+
+/// Parses a point in time: either a duration before now (`15m`, `1h30m`) or an RFC 3339
+/// timestamp (`2024-06-01T13:00:00Z`).
+pub fn parse_time(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if value.contains(['-', ':']) {
+        return humantime::parse_rfc3339_weak(value).map_err(|_| {
+            format!(
+                "invalid timestamp {value:?}; expected an RFC 3339 timestamp like \
+                 2024-06-01T13:00:00Z"
+            )
+        });
+    }
+    let duration = parse_duration(value)
+        .map_err(|e| format!("{e}, or an RFC 3339 timestamp like 2024-06-01T13:00:00Z"))?;
+    SystemTime::now()
+        .checked_sub(duration)
+        .ok_or_else(|| format!("duration {value:?} reaches too far into the past"))
+}
@@ -0,0 +1,137 @@
+//! TLS settings of the WebSocket connections: additional trusted roots, client certificates
+//! for mutual TLS, an overridden server name, and skipping verification in lab environments.
+
+use log::warn;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The TLS settings of all WebSocket connections.
+pub struct TlsSettings {
+    pub client_config: Arc<ClientConfig>,
+    /// Name to send in SNI and verify the certificates against, instead of the node domain.
+    pub server_name: Option<ServerName<'static>>,
+}
+
+impl TlsSettings {
+    /// Builds the settings, trusting the root certificates in the `ca_certs` PEM files in
+    /// addition to the webpki roots, and authenticating with the certificate chain and private
+    /// key in the `client_cert` PEM files, if given.
+    pub fn new(
+        ca_certs: &[PathBuf],
+        client_cert: Option<(&Path, &Path)>,
+        server_name: Option<&str>,
+        insecure_skip_verify: bool,
+    ) -> Result<Self, String> {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        for path in ca_certs {
+            let certs = read_certs(path)?;
+            let (added, ignored) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("no valid certificate in {}", path.display()));
+            }
+            if ignored > 0 {
+                warn!(
+                    "Ignored {ignored} invalid certificates in {}.",
+                    path.display()
+                );
+            }
+        }
+
+        let builder = if insecure_skip_verify {
+            warn!("Accepting the certificates of all nodes without verification.");
+            let provider = CryptoProvider::get_default()
+                .cloned()
+                .ok_or("no crypto provider is installed")?;
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipVerification(provider)))
+        } else {
+            ClientConfig::builder().with_root_certificates(roots)
+        };
+        let client_config = match client_cert {
+            Some((cert_path, key_path)) => {
+                let key = PrivateKeyDer::from_pem_file(key_path)
+                    .map_err(|e| format!("failed to read {}: {e}", key_path.display()))?;
+                builder
+                    .with_client_auth_cert(read_certs(cert_path)?, key)
+                    .map_err(|e| format!("invalid client certificate or key: {e}"))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_name = server_name
+            .map(|name| ServerName::try_from(name.to_string()).map_err(|e| format!("{name}: {e}")))
+            .transpose()?;
+        Ok(Self {
+            client_config: Arc::new(client_config),
+            server_name,
+        })
+    }
+}
+
+/// Reads all certificates from a PEM file.
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Accepts any server certificate, while still checking that the server holds its key.
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,135 @@
+//! A lightweight, top-like view of the statistics, redrawn periodically on stderr.
+
+use crate::connection::ConnectionConfig;
+use crate::stats::LiveCounters;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write as _};
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant};
+
+/// Starts a task that redraws the statistics view at the given interval.
+///
+/// On a terminal, every redraw replaces the previous table; otherwise the tables are appended.
+pub fn spawn(config: Arc<ConnectionConfig>, every: Duration) {
+    tokio::spawn(async move {
+        let terminal = io::stderr().is_terminal();
+        let mut ticker = interval(every);
+        let mut previous: Option<(Instant, LiveCounters)> = None;
+        let mut drawn_lines = 0;
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let counters = config.stats.live();
+            let table = render(
+                &counters,
+                previous
+                    .as_ref()
+                    .map(|(at, counters)| (now - *at, counters)),
+            );
+
+            let mut stderr = io::stderr().lock();
+            if terminal && drawn_lines > 0 {
+                // Move the cursor to the start of the previous table and clear from there.
+                let _ = write!(stderr, "\x1b[{drawn_lines}A\x1b[J");
+            }
+            let _ = stderr.write_all(table.as_bytes());
+            let _ = stderr.flush();
+            drawn_lines = table.lines().count();
+            previous = Some((now, counters));
+        }
+    });
+}
+
+/// Renders the table of nodes and canisters, with rates relative to the previous counters.
+fn render(counters: &LiveCounters, previous: Option<(Duration, &LiveCounters)>) -> String {
+    let elapsed = previous.map_or(0.0, |(elapsed, _)| elapsed.as_secs_f64());
+    let rate = |current: u64, before: Option<u64>| match before {
+        Some(before) if elapsed > 0.0 => current.saturating_sub(before) as f64 / elapsed,
+        _ => 0.0,
+    };
+    let previous_nodes: HashMap<&str, (u64, u64)> = previous
+        .map(|(_, counters)| {
+            counters
+                .nodes
+                .iter()
+                .map(|node| (node.domain.as_str(), (node.messages, node.bytes)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let previous_canisters: HashMap<&str, u64> = previous
+        .map(|(_, counters)| {
+            counters
+                .canisters
+                .iter()
+                .map(|(canister_id, messages)| (canister_id.as_str(), *messages))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let width = counters
+        .nodes
+        .iter()
+        .map(|node| node.domain.len())
+        .chain(
+            counters
+                .canisters
+                .iter()
+                .map(|(canister_id, _)| canister_id.len()),
+        )
+        .max()
+        .unwrap_or(0)
+        .max(8);
+    let connected = counters.nodes.iter().filter(|node| node.connected).count();
+    let total_rate: f64 = counters
+        .nodes
+        .iter()
+        .map(|node| {
+            rate(
+                node.messages,
+                previous_nodes.get(node.domain.as_str()).map(|p| p.0),
+            )
+        })
+        .sum();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{connected}/{} nodes connected, {total_rate:.1} lines/s",
+        counters.nodes.len()
+    );
+    let _ = writeln!(
+        out,
+        "{:<width$}  {:<5}  {:>9}  {:>9}  {:>10}  {:>7}",
+        "NODE", "STATE", "LINES/S", "KB/S", "LINES", "DROPPED"
+    );
+    for node in &counters.nodes {
+        let before = previous_nodes.get(node.domain.as_str());
+        let _ = writeln!(
+            out,
+            "{:<width$}  {:<5}  {:>9.1}  {:>9.1}  {:>10}  {:>7}",
+            node.domain,
+            if node.connected { "up" } else { "down" },
+            rate(node.messages, before.map(|p| p.0)),
+            rate(node.bytes, before.map(|p| p.1)) / 1024.0,
+            node.messages,
+            node.dropped,
+        );
+    }
+    if !counters.canisters.is_empty() {
+        let _ = writeln!(
+            out,
+            "{:<width$}  {:>9}  {:>10}",
+            "CANISTER", "LINES/S", "LINES"
+        );
+        for (canister_id, messages) in &counters.canisters {
+            let before = previous_canisters.get(canister_id.as_str()).copied();
+            let _ = writeln!(
+                out,
+                "{canister_id:<width$}  {:>9.1}  {messages:>10}",
+                rate(*messages, before)
+            );
+        }
+    }
+    out
+}
@@ -0,0 +1,335 @@
+//! Updating the client in place from its GitHub releases with the `self-update` subcommand.
+//!
+//! Each release carries one binary per platform, named `ic-bn-logs-client-<TARGET>` after the
+//! Rust target triple, e.g. `ic-bn-logs-client-x86_64-unknown-linux-gnu`, with `.exe` on
+//! Windows, a `SHA256SUMS` file in the format of `sha256sum`, and `SHA256SUMS.minisig`, the
+//! minisign signature of the checksums by the release key, whose trusted comment names the
+//! version, e.g. `version:0.3.0`. The signature is verified against the public key embedded at
+//! build time from `IC_BN_LOGS_RELEASE_PUBLIC_KEY`, so that a release that was tampered with on
+//! GitHub is rejected, and its version against the tag of the release, so that the signed
+//! files of an older release cannot be passed off as a newer one; builds without the key can
+//! only check for updates. Releases older than the running client are only installed with
+//! `--allow-downgrade`. The binary for the platform the client was built for is then downloaded,
+//! checked against its signed checksum, and only then moved over the running executable, so
+//! that an interrupted, corrupted, or forged download never replaces a working client.
+//!
+//! On Unix, the new binary is renamed over the old one, which the running process keeps
+//! executing. Windows does not allow replacing a running executable, but allows renaming it,
+//! so the old binary is moved aside to `<NAME>.old` first, and removed by the next update.
+
+use crate::error::Error;
+use crate::proxy;
+use crate::SelfUpdateArgs;
+use log::info;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The repository whose releases are installed by default.
+pub const DEFAULT_REPOSITORY: &str = "dfinity/ic-bn-logs";
+
+/// The API of github.com.
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+
+/// The version of the running client.
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The release asset listing the SHA-256 checksums of the binaries.
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// The release asset with the minisign signature of [`CHECKSUMS_ASSET`].
+const SIGNATURE_ASSET: &str = "SHA256SUMS.minisig";
+
+/// The minisign public key of the releases, in base64 as on the second line of a `.pub` file.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("IC_BN_LOGS_RELEASE_PUBLIC_KEY");
+
+/// A release, as returned by the GitHub API.
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    /// The API URL of the asset, which also serves the assets of private repositories.
+    url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset, Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| Error::Update(format!("release {} has no {name}", self.tag_name)))
+    }
+}
+
+/// Downloads the files of the releases.
+struct Releases {
+    client: reqwest::Client,
+    api_url: String,
+    repository: String,
+    token: Option<String>,
+}
+
+impl Releases {
+    fn get(&self, url: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .get(url)
+            .header(USER_AGENT, format!("ic-bn-logs-client/{CURRENT_VERSION}"));
+        match &self.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// Fetches the release with the tag, or the latest release.
+    async fn release(&self, tag: Option<&str>) -> Result<Release, Error> {
+        let url = match tag {
+            Some(tag) => format!(
+                "{}/repos/{}/releases/tags/{tag}",
+                self.api_url, self.repository
+            ),
+            None => format!("{}/repos/{}/releases/latest", self.api_url, self.repository),
+        };
+        let response = self
+            .get(&url)
+            .header(ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .map_err(|e| Error::Update(format!("failed to fetch {url}: {e}")))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::Update(match tag {
+                Some(tag) => format!("{} has no release {tag}", self.repository),
+                None => format!("{} has no releases", self.repository),
+            }));
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|e| Error::Update(format!("failed to fetch {url}: {e}")))?;
+        response
+            .json()
+            .await
+            .map_err(|e| Error::Update(format!("invalid release from {url}: {e}")))
+    }
+
+    async fn download(&self, asset: &Asset) -> Result<Vec<u8>, Error> {
+        let failed =
+            |e: reqwest::Error| Error::Update(format!("failed to download {}: {e}", asset.name));
+        let response = self
+            .get(&asset.url)
+            .header(ACCEPT, "application/octet-stream")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(failed)?;
+        Ok(response.bytes().await.map_err(failed)?.to_vec())
+    }
+}
+
+/// Checks for a newer release and installs it, or with `--check` only reports it. Returns
+/// whether the client is up to date.
+pub async fn run(args: &SelfUpdateArgs) -> Result<bool, Error> {
+    let proxy = proxy::resolve(args.proxy.as_deref())?;
+    let releases = Releases {
+        client: proxy::http_client(proxy.as_ref())?,
+        api_url: args.api_url.trim_end_matches('/').to_string(),
+        repository: args.repository.clone(),
+        token: args.github_token.clone(),
+    };
+    let release = releases.release(args.tag.as_deref()).await?;
+    let version = release.tag_name.trim_start_matches('v');
+
+    if args.tag.is_none() && compare_versions(version, CURRENT_VERSION) != Ordering::Greater {
+        println!("ic-bn-logs-client {CURRENT_VERSION} is up to date.");
+        return Ok(true);
+    }
+    if args.check {
+        println!(
+            "ic-bn-logs-client {version} is available, {CURRENT_VERSION} is installed; run \
+             'ic-bn-logs-client self-update' to install it."
+        );
+        return Ok(false);
+    }
+
+    if compare_versions(version, CURRENT_VERSION) == Ordering::Less && !args.allow_downgrade {
+        return Err(Error::Update(format!(
+            "release {} is older than the installed {CURRENT_VERSION}; pass --allow-downgrade \
+             to install it anyway",
+            release.tag_name
+        )));
+    }
+    let name = format!(
+        "ic-bn-logs-client-{}{}",
+        env!("TARGET"),
+        std::env::consts::EXE_SUFFIX
+    );
+    let public_key = release_public_key()?;
+    let binary = release.asset(&name)?;
+    let checksums = releases.download(release.asset(CHECKSUMS_ASSET)?).await?;
+    let signature = releases.download(release.asset(SIGNATURE_ASSET)?).await?;
+    let trusted_comment = verify_signature(&public_key, &checksums, &signature).map_err(|e| {
+        Error::Update(format!(
+            "the signature of {CHECKSUMS_ASSET} of release {} is invalid: {e}",
+            release.tag_name
+        ))
+    })?;
+    match signed_version(&trusted_comment) {
+        Some(signed) if signed == version => {}
+        Some(signed) => {
+            return Err(Error::Update(format!(
+                "{CHECKSUMS_ASSET} of release {} is signed for version {signed}",
+                release.tag_name
+            )));
+        }
+        None => {
+            return Err(Error::Update(format!(
+                "the signature of {CHECKSUMS_ASSET} of release {} does not name its version",
+                release.tag_name
+            )));
+        }
+    }
+    let checksums = String::from_utf8(checksums)
+        .map_err(|_| Error::Update(format!("{CHECKSUMS_ASSET} is not text")))?;
+    let expected = checksum_of(&checksums, &name).ok_or_else(|| {
+        Error::Update(format!(
+            "{CHECKSUMS_ASSET} of release {} has no checksum for {name}",
+            release.tag_name
+        ))
+    })?;
+    info!("Downloading {name} of release {}.", release.tag_name);
+    let contents = releases.download(binary).await?;
+    let actual = hex::encode(Sha256::digest(&contents));
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::Update(format!(
+            "the checksum of {name} is {actual}, but {CHECKSUMS_ASSET} lists {expected}; the \
+             download is corrupt"
+        )));
+    }
+
+    let exe = std::env::current_exe()
+        .and_then(fs::canonicalize)
+        .map_err(Error::io("failed to locate the running executable"))?;
+    replace(&exe, &contents).map_err(Error::io(format!("failed to replace {}", exe.display())))?;
+    println!(
+        "Updated ic-bn-logs-client from {CURRENT_VERSION} to {version} at {}.",
+        exe.display()
+    );
+    Ok(true)
+}
+
+/// Returns the public key embedded at build time.
+fn release_public_key() -> Result<PublicKey, Error> {
+    let key = RELEASE_PUBLIC_KEY.ok_or_else(|| {
+        Error::Update(
+            "this build has no release public key to verify the download with; build it with \
+             IC_BN_LOGS_RELEASE_PUBLIC_KEY set, or install the release manually"
+                .to_string(),
+        )
+    })?;
+    PublicKey::from_base64(key.trim())
+        .map_err(|e| Error::Update(format!("the embedded release public key is invalid: {e}")))
+}
+
+/// Verifies the minisign signature of the checksums, returning its trusted comment.
+fn verify_signature(
+    public_key: &PublicKey,
+    checksums: &[u8],
+    signature: &[u8],
+) -> Result<String, minisign_verify::Error> {
+    let signature =
+        std::str::from_utf8(signature).map_err(|_| minisign_verify::Error::InvalidEncoding)?;
+    let signature = Signature::decode(signature)?;
+    public_key.verify(checksums, &signature, false)?;
+    Ok(signature.trusted_comment().to_string())
+}
+
+/// Returns the version in the trusted comment of a signature, given as `version:<VERSION>`
+/// among the words of the comment, without a leading `v`.
+fn signed_version(trusted_comment: &str) -> Option<&str> {
+    trusted_comment
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix("version:"))
+        .map(|version| version.trim_start_matches('v'))
+}
+
+/// Returns the checksum listed for the file in the lines of `sha256sum`, which mark binary
+/// files with an asterisk.
+fn checksum_of<'a>(checksums: &'a str, name: &str) -> Option<&'a str> {
+    checksums.lines().find_map(|line| {
+        let (checksum, file) = line.split_once(char::is_whitespace)?;
+        (file.trim_start().trim_start_matches('*') == name).then_some(checksum)
+    })
+}
+
+/// Compares two versions by their dot-separated numbers, ignoring pre-release and build
+/// suffixes; parts that are not numbers count as 0.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let numbers = |version: &str| -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    let (a, b) = (numbers(a), numbers(b));
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Writes the new binary next to the executable and moves it into place.
+fn replace(exe: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut staged = exe.as_os_str().to_owned();
+    staged.push(".new");
+    let staged = PathBuf::from(staged);
+    let mut file = File::create(&staged)?;
+    let written = file
+        .write_all(contents)
+        .and_then(|()| file.set_permissions(fs::metadata(exe)?.permissions()))
+        .and_then(|()| file.sync_all());
+    drop(file);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&staged);
+        return Err(e);
+    }
+    #[cfg(windows)]
+    {
+        let mut old = exe.as_os_str().to_owned();
+        old.push(".old");
+        let _ = fs::remove_file(&old);
+        fs::rename(exe, &old)?;
+    }
+    fs::rename(&staged, exe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_version_is_read_from_the_trusted_comment() {
+        let comment = "timestamp:1717246800 file:SHA256SUMS version:v0.3.0";
+        assert_eq!(signed_version(comment), Some("0.3.0"));
+        assert_eq!(signed_version("timestamp:1717246800 file:SHA256SUMS"), None);
+    }
+
+    #[test]
+    fn versions_compare_by_their_numbers() {
+        assert_eq!(compare_versions("0.10.0", "0.9.1"), Ordering::Greater);
+        assert_eq!(compare_versions("0.2", "0.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("0.1.9-rc.1", "0.2.0"), Ordering::Less);
+    }
+}
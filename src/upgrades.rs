@@ -0,0 +1,278 @@
+//! Annotation of canister upgrades and restarts, with `--annotate-upgrades`.
+//!
+//! A debugging session that spans an upgrade mixes the lines of two versions of the code, and
+//! nothing in the stream says where one ends. Lines that mark an upgrade or a restart, such as
+//! the logs of `post_upgrade`, are preceded by an annotation line. The module hash of every
+//! canister is read from the certified state at the poll interval, and also right after a
+//! marker or after a canister logged again following a quiet gap, as canisters are stopped
+//! while they are upgraded; a changed hash is annotated as an upgrade. Annotations are written
+//! among the lines on stdout, as text or, with `--json`, as JSON objects.
+
+use crate::clock;
+use crate::connection::ConnectionConfig;
+use crate::event::LogEvent;
+use crate::nodes::Endpoints;
+use crate::output::Output;
+use candid::Principal;
+use chrono::SecondsFormat;
+use ic_agent::{Agent, AgentError};
+use log::debug;
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+use tokio::time::{interval, timeout, Duration, Instant};
+
+/// Time without lines from a canister after which its next line triggers a read of its module
+/// hash. Markers repeated by other nodes within this time are annotated once.
+const QUIET_GAP: Duration = Duration::from_secs(30);
+/// Longest time reading the module hash of a canister may take.
+const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest part of a marker line quoted in its annotation, in characters.
+const MAX_QUOTED_CHARS: usize = 100;
+
+/// Lines that mark an upgrade or restart when no `--upgrade-pattern` is given: the upgrade and
+/// init hooks of Rust and Motoko canisters, and reinstalls.
+const DEFAULT_PATTERNS: &[&str] = &[
+    r"(?i)\b(?:pre|post)_?upgrade\b",
+    r"(?i)\bcanister_init\b",
+    r"(?i)\bcanister (?:was )?(?:re)?(?:installed|started|upgraded)\b",
+];
+
+/// The lines of a canister seen so far.
+#[derive(Default)]
+struct Canister {
+    last_line: Option<Instant>,
+    /// The last marker line and when it was annotated.
+    last_marker: Option<(String, Instant)>,
+}
+
+/// What an annotation reports.
+enum Annotation<'a> {
+    /// A line marked a restart.
+    Restarted { line: &'a str },
+    /// The module hash changed; `None` means that no code is installed.
+    Upgraded {
+        before: Option<Vec<u8>>,
+        after: Option<Vec<u8>>,
+    },
+}
+
+/// Watches the canisters for upgrades and restarts and annotates them in the output.
+pub struct UpgradeAnnotator {
+    patterns: Vec<Regex>,
+    every: Duration,
+    json: bool,
+    canisters: Mutex<HashMap<String, Canister>>,
+    /// The module hash last read for every canister, once it was read.
+    module_hashes: Mutex<HashMap<String, Option<Vec<u8>>>>,
+    /// Wakes the poller to read the module hashes right away.
+    poll: Notify,
+}
+
+impl UpgradeAnnotator {
+    /// Creates an annotator recognizing markers by the given patterns, or by the default ones
+    /// if none are given, and reading the module hashes at the given interval.
+    pub fn new(patterns: Vec<Regex>, every: Duration, json: bool) -> Self {
+        let patterns = if patterns.is_empty() {
+            DEFAULT_PATTERNS
+                .iter()
+                .map(|pattern| Regex::new(pattern).expect("the pattern is valid"))
+                .collect()
+        } else {
+            patterns
+        };
+        Self {
+            patterns,
+            every,
+            json,
+            canisters: Mutex::new(HashMap::new()),
+            module_hashes: Mutex::new(HashMap::new()),
+            poll: Notify::new(),
+        }
+    }
+
+    /// Checks a line of a canister for a marker, annotating it before the line is written, and
+    /// for a quiet gap before it.
+    pub fn observe(&self, event: &LogEvent, output: &Output) {
+        let now = Instant::now();
+        let mut canisters = self.canisters.lock().unwrap();
+        let canister = canisters.entry(event.canister_id.clone()).or_default();
+        // Backfilled lines arrive in a burst, so their gaps say nothing.
+        let gap = !event.backfill
+            && canister
+                .last_line
+                .is_some_and(|last| now.duration_since(last) >= QUIET_GAP);
+        if !event.backfill {
+            canister.last_line = Some(now);
+        }
+        if !self
+            .patterns
+            .iter()
+            .any(|pattern| pattern.is_match(&event.message))
+        {
+            if gap {
+                self.poll.notify_one();
+            }
+            return;
+        }
+        let repeated = canister.last_marker.as_ref().is_some_and(|(line, at)| {
+            *line == event.message && now.duration_since(*at) < QUIET_GAP
+        });
+        if repeated {
+            return;
+        }
+        canister.last_marker = Some((event.message.clone(), now));
+        drop(canisters);
+        self.annotate(
+            output,
+            &event.canister_id,
+            event.timestamp,
+            Annotation::Restarted {
+                line: &event.message,
+            },
+        );
+        self.poll.notify_one();
+    }
+
+    /// Records the module hash read for a canister, returning the previous one if it changed.
+    fn record_module_hash(
+        &self,
+        canister_id: &str,
+        hash: Option<Vec<u8>>,
+    ) -> Option<Option<Vec<u8>>> {
+        let mut module_hashes = self.module_hashes.lock().unwrap();
+        match module_hashes.insert(canister_id.to_string(), hash.clone()) {
+            Some(before) if before != hash => Some(before),
+            _ => None,
+        }
+    }
+
+    /// Writes an annotation among the lines on stdout.
+    fn annotate(
+        &self,
+        output: &Output,
+        canister_id: &str,
+        time: SystemTime,
+        annotation: Annotation,
+    ) {
+        let time = clock::local_time(time).to_rfc3339_opts(SecondsFormat::Millis, true);
+        if self.json {
+            let annotation = match annotation {
+                Annotation::Restarted { line } => json!({
+                    "canister_id": canister_id,
+                    "event": "restarted",
+                    "time": time,
+                    "line": line,
+                }),
+                Annotation::Upgraded { before, after } => json!({
+                    "canister_id": canister_id,
+                    "event": "upgraded",
+                    "time": time,
+                    "module_hash_before": before.map(hex::encode),
+                    "module_hash": after.map(hex::encode),
+                }),
+            };
+            output.write_text(&format!("{}\n", json!({ "annotation": annotation })));
+            return;
+        }
+        let text = match annotation {
+            Annotation::Restarted { line } => {
+                let line = line.lines().next().unwrap_or_default();
+                let quoted: String = line.chars().take(MAX_QUOTED_CHARS).collect();
+                format!("--- Canister {canister_id} restarted at {time}: {quoted} ---\n")
+            }
+            Annotation::Upgraded { before, after } => format!(
+                "--- Canister {canister_id} upgraded at {time}, module hash {} -> {} ---\n",
+                short_hash(before.as_deref()),
+                short_hash(after.as_deref())
+            ),
+        };
+        output.write_text(&text);
+    }
+}
+
+/// Abbreviates a module hash to its first 8 bytes in hex, or `none` if no code is installed.
+fn short_hash(hash: Option<&[u8]>) -> String {
+    match hash {
+        Some(hash) => hex::encode(&hash[..hash.len().min(8)]),
+        None => "none".to_string(),
+    }
+}
+
+/// Reads the module hash of a canister from its certified state, `None` if it has no code.
+async fn module_hash(agent: &Agent, canister_id: &str) -> Result<Option<Vec<u8>>, String> {
+    let principal = Principal::from_text(canister_id).map_err(|e| e.to_string())?;
+    match agent
+        .read_state_canister_info(principal, "module_hash")
+        .await
+    {
+        Ok(hash) => Ok(Some(hash)),
+        Err(AgentError::LookupPathAbsent(_)) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Starts a task that reads the module hashes of the canisters at the poll interval and when
+/// woken, and annotates the changes.
+pub fn spawn(config: Arc<ConnectionConfig>, http_client: reqwest::Client, endpoints: Endpoints) {
+    let Some(annotator) = &config.upgrades else {
+        return;
+    };
+    let every = annotator.every;
+    tokio::spawn(async move {
+        let mut agent = None;
+        let mut ticker = interval(every);
+        loop {
+            let Some(annotator) = &config.upgrades else {
+                return;
+            };
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = annotator.poll.notified() => {}
+            }
+            // While parked, the client stays off the network; changes are found once it is back.
+            if config.parked.load(Ordering::Relaxed) {
+                continue;
+            }
+            let agent = match &agent {
+                Some(agent) => agent,
+                None => match endpoints.agent(http_client.clone()).await {
+                    Ok(created) => agent.insert(created),
+                    Err(e) => {
+                        debug!("Could not read the module hashes of the canisters: {e}");
+                        continue;
+                    }
+                },
+            };
+            let canister_ids = config.canister_ids.borrow().clone();
+            for canister_id in canister_ids {
+                let hash = match timeout(POLL_TIMEOUT, module_hash(agent, &canister_id)).await {
+                    Ok(Ok(hash)) => hash,
+                    Ok(Err(e)) => {
+                        debug!("Could not read the module hash of canister {canister_id}: {e}");
+                        continue;
+                    }
+                    Err(_) => {
+                        debug!("Reading the module hash of canister {canister_id} timed out.");
+                        continue;
+                    }
+                };
+                if let Some(before) = annotator.record_module_hash(&canister_id, hash.clone()) {
+                    annotator.annotate(
+                        &config.output,
+                        &canister_id,
+                        SystemTime::now(),
+                        Annotation::Upgraded {
+                            before,
+                            after: hash,
+                        },
+                    );
+                }
+            }
+        }
+    });
+}
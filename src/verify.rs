@@ -0,0 +1,401 @@
+//! Verification of the log fan-out: every node should deliver the same lines of a canister, in
+//! the same order.
+//!
+//! Boundary node operators can check the log path after an upgrade: the `verify` subcommand
+//! connects to every node for a canister, records the lines each node delivers for a while, and
+//! compares the streams. A line counts as expected if the majority of the nodes that stayed
+//! connected delivered it. Each node is reported with the expected lines it missed, the lines
+//! it delivered out of order, and the lines only a minority delivered.
+//!
+//! Only lines first delivered by any node at least a grace period after the last node
+//! connected, and before the end of the window, are compared, since the nodes connect at
+//! slightly different times. The connections stay open for the grace period after the window,
+//! so that late lines still arrive. Identical lines are told apart by how often each node
+//! delivered them before.
+
+use crate::ansi;
+use crate::codec::{self, Codec};
+use crate::connection::{self, ConnectTimeouts, Upgrade, WsStream};
+use crate::deflate::Compression;
+use crate::endpoint::PathTemplate;
+use crate::error::Error;
+use crate::nodes;
+use crate::proxy;
+use crate::resolve::Resolver;
+use crate::tls::TlsSettings;
+use crate::VerifyArgs;
+use futures_util::StreamExt;
+use log::info;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout_at, Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+/// Most lines listed per node as examples of missed and extra lines.
+const MAX_LISTED_LINES: usize = 3;
+
+/// A line, told apart from identical lines by how often its node delivered them before.
+type Key = (Arc<str>, usize);
+
+/// The lines a node delivered, with their arrival times.
+struct Stream {
+    domain: String,
+    connected_at: Option<Instant>,
+    /// Why the connection failed or ended before the end of the verification.
+    ended: Option<String>,
+    arrivals: Vec<(Key, Instant)>,
+}
+
+/// Settings shared by all connections.
+struct Target {
+    canister_id: String,
+    endpoint: PathTemplate,
+    proxy: Option<Url>,
+    tls: TlsSettings,
+    resolver: Resolver,
+}
+
+/// The verdict on one node.
+#[derive(Serialize)]
+struct NodeVerdict {
+    domain: String,
+    /// Whether the node stayed connected and its stream was compared.
+    compared: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Compared lines the node delivered.
+    lines: usize,
+    missed: usize,
+    out_of_order: usize,
+    extra: usize,
+    missed_lines: Vec<String>,
+    extra_lines: Vec<String>,
+}
+
+impl NodeVerdict {
+    fn is_clean(&self) -> bool {
+        self.compared && self.missed == 0 && self.out_of_order == 0 && self.extra == 0
+    }
+}
+
+/// The report of the verification.
+#[derive(Serialize)]
+struct Report {
+    canister_id: String,
+    /// Lines delivered by the majority of the nodes within the window.
+    expected_lines: usize,
+    nodes: Vec<NodeVerdict>,
+}
+
+/// Runs the verification and prints the report. Returns whether all nodes stayed connected
+/// and delivered the same lines in the same order.
+pub async fn run(args: &VerifyArgs) -> Result<bool, Error> {
+    let proxy = proxy::resolve(args.proxy.as_deref())?;
+    let domains = if args.node.is_empty() {
+        let http_client = proxy::http_client(proxy.as_ref())?;
+        nodes::fetch(http_client, &args.ic.endpoints(), args.all_subnets)
+            .await?
+            .into_iter()
+            .map(|node| node.domain)
+            .collect()
+    } else {
+        args.node.clone()
+    };
+    if domains.len() < 2 {
+        return Err("at least two boundary nodes are needed to compare their streams".into());
+    }
+
+    let target = Arc::new(Target {
+        canister_id: args.canister_id.clone(),
+        endpoint: args.endpoint_path_template.clone(),
+        proxy,
+        tls: TlsSettings::new(&args.tls_ca_cert, None, None, false)?,
+        resolver: Resolver::new(args.resolve.clone(), None)?,
+    });
+    info!(
+        "Recording the lines of {} nodes for {}.",
+        domains.len(),
+        humantime::format_duration(args.duration)
+    );
+    let end = Instant::now() + args.duration;
+    let close = end + args.grace;
+    let mut tasks = JoinSet::new();
+    for (index, domain) in domains.into_iter().enumerate() {
+        let target = target.clone();
+        tasks.spawn(async move { (index, record(domain, &target, close).await) });
+    }
+    let mut streams = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        streams.push(joined?);
+    }
+    streams.sort_by_key(|(index, _)| *index);
+    let streams: Vec<Stream> = streams.into_iter().map(|(_, stream)| stream).collect();
+
+    let report = compare(args.canister_id.clone(), &streams, args.grace, end);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(report.nodes.iter().all(NodeVerdict::is_clean))
+}
+
+/// Connects to a node and records the lines it delivers until `close`.
+async fn record(domain: String, target: &Target, close: Instant) -> Stream {
+    let mut stream = Stream {
+        domain,
+        connected_at: None,
+        ended: None,
+        arrivals: Vec::new(),
+    };
+    let url = match target.endpoint.url(&stream.domain, &target.canister_id) {
+        Ok(url) => url,
+        Err(e) => {
+            stream.ended = Some(e);
+            return stream;
+        }
+    };
+    let connection = connection::connect_websocket(
+        &url,
+        target.proxy.as_ref(),
+        Upgrade {
+            compression: Compression::Off,
+            headers: &[],
+        },
+        None,
+        &target.tls,
+        &target.resolver,
+        ConnectTimeouts::DEFAULT,
+    );
+    let (mut ws, codec): (WsStream, &dyn Codec) = match timeout_at(close, connection).await {
+        Ok(Ok((ws, response))) => {
+            let default = codec::find("auto").expect("the auto codec exists");
+            let codec = codec::negotiate(&stream.domain, &response, default);
+            (ws, codec)
+        }
+        Ok(Err(e)) => {
+            stream.ended = Some(e.to_string());
+            return stream;
+        }
+        Err(_) => {
+            stream.ended = Some("the handshake did not complete in time".to_string());
+            return stream;
+        }
+    };
+    stream.connected_at = Some(Instant::now());
+
+    let mut occurrences: HashMap<Arc<str>, usize> = HashMap::new();
+    loop {
+        let payload = tokio::select! {
+            message = ws.next() => match message {
+                Some(Ok(Message::Binary(bin))) => bin.to_vec(),
+                Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+                Some(Ok(Message::Close(frame))) => {
+                    stream.ended = Some(match frame {
+                        Some(frame) => format!("closed by the node with code {}", frame.code),
+                        None => "closed by the node".to_string(),
+                    });
+                    return stream;
+                }
+                // Pings are answered by the WebSocket library while reading.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    stream.ended = Some(e.to_string());
+                    return stream;
+                }
+                None => {
+                    stream.ended = Some("closed by the node".to_string());
+                    return stream;
+                }
+            },
+            _ = sleep_until(close) => {
+                let _ = ws.close(None).await;
+                return stream;
+            }
+        };
+        let arrived = Instant::now();
+        let messages: Vec<String> = match codec.decode(&payload) {
+            Ok(records) => records
+                .into_iter()
+                .map(|record| ansi::clean(record.message, false))
+                .collect(),
+            Err(_) => vec![String::from_utf8_lossy(&payload).into_owned()],
+        };
+        for message in messages {
+            let message: Arc<str> = message.into();
+            let occurrence = occurrences.entry(message.clone()).or_default();
+            stream.arrivals.push(((message, *occurrence), arrived));
+            *occurrence += 1;
+        }
+    }
+}
+
+/// Compares the streams of the nodes over the lines first delivered between the grace period
+/// after the last node connected and `end`.
+fn compare(canister_id: String, streams: &[Stream], grace: Duration, end: Instant) -> Report {
+    let complete: Vec<&Stream> = streams
+        .iter()
+        .filter(|stream| stream.connected_at.is_some() && stream.ended.is_none())
+        .collect();
+    let start = complete
+        .iter()
+        .filter_map(|stream| stream.connected_at)
+        .max()
+        .map(|connected| connected + grace);
+
+    // When each line was first delivered, and by how many of the complete nodes.
+    let mut first_seen: HashMap<&Key, Instant> = HashMap::new();
+    let mut delivered_by: HashMap<&Key, usize> = HashMap::new();
+    let mut arrivals: HashMap<&Key, Vec<Instant>> = HashMap::new();
+    for stream in &complete {
+        for (key, arrived) in &stream.arrivals {
+            first_seen
+                .entry(key)
+                .and_modify(|first| *first = (*first).min(*arrived))
+                .or_insert(*arrived);
+            *delivered_by.entry(key).or_default() += 1;
+            arrivals.entry(key).or_default().push(*arrived);
+        }
+    }
+    let in_window = |key: &Key| {
+        start.is_some_and(|start| {
+            first_seen
+                .get(key)
+                .is_some_and(|first| *first >= start && *first <= end)
+        })
+    };
+    let quorum = complete.len() / 2 + 1;
+
+    // The expected lines in the order of their median arrival, which one slow or reordering
+    // node does not change.
+    let mut expected: Vec<(&Key, Instant)> = delivered_by
+        .iter()
+        .filter(|(key, count)| **count >= quorum && in_window(key))
+        .map(|(key, _)| {
+            let times = arrivals.get_mut(key).expect("every delivered line arrived");
+            times.sort();
+            (*key, times[times.len() / 2])
+        })
+        .collect();
+    expected.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    let rank: HashMap<&Key, usize> = expected
+        .iter()
+        .enumerate()
+        .map(|(rank, (key, _))| (*key, rank))
+        .collect();
+
+    let nodes = streams
+        .iter()
+        .map(|stream| {
+            let mut verdict = NodeVerdict {
+                domain: stream.domain.clone(),
+                compared: false,
+                error: stream.ended.clone(),
+                lines: 0,
+                missed: 0,
+                out_of_order: 0,
+                extra: 0,
+                missed_lines: Vec::new(),
+                extra_lines: Vec::new(),
+            };
+            if stream.connected_at.is_none() || stream.ended.is_some() || complete.len() < 2 {
+                if verdict.error.is_none() {
+                    verdict.error = Some("fewer than two nodes stayed connected".to_string());
+                }
+                return verdict;
+            }
+            verdict.compared = true;
+            let mut seen = HashSet::new();
+            let mut ranks = Vec::new();
+            for (key, _) in &stream.arrivals {
+                if !in_window(key) {
+                    continue;
+                }
+                verdict.lines += 1;
+                seen.insert(key);
+                match rank.get(key) {
+                    Some(rank) => ranks.push(*rank),
+                    None => {
+                        verdict.extra += 1;
+                        if verdict.extra_lines.len() < MAX_LISTED_LINES {
+                            verdict.extra_lines.push(key.0.to_string());
+                        }
+                    }
+                }
+            }
+            verdict.out_of_order = ranks.len() - longest_increasing(&ranks);
+            for (key, _) in &expected {
+                if !seen.contains(key) {
+                    verdict.missed += 1;
+                    if verdict.missed_lines.len() < MAX_LISTED_LINES {
+                        verdict.missed_lines.push(key.0.to_string());
+                    }
+                }
+            }
+            verdict
+        })
+        .collect();
+    Report {
+        canister_id,
+        expected_lines: expected.len(),
+        nodes,
+    }
+}
+
+/// Returns the length of the longest increasing subsequence; the other lines of a node are
+/// the fewest that have to move to restore the expected order.
+fn longest_increasing(ranks: &[usize]) -> usize {
+    let mut tails: Vec<usize> = Vec::new();
+    for &rank in ranks {
+        let position = tails.partition_point(|&tail| tail < rank);
+        if position == tails.len() {
+            tails.push(rank);
+        } else {
+            tails[position] = rank;
+        }
+    }
+    tails.len()
+}
+
+fn print_report(report: &Report) {
+    let width = report
+        .nodes
+        .iter()
+        .map(|node| node.domain.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "Compared {} lines of canister {}.",
+        report.expected_lines, report.canister_id
+    );
+    println!(
+        "{:<width$}  {:>8}  {:>9}  {:>6}  {:>12}  {:>5}",
+        "NODE", "COMPARED", "LINES", "MISSED", "OUT OF ORDER", "EXTRA",
+    );
+    for node in &report.nodes {
+        println!(
+            "{:<width$}  {:>8}  {:>9}  {:>6}  {:>12}  {:>5}",
+            node.domain,
+            if node.compared { "yes" } else { "no" },
+            node.lines,
+            node.missed,
+            node.out_of_order,
+            node.extra,
+        );
+    }
+    for node in &report.nodes {
+        if let Some(error) = &node.error {
+            println!("{}: not compared: {error}", node.domain);
+        }
+        for line in &node.missed_lines {
+            println!("{}: missed: {line}", node.domain);
+        }
+        for line in &node.extra_lines {
+            println!("{}: extra: {line}", node.domain);
+        }
+    }
+}
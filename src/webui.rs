@@ -0,0 +1,354 @@
+//! Local web dashboard enabled with `--web-ui`, a single page showing the live log stream, the
+//! status of the nodes, and controls to filter the stream by canister, regular expression, and
+//! `--where` expression.
+//!
+//! The page is embedded in the binary and served with two endpoints it polls and subscribes to:
+//!
+//! - `GET /api/status` returns the monitored canisters, whether printing is paused, and the
+//!   counters and connection quality of every node.
+//! - `GET /api/events` streams the events written to the output as server-sent events, each a
+//!   JSON object like the lines of `--json`. The query parameters `canister_id`, `filter`, and
+//!   `where` restrict the stream like the subscriptions of `--serve-ws`, and with `validate=1`
+//!   the parameters are only checked. A client that falls too far behind receives a `skipped`
+//!   event with the number of events it missed.
+//!
+//! Like the control interface, the dashboard has no authentication and should only listen on
+//! a loopback address. It then only answers requests for a loopback host name, so that
+//! websites cannot read the logs by rebinding their domain names to the loopback address.
+
+use crate::connection::ConnectionConfig;
+use crate::control;
+use crate::event::LogEvent;
+use crate::query::Query;
+use crate::server;
+use crate::sinks::LogSink;
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval_at, timeout, Duration, Instant};
+use url::form_urlencoded;
+
+/// The dashboard.
+const PAGE: &str = include_str!("webui.html");
+/// Number of events buffered for each client.
+const CLIENT_BUFFER: usize = 4096;
+/// Largest request head accepted.
+const MAX_REQUEST_BYTES: usize = 8192;
+/// Time within which a client must send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of the comments that keep idle event streams open through proxies.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// An event as streamed to the clients, serialized once for all of them.
+struct StreamedEvent {
+    event: LogEvent,
+    json: String,
+}
+
+/// Publishes the written events to the event streams of the dashboard.
+#[derive(Clone)]
+pub struct WebUiSink {
+    events: broadcast::Sender<Arc<StreamedEvent>>,
+}
+
+impl Default for WebUiSink {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(CLIENT_BUFFER).0,
+        }
+    }
+}
+
+impl LogSink for WebUiSink {
+    fn name(&self) -> &'static str {
+        "web UI"
+    }
+
+    /// Publishes an event to all open event streams.
+    fn write_event<'a>(&'a self, event: &'a LogEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.events.receiver_count() == 0 {
+                return;
+            }
+            // Sending only fails if the last stream closed meanwhile.
+            let _ = self.events.send(Arc::new(StreamedEvent {
+                event: event.clone(),
+                json: event.to_json().to_string(),
+            }));
+        })
+    }
+}
+
+/// Which events a client receives, from the query string of its request.
+struct Subscription {
+    canister_id: Option<String>,
+    filter: Option<Regex>,
+    condition: Option<Query>,
+}
+
+impl Subscription {
+    /// Reads the subscription from the query string, checking the canister against the
+    /// monitored ones.
+    fn parse(query: &str, config: &ConnectionConfig) -> Result<Self, (u16, String)> {
+        let mut subscription = Self {
+            canister_id: None,
+            filter: None,
+            condition: None,
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "canister_id" if !value.is_empty() => {
+                    let canister_ids = config.canister_ids.borrow();
+                    if !canister_ids.iter().any(|id| *id == value) {
+                        return Err((
+                            404,
+                            format!(
+                                "canister {value} is not monitored; monitored canisters: {}",
+                                canister_ids.join(", ")
+                            ),
+                        ));
+                    }
+                    subscription.canister_id = Some(value.into_owned());
+                }
+                "filter" if !value.is_empty() => {
+                    let filter = Regex::new(&value)
+                        .map_err(|e| (400, format!("invalid filter {value}: {e}")))?;
+                    subscription.filter = Some(filter);
+                }
+                "where" if !value.trim().is_empty() => {
+                    let condition = Query::parse(&value).map_err(|e| (400, e))?;
+                    subscription.condition = Some(condition);
+                }
+                _ => {}
+            }
+        }
+        Ok(subscription)
+    }
+
+    fn accepts(&self, event: &LogEvent) -> bool {
+        self.canister_id
+            .as_ref()
+            .is_none_or(|id| *id == event.canister_id)
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.is_match(&event.message))
+            && self
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.matches(event))
+    }
+}
+
+/// Settings shared by all connections to the server.
+struct Server {
+    events: broadcast::Sender<Arc<StreamedEvent>>,
+    config: Arc<ConnectionConfig>,
+    /// Whether requests must name a loopback host.
+    loopback: bool,
+}
+
+impl Server {
+    async fn handle(&self, mut stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        let head = match timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => return respond_json(&mut stream, 400, "malformed request").await,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return respond_json(&mut stream, 408, "request timed out").await,
+        };
+        let mut lines = head.lines();
+        let mut parts = lines.next().unwrap_or_default().split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return respond_json(&mut stream, 400, "malformed request").await;
+        };
+        if self.loopback {
+            let host = lines
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+                .map(|(_, value)| value.trim());
+            if !host.is_some_and(control::is_loopback_host) {
+                return respond_json(&mut stream, 403, "the host name is not a loopback name")
+                    .await;
+            }
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        match (method, path) {
+            ("GET", "/") => respond(&mut stream, 200, "text/html; charset=utf-8", PAGE).await,
+            ("GET", "/api/status") => {
+                let body = self.status().to_string();
+                respond(&mut stream, 200, "application/json", &body).await
+            }
+            ("GET", "/api/events") => match Subscription::parse(query, &self.config) {
+                Err((status, e)) => respond_json(&mut stream, status, &e).await,
+                Ok(_) if query.split('&').any(|pair| pair == "validate=1") => {
+                    respond(&mut stream, 200, "application/json", "{}").await
+                }
+                Ok(subscription) => self.stream(stream, peer, subscription).await,
+            },
+            ("GET", _) => respond_json(&mut stream, 404, "not found").await,
+            (method, _) => {
+                let e = format!("method {method} is not allowed");
+                respond_json(&mut stream, 405, &e).await
+            }
+        }
+    }
+
+    /// Returns the canisters, pause state, and nodes shown by the dashboard.
+    fn status(&self) -> Value {
+        let config = &self.config;
+        let live = config.stats.live();
+        let health = config.health.snapshot();
+        let nodes: Vec<Value> = live
+            .nodes
+            .iter()
+            .map(|node| {
+                let health = health
+                    .iter()
+                    .find(|(domain, _)| *domain == node.domain)
+                    .map(|(_, health)| health);
+                json!({
+                    "domain": node.domain,
+                    "connected": node.connected,
+                    "messages": node.messages,
+                    "bytes": node.bytes,
+                    "dropped": node.dropped,
+                    "rtt_ms": health.and_then(|health| health.rtt_ms),
+                    "lag_ms": health.and_then(|health| health.lag_ms),
+                    "clock_offset_ms": health.and_then(|health| health.clock_offset_ms),
+                    "stalls": health.map_or(0.0, |health| health.stalls),
+                })
+            })
+            .collect();
+        json!({
+            "canisters": *config.canister_ids.borrow(),
+            "paused": config.output.is_paused(),
+            "nodes": nodes,
+        })
+    }
+
+    /// Streams the subscribed events to a client until it disconnects.
+    async fn stream(
+        &self,
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        subscription: Subscription,
+    ) -> io::Result<()> {
+        let mut events = self.events.subscribe();
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .await?;
+        info!("Web dashboard client {peer} subscribed to the log stream.");
+        let mut keepalive = interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL);
+        let mut buf = [0; 64];
+        let result = loop {
+            let chunk = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if subscription.accepts(&event.event) => {
+                        format!("data: {}\n\n", event.json)
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Web dashboard client {peer} fell behind, skipped {skipped} events.");
+                        format!("event: skipped\ndata: {skipped}\n\n")
+                    }
+                    Err(RecvError::Closed) => break Ok(()),
+                },
+                _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+                // The client sends nothing more; reading notices when it disconnects.
+                read = stream.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => break Ok(()),
+                    Ok(_) => continue,
+                },
+            };
+            if let Err(e) = stream.write_all(chunk.as_bytes()).await {
+                break Err(e);
+            }
+        };
+        info!("Web dashboard client {peer} unsubscribed from the log stream.");
+        result
+    }
+}
+
+/// Reads the head of a request, or returns None if it is malformed.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        if let Some(at) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            return Ok(Some(String::from_utf8_lossy(&request[..at]).into_owned()));
+        }
+        if request.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n{body}",
+        control::reason(status),
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn respond_json(stream: &mut TcpStream, status: u16, error: &str) -> io::Result<()> {
+    let body = json!({ "error": error }).to_string();
+    respond(stream, status, "application/json", &body).await
+}
+
+/// Starts the dashboard on the address, streaming the events published through the sink.
+pub async fn serve(
+    addr: SocketAddr,
+    sink: &WebUiSink,
+    config: Arc<ConnectionConfig>,
+) -> io::Result<()> {
+    // Bind before returning, so that an unavailable address is reported as a startup error.
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Web dashboard listening on http://{}.",
+        listener.local_addr()?
+    );
+    if !addr.ip().is_loopback() {
+        warn!(
+            "The web dashboard on {addr} has no authentication; anyone who can reach it can \
+             read the logs."
+        );
+    }
+    let server = Arc::new(Server {
+        events: sink.events.clone(),
+        config,
+        loopback: addr.ip().is_loopback(),
+    });
+    server::spawn_accept_loop(listener, "Web dashboard", move |stream, peer| {
+        let server = server.clone();
+        async move {
+            if let Err(e) = server.handle(stream, peer).await {
+                warn!("Web dashboard request from {peer} failed: {e}");
+            }
+        }
+    });
+    Ok(())
+}
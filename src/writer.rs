@@ -0,0 +1,338 @@
+//! The output actor: a dedicated thread that writes the log events to stdout and the local
+//! files.
+//!
+//! The connections send complete events to the thread, which renders them and writes every
+//! line to stdout, the output file, and the split outputs at once and in the same order, so
+//! that lines of different nodes can neither interleave within a line nor appear in different
+//! orders in different destinations. Printing to stdout can be paused, holding back the lines.
+//!
+//! Flushing stdout after every line keeps the latency minimal, but costs a system call per
+//! line, which limits the throughput at high message rates. With batching, the writer collects
+//! the lines for stdout and writes them out together once the batch is full or its oldest
+//! line has waited for the flush interval, whichever comes first.
+//!
+//! When the reader of stdout goes away, e.g. `head` has seen enough lines, stdout is given up
+//! on and the client is told, so that it can shut down cleanly or carry on with the other
+//! destinations.
+
+use crate::event::LogEvent;
+use crate::memory::{self, Reservation};
+use crate::output::{LocalOutput, Resumed};
+use log::error;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Commands that may wait for the writer before senders block, so that a slow reader of stdout
+/// slows down the client instead of filling the memory.
+const CHANNEL_CAPACITY: usize = 10_000;
+
+/// Size from which a batch is written out regardless of its line count and age.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// Flush interval used with `--batch-lines` when `--flush-interval` is not given.
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When batched lines are written out.
+#[derive(Clone, Copy)]
+pub struct Batching {
+    lines: usize,
+    interval: Duration,
+}
+
+impl Batching {
+    /// Writes every line as soon as it arrives.
+    pub const IMMEDIATE: Self = Self {
+        lines: 1,
+        interval: Duration::ZERO,
+    };
+
+    /// Batches of up to `lines` lines, written at the latest `interval` after their first line.
+    /// Without either, every line is written immediately; without a line count, batches are
+    /// only limited by time.
+    pub fn new(lines: Option<NonZeroUsize>, interval: Option<Duration>) -> Self {
+        if lines.is_none() && interval.is_none() {
+            return Self::IMMEDIATE;
+        }
+        Self {
+            lines: lines.map_or(usize::MAX, NonZeroUsize::get),
+            interval: interval.unwrap_or(DEFAULT_FLUSH_INTERVAL),
+        }
+    }
+}
+
+/// Changes the destinations before the first event.
+type Configure = Box<dyn FnOnce(&mut LocalOutput) + Send>;
+
+enum Command {
+    Configure(Configure),
+    Event(LogEvent),
+    /// Text printed to stdout as is, even while paused.
+    Text(Vec<u8>),
+    Pause,
+    Resume(mpsc::Sender<Resumed>),
+    Reopen(mpsc::Sender<io::Result<()>>),
+    /// Writes out the pending lines, finishes the output file, and reports how that went.
+    Close(mpsc::Sender<io::Result<()>>),
+}
+
+/// Numbers of lines held back and dropped while paused, readable from other threads.
+#[derive(Default)]
+struct PauseCounters {
+    buffered: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Sends events to the output thread.
+pub struct OutputWriter {
+    sender: SyncSender<Command>,
+    counters: Arc<PauseCounters>,
+    /// Set once the reader of stdout is gone.
+    stdout_closed: watch::Receiver<bool>,
+}
+
+impl OutputWriter {
+    pub fn spawn(local: LocalOutput) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        let counters = Arc::new(PauseCounters::default());
+        let (closed, stdout_closed) = watch::channel(false);
+        let writer = Writer {
+            local,
+            stdout: Stdout {
+                failed: false,
+                closed,
+            },
+            batch: Vec::new(),
+            lines: 0,
+            deadline: None,
+            backlog: None,
+            counters: counters.clone(),
+        };
+        thread::Builder::new()
+            .name("output-writer".to_string())
+            .spawn(move || writer.run(receiver))
+            .expect("failed to spawn the output writer thread");
+        Self {
+            sender,
+            counters,
+            stdout_closed,
+        }
+    }
+
+    /// Waits until the reader of stdout is gone.
+    pub async fn stdout_closed(&self) {
+        let mut stdout_closed = self.stdout_closed.clone();
+        let _ = stdout_closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Changes the destinations; takes effect before the events sent afterwards.
+    pub fn configure(&self, configure: impl FnOnce(&mut LocalOutput) + Send + 'static) {
+        let _ = self.sender.send(Command::Configure(Box::new(configure)));
+    }
+
+    /// Queues an event, blocking while the writer is too far behind.
+    pub fn write(&self, event: LogEvent) {
+        let _ = self.sender.send(Command::Event(event));
+    }
+
+    /// Queues text to print to stdout among the lines.
+    pub fn write_text(&self, text: Vec<u8>) {
+        let _ = self.sender.send(Command::Text(text));
+    }
+
+    /// Holds back the lines for stdout from the events queued afterwards.
+    pub fn pause(&self) {
+        let _ = self.sender.send(Command::Pause);
+    }
+
+    /// Prints the held back lines and resumes printing, waiting until the lines are queued
+    /// for stdout.
+    pub fn resume(&self) -> Resumed {
+        let (done, resumed) = mpsc::channel();
+        if self.sender.send(Command::Resume(done)).is_ok()
+            && let Ok(resumed) = resumed.recv()
+        {
+            return resumed;
+        }
+        Resumed {
+            printed: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Returns the number of lines held back and dropped since pausing.
+    pub fn backlog(&self) -> (usize, u64) {
+        (
+            self.counters.buffered.load(Ordering::Relaxed),
+            self.counters.dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Opens the output files again once the events queued before are written.
+    pub fn reopen(&self) -> io::Result<()> {
+        self.request(Command::Reopen)
+    }
+
+    /// Writes out all queued events and finishes the output file, waiting until it is done.
+    pub fn close(&self) -> io::Result<()> {
+        self.request(Command::Close)
+    }
+
+    fn request(&self, command: fn(mpsc::Sender<io::Result<()>>) -> Command) -> io::Result<()> {
+        let (done, result) = mpsc::channel();
+        if self.sender.send(command(done)).is_err() {
+            return Ok(());
+        }
+        result.recv().unwrap_or(Ok(()))
+    }
+}
+
+/// Lines held back while printing to stdout is paused, counting against the memory budget.
+#[derive(Default)]
+struct Backlog {
+    lines: VecDeque<(Vec<u8>, Reservation)>,
+    /// Lines not printed because the backlog or the memory budget was full.
+    dropped: u64,
+}
+
+/// The state of the output thread.
+struct Writer {
+    local: LocalOutput,
+    stdout: Stdout,
+    /// Lines waiting to be written to stdout together.
+    batch: Vec<u8>,
+    lines: usize,
+    /// When the batch is written out at the latest.
+    deadline: Option<Instant>,
+    /// The lines held back while paused.
+    backlog: Option<Backlog>,
+    counters: Arc<PauseCounters>,
+}
+
+impl Writer {
+    fn run(mut self, receiver: Receiver<Command>) {
+        loop {
+            let command = match self.deadline {
+                Some(deadline) => {
+                    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(command) => command,
+                        // The oldest line waited for the flush interval.
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.write_out();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(command) => command,
+                    Err(_) => break,
+                },
+            };
+            match command {
+                Command::Configure(configure) => configure(&mut self.local),
+                Command::Event(event) => {
+                    if let Some(line) = self.local.write(&event) {
+                        self.print(line);
+                    }
+                }
+                Command::Text(text) => {
+                    self.batch.extend_from_slice(&text);
+                    self.write_out();
+                }
+                Command::Pause => {
+                    self.backlog.get_or_insert_with(Backlog::default);
+                }
+                Command::Resume(done) => {
+                    let backlog = self.backlog.take().unwrap_or_default();
+                    let printed = backlog.lines.len();
+                    for (line, _) in backlog.lines {
+                        self.batch.extend_from_slice(&line);
+                    }
+                    self.write_out();
+                    self.counters.buffered.store(0, Ordering::Relaxed);
+                    self.counters.dropped.store(0, Ordering::Relaxed);
+                    let _ = done.send(Resumed {
+                        printed,
+                        dropped: backlog.dropped,
+                    });
+                }
+                Command::Reopen(done) => {
+                    let _ = done.send(self.local.reopen());
+                }
+                Command::Close(done) => {
+                    self.write_out();
+                    let _ = done.send(self.local.finish());
+                }
+            }
+        }
+        self.write_out();
+    }
+
+    /// Prints a line to stdout, or holds it back while paused.
+    fn print(&mut self, line: Vec<u8>) {
+        if let Some(backlog) = &mut self.backlog {
+            if backlog.lines.len() < self.local.pause_buffer_size
+                && let Some(reservation) = memory::reserve(line.len())
+            {
+                backlog.lines.push_back((line, reservation));
+                self.counters.buffered.fetch_add(1, Ordering::Relaxed);
+            } else {
+                backlog.dropped += 1;
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+        let due = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + self.local.batching.interval);
+        self.batch.extend_from_slice(&line);
+        self.lines += 1;
+        if self.lines >= self.local.batching.lines
+            || self.batch.len() >= MAX_BATCH_BYTES
+            || Instant::now() >= due
+        {
+            self.write_out();
+        }
+    }
+
+    /// Writes out the batch.
+    fn write_out(&mut self) {
+        self.stdout.write(&mut self.batch);
+        self.lines = 0;
+        self.deadline = None;
+    }
+}
+
+/// Stdout, given up on after the first failed write.
+struct Stdout {
+    failed: bool,
+    /// Set when a write failed because the reader is gone.
+    closed: watch::Sender<bool>,
+}
+
+impl Stdout {
+    /// Writes and clears the batch with a single write where possible.
+    fn write(&mut self, batch: &mut Vec<u8>) {
+        if !batch.is_empty() && !self.failed {
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout.write_all(batch).and_then(|()| stdout.flush()) {
+                // The reader of the pipe is gone, e.g. `head` has seen enough lines.
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    self.closed.send_replace(true);
+                } else {
+                    error!("Failed to write to stdout: {e}");
+                }
+                self.failed = true;
+            }
+        }
+        batch.clear();
+    }
+}